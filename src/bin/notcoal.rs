@@ -1,21 +1,38 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use mailparse::MailHeaderMap;
+use notcoal::edit::RulesFile;
 use notcoal::*;
 use notmuch::{ConfigKey, Database, DatabaseMode};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt::Write as _;
+use std::io::{stdout, IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process;
+use std::process::{self, Command, Stdio};
+use std::rc::Rc;
 
 #[derive(Parser, Debug)]
 #[command(name = "notcoal", about = "notmuch filters, not made from coal.")]
 struct Opt {
     #[arg(short, long = "config")]
-    /// Configuration file [default: same as notmuch]
-    config: Option<PathBuf>,
+    /// Configuration file [default: same as notmuch]. Repeat to filter the
+    /// databases of several configurations in one run, everything else uses
+    /// the first one
+    config: Vec<PathBuf>,
     #[arg(short, long = "filters")]
     /// Rule file [default: $notmuchdb/.notmuch/hooks/notcoal-rules.json]
     filters: Option<PathBuf>,
     #[arg(short, long = "tag", default_value = "new")]
-    /// Tag to query
+    /// Tag to query, an empty one meaning the same as --all
     tag: String,
+    #[arg(long = "all")]
+    /// Don't select messages by the query tag at all, but by --query
+    all: bool,
+    #[arg(long = "query", value_name = "QUERY")]
+    /// notmuch query selecting the messages to filter with --all, e.g. `*`
+    /// for every message
+    query: Option<String>,
     #[arg(long = "leave-tag")]
     /// Leave the "query tag" in place instead of removing once all filters ran
     leave: bool,
@@ -24,6 +41,434 @@ struct Opt {
     flags: Option<bool>,
     #[arg(long = "dry-run")]
     dry: bool,
+    #[arg(long = "normalize-tags")]
+    /// Normalize tags to Unicode NFC before applying them
+    normalize: bool,
+    #[arg(long = "no-color")]
+    /// Don't color output, even if stdout is a terminal
+    no_color: bool,
+    #[arg(long = "no-pager")]
+    /// Don't pipe long output into $PAGER
+    no_pager: bool,
+    #[arg(long = "output", value_enum, default_value = "text")]
+    /// Output format of dry runs, `list` and `classify`, `sexp` being meant
+    /// for Emacs
+    output: Output,
+    #[arg(long = "body-limit", value_name = "KIB")]
+    /// Only read this many KiB of each message when matching bodies and
+    /// attachments, counting the whole file rather than each part
+    body_limit: Option<usize>,
+    #[arg(long = "no-mmap")]
+    /// Read message files instead of memory-mapping them, e.g. on network
+    /// filesystems or if other programs may truncate message files while
+    /// they're mapped, which crashes notcoal with SIGBUS
+    no_mmap: bool,
+    #[arg(long = "cache")]
+    /// Skip messages that were processed with the same rules before,
+    /// remembering matches in $XDG_STATE_HOME/notcoal/matches.json
+    cache: bool,
+    #[arg(long = "timing")]
+    /// Report how long each filter and special field took to match at the
+    /// end of the run
+    timing: bool,
+    #[arg(long = "max-children", value_name = "N")]
+    /// Run at most N `run` commands at the same time, waiting for earlier
+    /// ones to exit before spawning more
+    max_children: Option<usize>,
+    #[arg(long = "max-deletes", value_name = "N")]
+    /// Delete, move or copy at most N messages per run, skipping (and keeping
+    /// the query tag of) any others [default: notcoal.max_deletes of the notmuch config, or
+    /// 100]
+    max_deletes: Option<usize>,
+    #[arg(long = "yes-really")]
+    /// Delete, move and copy as many messages as the rules say, ignoring
+    /// --max-deletes
+    yes_really: bool,
+    #[arg(long = "first-match-only")]
+    /// Stop processing a message once a filter matched it
+    first_match_only: bool,
+    #[arg(long = "shadow-prefix")]
+    /// Add tags under this prefix instead of changing the real ones, skipping
+    /// `run` and `del` and leaving the query tag in place
+    shadow: Option<String>,
+    #[arg(long = "fail-if-destructive")]
+    /// With --dry-run or `plan`, exit with status 3 if any operation that
+    /// can't be undone by changing tags back (like `run` or `del`) would be
+    /// applied, e.g. to check changes to the rules in CI
+    fail_if_destructive: bool,
+    #[command(subcommand)]
+    cmd: Option<Cmd>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Apply a single filter to all messages matching a query, leaving the
+    /// query tag and all other filters alone
+    Backfill {
+        #[arg(long = "filter")]
+        /// Name of the filter to apply
+        filter: String,
+        #[arg(short, long = "query")]
+        /// notmuch query selecting the messages to apply the filter to
+        query: String,
+    },
+    /// Rename a tag on all messages as well as in the rules file
+    Retag {
+        #[arg(long = "from")]
+        /// Tag to rename
+        from: String,
+        #[arg(long = "to")]
+        /// New name of the tag
+        to: String,
+        #[arg(short, long = "query")]
+        /// Only rename the tag on messages matching this notmuch query
+        query: Option<String>,
+    },
+    /// List all filters and their operations, in the order they're applied
+    List,
+    /// Give snoozed messages that are due their `inbox` and `unread` tags
+    /// back, tag sent messages still without a reply as `needs-followup` and
+    /// send digests that are due, meant to be run periodically, e.g. from cron
+    Wake,
+    /// Show which filters match the messages in a maildir or file and the tags
+    /// they'd end up with, without any notmuch database
+    Simulate {
+        #[arg(long = "maildir")]
+        /// Maildir, directory, mbox or single message to read messages from
+        maildir: PathBuf,
+    },
+    /// Print the operations filters would apply, without applying them
+    Plan {
+        #[arg(long = "format", value_enum, default_value = "json")]
+        /// Output format, `batch` being input for `notmuch tag --batch`
+        format: PlanFormat,
+    },
+    /// Apply a plan written by `plan`
+    ApplyPlan {
+        #[arg(long = "remote", value_name = "[USER@]HOST")]
+        /// Apply the tag operations to the database on another machine, by
+        /// running `notmuch tag --batch` over ssh
+        remote: Option<String>,
+        /// File containing the plan, `-` for stdin
+        file: PathBuf,
+    },
+    /// Show which filters match messages, without applying them
+    ///
+    /// Without a query, the message is read from stdin and looked up in the
+    /// database by its Message-ID.
+    Classify {
+        #[arg(long = "format", value_enum, default_value = "text")]
+        /// Output format, `mutt` prints the message with an added
+        /// `X-Notcoal` header, for use as a mutt `display_filter`
+        format: ClassifyFormat,
+        #[arg(short, long = "query", num_args = 1..)]
+        /// notmuch query selecting the messages to classify
+        query: Vec<String>,
+    },
+    /// Evaluate every filter against messages, showing each condition, the
+    /// values it inspected and whether it held, without applying anything
+    Trace {
+        /// notmuch query selecting the messages, e.g. `id:<Message-ID>`
+        #[arg(required = true)]
+        query: Vec<String>,
+    },
+    /// Show which filters matched messages in earlier runs with `--cache`,
+    /// along with their operations
+    Why {
+        /// notmuch query selecting the messages, e.g. `id:<Message-ID>`
+        #[arg(required = true)]
+        query: Vec<String>,
+    },
+    /// Append a new filter to the rules file, creating it if necessary
+    AddRule(Box<NewRule>),
+    /// Escape text for use in a pattern, so characters like `.` match
+    /// themselves instead of any character
+    Escape {
+        #[arg(long = "json")]
+        /// Print it as a JSON string, ready to be pasted into the rules file
+        json: bool,
+        /// Text to match literally, e.g. an email address
+        text: String,
+    },
+    /// Check the whole setup for problems, from the notmuch configuration to
+    /// the commands filters run
+    Doctor,
+    /// Reports helping to maintain the rules file
+    Report {
+        #[arg(short, long = "output", default_value = "text")]
+        /// Output format: text, markdown or html
+        output: Format,
+        #[command(subcommand)]
+        report: Report,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Output {
+    Text,
+    Sexp,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PlanFormat {
+    Json,
+    Batch,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ClassifyFormat {
+    Text,
+    Sexp,
+    Mutt,
+}
+
+/// The filter `add-rule` appends to the rules file
+#[derive(Args, Debug)]
+struct NewRule {
+    #[arg(long = "name")]
+    /// Name of the new filter
+    name: Option<String>,
+    #[arg(long = "desc")]
+    /// Description of the new filter
+    desc: Option<String>,
+    #[arg(long = "from-message", value_name = "QUERY")]
+    /// Base the rule on the mailing list or sender domain of the message
+    /// matching this query, e.g. `id:<msgid>`
+    from_message: Option<String>,
+    #[arg(short, long = "yes")]
+    /// Don't ask for confirmation when using --from-message
+    yes: bool,
+    #[arg(long = "from")]
+    /// Pattern matching the From header
+    from: Option<String>,
+    #[arg(long = "to")]
+    /// Pattern matching the To header
+    to: Option<String>,
+    #[arg(long = "subject")]
+    /// Pattern matching the Subject header
+    subject: Option<String>,
+    #[arg(long = "from-literal", value_name = "TEXT", conflicts_with = "from")]
+    /// Text the From header has to contain, escaped so e.g. `.` only
+    /// matches a dot
+    from_literal: Option<String>,
+    #[arg(long = "to-literal", value_name = "TEXT", conflicts_with = "to")]
+    /// Text the To header has to contain, escaped like --from-literal
+    to_literal: Option<String>,
+    #[arg(
+        long = "subject-literal",
+        value_name = "TEXT",
+        conflicts_with = "subject"
+    )]
+    /// Text the Subject header has to contain, escaped like --from-literal
+    subject_literal: Option<String>,
+    #[arg(long = "rule", value_name = "FIELD=PATTERN")]
+    /// Pattern matching any other header or special field, e.g.
+    /// `@tags=^work$`
+    rules: Vec<String>,
+    #[arg(long = "add", value_delimiter = ',')]
+    /// Tags to add, separated by commas
+    add: Vec<String>,
+    #[arg(long = "rm", value_delimiter = ',')]
+    /// Tags to remove, separated by commas
+    rm: Vec<String>,
+    #[arg(long = "dry")]
+    /// Only observe what the filter matches, see `"dry"`
+    dry: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Report {
+    /// Compare tags in the database against tags added by filters
+    Tags,
+    /// List messages no filter matches
+    Coverage {
+        #[arg(short, long = "query", num_args = 1.., required = true)]
+        /// notmuch query selecting the messages to check
+        query: Vec<String>,
+        #[arg(long = "by-domain")]
+        /// Count messages by sender domain instead of listing them
+        by_domain: bool,
+    },
+    /// Count messages by mailing list or sender domain
+    Senders {
+        #[arg(short, long = "query", num_args = 1.., required = true)]
+        /// notmuch query selecting the messages to count
+        query: Vec<String>,
+    },
+    /// Count matches by filter and list the most recent ones
+    Filters {
+        #[arg(short, long = "query", num_args = 1.., required = true)]
+        /// notmuch query selecting the messages to check
+        query: Vec<String>,
+        #[arg(long = "recent", default_value = "20")]
+        /// How many of the most recent matches to list
+        recent: usize,
+    },
+}
+
+/// ANSI colors for human readable output, if enabled
+struct Style {
+    color: bool,
+}
+
+impl Style {
+    fn new(no_color: bool) -> Self {
+        let color = !no_color && env::var_os("NO_COLOR").is_none() && stdout().is_terminal();
+        Style { color }
+    }
+
+    fn paint(&self, code: &str, s: &str) -> String {
+        if self.color {
+            format!("\x1b[{code}m{s}\x1b[0m")
+        } else {
+            s.to_string()
+        }
+    }
+
+    fn filter(&self, s: &str) -> String {
+        self.paint("1", s)
+    }
+
+    /// Describes what a filter's operations do, e.g. `+tag -tag run DELETE`
+    fn operations(&self, op: &Operations) -> String {
+        let mut parts = Vec::new();
+        for tag in op.added_tags() {
+            parts.push(self.paint("32", &format!("+{tag}")));
+        }
+        for tag in op.removed_tags() {
+            parts.push(self.paint("31", &format!("-{tag}")));
+        }
+        if let Some(capture) = &op.run_capture {
+            let cmd = capture.cmd.join(" ");
+            parts.push(self.paint("33", &format!("capture:{cmd}")));
+        }
+        if let Some(argv) = &op.run {
+            parts.push(self.paint("33", &format!("run:{}", argv.join(" "))));
+        }
+        if op.calendar.is_some() {
+            parts.push(self.paint("33", "calendar"));
+        }
+        if let Some(duration) = &op.snooze {
+            parts.push(self.paint("36", &format!("snooze:{duration}")));
+        }
+        if let Some(duration) = &op.expect_reply {
+            parts.push(self.paint("36", &format!("expect_reply:{duration}")));
+        }
+        for plugin in op.plugins.iter().flatten() {
+            parts.push(self.paint("33", &format!("plugin:{plugin}")));
+        }
+        if let Some(digest) = &op.digest {
+            parts.push(self.paint("36", &format!("digest:{}", digest.group)));
+        }
+        if let Some(export) = &op.export {
+            parts.push(self.paint("33", &format!("export:{}", export.to)));
+        }
+        if let Some(folder) = &op.copy_to {
+            parts.push(self.paint("33", &format!("copy:{folder}")));
+        }
+        if let Some(folder) = &op.move_to {
+            parts.push(self.paint("33", &format!("move:{folder}")));
+        }
+        if op.del == Some(true) {
+            parts.push(self.paint("1;41", "DELETE"));
+        }
+        parts.join(" ")
+    }
+}
+
+/// Quotes a string as an Emacs Lisp string
+fn sexp_str(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Emacs Lisp list of strings, `nil` if empty
+fn sexp_list<S: AsRef<str>>(items: &[S]) -> String {
+    if items.is_empty() {
+        return "nil".to_string();
+    }
+    let items: Vec<_> = items.iter().map(|s| sexp_str(s.as_ref())).collect();
+    format!("({})", items.join(" "))
+}
+
+fn sexp_bool(b: bool) -> &'static str {
+    if b {
+        "t"
+    } else {
+        "nil"
+    }
+}
+
+/// What every filter matched, as list of property lists, `nil` if empty
+fn sexp_spans(matches: &[FilterMatch]) -> String {
+    let spans: Vec<_> = matches
+        .iter()
+        .flat_map(|m| m.spans.iter().map(move |s| (&m.filter, s)))
+        .map(|(filter, s)| {
+            format!(
+                "(:filter {} :field {} :value {} :start {} :end {} :text {})",
+                sexp_str(filter),
+                sexp_str(&s.field),
+                s.value,
+                s.start,
+                s.end,
+                sexp_str(&s.text)
+            )
+        })
+        .collect();
+    match spans.is_empty() {
+        true => "nil".to_string(),
+        false => format!("({})", spans.join(" ")),
+    }
+}
+
+fn filter_names(matches: &[FilterMatch]) -> Vec<String> {
+    matches.iter().map(|m| m.filter.clone()).collect()
+}
+
+/// A filter as property list, like notmuch's `--format=sexp`
+fn sexp_filter(filter: &Filter) -> String {
+    let op = filter.operations();
+    format!(
+        "(:name {} :add {} :rm {} :run {} :delete {} :dry {})",
+        sexp_str(&filter.name()),
+        sexp_list(&op.added_tags()),
+        sexp_list(&op.removed_tags()),
+        sexp_list(op.run.as_deref().unwrap_or_default()),
+        sexp_bool(op.del == Some(true)),
+        sexp_bool(filter.is_dry()),
+    )
+}
+
+/// Print output, piping it through `$PAGER` (`less` by default) if stdout is a
+/// terminal
+///
+/// Like git, `LESS` defaults to `FRX`, so the pager exits right away if the
+/// output fits on one screen.
+fn page(out: &str, no_pager: bool) {
+    if no_pager || !stdout().is_terminal() {
+        print!("{out}");
+        return;
+    }
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less".to_string());
+    let child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(&pager)
+        .env(
+            "LESS",
+            env::var("LESS").unwrap_or_else(|_| "FRX".to_string()),
+        )
+        .stdin(Stdio::piped())
+        .spawn();
+    match child {
+        Ok(mut child) => {
+            if let Some(mut stdin) = child.stdin.take() {
+                // the pager may have been quit before reading everything
+                let _ = stdin.write_all(out.as_bytes());
+            }
+            let _ = child.wait();
+        }
+        Err(_) => print!("{out}"),
+    }
 }
 
 pub fn get_maildir_sync_db(db: &Database) -> bool {
@@ -37,71 +482,1504 @@ pub fn get_maildir_sync_db(db: &Database) -> bool {
     }
 }
 
-pub fn get_filters(path: &Option<PathBuf>, db: &Database) -> Vec<Filter> {
-    let mut p: PathBuf;
-    let filter_path = match path {
-        Some(p) => p,
-        None => {
-            p = match db.config(ConfigKey::HookDir) {
-                Some(path) => PathBuf::from(path),
-                None => {
-                    eprintln!("Could not determine notmuch hooks directory, aborting!");
-                    process::exit(1);
-                }
-            };
-            p.push("notcoal-rules.json");
-            &p
+pub fn get_filters_path(path: &Option<PathBuf>, db: &Database) -> PathBuf {
+    match path {
+        Some(p) => p.clone(),
+        None => {
+            let mut p = match db.config(ConfigKey::HookDir) {
+                Some(path) => PathBuf::from(path),
+                None => {
+                    eprintln!("Could not determine notmuch hooks directory, aborting!");
+                    process::exit(1);
+                }
+            };
+            p.push("notcoal-rules.json");
+            p
+        }
+    }
+}
+
+pub fn get_filters(path: &Path) -> Vec<Filter> {
+    match filters_from_file(&path) {
+        Ok(f) => f,
+        Err(e) => {
+            // using {} here results in stack overflow when getting a JSONError…
+            eprintln!("Couldn't load filters: {:?}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn print_warnings(warnings: &[error::Warning]) {
+    for warning in warnings {
+        eprintln!("Warning: {warning}");
+    }
+}
+
+fn print_outcome_errors(outcome: &RunOutcome) {
+    print_warnings(&outcome.warnings);
+    for e in &outcome.errors {
+        eprintln!("Error: {e}");
+    }
+}
+
+fn print_command_output(output: &[CommandOutput]) {
+    for o in output {
+        let code = match o.code {
+            Some(code) => format!("exited with {code}"),
+            None => "killed".to_string(),
+        };
+        println!(
+            "{} ran `{}` for {}, {code}:",
+            o.filter,
+            o.cmd.join(" "),
+            o.id
+        );
+        for out in o.stdout.iter().chain(&o.stderr) {
+            print!("{out}");
+            if !out.is_empty() && !out.ends_with('\n') {
+                println!();
+            }
+        }
+    }
+}
+
+fn open_cache(filters: &[Filter]) -> RefCell<MatchCache> {
+    let mut path = match dirs::state_dir().or_else(dirs::cache_dir) {
+        Some(path) => path,
+        None => {
+            eprintln!("Could not determine where to keep the cache, aborting!");
+            process::exit(1);
+        }
+    };
+    path.push("notcoal");
+    path.push("matches.json");
+    match MatchCache::open(&path, filters) {
+        Ok(cache) => RefCell::new(cache),
+        Err(e) => {
+            eprintln!("Couldn't read cache: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn save_cache(options: &FilterOptions) {
+    if let Some(cache) = &options.cache {
+        if let Err(e) = cache.borrow().save() {
+            eprintln!("Couldn't write cache: {e}");
+        }
+    }
+    if let Some(snoozes) = &options.snoozes {
+        if let Err(e) = snoozes.borrow().save() {
+            eprintln!("Couldn't write snoozed messages: {e}");
+        }
+    }
+    if let Some(followups) = &options.followups {
+        if let Err(e) = followups.borrow().save() {
+            eprintln!("Couldn't write messages waiting for a reply: {e}");
+        }
+    }
+    if let Some(senders) = &options.senders {
+        if let Err(e) = senders.borrow().save() {
+            eprintln!("Couldn't write sender statistics: {e}");
+        }
+    }
+    if let Some(digests) = &options.digests {
+        if let Err(e) = digests.borrow().save() {
+            eprintln!("Couldn't write digests: {e}");
+        }
+    }
+}
+
+fn state_file(name: &str, what: &str) -> PathBuf {
+    let mut path = match dirs::state_dir().or_else(dirs::data_dir) {
+        Some(path) => path,
+        None => {
+            eprintln!("Could not determine where to keep {what}, aborting!");
+            process::exit(1);
+        }
+    };
+    path.push("notcoal");
+    path.push(name);
+    path
+}
+
+fn open_snoozes() -> RefCell<Snoozes> {
+    let path = state_file("snoozed.json", "snoozed messages");
+    match Snoozes::open(&path) {
+        Ok(snoozes) => RefCell::new(snoozes),
+        Err(e) => {
+            eprintln!("Couldn't read snoozed messages: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn open_digests() -> RefCell<Digests> {
+    let path = state_file("digests.json", "digests");
+    match Digests::open(&path) {
+        Ok(digests) => RefCell::new(digests),
+        Err(e) => {
+            eprintln!("Couldn't read digests: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn open_sender_history() -> SenderHistory {
+    let path = state_file("senders.json", "sender statistics");
+    match SenderHistory::open(&path) {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("Couldn't read sender statistics: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Loads the plugins filters refer to from the `plugins` directory in
+/// notcoal's data directory, registering those used in rules
+fn load_plugins(filters: &mut [Filter]) -> BTreeMap<String, Rc<Plugin>> {
+    let mut names: Vec<String> = filters
+        .iter()
+        .flat_map(|f| &f.rules)
+        .flat_map(|r| r.keys())
+        .filter_map(|k| k.trim_start_matches('!').strip_prefix("@plugin:"))
+        .map(|n| n.to_string())
+        .collect();
+    names.extend(
+        filters
+            .iter()
+            .flat_map(|f| f.op.plugins.iter().flatten().cloned()),
+    );
+    names.sort();
+    names.dedup();
+    let mut plugins = BTreeMap::new();
+    for name in names {
+        let mut path = match dirs::data_dir() {
+            Some(path) => path,
+            None => {
+                eprintln!("Could not determine where to find plugins, aborting!");
+                process::exit(1);
+            }
+        };
+        path.push("notcoal");
+        path.push("plugins");
+        path.push(format!("{name}.wasm"));
+        let plugin = match Plugin::load(&name, &path) {
+            Ok(plugin) => Rc::new(plugin),
+            Err(e) => {
+                eprintln!("Couldn't load plugin {name} from {}: {e}", path.display());
+                process::exit(1);
+            }
+        };
+        if let Err(e) = register_matcher(filters, &plugin.field(), plugin.clone()) {
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+        plugins.insert(name, plugin);
+    }
+    plugins
+}
+
+/// Checks if any rule has a condition on `field`, negated or not
+fn uses_field(filters: &[Filter], field: &str) -> bool {
+    filters
+        .iter()
+        .flat_map(|f| &f.rules)
+        .flat_map(|r| r.keys())
+        .any(|k| k.trim_start_matches('!') == field)
+}
+
+fn open_followups() -> RefCell<FollowUps> {
+    let path = state_file("followups.json", "messages waiting for a reply");
+    match FollowUps::open(&path) {
+        Ok(followups) => RefCell::new(followups),
+        Err(e) => {
+            eprintln!("Couldn't read messages waiting for a reply: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn run_wake(db: &Database) {
+    let snoozes = open_snoozes();
+    let res = wake(db, &mut snoozes.borrow_mut());
+    match res {
+        Ok((woken, warnings)) => {
+            print_warnings(&warnings);
+            if let Err(e) = snoozes.borrow().save() {
+                eprintln!("Couldn't write snoozed messages: {e}");
+                process::exit(1);
+            }
+            println!(
+                "Woke {} messages, {} still snoozed",
+                woken.len(),
+                snoozes.borrow().len()
+            );
+        }
+        Err(e) => {
+            // messages woken before the error have their tags back already
+            let _ = snoozes.borrow().save();
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+    }
+    let followups = open_followups();
+    let res = check_followups(db, &mut followups.borrow_mut());
+    match res {
+        Ok((tagged, warnings)) => {
+            print_warnings(&warnings);
+            if let Err(e) = followups.borrow().save() {
+                eprintln!("Couldn't write messages waiting for a reply: {e}");
+                process::exit(1);
+            }
+            println!(
+                "Tagged {} messages as needing a follow-up, {} still waiting for a reply",
+                tagged.len(),
+                followups.borrow().len()
+            );
+        }
+        Err(e) => {
+            // messages tagged before the error aren't waiting anymore
+            let _ = followups.borrow().save();
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+    }
+    let digests = open_digests();
+    let res = send_digests(db, &mut digests.borrow_mut());
+    match res {
+        Ok((sent, warnings)) => {
+            print_warnings(&warnings);
+            if let Err(e) = digests.borrow().save() {
+                eprintln!("Couldn't write digests: {e}");
+                process::exit(1);
+            }
+            println!(
+                "Sent {} digests, {} messages still collected",
+                sent.len(),
+                digests.borrow().len()
+            );
+        }
+        Err(e) => {
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn print_timings(timings: &Timings) {
+    for (title, times) in [("filter", &timings.filters), ("field", &timings.fields)] {
+        if times.is_empty() {
+            continue;
+        }
+        let mut times: Vec<_> = times.iter().collect();
+        times.sort_by(|a, b| b.1.cmp(a.1));
+        let width = times.iter().map(|(n, _)| n.chars().count()).max();
+        eprintln!("Time spent per {title}:");
+        for (name, time) in times {
+            let pad = " ".repeat(width.unwrap_or(0) - name.chars().count());
+            eprintln!("  {name}{pad}  {:>10.3}ms", time.as_secs_f64() * 1000.0);
+        }
+    }
+}
+
+fn run_backfill(
+    db: &Database,
+    mut options: FilterOptions,
+    filters: Vec<Filter>,
+    name: &str,
+    query: &str,
+    cache: bool,
+) {
+    let filter = match filters.into_iter().find(|f| f.name() == name) {
+        Some(f) => f,
+        None => {
+            eprintln!("No filter named {name}");
+            process::exit(1);
+        }
+    };
+    let filters = [filter];
+    if cache {
+        options.cache = Some(open_cache(&filters));
+    }
+    let progress = |done, total| eprint!("\rProcessed {done}/{total} messages");
+    let res = backfill(db, query, &options, &filters, progress);
+    save_cache(&options);
+    match res {
+        Ok(outcome) => {
+            eprintln!();
+            print_outcome_errors(&outcome);
+            print_command_output(&outcome.output);
+            print_timings(&outcome.timings);
+            println!("Applied {name} {} times", outcome.applied);
+            if outcome.cached > 0 {
+                println!("Skipped {} messages processed before", outcome.cached);
+            }
+            if !outcome.is_success() {
+                eprintln!("Failed to process {} messages", outcome.errors.len());
+                process::exit(2);
+            }
+        }
+        Err(e) => {
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn run_retag(db: &Database, path: &Option<PathBuf>, from: &str, to: &str, query: &Option<String>) {
+    let path = get_filters_path(path, db);
+    let mut renamed = 0;
+    let res = RulesFile::open(&path).and_then(|mut rules| {
+        rules.update_all(|f| renamed += f.rename_tag(from, to))?;
+        if renamed > 0 {
+            rules.save(&path)?;
+        }
+        Ok(())
+    });
+    if let Err(e) = res {
+        eprintln!("Couldn't rewrite filters: {e}");
+        process::exit(1);
+    }
+    println!("Renamed {renamed} occurrences in {}", path.display());
+    match retag(db, from, to, query.as_deref()) {
+        Ok(n) => println!("Retagged {n} messages"),
+        Err(e) => {
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Turns a list of tags into what would be written in the rules file
+fn tags_value(tags: &[String]) -> Option<Value> {
+    match tags {
+        [] => None,
+        [tag] => Some(Value::Single(tag.clone())),
+        tags => Some(Value::Multiple(tags.to_vec())),
+    }
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no
+fn confirm(question: &str) -> bool {
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim(), "y" | "Y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+/// Suggests a rule for the single message a query is supposed to select
+fn suggest_rule(
+    db: &Database,
+    query: &str,
+) -> error::Result<Option<(String, BTreeMap<String, Value>)>> {
+    let q = db.create_query(query)?;
+    let mut msgs = q.search_messages()?;
+    match (msgs.next(), msgs.next()) {
+        (Some(msg), None) => edit::suggest_rule(&msg),
+        _ => Err(error::Error::UnsupportedQuery(format!(
+            "{query} has to match exactly one message"
+        ))),
+    }
+}
+
+fn run_add_rule(db: &Database, path: &Path, new: &NewRule) {
+    let NewRule {
+        name,
+        desc,
+        from_message,
+        yes,
+        from,
+        to,
+        subject,
+        from_literal,
+        to_literal,
+        subject_literal,
+        rules,
+        add,
+        rm,
+        dry,
+    } = new;
+    let mut rule = BTreeMap::new();
+    let mut suggested_name = None;
+    if let Some(query) = from_message {
+        match suggest_rule(db, query) {
+            Ok(Some((name, suggested))) => {
+                suggested_name = Some(name);
+                rule = suggested;
+            }
+            Ok(None) => {
+                eprintln!("Message has neither a List-Id nor a sender domain");
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Oops: {e}");
+                process::exit(1);
+            }
+        }
+    }
+    let headers = [
+        ("from", from, from_literal),
+        ("to", to, to_literal),
+        ("subject", subject, subject_literal),
+    ];
+    for (field, pattern, literal) in headers {
+        let pattern = pattern
+            .clone()
+            .or_else(|| literal.as_deref().map(regex::escape));
+        if let Some(pattern) = pattern {
+            rule.insert(field.to_string(), Value::Single(pattern));
+        }
+    }
+    for r in rules {
+        match r.split_once('=') {
+            Some((field, pattern)) => {
+                rule.insert(field.to_string(), Value::Single(pattern.to_string()));
+            }
+            None => {
+                eprintln!("Rules need to look like FIELD=PATTERN, got {r}");
+                process::exit(1);
+            }
+        }
+    }
+    if rule.is_empty() {
+        eprintln!("A filter needs at least one rule");
+        process::exit(1);
+    }
+
+    let mut filter = Filter::new();
+    if let Some(name) = name.as_ref().or(suggested_name.as_ref()) {
+        filter.set_name(name);
+    }
+    filter.desc = desc.clone();
+    filter.rules.push(rule);
+    filter.op.add = tags_value(add);
+    filter.op.rm = tags_value(rm);
+    if *dry {
+        filter.dry = Some(true);
+    }
+    let name = filter.name();
+
+    if from_message.is_some() && !yes {
+        match serde_json::to_string_pretty(&filter) {
+            Ok(json) => println!("{json}"),
+            Err(e) => {
+                eprintln!("Oops: {e:?}");
+                process::exit(1);
+            }
+        }
+        if !confirm(&format!("Add this filter to {}?", path.display())) {
+            process::exit(1);
+        }
+    }
+
+    let res = RulesFile::open_or_new(&path).and_then(|mut rules| {
+        rules.add(filter)?;
+        rules.save(&path)
+    });
+    match res {
+        Ok(()) => println!("Added {name} to {}", path.display()),
+        Err(e) => {
+            eprintln!("Couldn't add filter: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Reads a message from stdin, returning it along with a query for its
+/// Message-ID
+fn read_message() -> (Vec<u8>, String) {
+    let mut buf = Vec::new();
+    if let Err(e) = std::io::stdin().read_to_end(&mut buf) {
+        eprintln!("Couldn't read message: {e}");
+        process::exit(1);
+    }
+    let id = mailparse::parse_headers(&buf)
+        .ok()
+        .and_then(|(headers, _)| headers.get_first_value("Message-ID"));
+    let id = match id {
+        Some(id) => id
+            .trim()
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+            .to_string(),
+        None => {
+            eprintln!("Message has no Message-ID");
+            process::exit(1);
+        }
+    };
+    (buf, format!("id:\"{}\"", id.replace('"', "\"\"")))
+}
+
+fn run_classify(db: &Database, filters: &[Filter], format: ClassifyFormat, query: &[String]) {
+    let (message, query) = if query.is_empty() {
+        let (buf, query) = read_message();
+        (Some(buf), query)
+    } else {
+        (None, query.join(" "))
+    };
+    let classified = match classify(db, &query, filters) {
+        Ok((classified, warnings)) => {
+            print_warnings(&warnings);
+            classified
+        }
+        Err(e) => {
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+    };
+    match format {
+        ClassifyFormat::Text => {
+            for (id, matches) in classified {
+                println!("{id}  {}", filter_names(&matches).join(" "));
+            }
+        }
+        ClassifyFormat::Sexp => {
+            let items: Vec<_> = classified
+                .iter()
+                .map(|(id, matches)| {
+                    format!(
+                        "(:id {} :filters {} :spans {})",
+                        sexp_str(id),
+                        sexp_list(&filter_names(matches)),
+                        sexp_spans(matches)
+                    )
+                })
+                .collect();
+            println!("({})", items.join("\n "));
+        }
+        ClassifyFormat::Mutt => {
+            let mut out = stdout().lock();
+            for (_, matches) in classified.iter().filter(|(_, m)| !m.is_empty()) {
+                let _ = writeln!(out, "X-Notcoal: {}", filter_names(matches).join(", "));
+            }
+            if let Some(buf) = message {
+                let _ = out.write_all(&buf);
+            }
+        }
+    }
+}
+
+fn run_trace(db: &Database, filters: &[Filter], query: &str, style: &Style) -> String {
+    let traced = match trace(db, query, filters) {
+        Ok((traced, warnings)) => {
+            print_warnings(&warnings);
+            traced
+        }
+        Err(e) => {
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+    };
+    if traced.is_empty() {
+        eprintln!("No messages match {query:?}");
+        process::exit(1);
+    }
+    let mut out = String::new();
+    for (id, filter_traces) in traced {
+        let _ = writeln!(out, "{id}");
+        for ft in filter_traces {
+            let result = match ft.matched {
+                true => style.paint("32", "MATCH"),
+                false => style.paint("2", "no match"),
+            };
+            let _ = writeln!(out, "  {}  {result}", style.filter(&ft.filter));
+            let rules = filters
+                .iter()
+                .find(|f| f.name() == ft.filter)
+                .map(|f| &f.rules);
+            for step in ft.steps {
+                let patterns = rules
+                    .and_then(|r| r.get(step.rule)?.get(&step.field))
+                    .and_then(|v| serde_json::to_string(v).ok())
+                    .unwrap_or_default();
+                let values = match step.values.is_empty() {
+                    true => "(none)".to_string(),
+                    false => step
+                        .values
+                        .iter()
+                        .map(|v| format!("{:?}", truncate(v, 80)))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                };
+                let held = match step.matched {
+                    true => style.paint("32", "yes"),
+                    false => style.paint("31", "no"),
+                };
+                let _ = writeln!(
+                    out,
+                    "    rule {}: {} {values} =~ {patterns} -> {held}",
+                    step.rule, step.field
+                );
+                for span in &step.spans {
+                    let _ = writeln!(
+                        out,
+                        "      matched {:?} at {}..{} of value {}",
+                        truncate(&span.text, 80),
+                        span.start,
+                        span.end,
+                        span.value
+                    );
+                }
+            }
+        }
+    }
+    out
+}
+
+fn run_why(db: &Database, filters: &[Filter], query: &str, style: &Style) -> String {
+    let cache = open_cache(filters).into_inner();
+    let ids = db
+        .create_query(query)
+        .and_then(|q| q.search_messages())
+        .map(|msgs| msgs.map(|m| m.id().to_string()).collect::<Vec<_>>());
+    let ids = match ids {
+        Ok(ids) if ids.is_empty() => {
+            eprintln!("No messages match {query:?}");
+            process::exit(1);
+        }
+        Ok(ids) => ids,
+        Err(e) => {
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+    };
+    let mut out = String::new();
+    for id in ids {
+        let _ = writeln!(out, "{id}");
+        let names = match cache.get(db, &id) {
+            Some(names) => names,
+            None => {
+                let _ = writeln!(out, "  not recorded since the rules last changed");
+                continue;
+            }
+        };
+        if names.is_empty() {
+            let _ = writeln!(out, "  no filter matched");
+        }
+        for name in names {
+            let filter = filters.iter().find(|f| &f.name() == name);
+            let op = filter
+                .map(|f| style.operations(&f.operations()))
+                .unwrap_or_default();
+            let _ = writeln!(out, "  {}  {op}", style.filter(name));
+        }
+    }
+    out
+}
+
+/// Shortens `s` to at most `max` characters, marking that it was cut
+fn truncate(s: &str, max: usize) -> String {
+    match s.char_indices().nth(max) {
+        Some((i, _)) => format!("{}…", &s[..i]),
+        None => s.to_string(),
+    }
+}
+
+/// Sums up tag changes and irreversible operations, e.g. `Tag changes: 3`
+/// and `Irreversible operations: del 1, run 2`
+fn op_summary(counts: &OpCounts) -> String {
+    let irreversible: Vec<_> = counts
+        .irreversible
+        .iter()
+        .map(|(name, n)| format!("{name} {n}"))
+        .collect();
+    let irreversible = match irreversible.is_empty() {
+        true => "none".to_string(),
+        false => irreversible.join(", "),
+    };
+    format!(
+        "Tag changes: {}\nIrreversible operations: {irreversible}\n",
+        counts.tag_changes
+    )
+}
+
+/// Exits with status 3 for --fail-if-destructive if there are irreversible
+/// operations
+fn check_destructive(counts: &OpCounts, fail_if_destructive: bool) {
+    if fail_if_destructive && counts.is_destructive() {
+        eprintln!("Irreversible operations would be applied, failing as asked");
+        process::exit(3);
+    }
+}
+
+fn run_plan(
+    db: &Database,
+    options: &FilterOptions,
+    selection: &Selection,
+    filters: &[Filter],
+    format: PlanFormat,
+    fail_if_destructive: bool,
+) {
+    let res = match selection {
+        Selection::Tag(tag) => plan(db, tag, filters),
+        Selection::Query(query) => plan_query(db, query, filters),
+    };
+    let res = res.and_then(|(plan, warnings)| {
+        print_warnings(&warnings);
+        let out = match format {
+            PlanFormat::Json => serde_json::to_string_pretty(&plan)? + "\n",
+            PlanFormat::Batch => {
+                let (batch, warnings) = plan.tag_batch(options);
+                print_warnings(&warnings);
+                batch
+            }
+        };
+        Ok((out, plan.op_counts()))
+    });
+    match res {
+        Ok((out, counts)) => {
+            print!("{out}");
+            // stdout is meant for machines
+            eprint!("{}", op_summary(&counts));
+            check_destructive(&counts, fail_if_destructive);
+        }
+        Err(e) => {
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Pipes a plan's tag operations into `notmuch tag --batch` on `host`
+fn apply_plan_remote(plan: &Plan, options: &FilterOptions, host: &str) {
+    // ssh would take it for an option
+    if host.starts_with('-') {
+        eprintln!("Invalid host {host:?}");
+        process::exit(1);
+    }
+    let (batch, warnings) = plan.tag_batch(options);
+    print_warnings(&warnings);
+    let child = Command::new("ssh")
+        .arg("--")
+        .arg(host)
+        .arg("notmuch tag --batch")
+        .stdin(Stdio::piped())
+        .spawn();
+    let status = child.and_then(|mut child| {
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(batch.as_bytes())?;
+        }
+        child.wait()
+    });
+    match status {
+        Ok(status) if status.success() => {
+            println!(
+                "Applied {} lines of tag operations on {host}",
+                batch.lines().count()
+            );
+        }
+        Ok(status) => {
+            eprintln!("notmuch tag on {host} failed: {status}");
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Couldn't run ssh: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn run_apply_plan(db: &Database, mut options: FilterOptions, file: &Path, remote: Option<&str>) {
+    let mut buf = Vec::new();
+    let res = if file == Path::new("-") {
+        std::io::stdin().read_to_end(&mut buf)
+    } else {
+        std::fs::File::open(file).and_then(|mut f| f.read_to_end(&mut buf))
+    };
+    if let Err(e) = res {
+        eprintln!("Couldn't read plan: {e}");
+        process::exit(1);
+    }
+    let plan: Plan = match serde_json::from_slice(&buf) {
+        Ok(plan) => plan,
+        Err(e) => {
+            eprintln!("Couldn't parse plan: {e}");
+            process::exit(1);
+        }
+    };
+    if let Some(host) = remote {
+        apply_plan_remote(&plan, &options, host);
+        return;
+    }
+    let snooze = |m: &PlannedMessage| m.ops.iter().any(|p| p.op.snooze.is_some());
+    if plan.messages.iter().any(snooze) {
+        options.snoozes = Some(open_snoozes());
+    }
+    let expect_reply = |m: &PlannedMessage| m.ops.iter().any(|p| p.op.expect_reply.is_some());
+    if plan.messages.iter().any(expect_reply) {
+        options.followups = Some(open_followups());
+    }
+    let digest = |m: &PlannedMessage| m.ops.iter().any(|p| p.op.digest.is_some());
+    if plan.messages.iter().any(digest) {
+        options.digests = Some(open_digests());
+    }
+    let res = plan.apply(db, &options);
+    save_cache(&options);
+    match res {
+        Ok(outcome) => {
+            print_outcome_errors(&outcome);
+            print_command_output(&outcome.output);
+            println!(
+                "Applied {} of {} planned operations",
+                outcome.applied,
+                plan.len()
+            );
+            if !outcome.is_success() {
+                eprintln!("Failed to process {} messages", outcome.errors.len());
+                process::exit(2);
+            }
+        }
+        Err(e) => {
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn run_report(db: &Database, filters: &[Filter], report: &Report, format: Format) -> String {
+    let res = match report {
+        Report::Tags => tag_report(db, filters).map(|r| ("Tags", r.tables())),
+        Report::Coverage { query, by_domain } => coverage_report(db, &query.join(" "), filters)
+            .map(|r| {
+                print_warnings(&r.warnings);
+                ("Coverage", r.tables(*by_domain))
+            }),
+        Report::Senders { query } => sender_report(db, &query.join(" "), filters).map(|r| {
+            print_warnings(&r.warnings);
+            ("Senders", r.tables())
+        }),
+        Report::Filters { query, recent } => filter_report(db, &query.join(" "), filters, *recent)
+            .map(|r| {
+                print_warnings(&r.warnings);
+                ("Filters", r.tables())
+            }),
+    };
+    match res {
+        Ok((title, tables)) => render(&format!("notcoal: {title}"), &tables, format),
+        Err(e) => {
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn run_list(filters: &[Filter], style: &Style, output: Output) -> String {
+    if output == Output::Sexp {
+        let items: Vec<_> = in_phase_order(filters).map(sexp_filter).collect();
+        return format!("({})\n", items.join("\n "));
+    }
+    let mut out = String::new();
+    let width = filters
+        .iter()
+        .map(|f| f.name().chars().count())
+        .max()
+        .unwrap_or(0);
+    for filter in in_phase_order(filters) {
+        let name = filter.name();
+        let pad = " ".repeat(width - name.chars().count());
+        let _ = writeln!(
+            out,
+            "{}{pad}  {}",
+            style.filter(&name),
+            style.operations(&filter.operations())
+        );
+    }
+    out
+}
+
+/// Path of the notmuch config file notmuch itself would use
+fn notmuch_config(opt: &Opt) -> Option<PathBuf> {
+    opt.config
+        .first()
+        .cloned()
+        .or_else(|| env::var_os("NOTMUCH_CONFIG").map(PathBuf::from))
+        .or_else(|| {
+            let xdg = dirs::config_dir()?.join("notmuch/default/config");
+            let home = dirs::home_dir()?.join(".notmuch-config");
+            [xdg, home].into_iter().find(|p| p.exists())
+        })
+}
+
+/// Looks up `key` in the `[notcoal]` section of the notmuch config file
+fn notcoal_config(opt: &Opt, key: &str) -> Option<String> {
+    let config = std::fs::read_to_string(notmuch_config(opt)?).ok()?;
+    let mut section = "";
+    for line in config.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim();
+        } else if let Some((k, v)) = line.split_once('=') {
+            if section == "notcoal" && k.trim() == key {
+                return Some(v.trim().to_string());
+            }
+        }
+    }
+    None
+}
+
+/// Messages a run may delete, from `--max-deletes` or the notmuch config
+fn max_deletes(opt: &Opt) -> usize {
+    if let Some(max) = opt.max_deletes {
+        return max;
+    }
+    match notcoal_config(opt, "max_deletes") {
+        Some(max) => max.parse().unwrap_or_else(|_| {
+            eprintln!("notcoal.max_deletes must be a number, not {max:?}");
+            process::exit(1);
+        }),
+        None => 100,
+    }
+}
+
+fn open_db(config: Option<&PathBuf>, mode: DatabaseMode) -> Database {
+    match Database::open_with_config::<&Path, _>(None, mode, config, None) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("Could not open notmuch database, aborting!");
+            eprintln!("Error: {err}");
+            eprintln!("Do you have notmuch configured?");
+            process::exit(1);
+        }
+    }
+}
+
+/// Results of `notcoal doctor`, printed as they come in
+struct Checkup {
+    style: Style,
+    failed: bool,
+}
+
+impl Checkup {
+    fn ok(&self, what: &str) {
+        println!("{}  {what}", self.style.paint("32", " ok "));
+    }
+
+    fn warn(&self, what: &str, hint: &str) {
+        println!("{}  {what}", self.style.paint("33", "warn"));
+        println!("      {hint}");
+    }
+
+    fn fail(&mut self, what: &str, hint: &str) {
+        self.failed = true;
+        println!("{}  {what}", self.style.paint("31", "FAIL"));
+        println!("      {hint}");
+    }
+}
+
+/// Checks if `path` is a file anyone may execute
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Finds a command the way the shell would
+fn find_command(cmd: &str) -> Option<PathBuf> {
+    if cmd.contains('/') {
+        let path = PathBuf::from(cmd);
+        return is_executable(&path).then_some(path);
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(cmd))
+        .find(|path| is_executable(path))
+}
+
+fn run_doctor(opt: &Opt) {
+    let mut c = Checkup {
+        style: Style::new(opt.no_color),
+        failed: false,
+    };
+
+    match &notmuch_config(opt) {
+        Some(path) if path.exists() => c.ok(&format!("notmuch config {}", path.display())),
+        Some(path) => c.fail(
+            &format!("notmuch config {} doesn't exist", path.display()),
+            "Run `notmuch setup` or point --config at your notmuch config",
+        ),
+        None => c.warn(
+            "No notmuch config found",
+            "notmuch may still work with its defaults, otherwise run `notmuch setup`",
+        ),
+    }
+
+    let db = match Database::open_with_config::<&Path, _>(
+        None,
+        DatabaseMode::ReadOnly,
+        opt.config.first(),
+        None,
+    ) {
+        Ok(db) => {
+            c.ok(&format!("notmuch database {}", db.path().display()));
+            db
+        }
+        Err(e) => {
+            c.fail(
+                &format!("Can't open notmuch database: {e}"),
+                "Check database.path in your notmuch config and run `notmuch new` once",
+            );
+            process::exit(1);
         }
     };
 
-    match filters_from_file(filter_path) {
-        Ok(f) => f,
+    match db.config(ConfigKey::NewTags) {
+        Some(tags) if tags.split(';').any(|t| t.trim() == opt.tag) => {
+            c.ok(&format!("New messages are tagged {}", opt.tag))
+        }
+        _ => c.fail(
+            &format!("New messages aren't tagged {}", opt.tag),
+            &format!("Add it with `notmuch config set new.tags {}`", opt.tag),
+        ),
+    }
+
+    match db.config(ConfigKey::HookDir) {
+        Some(dir) => {
+            let hook = Path::new(&dir).join("post-new");
+            let script = std::fs::read_to_string(&hook).unwrap_or_default();
+            if !hook.exists() {
+                c.fail(
+                    &format!("No post-new hook at {}", hook.display()),
+                    "Create it, calling notcoal, to filter new mail automatically",
+                );
+            } else if !is_executable(&hook) {
+                c.fail(
+                    &format!("post-new hook {} isn't executable", hook.display()),
+                    &format!("Run `chmod +x {}`", hook.display()),
+                );
+            } else if !script.contains("notcoal") {
+                c.warn(
+                    &format!("post-new hook {} doesn't mention notcoal", hook.display()),
+                    "Make sure it runs notcoal, possibly through another script",
+                );
+            } else {
+                c.ok(&format!("post-new hook {}", hook.display()));
+            }
+        }
+        None => c.warn(
+            "Could not determine notmuch hooks directory",
+            "Pass the rules file with --filters",
+        ),
+    }
+
+    let path = get_filters_path(&opt.filters, &db);
+    let filters = match filters_from_file(&path) {
+        Ok(filters) => {
+            c.ok(&format!("{} filters in {}", filters.len(), path.display()));
+            filters
+        }
         Err(e) => {
-            // using {} here results in stack overflow when getting a JSONError…
-            eprintln!("Couldn't load filters: {:?}", e);
+            c.fail(
+                &format!("Can't load filters from {}: {e:?}", path.display()),
+                "Fix the rules file, every tag and regular expression is checked",
+            );
             process::exit(1);
         }
+    };
+
+    for lint in lint(&filters) {
+        c.warn(&lint.to_string(), &lint.hint);
+    }
+
+    for filter in &filters {
+        let commands = filter
+            .op
+            .run
+            .iter()
+            .chain(filter.op.run_capture.iter().map(|c| &c.cmd));
+        for argv in commands {
+            let cmd = argv.first().map(|c| c.as_str()).unwrap_or_default();
+            match find_command(cmd) {
+                Some(path) => c.ok(&format!("{}: runs {}", filter.name(), path.display())),
+                None => c.fail(
+                    &format!("{}: command {cmd:?} not found", filter.name()),
+                    "Install it, use an absolute path or make it executable",
+                ),
+            }
+        }
+    }
+
+    if c.failed {
+        process::exit(1);
+    }
+}
+
+/// Collects all message files in a maildir (or any other directory), skipping
+/// hidden files and `tmp` directories
+fn maildir_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if name != "tmp" {
+                maildir_files(&path, files)?;
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn run_simulate(path: &Path, filters: &[Filter], tag: &str, leave: bool, style: &Style) -> String {
+    let mut files = Vec::new();
+    let res = if path.is_dir() {
+        maildir_files(path, &mut files)
+    } else {
+        files.push(path.to_path_buf());
+        Ok(())
+    };
+    if let Err(e) = res {
+        eprintln!("Couldn't read {}: {e}", path.display());
+        process::exit(1);
+    }
+    let mut out = String::new();
+    let mut warnings = Vec::new();
+    let mut mails = Vec::new();
+    for file in &files {
+        let res = MailFile::is_mbox(file).and_then(|mbox| {
+            if mbox {
+                MailFile::open_mbox(file)
+            } else {
+                MailFile::open(file).map(|mail| vec![mail])
+            }
+        });
+        match res {
+            Ok(mut m) => mails.append(&mut m),
+            Err(e) => {
+                let w = format!("{}: {e}", file.display());
+                warnings.push(error::Warning::SkippedMessage(w));
+            }
+        }
+    }
+    let mut matched = 0;
+    for mail in &mut mails {
+        if !tag.is_empty() {
+            mail.tags.insert(tag.to_string());
+        }
+        let names = match simulate(mail, filters, &mut warnings) {
+            Ok(names) => names,
+            Err(e) => {
+                eprintln!("Oops: {}: {e}", mail.describe());
+                process::exit(1);
+            }
+        };
+        if !leave {
+            mail.tags.remove(tag);
+        }
+        if !names.is_empty() {
+            matched += 1;
+        }
+        let names: Vec<_> = names.iter().map(|n| style.filter(n)).collect();
+        let tags: Vec<_> = mail.tags.iter().map(String::as_str).collect();
+        let _ = writeln!(
+            out,
+            "{}  {}  [{}]",
+            mail.describe(),
+            names.join(" "),
+            tags.join(" ")
+        );
+    }
+    print_warnings(&warnings);
+    let _ = writeln!(out, "{matched} of {} messages matched", mails.len());
+    out
+}
+
+/// Which messages a run processes, see `--all`
+enum Selection {
+    Tag(String),
+    Query(String),
+}
+
+impl Selection {
+    fn new(opt: &Opt) -> Self {
+        match (opt.all || opt.tag.is_empty(), &opt.query) {
+            (false, None) => Selection::Tag(opt.tag.clone()),
+            (true, Some(query)) => Selection::Query(query.clone()),
+            (true, None) => {
+                eprintln!("--all needs a --query selecting the messages, e.g. --query '*'");
+                process::exit(1);
+            }
+            (false, Some(_)) => {
+                eprintln!("--query only selects messages together with --all");
+                process::exit(1);
+            }
+        }
+    }
+
+    fn filter(
+        &self,
+        db: &Database,
+        options: &FilterOptions,
+        filters: &[Filter],
+    ) -> error::Result<RunOutcome> {
+        match self {
+            Selection::Tag(tag) => filter(db, tag, options, filters),
+            Selection::Query(query) => backfill(db, query, options, filters, |_, _| {}),
+        }
+    }
+
+    fn filter_dry(
+        &self,
+        db: &Database,
+        filters: &[Filter],
+    ) -> error::Result<(usize, Vec<DryMatch>, Vec<error::Warning>)> {
+        match self {
+            Selection::Tag(tag) => filter_dry(db, tag, filters),
+            Selection::Query(query) => {
+                let (classified, warnings) = classify(db, query, filters)?;
+                let infos: Vec<DryMatch> = classified
+                    .into_iter()
+                    .flat_map(|(id, matches)| {
+                        matches.into_iter().map(move |m| (id.clone(), m.filter))
+                    })
+                    .collect();
+                Ok((infos.len(), infos, warnings))
+            }
+        }
     }
 }
 
 fn main() {
     let opt = Opt::parse();
 
-    let db = match Database::open_with_config::<&Path, _>(
-        None,
-        if opt.dry {
+    if let Some(Cmd::Simulate { maildir }) = &opt.cmd {
+        let path = match &opt.filters {
+            Some(path) => path.clone(),
+            None => get_filters_path(&None, &open_db(opt.config.first(), DatabaseMode::ReadOnly)),
+        };
+        let mut filters = get_filters(&path);
+        if opt.normalize {
+            filters.iter_mut().for_each(|f| f.normalize_tags());
+        }
+        load_plugins(&mut filters);
+        if uses_field(&filters, "@sender-score") {
+            let matcher = open_sender_history().matcher();
+            if let Err(e) = register_matcher(&mut filters, "@sender-score", matcher) {
+                eprintln!("Oops: {e}");
+                process::exit(1);
+            }
+        }
+        let style = Style::new(opt.no_color);
+        let tag = if opt.all { "" } else { &opt.tag };
+        let out = run_simulate(maildir, &filters, tag, opt.leave, &style);
+        page(&out, opt.no_pager);
+        process::exit(0);
+    }
+
+    if let Some(Cmd::Escape { json, text }) = &opt.cmd {
+        let escaped = regex::escape(text);
+        match json {
+            true => println!("{}", serde_json::Value::String(escaped)),
+            false => println!("{escaped}"),
+        }
+        process::exit(0);
+    }
+
+    if let Some(Cmd::Doctor) = &opt.cmd {
+        run_doctor(&opt);
+        process::exit(0);
+    }
+
+    let selection = Selection::new(&opt);
+
+    let db = open_db(
+        opt.config.first(),
+        if opt.dry
+            || matches!(
+                opt.cmd,
+                Some(Cmd::Report { .. })
+                    | Some(Cmd::List)
+                    | Some(Cmd::Plan { .. })
+                    | Some(Cmd::ApplyPlan {
+                        remote: Some(_),
+                        ..
+                    })
+                    | Some(Cmd::Classify { .. })
+                    | Some(Cmd::Trace { .. })
+                    | Some(Cmd::Why { .. })
+                    | Some(Cmd::AddRule(_))
+            )
+        {
             DatabaseMode::ReadOnly
         } else {
             DatabaseMode::ReadWrite
         },
-        opt.config,
-        None,
-    ) {
-        Ok(db) => db,
-        Err(err) => {
-            eprintln!("Could not open notmuch database, aborting!");
-            eprintln!("Error: {err}");
-            eprintln!("Do you have notmuch configured?");
-            process::exit(1);
-        }
-    };
+    );
 
-    let options = FilterOptions {
+    let max_destructive = (!opt.yes_really).then(|| max_deletes(&opt));
+    let mut options = FilterOptions {
         sync_tags: match &opt.flags {
             Some(b) => *b,
             None => get_maildir_sync_db(&db),
         },
         leave_tag: opt.leave,
+        shadow_prefix: opt.shadow,
+        skip_if: None,
+        body_limit: opt.body_limit.map(|kib| kib * 1024),
+        mmap: !opt.no_mmap,
+        hooks: Default::default(),
+        cache: None,
+        timing: opt.timing,
+        max_children: opt.max_children,
+        max_destructive,
+        first_match_only: opt.first_match_only,
+        snoozes: None,
+        followups: None,
+        senders: None,
+        plugins: BTreeMap::new(),
+        digests: None,
     };
-    let filters = get_filters(&opt.filters, &db);
+    if let Some(Cmd::Wake) = &opt.cmd {
+        run_wake(&db);
+        process::exit(0);
+    }
+    if let Some(Cmd::Retag { from, to, query }) = &opt.cmd {
+        run_retag(&db, &opt.filters, from, to, query);
+        process::exit(0);
+    }
+    if let Some(Cmd::ApplyPlan { file, remote }) = &opt.cmd {
+        run_apply_plan(&db, options, file, remote.as_deref());
+        process::exit(0);
+    }
+    if let Some(Cmd::AddRule(new)) = &opt.cmd {
+        run_add_rule(&db, &get_filters_path(&opt.filters, &db), new);
+        process::exit(0);
+    }
+
+    let mut filters = get_filters(&get_filters_path(&opt.filters, &db));
+    if opt.normalize {
+        filters.iter_mut().for_each(|f| f.normalize_tags());
+    }
+    if filters.iter().any(|f| f.op.snooze.is_some()) && !opt.dry {
+        options.snoozes = Some(open_snoozes());
+    }
+    if filters.iter().any(|f| f.op.expect_reply.is_some()) && !opt.dry {
+        options.followups = Some(open_followups());
+    }
+    if filters.iter().any(|f| f.op.digest.is_some()) && !opt.dry {
+        options.digests = Some(open_digests());
+    }
+    options.plugins = load_plugins(&mut filters);
+    if uses_field(&filters, "@sender-score") {
+        let mut history = open_sender_history();
+        // only runs applying filters record messages
+        let record = !opt.dry && matches!(opt.cmd, None | Some(Cmd::Backfill { .. }));
+        if record {
+            match update_sender_history(&db, &mut history) {
+                Ok((_, warnings)) => print_warnings(&warnings),
+                Err(e) => {
+                    eprintln!("Couldn't update sender statistics: {e}");
+                    process::exit(1);
+                }
+            }
+        }
+        if let Err(e) = register_matcher(&mut filters, "@sender-score", history.matcher()) {
+            eprintln!("Oops: {e}");
+            process::exit(1);
+        }
+        if record {
+            options.senders = Some(RefCell::new(history));
+        }
+    }
+
+    if let Some(Cmd::Report { report, output }) = &opt.cmd {
+        page(&run_report(&db, &filters, report, *output), opt.no_pager);
+        process::exit(0);
+    }
+
+    if let Some(Cmd::Plan { format }) = &opt.cmd {
+        let fail = opt.fail_if_destructive;
+        run_plan(&db, &options, &selection, &filters, *format, fail);
+        process::exit(0);
+    }
+
+    if let Some(Cmd::Classify { format, query }) = &opt.cmd {
+        let format = match (opt.output, format) {
+            (Output::Sexp, ClassifyFormat::Text) => ClassifyFormat::Sexp,
+            (_, format) => *format,
+        };
+        run_classify(&db, &filters, format, query);
+        process::exit(0);
+    }
+
+    if let Some(Cmd::Trace { query }) = &opt.cmd {
+        let style = Style::new(opt.no_color);
+        page(
+            &run_trace(&db, &filters, &query.join(" "), &style),
+            opt.no_pager,
+        );
+        process::exit(0);
+    }
+
+    if let Some(Cmd::Why { query }) = &opt.cmd {
+        let style = Style::new(opt.no_color);
+        page(
+            &run_why(&db, &filters, &query.join(" "), &style),
+            opt.no_pager,
+        );
+        process::exit(0);
+    }
+
+    if let Some(Cmd::Backfill { filter, query }) = &opt.cmd {
+        run_backfill(&db, options, filters, filter, query, opt.cache);
+        process::exit(0);
+    }
+
+    let style = Style::new(opt.no_color);
+
+    if let Some(Cmd::List) = &opt.cmd {
+        page(&run_list(&filters, &style, opt.output), opt.no_pager);
+        process::exit(0);
+    }
 
     if opt.dry {
-        match filter_dry(&db, &opt.tag, &filters) {
-            Ok((amount, infos)) => {
-                println!("There are {amount} matches:");
-                for info in infos {
-                    println!("{info}");
+        let by_name = filters
+            .iter()
+            .map(|f| (f.name(), f))
+            .collect::<HashMap<_, _>>();
+        let op_counts = |infos: &[DryMatch]| {
+            let mut counts = OpCounts::default();
+            // filters in observation-only mode don't apply anything
+            let applied = infos.iter().filter_map(|(_, name)| by_name.get(name));
+            for f in applied.filter(|f| !f.is_dry()) {
+                counts.add(&f.operations());
+            }
+            counts
+        };
+        match selection.filter_dry(&db, &filters) {
+            Ok((_, infos, warnings)) if opt.output == Output::Sexp => {
+                print_warnings(&warnings);
+                let items: Vec<_> = infos
+                    .iter()
+                    .map(|(id, name)| format!("(:id {} :filter {})", sexp_str(id), sexp_str(name)))
+                    .collect();
+                println!("({})", items.join("\n "));
+                check_destructive(&op_counts(&infos), opt.fail_if_destructive);
+            }
+            Ok((amount, infos, warnings)) => {
+                print_warnings(&warnings);
+                let mut out = format!("There are {amount} matches:\n");
+                let counts = op_counts(&infos);
+                let width = infos.iter().map(|(id, _)| id.chars().count()).max();
+                for (id, name) in infos {
+                    let pad = " ".repeat(width.unwrap_or(0) - id.chars().count());
+                    let ops = by_name
+                        .get(&name)
+                        .map(|f| style.operations(&f.operations()))
+                        .unwrap_or_default();
+                    let _ = writeln!(out, "{id}{pad}  {}  {ops}", style.filter(&name));
                 }
+                out.push_str(&op_summary(&counts));
+                page(&out, opt.no_pager);
+                check_destructive(&counts, opt.fail_if_destructive);
             }
             Err(e) => {
                 eprintln!("Oops: {e}");
@@ -111,13 +1989,53 @@ fn main() {
         process::exit(0);
     }
 
-    match filter(&db, &opt.tag, &options, &filters) {
-        Ok(m) => {
+    if opt.cache {
+        options.cache = Some(open_cache(&filters));
+    }
+    let mut res = selection.filter(&db, &options, &filters);
+    for config in opt.config.iter().skip(1) {
+        let db = open_db(Some(config), DatabaseMode::ReadWrite);
+        if opt.flags.is_none() {
+            options.sync_tags = get_maildir_sync_db(&db);
+        }
+        res = res.and_then(|mut outcome| {
+            outcome.merge(selection.filter(&db, &options, &filters)?);
+            Ok(outcome)
+        });
+    }
+    save_cache(&options);
+    match res {
+        Ok(outcome) => {
+            print_outcome_errors(&outcome);
+            print_command_output(&outcome.output);
+            print_timings(&outcome.timings);
+            if outcome.cached > 0 {
+                println!("Skipped {} messages processed before", outcome.cached);
+            }
+            if !outcome.observed.is_empty() {
+                println!("Dry filters matched {} times:", outcome.observed.len());
+                for info in &outcome.observed {
+                    println!("{info}");
+                }
+            }
+            if !outcome.urgency.is_empty() {
+                let levels: Vec<_> = outcome
+                    .urgency
+                    .iter()
+                    .map(|(u, n)| format!("{} {n}", u.as_str()))
+                    .collect();
+                println!("Messages by urgency: {}", levels.join(", "));
+            }
+            let m = outcome.applied;
             if m > 0 {
                 println!("Yay you successfully applied {m} filters");
-            } else {
+            } else if outcome.is_success() {
                 println!("No message filtering necessary!");
             }
+            if !outcome.is_success() {
+                eprintln!("Failed to process {} messages", outcome.errors.len());
+                process::exit(2);
+            }
         }
         Err(e) => {
             eprintln!("Oops: {e}");
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use notmuch::{Database, Message};
+
+use crate::error::Result;
+use crate::state;
+use crate::Filter;
+
+/// Which filters matched which messages, kept across runs
+///
+/// The cache belongs to a specific set of filters, identified by
+/// [`rules_hash`]. Once any filter changes, everything recorded so far is
+/// discarded. Messages are identified by the database they're in and their
+/// Message-ID, and recorded along with their state once they were processed,
+/// see [`message_state`].
+///
+/// As long as a message is still in the state it was left in, [`filter`] and
+/// [`backfill`] don't evaluate any filters for it, as it was processed with
+/// the very same rules before. Filters whose outcome may change while the
+/// message doesn't (see [`Filter::depends_on_state`]) keep all messages from
+/// being skipped, but matches are still recorded.
+///
+/// [`rules_hash`]: fn.rules_hash.html
+/// [`message_state`]: fn.message_state.html
+/// [`filter`]: fn.filter.html
+/// [`backfill`]: fn.backfill.html
+/// [`Filter::depends_on_state`]: struct.Filter.html#method.depends_on_state
+#[derive(Debug, Clone)]
+pub struct MatchCache {
+    path: PathBuf,
+    contents: CacheFile,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct CacheFile {
+    #[serde(default)]
+    rules: String,
+    /// Messages by database path and Message-ID
+    #[serde(default)]
+    databases: BTreeMap<String, BTreeMap<String, CachedMessage>>,
+}
+
+/// A message that was processed, see [`MatchCache`]
+///
+/// [`MatchCache`]: struct.MatchCache.html
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct CachedMessage {
+    /// See [`message_state`]
+    ///
+    /// [`message_state`]: fn.message_state.html
+    state: String,
+    /// Names of the filters that matched
+    filters: Vec<String>,
+}
+
+impl MatchCache {
+    /// Reads the cache from `path` and keeps it if it was made with the same
+    /// filters, otherwise (or if there's no such file yet) starts out empty
+    pub fn open<P>(path: &P, filters: &[Filter]) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let rules = rules_hash(filters)?;
+        let mut contents: CacheFile = state::read(path.as_ref())?;
+        if contents.rules != rules {
+            contents = CacheFile {
+                rules,
+                databases: BTreeMap::new(),
+            };
+        }
+        Ok(MatchCache {
+            path: path.as_ref().to_path_buf(),
+            contents,
+        })
+    }
+
+    /// The message with Message-ID `id` in `db`, if it's been processed
+    /// before
+    fn cached(&self, db: &Database, id: &str) -> Option<&CachedMessage> {
+        let db = db.path().to_string_lossy();
+        self.contents.databases.get(db.as_ref())?.get(id)
+    }
+
+    /// Names of the filters that matched the message with Message-ID `id`
+    /// in `db`, if it's been processed before
+    pub fn get(&self, db: &Database, id: &str) -> Option<&[String]> {
+        self.cached(db, id).map(|m| m.filters.as_slice())
+    }
+
+    /// Checks if `msg` was processed before and hasn't changed since, see
+    /// [`message_state`]
+    ///
+    /// [`message_state`]: fn.message_state.html
+    pub fn is_processed(&self, db: &Database, msg: &Message, query_tag: Option<&str>) -> bool {
+        self.cached(db, &msg.id())
+            .is_some_and(|m| m.state == message_state(msg, query_tag))
+    }
+
+    /// Records which filters matched a message, along with the state it was
+    /// left in
+    pub fn insert(
+        &mut self,
+        db: &Database,
+        msg: &Message,
+        query_tag: Option<&str>,
+        filters: Vec<String>,
+    ) {
+        let db = db.path().to_string_lossy().into_owned();
+        let cached = CachedMessage {
+            state: message_state(msg, query_tag),
+            filters,
+        };
+        self.contents
+            .databases
+            .entry(db)
+            .or_default()
+            .insert(msg.id().to_string(), cached);
+    }
+
+    /// How many messages are cached
+    pub fn len(&self) -> usize {
+        self.contents.databases.values().map(|m| m.len()).sum()
+    }
+
+    /// Checks if no message is cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the cache back to the file it was opened from
+    pub fn save(&self) -> Result<()> {
+        state::write(&self.path, &self.contents)
+    }
+}
+
+/// Identifies a set of filters, changing whenever any of them does
+///
+/// This is a 64 bit FNV-1a hash of the filters' JSON representation, which
+/// unlike the hashers in `std` is guaranteed to stay the same across builds.
+pub fn rules_hash(filters: &[Filter]) -> Result<String> {
+    Ok(fnv(&serde_json::to_vec(filters)?))
+}
+
+/// Identifies what a message looks like to filters that don't depend on
+/// anything but the message itself: its tags (except `query_tag`) and the
+/// names of its files, which include its maildir flags
+///
+/// This is a hash just like [`rules_hash`].
+///
+/// [`rules_hash`]: fn.rules_hash.html
+pub fn message_state(msg: &Message, query_tag: Option<&str>) -> String {
+    let mut tags: Vec<String> = msg
+        .tags()
+        .filter(|t| Some(t.as_str()) != query_tag)
+        .collect();
+    tags.sort();
+    let mut files: Vec<String> = msg
+        .filenames()
+        .map(|f| f.to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    fnv(format!("{}\0{}", tags.join("\n"), files.join("\n")).as_bytes())
+}
+
+/// 64 bit FNV-1a hash of `buf`, as hex
+fn fnv(buf: &[u8]) -> String {
+    let hash = buf.iter().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
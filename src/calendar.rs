@@ -0,0 +1,378 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use mailparse::{parse_mail, ParsedMail};
+use serde::{Deserialize, Serialize};
+
+use crate::date::{format_ical, format_rfc2822, now};
+use crate::digest::encode_header;
+use crate::error::Error::*;
+use crate::error::{Result, RunError, Stage};
+use crate::mail::DbMail;
+use crate::Hooks;
+use crate::Mail;
+
+use notmuch::{ConfigKey, Database, Message};
+
+/// Handle a calendar invitation, see [`Operations::calendar`]
+///
+/// [`Operations::calendar`]: struct.Operations.html#structfield.calendar
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CalendarOp {
+    /// Command and its arguments getting the `text/calendar` part on stdin,
+    /// e.g. `["khal", "import", "--batch"]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub import_cmd: Option<Vec<String>>,
+    /// Answer invitations with an iTIP reply to the organizer, sent with
+    /// `sendmail`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply: Option<CalendarReply>,
+}
+
+/// How to answer an invitation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CalendarReply {
+    /// Attend
+    Accept,
+    /// Don't attend
+    Decline,
+    /// Maybe attend
+    Tentative,
+}
+
+impl CalendarReply {
+    /// The attendee's participation status in the reply
+    fn partstat(&self) -> &'static str {
+        match self {
+            CalendarReply::Accept => "ACCEPTED",
+            CalendarReply::Decline => "DECLINED",
+            CalendarReply::Tentative => "TENTATIVE",
+        }
+    }
+
+    /// Prefix of the reply's subject, like calendar applications use
+    fn subject(&self) -> &'static str {
+        match self {
+            CalendarReply::Accept => "Accepted",
+            CalendarReply::Decline => "Declined",
+            CalendarReply::Tentative => "Tentative",
+        }
+    }
+}
+
+/// Checks that `op` does anything at all
+pub(crate) fn validate_calendar(op: &CalendarOp) -> Result<()> {
+    if op.import_cmd.as_ref().is_some_and(|c| c.is_empty()) {
+        return Err(UnsupportedValue("import_cmd needs a command".to_string()));
+    }
+    if op.import_cmd.is_none() && op.reply.is_none() {
+        let e = "calendar needs an import_cmd or a reply".to_string();
+        return Err(UnsupportedValue(e));
+    }
+    Ok(())
+}
+
+/// The first `text/calendar` part of a message, if any
+fn calendar_part(mail: &ParsedMail) -> Result<Option<String>> {
+    if mail.ctype.mimetype.eq_ignore_ascii_case("text/calendar") {
+        return Ok(Some(mail.get_body()?));
+    }
+    for part in &mail.subparts {
+        if let Some(ics) = calendar_part(part)? {
+            return Ok(Some(ics));
+        }
+    }
+    Ok(None)
+}
+
+/// Lines of an iCalendar object, with folded lines joined back together
+fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in ics.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Splits a content line into its uppercased name, its parameters and its
+/// value, e.g. `ORGANIZER;CN="Doe: Jane":mailto:jane@example.org`
+fn property(line: &str) -> Option<(String, &str, &str)> {
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            quoted = !quoted;
+            None
+        }
+        ':' if !quoted => Some(i),
+        _ => None,
+    })?;
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+    let (name, params) = head.split_once(';').unwrap_or((head, ""));
+    Some((name.to_ascii_uppercase(), params, value))
+}
+
+/// The address of a `mailto:` value
+fn mailto(value: &str) -> String {
+    let value = value.trim();
+    match value.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => value[7..].to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Checks that `addr` is a single plain address, e.g. `jane@example.org`,
+/// rather than a list or something carrying a display name or comments
+fn is_addr_spec(addr: &str) -> bool {
+    let (local, domain) = match addr.split_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let plain = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_graphic() && !"@,;:<>()[]\\\"".contains(c))
+    };
+    plain(local) && plain(domain)
+}
+
+/// An iTIP reply to the invitation `ics` as a message ready for `sendmail
+/// -t`, or `None` if it isn't an invitation with one of `me` as attendee
+///
+/// As `sendmail -t` takes the recipients from the message, invitations
+/// whose organizer isn't a single plain address aren't answered either.
+fn reply_message(ics: &str, me: &[String], reply: CalendarReply) -> Option<String> {
+    let lines = unfold(ics);
+    let method = lines
+        .iter()
+        .filter_map(|l| property(l))
+        .find(|p| p.0 == "METHOD");
+    if !method.is_some_and(|p| p.2.trim().eq_ignore_ascii_case("REQUEST")) {
+        return None;
+    }
+    let start = lines
+        .iter()
+        .position(|l| l.eq_ignore_ascii_case("BEGIN:VEVENT"))?;
+    let mut event = Vec::new();
+    let mut organizer = None;
+    let mut attendee = None;
+    let mut summary = String::new();
+    for line in &lines[start + 1..] {
+        if line.eq_ignore_ascii_case("END:VEVENT") {
+            break;
+        }
+        let (name, _, value) = match property(line) {
+            Some(p) => p,
+            None => continue,
+        };
+        match name.as_str() {
+            "UID" | "RECURRENCE-ID" | "SEQUENCE" | "DTSTART" | "DTEND" => event.push(line.clone()),
+            "SUMMARY" => {
+                event.push(line.clone());
+                summary = value.to_string();
+            }
+            "ORGANIZER" => {
+                event.push(line.clone());
+                organizer = Some(mailto(value));
+            }
+            "ATTENDEE" => {
+                let addr = mailto(value);
+                if attendee.is_none() && me.iter().any(|m| m.eq_ignore_ascii_case(&addr)) {
+                    attendee = Some(addr);
+                }
+            }
+            _ => (),
+        }
+    }
+    let (organizer, attendee) = (organizer.filter(|o| is_addr_spec(o))?, attendee?);
+    let mut reply_ics = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "PRODID:-//notcoal//EN".to_string(),
+        "VERSION:2.0".to_string(),
+        "METHOD:REPLY".to_string(),
+        "BEGIN:VEVENT".to_string(),
+        format!("DTSTAMP:{}", format_ical(now())),
+    ];
+    reply_ics.extend(event);
+    reply_ics.push(format!(
+        "ATTENDEE;PARTSTAT={}:mailto:{}",
+        reply.partstat(),
+        attendee
+    ));
+    reply_ics.push("END:VEVENT".to_string());
+    reply_ics.push("END:VCALENDAR".to_string());
+    Some(format!(
+        "From: {attendee}\r\nTo: {organizer}\r\nSubject: {}\r\nDate: {}\r\n\
+         MIME-Version: 1.0\r\nContent-Type: text/calendar; method=REPLY; charset=utf-8\r\n\
+         Content-Transfer-Encoding: 8bit\r\n\r\n{}\r\n",
+        encode_header(&format!("{}: {}", reply.subject(), summary)),
+        format_rfc2822(now()),
+        reply_ics.join("\r\n")
+    ))
+}
+
+/// Pipes `input` into a command and waits for it to exit successfully
+fn pipe(cmd: &[String], input: &[u8], msg: &Message, name: &str) -> Result<()> {
+    let mut child = Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .stdin(Stdio::piped())
+        .env("NOTCOAL_FILE_NAME", msg.filename())
+        .env("NOTCOAL_MSG_ID", msg.id().as_ref())
+        .env("NOTCOAL_FILTER_NAME", name)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input)?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        let e = format!("{} exited with {}", cmd[0], status);
+        return Err(UnsupportedValue(e));
+    }
+    Ok(())
+}
+
+/// Imports the calendar part of a message and answers the invitation, as
+/// far as `op` asks for it
+///
+/// Messages without a calendar part are left alone, as are replies to
+/// invitations that don't list any of the addresses in notmuch's
+/// configuration as attendee.
+pub(crate) fn apply_calendar(
+    msg: &Message,
+    db: &Database,
+    op: &CalendarOp,
+    name: &str,
+    hooks: &Hooks,
+) -> Result<()> {
+    let wrap = |e| RunError::wrap(e, msg, Some(name), Stage::Run);
+    let mail = DbMail::new(msg, db, None, false);
+    let raw = mail.raw().map_err(wrap)?;
+    let parsed = parse_mail(&raw).map_err(|e| wrap(e.into()))?;
+    let ics = match calendar_part(&parsed).map_err(wrap)? {
+        Some(ics) => ics,
+        None => return Ok(()),
+    };
+    if let Some(cmd) = &op.import_cmd {
+        pipe(cmd, ics.as_bytes(), msg, name).map_err(wrap)?;
+        if let Some(hook) = &hooks.on_command_spawned {
+            hook(msg, cmd);
+        }
+    }
+    if let Some(reply) = op.reply {
+        let mut me: Vec<String> = db.config(ConfigKey::PrimaryEmail).into_iter().collect();
+        if let Some(other) = db.config(ConfigKey::OtherEmail) {
+            me.extend(other.split(';').map(|a| a.trim().to_string()));
+        }
+        if let Some(message) = reply_message(&ics, &me, reply) {
+            let sendmail = ["sendmail".to_string(), "-t".to_string(), "-oi".to_string()];
+            pipe(&sendmail, message.as_bytes(), msg, name).map_err(wrap)?;
+            if let Some(hook) = &hooks.on_command_spawned {
+                hook(msg, &sendmail);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVITE: &str = "BEGIN:VCALENDAR\r\n\
+        METHOD:REQUEST\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:1234@example.org\r\n\
+        SUMMARY:Caf\u{e9} planning\r\n\
+        ORGANIZER;CN=\"Doe: Jane\":mailto:jane@example.org\r\n\
+        ATTENDEE;CN=Joe;PARTSTAT=NEEDS-ACTION:mailto:joe@example.org\r\n\
+        ATTENDEE;PARTSTAT=NEEDS-ACTION:MAILTO:\r\n \
+         other@example.org\r\n\
+        END:VEVENT\r\n\
+        END:VCALENDAR\r\n";
+
+    fn me() -> Vec<String> {
+        vec!["Joe@Example.org".to_string()]
+    }
+
+    fn header<'m>(msg: &'m str, name: &str) -> Option<&'m str> {
+        let head = msg.split("\r\n\r\n").next()?;
+        head.split("\r\n")
+            .find_map(|l| l.strip_prefix(name)?.strip_prefix(": "))
+    }
+
+    #[test]
+    fn folded_lines_are_joined() {
+        let lines = unfold("A:1\r\n B\r\n\tC\r\nD:2\n E");
+        assert_eq!(lines, ["A:1BC", "D:2E"]);
+        assert_eq!(unfold(" leading"), [" leading"]);
+    }
+
+    #[test]
+    fn properties_are_split_outside_quotes() {
+        let line = r#"organizer;CN="Doe: Jane":mailto:jane@example.org"#;
+        assert_eq!(
+            property(line),
+            Some((
+                "ORGANIZER".to_string(),
+                r#"CN="Doe: Jane""#,
+                "mailto:jane@example.org"
+            ))
+        );
+        assert_eq!(property("UID:a:b"), Some(("UID".to_string(), "", "a:b")));
+        assert_eq!(property("no value"), None);
+        assert_eq!(property(r#"X;P="unterminated:value"#), None);
+    }
+
+    #[test]
+    fn invitations_are_answered_to_the_organizer() {
+        let msg = reply_message(INVITE, &me(), CalendarReply::Accept).unwrap();
+        assert_eq!(header(&msg, "From"), Some("joe@example.org"));
+        assert_eq!(header(&msg, "To"), Some("jane@example.org"));
+        assert_eq!(
+            header(&msg, "Subject"),
+            Some("=?utf-8?Q?Accepted=3A_Caf=C3=A9_planning?=")
+        );
+        assert!(msg.contains("\r\nMETHOD:REPLY\r\n"));
+        assert!(msg.contains("\r\nUID:1234@example.org\r\n"));
+        assert!(msg.contains("\r\nATTENDEE;PARTSTAT=ACCEPTED:mailto:joe@example.org\r\n"));
+        assert!(!msg.contains("other@example.org"));
+
+        let other = vec!["other@example.org".to_string()];
+        let msg = reply_message(INVITE, &other, CalendarReply::Decline).unwrap();
+        assert_eq!(header(&msg, "From"), Some("other@example.org"));
+    }
+
+    #[test]
+    fn only_requests_are_answered() {
+        let cancel = INVITE.replace("METHOD:REQUEST", "METHOD:CANCEL");
+        assert!(reply_message(&cancel, &me(), CalendarReply::Accept).is_none());
+        let none = INVITE.replace("METHOD:REQUEST\r\n", "");
+        assert!(reply_message(&none, &me(), CalendarReply::Accept).is_none());
+    }
+
+    #[test]
+    fn invitations_without_me_are_not_answered() {
+        let nobody = vec!["nobody@example.org".to_string()];
+        assert!(reply_message(INVITE, &nobody, CalendarReply::Accept).is_none());
+        assert!(reply_message(INVITE, &[], CalendarReply::Accept).is_none());
+    }
+
+    #[test]
+    fn organizers_must_be_a_single_address() {
+        for organizer in [
+            "mailto:jane@example.org, boss@example.org",
+            "mailto:Jane <jane@example.org>",
+            "mailto:jane@example.org\tboss@example.org",
+            "mailto:jane",
+            "mailto:",
+        ] {
+            let ics = INVITE.replace("mailto:jane@example.org", organizer);
+            let msg = reply_message(&ics, &me(), CalendarReply::Accept);
+            assert!(msg.is_none(), "{}", organizer);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::net::IpAddr;
+
+/// A range of IP addresses like `192.0.2.0/24` or `2001:db8::/32`, as used by
+/// `@received-ip` rules
+///
+/// A plain address is a range containing just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    /// Parses a range, or returns `None` if `spec` isn't one
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (addr, prefix) = match spec.trim().split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (spec.trim().parse().ok()?, None),
+        };
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(bits);
+        if prefix > bits {
+            return None;
+        }
+        Some(Cidr { addr, prefix })
+    }
+
+    /// Checks if `addr` is in the range
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let mask = |bits: u32| match self.prefix {
+            0 => 0,
+            p => u128::MAX << (bits - p),
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(a)) => {
+                let mask = mask(32) as u32;
+                u32::from(net) & mask == u32::from(*a) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(a)) => {
+                let mask = mask(128);
+                u128::from(net) & mask == u128::from(*a) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(spec: &str, addr: &str) -> bool {
+        Cidr::parse(spec).unwrap().contains(&addr.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4_masks() {
+        assert!(contains("192.0.2.0/24", "192.0.2.255"));
+        assert!(!contains("192.0.2.0/24", "192.0.3.0"));
+        assert!(contains("192.0.2.7/32", "192.0.2.7"));
+        assert!(!contains("192.0.2.7/32", "192.0.2.6"));
+        assert!(contains("192.0.2.7", "192.0.2.7"));
+        assert!(!contains("192.0.2.7", "192.0.2.8"));
+        assert!(contains("192.0.2.6/31", "192.0.2.7"));
+        assert!(!contains("192.0.2.6/31", "192.0.2.8"));
+        assert!(contains("128.0.0.0/1", "255.255.255.255"));
+        assert!(!contains("128.0.0.0/1", "127.255.255.255"));
+        assert!(contains("0.0.0.0/0", "203.0.113.1"));
+        // host bits of the range itself don't matter
+        assert!(contains("192.0.2.77/24", "192.0.2.1"));
+    }
+
+    #[test]
+    fn ipv6_masks() {
+        assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(contains("2001:db8::1/128", "2001:db8::1"));
+        assert!(!contains("2001:db8::1/128", "2001:db8::2"));
+        assert!(contains("2001:db8::/127", "2001:db8::1"));
+        assert!(!contains("2001:db8::/127", "2001:db8::2"));
+        assert!(contains("8000::/1", "ffff::"));
+        assert!(!contains("8000::/1", "7fff::"));
+        assert!(contains("::/0", "2001:db8::1"));
+    }
+
+    #[test]
+    fn families_dont_mix() {
+        assert!(!contains("0.0.0.0/0", "::1"));
+        assert!(!contains("::/0", "127.0.0.1"));
+        assert!(!contains("::ffff:0:0/96", "192.0.2.1"));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        for spec in [
+            "",
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2.0/",
+            "192.0.2/24",
+            "x/8",
+        ] {
+            assert_eq!(Cidr::parse(spec), None, "{spec:?}");
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Error::*;
+use crate::error::Result;
+
+pub(crate) const DAY: i64 = 24 * 60 * 60;
+
+/// A condition on a message's date, as used by `@date` rules
+///
+/// Supported are single days (`2024-01-31`), ranges of days where either end
+/// may be left out (`2024-01-01..2024-01-31`, both ends included), days
+/// before or after a given one (`<2024-01-01`, `>2024-01-31`), and ages
+/// relative to now (`>30d`, `<12h`). Ages may be given in hours (`h`), days
+/// (`d`) or weeks (`w`). Days are in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DateSpec {
+    /// Timestamps from (including) and until (excluding)
+    Range(Option<i64>, Option<i64>),
+    /// At least this many seconds old
+    OlderThan(i64),
+    /// At most this many seconds old
+    NewerThan(i64),
+}
+
+impl DateSpec {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        let invalid = || UnsupportedValue(format!("Invalid @date {:?}", spec));
+        if let Some((from, to)) = spec.split_once("..") {
+            let from = match from.trim() {
+                "" => None,
+                from => Some(parse_day(from).ok_or_else(invalid)?),
+            };
+            let to = match to.trim() {
+                "" => None,
+                to => Some(parse_day(to).ok_or_else(invalid)? + DAY),
+            };
+            return Ok(DateSpec::Range(from, to));
+        }
+        if let Some(rest) = spec.strip_prefix('>') {
+            let rest = rest.trim();
+            if let Some(age) = parse_age(rest) {
+                return Ok(DateSpec::OlderThan(age));
+            }
+            let day = parse_day(rest).ok_or_else(invalid)?;
+            return Ok(DateSpec::Range(Some(day + DAY), None));
+        }
+        if let Some(rest) = spec.strip_prefix('<') {
+            let rest = rest.trim();
+            if let Some(age) = parse_age(rest) {
+                return Ok(DateSpec::NewerThan(age));
+            }
+            let day = parse_day(rest).ok_or_else(invalid)?;
+            return Ok(DateSpec::Range(None, Some(day)));
+        }
+        let day = parse_day(spec).ok_or_else(invalid)?;
+        Ok(DateSpec::Range(Some(day), Some(day + DAY)))
+    }
+
+    /// Checks if the Unix timestamp `date` satisfies the condition
+    pub fn matches(&self, date: i64) -> bool {
+        match *self {
+            DateSpec::Range(from, to) => {
+                from.is_none_or(|f| date >= f) && to.is_none_or(|t| date < t)
+            }
+            DateSpec::OlderThan(age) => now() - date >= age,
+            DateSpec::NewerThan(age) => now() - date <= age,
+        }
+    }
+}
+
+pub(crate) fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Parses a duration like `3d` for operations like `snooze` into seconds,
+/// `what` naming the operation in errors
+pub(crate) fn parse_duration(duration: &str, what: &str) -> Result<i64> {
+    parse_age(duration.trim())
+        .filter(|d| *d > 0)
+        .ok_or_else(|| UnsupportedValue(format!("Invalid {} duration {:?}", what, duration)))
+}
+
+/// The Unix timestamp `duration` (see [`parse_duration`]) after `ts`
+///
+/// [`parse_duration`]: fn.parse_duration.html
+pub(crate) fn after(ts: i64, duration: &str, what: &str) -> Result<i64> {
+    ts.checked_add(parse_duration(duration, what)?)
+        .ok_or_else(|| UnsupportedValue(format!("Invalid {} duration {:?}", what, duration)))
+}
+
+/// Parses an age like `30d` into seconds
+pub(crate) fn parse_age(age: &str) -> Option<i64> {
+    let unit = match age.chars().last()? {
+        'h' => 60 * 60,
+        'd' => DAY,
+        'w' => 7 * DAY,
+        _ => return None,
+    };
+    let n: i64 = age[..age.len() - 1].parse().ok()?;
+    if n < 0 {
+        return None;
+    }
+    n.checked_mul(unit)
+}
+
+/// Year, month and day of a timestamp in UTC
+fn civil(ts: i64) -> (i64, i64, i64) {
+    // see http://howardhinnant.github.io/date_algorithms.html
+    let z = ts.div_euclid(DAY) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(m <= 2), m, d)
+}
+
+/// Formats a timestamp as a day like `2024-01-31` in UTC
+pub(crate) fn format_day(ts: i64) -> String {
+    let (y, m, d) = civil(ts);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// Formats a timestamp like `20240131T120000Z`, as iCalendar does for UTC
+pub(crate) fn format_ical(ts: i64) -> String {
+    let (y, m, d) = civil(ts);
+    let secs = ts.rem_euclid(DAY);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        y,
+        m,
+        d,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Abbreviated weekdays, starting with the one of the epoch
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+/// Abbreviated months
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats a timestamp for a `Date` header, in UTC
+pub(crate) fn format_rfc2822(ts: i64) -> String {
+    let (y, m, d) = civil(ts);
+    let secs = ts.rem_euclid(DAY);
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[ts.div_euclid(DAY).rem_euclid(7) as usize],
+        d,
+        MONTHS[m as usize - 1],
+        y,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Formats a timestamp like C's `asctime`, as the `From ` lines of mbox
+/// files have it, in UTC
+pub(crate) fn format_asctime(ts: i64) -> String {
+    let (y, m, d) = civil(ts);
+    let secs = ts.rem_euclid(DAY);
+    format!(
+        "{} {} {:2} {:02}:{:02}:{:02} {}",
+        WEEKDAYS[ts.div_euclid(DAY).rem_euclid(7) as usize],
+        MONTHS[m as usize - 1],
+        d,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        y
+    )
+}
+
+/// Expands `%Y`, `%m` and `%d` in `template` to the year, month and day of
+/// a timestamp in UTC, and `%%` to `%`
+pub(crate) fn format_template(template: &str, ts: i64) -> Result<String> {
+    let (y, m, d) = civil(ts);
+    let mut formatted = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => formatted.push_str(&format!("{:04}", y)),
+            Some('m') => formatted.push_str(&format!("{:02}", m)),
+            Some('d') => formatted.push_str(&format!("{:02}", d)),
+            Some('%') => formatted.push('%'),
+            _ => {
+                let e = format!(
+                    "Unknown placeholder in {:?}, only %Y, %m and %d are supported",
+                    template
+                );
+                return Err(UnsupportedValue(e));
+            }
+        }
+    }
+    Ok(formatted)
+}
+
+/// How many days month `m` (counting from 1) of year `y` has
+fn days_in_month(y: i64, m: i64) -> i64 {
+    match m {
+        2 if y % 4 == 0 && (y % 100 != 0 || y % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parses a day like `2024-01-31` into the timestamp of its start in UTC,
+/// for years up to 9999
+fn parse_day(day: &str) -> Option<i64> {
+    let mut parts = day.splitn(3, '-');
+    let y: i64 = parts.next()?.parse().ok()?;
+    let m: i64 = parts.next()?.parse().ok()?;
+    let d: i64 = parts.next()?.parse().ok()?;
+    if !(0..=9999).contains(&y) || !(1..=12).contains(&m) || !(1..=days_in_month(y, m)).contains(&d)
+    {
+        return None;
+    }
+    // days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (m + if m > 2 { -3 } else { 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some((era * 146097 + doe - 719468) * DAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JAN_31: i64 = 1706659200;
+
+    #[test]
+    fn absolute_days() {
+        let day = DateSpec::parse("2024-01-31").unwrap();
+        assert_eq!(day, DateSpec::Range(Some(JAN_31), Some(JAN_31 + DAY)));
+        assert!(day.matches(JAN_31));
+        assert!(day.matches(JAN_31 + DAY - 1));
+        assert!(!day.matches(JAN_31 + DAY));
+        assert!(!day.matches(JAN_31 - 1));
+        assert_eq!(parse_day("2024-02-29"), Some(JAN_31 + 29 * DAY));
+        assert_eq!(parse_day("2000-02-29"), Some(951782400));
+
+        assert_eq!(
+            DateSpec::parse(">2024-01-31").unwrap(),
+            DateSpec::Range(Some(JAN_31 + DAY), None)
+        );
+        assert_eq!(
+            DateSpec::parse("< 2024-01-31").unwrap(),
+            DateSpec::Range(None, Some(JAN_31))
+        );
+        assert_eq!(parse_day("2024-03-01"), Some(JAN_31 + 30 * DAY));
+        assert_eq!(parse_day("1970-01-01"), Some(0));
+    }
+
+    #[test]
+    fn ranges_of_days() {
+        let feb = DateSpec::parse("2024-02-01..2024-02-29").unwrap();
+        assert_eq!(
+            feb,
+            DateSpec::Range(Some(JAN_31 + DAY), Some(JAN_31 + 30 * DAY))
+        );
+        assert_eq!(
+            DateSpec::parse("2024-01-31..").unwrap(),
+            DateSpec::Range(Some(JAN_31), None)
+        );
+        assert_eq!(
+            DateSpec::parse("..2024-01-31").unwrap(),
+            DateSpec::Range(None, Some(JAN_31 + DAY))
+        );
+        assert_eq!(DateSpec::parse("..").unwrap(), DateSpec::Range(None, None));
+    }
+
+    #[test]
+    fn relative_ages() {
+        assert_eq!(
+            DateSpec::parse(">30d").unwrap(),
+            DateSpec::OlderThan(30 * DAY)
+        );
+        assert_eq!(
+            DateSpec::parse("<12h").unwrap(),
+            DateSpec::NewerThan(12 * 60 * 60)
+        );
+        assert_eq!(
+            DateSpec::parse("> 2w").unwrap(),
+            DateSpec::OlderThan(14 * DAY)
+        );
+        assert!(DateSpec::parse(">30d").unwrap().matches(now() - 31 * DAY));
+        assert!(!DateSpec::parse(">30d").unwrap().matches(now() - 29 * DAY));
+        assert!(DateSpec::parse("<1d").unwrap().matches(now()));
+    }
+
+    #[test]
+    fn invalid_dates() {
+        for spec in [
+            "",
+            "30d",
+            "yesterday",
+            "2024-13-01",
+            "2024-01-32",
+            "2024-02-30",
+            "2024-02-31",
+            "2023-02-29",
+            "1900-02-29",
+            "2024-04-31",
+            "2024-01",
+            "2024-01-31..tomorrow",
+            ">-5d",
+            ">9223372036854775807w",
+            "99999999999999-01-01",
+        ] {
+            assert!(DateSpec::parse(spec).is_err(), "{spec:?}");
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration(" 3d ", "snooze").unwrap(), 3 * DAY);
+        for duration in ["0d", "-1d", "3", "3m", "9223372036854775807h"] {
+            assert!(parse_duration(duration, "snooze").is_err(), "{duration:?}");
+        }
+        assert_eq!(after(JAN_31, "1d", "snooze").unwrap(), JAN_31 + DAY);
+        assert!(after(i64::MAX - DAY, "2d", "snooze").is_err());
+    }
+}
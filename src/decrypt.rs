@@ -0,0 +1,77 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use mailparse::{parse_mail, ParsedMail};
+
+use crate::error::Error::*;
+use crate::error::Warning::*;
+use crate::error::{Result, Warning};
+use crate::filter::body_values;
+use crate::Mail;
+use crate::Matcher;
+
+/// A special field read from the body of a message, decrypting PGP/MIME
+/// encrypted messages first, see [`Filter::decrypt`]
+///
+/// [`Filter::decrypt`]: struct.Filter.html#structfield.decrypt
+pub(crate) struct Decrypted(pub(crate) &'static str);
+
+/// The encrypted payload of a PGP/MIME message (RFC 3156), if it is one
+fn encrypted(parsed: &ParsedMail) -> Result<Option<Vec<u8>>> {
+    let protocol = parsed.ctype.params.get("protocol");
+    if !parsed
+        .ctype
+        .mimetype
+        .eq_ignore_ascii_case("multipart/encrypted")
+        || !protocol.is_some_and(|p| p.eq_ignore_ascii_case("application/pgp-encrypted"))
+    {
+        return Ok(None);
+    }
+    match parsed.subparts.get(1) {
+        Some(part) => Ok(Some(part.get_body_raw()?)),
+        None => Ok(None),
+    }
+}
+
+/// Decrypts `ciphertext` with `gpg`, which asks the user's agent for the key
+fn decrypt(ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new("gpg")
+        .args(["--batch", "--quiet", "--decrypt"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take();
+    // gpg may start writing before it read everything
+    let output = std::thread::scope(|s| {
+        s.spawn(|| match stdin.take() {
+            Some(mut stdin) => stdin.write_all(ciphertext),
+            None => Ok(()),
+        });
+        child.wait_with_output()
+    })?;
+    if !output.status.success() {
+        let e = format!("gpg exited with {}", output.status);
+        return Err(UnsupportedValue(e));
+    }
+    Ok(output.stdout)
+}
+
+impl Matcher for Decrypted {
+    fn values(&self, msg: &dyn Mail, warnings: &mut Vec<Warning>) -> Result<Option<Vec<String>>> {
+        let buf = msg.raw()?;
+        let parsed = parse_mail(&buf)?;
+        let ciphertext = match encrypted(&parsed)? {
+            Some(ciphertext) => ciphertext,
+            None => return Ok(Some(body_values(self.0, &parsed)?)),
+        };
+        match decrypt(&ciphertext) {
+            Ok(plaintext) => Ok(Some(body_values(self.0, &parse_mail(&plaintext)?)?)),
+            Err(e) => {
+                let w = format!("{}: {}, matching its encrypted {}", msg.id(), e, self.0);
+                warnings.push(Undecryptable(w));
+                Ok(Some(body_values(self.0, &parsed)?))
+            }
+        }
+    }
+}
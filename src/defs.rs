@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use serde_json::{Map, Value};
+
+use crate::error::Error::*;
+use crate::error::Result;
+
+/// What filters in a rules file may refer to
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Defs {
+    /// Fragments under `defs`, referred to with `{"$ref": "name"}`
+    defs: Map<String, Value>,
+    /// Variables under `vars`, referred to with `{{name}}`
+    vars: BTreeMap<String, String>,
+}
+
+/// Splits a rules file into its definitions and filters
+///
+/// A rules file is either just the list of filters, or an object with the
+/// filters under `filters`, reusable fragments under `defs` and variables
+/// under `vars`. The latter is either an object or the path of a file
+/// holding one.
+pub(crate) fn split(file: Value) -> Result<(Defs, Vec<Value>)> {
+    let no_filters = || UnsupportedValue("Rules files need a list of filters".to_string());
+    match file {
+        Value::Array(filters) => Ok((Defs::default(), filters)),
+        Value::Object(mut file) => {
+            let defs = match file.remove("defs") {
+                Some(Value::Object(defs)) => defs,
+                None => Map::new(),
+                Some(_) => return Err(UnsupportedValue("defs has to be an object".to_string())),
+            };
+            let vars = match file.remove("vars") {
+                Some(Value::String(path)) => vars(read_vars(&path)?)?,
+                Some(v) => vars(v)?,
+                None => BTreeMap::new(),
+            };
+            let filters = match file.remove("filters") {
+                Some(Value::Array(filters)) => filters,
+                _ => return Err(no_filters()),
+            };
+            if let Some(key) = file.keys().next() {
+                return Err(UnsupportedValue(format!("Unknown key {:?}", key)));
+            }
+            Ok((Defs { defs, vars }, filters))
+        }
+        _ => Err(no_filters()),
+    }
+}
+
+/// Reads variables from the JSON file at `path`, where a leading `~/`
+/// stands for the home directory
+fn read_vars(path: &str) -> Result<Value> {
+    let path = match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    };
+    let mut buf = Vec::new();
+    File::open(&path)?.read_to_end(&mut buf)?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+/// Variables from an object of strings, numbers or booleans
+fn vars(value: Value) -> Result<BTreeMap<String, String>> {
+    let vars = match value {
+        Value::Object(vars) => vars,
+        _ => return Err(UnsupportedValue("vars has to be an object".to_string())),
+    };
+    vars.into_iter()
+        .map(|(name, value)| match value {
+            Value::String(s) => Ok((name, s)),
+            Value::Number(_) | Value::Bool(_) => Ok((name, value.to_string())),
+            _ => Err(UnsupportedValue(format!(
+                "Variable {} isn't a string",
+                name
+            ))),
+        })
+        .collect()
+}
+
+/// Replaces every `{{name}}` in `s` with the variable `name`, or the
+/// environment variable if there's no such variable
+///
+/// Braces not around a name made of letters, digits, `_`, `-` and `.` are
+/// left alone, so they don't get in the way of regular expressions.
+fn expand(s: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("{{") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let name = after
+            .find("}}")
+            .map(|end| &after[..end])
+            .filter(|n| !n.is_empty())
+            .filter(|n| {
+                n.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c))
+            });
+        match name {
+            Some(name) => {
+                match vars.get(name) {
+                    Some(value) => expanded.push_str(value),
+                    None => match std::env::var(name) {
+                        Ok(value) => expanded.push_str(&value),
+                        Err(_) => {
+                            let e = format!("Unknown variable {:?}", name);
+                            return Err(UnsupportedValue(e));
+                        }
+                    },
+                }
+                rest = &after[name.len() + 2..];
+            }
+            // the name may start at the next brace, like in `{{{name}}}`
+            None => {
+                expanded.push('{');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Replaces every `{"$ref": "name"}` in `value` with the definition `name`
+/// and expands variables in strings
+///
+/// References in a list whose definition is a list themselves are spliced
+/// into it, so lists of patterns or rules can be extended. Other keys next
+/// to `$ref` are added to the definition, which has to be an object then,
+/// overriding its keys.
+pub(crate) fn resolve(value: &Value, defs: &Defs) -> Result<Value> {
+    resolve_within(value, defs, &mut Vec::new())
+}
+
+/// Like [`resolve`], with `stack` holding the definitions being resolved,
+/// to catch cyclic ones
+///
+/// [`resolve`]: fn.resolve.html
+fn resolve_within(value: &Value, defs: &Defs, stack: &mut Vec<String>) -> Result<Value> {
+    match value {
+        Value::Object(map) => match map.get("$ref") {
+            Some(name) => {
+                let def = definition(name, defs, stack)?;
+                if map.len() == 1 {
+                    return Ok(def);
+                }
+                let mut def = match def {
+                    Value::Object(def) => def,
+                    _ => {
+                        let e = format!("Definition {} can't have keys added", name);
+                        return Err(UnsupportedValue(e));
+                    }
+                };
+                for (key, v) in map.iter().filter(|(k, _)| *k != "$ref") {
+                    let key = expand(key, &defs.vars)?;
+                    def.insert(key, resolve_within(v, defs, stack)?);
+                }
+                Ok(Value::Object(def))
+            }
+            None => {
+                let mut resolved = Map::new();
+                for (key, v) in map {
+                    let key = expand(key, &defs.vars)?;
+                    resolved.insert(key, resolve_within(v, defs, stack)?);
+                }
+                Ok(Value::Object(resolved))
+            }
+        },
+        Value::Array(values) => {
+            let mut resolved = Vec::new();
+            for v in values {
+                match resolve_within(v, defs, stack)? {
+                    Value::Array(spliced) if is_ref(v) => resolved.extend(spliced),
+                    v => resolved.push(v),
+                }
+            }
+            Ok(Value::Array(resolved))
+        }
+        Value::String(s) => Ok(Value::String(expand(s, &defs.vars)?)),
+        v => Ok(v.clone()),
+    }
+}
+
+/// Checks if `value` is nothing but a reference
+fn is_ref(value: &Value) -> bool {
+    value
+        .as_object()
+        .is_some_and(|m| m.len() == 1 && m.contains_key("$ref"))
+}
+
+/// The definition `name` refers to, resolved itself
+fn definition(name: &Value, defs: &Defs, stack: &mut Vec<String>) -> Result<Value> {
+    let name = match name {
+        Value::String(name) => name,
+        _ => return Err(UnsupportedValue(format!("Invalid $ref {}", name))),
+    };
+    if stack.contains(name) {
+        let e = format!("Definition {:?} refers to itself", name);
+        return Err(UnsupportedValue(e));
+    }
+    let def = defs
+        .defs
+        .get(name)
+        .ok_or_else(|| UnsupportedValue(format!("Unknown definition {:?}", name)))?;
+    stack.push(name.clone());
+    let resolved = resolve_within(def, defs, stack);
+    stack.pop();
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// The filters of `file`, with everything resolved
+    fn resolved(file: Value) -> Result<Vec<Value>> {
+        let (defs, filters) = split(file)?;
+        filters.iter().map(|f| resolve(f, &defs)).collect()
+    }
+
+    #[test]
+    fn refs_are_replaced_and_spliced() {
+        let filters = resolved(json!({
+            "defs": {
+                "work": ["@work\\.example$", "@client\\.example$"],
+                "bulk": {"precedence": "bulk"},
+                "rules": [{"$ref": "bulk"}, {"list-id": "."}]
+            },
+            "filters": [
+                {"rules": [{"from": {"$ref": "work"}}]},
+                {"rules": [{"from": ["^boss@", {"$ref": "work"}]}]},
+                {"rules": [{"$ref": "rules"}, {"subject": "x"}]},
+                {"rules": [{"$ref": "bulk", "from": "news@", "precedence": "list"}]}
+            ]
+        }))
+        .unwrap();
+        let work = json!(["@work\\.example$", "@client\\.example$"]);
+        assert_eq!(filters[0], json!({"rules": [{"from": work}]}));
+        assert_eq!(
+            filters[1],
+            json!({"rules": [{"from": ["^boss@", "@work\\.example$", "@client\\.example$"]}]})
+        );
+        assert_eq!(
+            filters[2],
+            json!({"rules": [{"precedence": "bulk"}, {"list-id": "."}, {"subject": "x"}]})
+        );
+        assert_eq!(
+            filters[3],
+            json!({"rules": [{"from": "news@", "precedence": "list"}]})
+        );
+    }
+
+    #[test]
+    fn definitions_used_twice_are_not_cycles() {
+        let filters = resolved(json!({
+            "defs": {"a": "x", "b": [{"$ref": "a"}, {"$ref": "a"}], "c": [{"$ref": "b"}, {"$ref": "a"}]},
+            "filters": [{"rules": [{"from": {"$ref": "c"}}]}]
+        }))
+        .unwrap();
+        assert_eq!(filters[0], json!({"rules": [{"from": ["x", "x", "x"]}]}));
+    }
+
+    #[test]
+    fn cyclic_refs_are_rejected() {
+        for defs in [
+            json!({"a": {"$ref": "a"}}),
+            json!({"a": [{"$ref": "b"}], "b": {"from": {"$ref": "a"}}}),
+            json!({"a": {"$ref": "b"}, "b": {"$ref": "c"}, "c": [{"$ref": "a"}]}),
+        ] {
+            let file = json!({"defs": defs, "filters": [{"rules": [{"$ref": "a"}]}]});
+            let e = resolved(file).unwrap_err();
+            assert!(format!("{:?}", e).contains("refers to itself"), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn invalid_refs_are_rejected() {
+        for (defs, rule) in [
+            (json!({}), json!({"$ref": "missing"})),
+            (json!({"a": "x"}), json!({"$ref": 1})),
+            (json!({"a": ["x"]}), json!({"$ref": "a", "from": "y"})),
+        ] {
+            let file = json!({"defs": defs, "filters": [{"rules": [rule]}]});
+            assert!(resolved(file).is_err());
+        }
+        assert!(split(json!({"defs": [], "filters": []})).is_err());
+        assert!(split(json!({"filters": [], "extra": 1})).is_err());
+        assert!(split(json!({"defs": {}})).is_err());
+    }
+
+    #[test]
+    fn vars_are_expanded_in_keys_and_values() {
+        let filters = resolved(json!({
+            "vars": {"domain": "work\\.example", "n": 3, "on": true},
+            "defs": {"me": "^me@{{domain}}$"},
+            "filters": [{
+                "name": "{{domain}}-{{n}}",
+                "rules": [{"from": {"$ref": "me"}, "x-{{on}}": "{{n}}{{n}}"}]
+            }]
+        }))
+        .unwrap();
+        assert_eq!(
+            filters[0],
+            json!({
+                "name": "work\\.example-3",
+                "rules": [{"from": "^me@work\\.example$", "x-true": "33"}]
+            })
+        );
+    }
+
+    #[test]
+    fn braces_around_anything_but_names_are_left_alone() {
+        let vars = BTreeMap::from([("a".to_string(), "x".to_string())]);
+        for s in [
+            "\\d{2}", "{{", "}}", "{{}}", "{{a b}}", "{{a", "a{{,3}}", "{{{a}}}",
+        ] {
+            let expected = match s {
+                "{{{a}}}" => "{x}",
+                s => s,
+            };
+            assert_eq!(expand(s, &vars).unwrap(), expected, "{s:?}");
+        }
+        assert_eq!(expand("{{a}}{{a}}", &vars).unwrap(), "xx");
+    }
+
+    #[test]
+    fn vars_fall_back_to_the_environment() {
+        let vars = BTreeMap::new();
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(expand("{{PATH}}", &vars).unwrap(), path);
+        let shadowed = BTreeMap::from([("PATH".to_string(), "mine".to_string())]);
+        assert_eq!(expand("{{PATH}}", &shadowed).unwrap(), "mine");
+        assert!(expand("{{notcoal-surely-unset}}", &vars).is_err());
+    }
+
+    #[test]
+    fn invalid_vars_are_rejected() {
+        assert!(split(json!({"vars": [], "filters": []})).is_err());
+        assert!(split(json!({"vars": {"a": ["x"]}, "filters": []})).is_err());
+        assert!(split(json!({"vars": {"a": null}, "filters": []})).is_err());
+    }
+}
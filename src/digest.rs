@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::date::{format_day, format_rfc2822, now, parse_duration};
+use crate::error::Error::*;
+use crate::error::Warning::*;
+use crate::error::{Result, RunError, Stage, Warning};
+use crate::maildir::{unique_name, write_file};
+use crate::operations::{home_path, store_folder};
+use crate::state;
+
+use notmuch::{ConfigKey, Database, Message};
+
+/// How often a digest is sent unless [`DigestOp::every`] says otherwise
+///
+/// [`DigestOp::every`]: struct.DigestOp.html#structfield.every
+const DEFAULT_EVERY: &str = "1d";
+
+/// Collect a message into a digest, see [`Operations::digest`]
+///
+/// [`Operations::digest`]: struct.Operations.html#structfield.digest
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DigestOp {
+    /// Name of the digest, filters using the same one share it
+    pub group: String,
+    /// How often the digest is sent, e.g. `12h`, once a day by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub every: Option<String>,
+    /// Where the digest goes, a notification by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deliver: Option<Delivery>,
+}
+
+/// Where a digest goes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Delivery {
+    /// A desktop notification, shown with `notify-send`
+    Notify,
+    /// Appended to a file, where a leading `~/` stands for the home
+    /// directory
+    File(PathBuf),
+    /// A message to yourself, delivered into this maildir folder of the
+    /// database (e.g. `Digests`), see [`Layout::folder`]
+    ///
+    /// [`Layout::folder`]: maildir/enum.Layout.html#method.folder
+    Maildir(String),
+}
+
+/// Messages collected for digests, see [`Operations::digest`]
+///
+/// Messages are recorded by group along with their sender and subject, and
+/// [`send_digests`] sends the digests that are due and starts collecting
+/// anew.
+///
+/// [`Operations::digest`]: struct.Operations.html#structfield.digest
+/// [`send_digests`]: fn.send_digests.html
+#[derive(Debug, Clone)]
+pub struct Digests {
+    path: PathBuf,
+    groups: BTreeMap<String, DigestGroup>,
+    _lock: state::Lock,
+}
+
+/// A single digest being collected
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DigestGroup {
+    /// Unix timestamp of when collecting started
+    pub since: i64,
+    /// How often the digest is sent, in seconds
+    pub every: i64,
+    /// Where the digest goes
+    pub deliver: Delivery,
+    /// Messages collected so far
+    pub entries: Vec<DigestEntry>,
+}
+
+/// A message in a digest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DigestEntry {
+    /// Message-ID
+    pub id: String,
+    /// Unix timestamp of when the message was sent
+    pub date: i64,
+    /// `From` header
+    pub from: String,
+    /// `Subject` header
+    pub subject: String,
+    /// Name of the filter that collected it
+    pub filter: String,
+}
+
+impl Digests {
+    /// Reads the digests being collected from `path`, starting out empty if
+    /// there's no such file yet
+    ///
+    /// Waits for whoever else has them open until they're dropped.
+    pub fn open<P>(path: &P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let (groups, lock) = state::open(path.as_ref())?;
+        Ok(Digests {
+            path: path.as_ref().to_path_buf(),
+            groups,
+            _lock: lock,
+        })
+    }
+
+    /// The digest `group`, if anything was collected for it
+    pub fn get(&self, group: &str) -> Option<&DigestGroup> {
+        self.groups.get(group)
+    }
+
+    /// All digests being collected by group
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &DigestGroup)> {
+        self.groups.iter()
+    }
+
+    /// How many messages are collected, across all digests
+    pub fn len(&self) -> usize {
+        self.groups.values().map(|g| g.entries.len()).sum()
+    }
+
+    /// Checks if no message is collected
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the digests back to the file they were read from
+    pub fn save(&self) -> Result<()> {
+        state::write(&self.path, &self.groups)
+    }
+}
+
+/// Checks that `op` can be applied, i.e. has a group and a valid interval
+pub(crate) fn validate_digest(op: &DigestOp) -> Result<i64> {
+    if op.group.is_empty() {
+        return Err(UnsupportedValue("Digests need a group".to_string()));
+    }
+    parse_duration(op.every.as_deref().unwrap_or(DEFAULT_EVERY), "digest")
+}
+
+/// Collects a message into the digest `op` names
+///
+/// The digest's interval and delivery are updated to the ones in `op`, so
+/// the filter applied last decides for all sharing it.
+pub(crate) fn collect(
+    msg: &Message,
+    op: &DigestOp,
+    name: &str,
+    digests: &mut Digests,
+) -> Result<()> {
+    let wrap = |e| RunError::wrap(e, msg, Some(name), Stage::Run);
+    let every = validate_digest(op).map_err(wrap)?;
+    let header = |h| -> Result<String> {
+        Ok(msg
+            .header(h)
+            .map_err(|e| wrap(e.into()))?
+            .unwrap_or_default()
+            .to_string())
+    };
+    let entry = DigestEntry {
+        id: msg.id().to_string(),
+        date: msg.date(),
+        from: header("from")?,
+        subject: header("subject")?,
+        filter: name.to_string(),
+    };
+    let group = digests
+        .groups
+        .entry(op.group.clone())
+        .or_insert_with(|| DigestGroup {
+            since: now(),
+            every,
+            deliver: Delivery::Notify,
+            entries: Vec::new(),
+        });
+    group.every = every;
+    group.deliver = op.deliver.clone().unwrap_or(Delivery::Notify);
+    if group.entries.iter().all(|e| e.id != entry.id) {
+        group.entries.push(entry);
+    }
+    Ok(())
+}
+
+/// Title and text of a digest
+fn render(name: &str, group: &DigestGroup) -> (String, String) {
+    let title = format!(
+        "{} messages for {} since {}",
+        group.entries.len(),
+        name,
+        format_day(group.since)
+    );
+    let mut text = String::new();
+    for entry in &group.entries {
+        text.push_str(&format!(
+            "{}  {}  {}\n",
+            format_day(entry.date),
+            entry.from,
+            entry.subject
+        ));
+    }
+    (title, text)
+}
+
+/// Sends a digest where it's supposed to go
+fn deliver(db: &Database, group: &DigestGroup, title: &str, text: &str) -> Result<()> {
+    match &group.deliver {
+        Delivery::Notify => {
+            let status = Command::new("notify-send")
+                .arg(format!("notcoal: {}", title))
+                .arg(text)
+                .status()?;
+            if !status.success() {
+                let e = format!("notify-send exited with {}", status);
+                return Err(UnsupportedValue(e));
+            }
+        }
+        Delivery::File(path) => {
+            let path = path.to_str().map_or_else(|| path.clone(), home_path);
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            file.write_all(format!("{}\n\n{}\n", title, text).as_bytes())?;
+        }
+        Delivery::Maildir(folder) => {
+            let dir = store_folder(db, folder)?;
+            let me = db
+                .config(ConfigKey::PrimaryEmail)
+                .unwrap_or_else(|| "notcoal@localhost".to_string());
+            let name = unique_name();
+            let message = format!(
+                "From: notcoal <{me}>\r\nTo: <{me}>\r\nSubject: {}\r\nDate: {}\r\n\
+                 Message-ID: <{}@notcoal>\r\nMIME-Version: 1.0\r\n\
+                 Content-Type: text/plain; charset=utf-8\r\n\
+                 Content-Transfer-Encoding: 8bit\r\n\r\n{}",
+                encode_header(title),
+                format_rfc2822(now()),
+                name.replace(['/', ':', '\\'], "."),
+                text.replace('\n', "\r\n")
+            );
+            write_file(&dir.join("new").join(name), message.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Encodes a header value as RFC 2047 encoded words, unless it's plain ASCII
+///
+/// Every word holds whole characters and stays within the 75 characters
+/// encoded words are limited to, and words are folded onto lines of their
+/// own.
+pub(crate) fn encode_header(value: &str) -> String {
+    if value.bytes().all(|b| (b' '..=b'~').contains(&b)) {
+        return value.to_string();
+    }
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in value.chars() {
+        let mut encoded = String::new();
+        for b in c.encode_utf8(&mut [0; 4]).bytes() {
+            match b {
+                b' ' => encoded.push('_'),
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'!' | b'*' | b'+' | b'-' | b'/' => {
+                    encoded.push(b as char)
+                }
+                b => encoded.push_str(&format!("={:02X}", b)),
+            }
+        }
+        // `=?utf-8?Q?` and `?=` take up 12 of the 75 characters
+        if word.len() + encoded.len() > 63 {
+            words.push(std::mem::take(&mut word));
+        }
+        word.push_str(&encoded);
+    }
+    words.push(word);
+    words
+        .iter()
+        .map(|w| format!("=?utf-8?Q?{}?=", w))
+        .collect::<Vec<_>>()
+        .join("\r\n ")
+}
+
+/// Sends the digests that are due and starts collecting them anew
+///
+/// Returns the groups of the digests sent. Digests that couldn't be
+/// delivered are kept as they are and reported as a [`Warning`], so they're
+/// tried again next time.
+///
+/// [`Warning`]: error/enum.Warning.html
+pub fn send_digests(db: &Database, digests: &mut Digests) -> Result<(Vec<String>, Vec<Warning>)> {
+    let now = now();
+    let mut sent = Vec::new();
+    let mut warnings = Vec::new();
+    for (name, group) in digests.groups.iter_mut() {
+        if now - group.since < group.every {
+            continue;
+        }
+        if !group.entries.is_empty() {
+            let (title, text) = render(name, group);
+            if let Err(e) = deliver(db, group, &title, &text) {
+                let w = format!("digest {}: {}", name, e);
+                warnings.push(SkippedOperation(w));
+                continue;
+            }
+            sent.push(name.clone());
+        }
+        group.entries.clear();
+        group.since = now;
+    }
+    // collecting starts over with the next message
+    digests.groups.retain(|_, g| !g.entries.is_empty());
+    Ok((sent, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailparse::parse_header;
+
+    fn decoded(header: &str) -> String {
+        let line = format!("Subject: {}", header);
+        parse_header(line.as_bytes()).unwrap().0.get_value()
+    }
+
+    #[test]
+    fn ascii_headers_stay_as_they_are() {
+        assert_eq!(
+            encode_header("3 messages for lists"),
+            "3 messages for lists"
+        );
+    }
+
+    #[test]
+    fn other_headers_are_encoded_words() {
+        let title = "3 messages for Bücher since 2024-01-31";
+        let encoded = encode_header(title);
+        assert!(encoded.is_ascii());
+        assert_eq!(decoded(&encoded), title);
+        // control characters can't end the header early
+        assert_eq!(decoded(&encode_header("a\r\nBcc: x")), "a\r\nBcc: x");
+    }
+
+    #[test]
+    fn long_headers_are_folded() {
+        let title = "Überweisungen für Ärztinnen und Zahnärzte ".repeat(4);
+        let encoded = encode_header(&title);
+        for line in encoded.split("\r\n") {
+            assert!(line.len() <= 76, "{line:?}");
+        }
+        assert!(encoded.lines().count() > 1);
+        assert_eq!(decoded(&encoded), title);
+    }
+}
@@ -0,0 +1,445 @@
+/*!
+Editing rule files without rewriting them from scratch
+
+Serializing a `Vec<Filter>` puts every key in the order of the struct
+definitions and drops any formatting the file had. [`RulesFile`] instead keeps
+the parsed JSON around and only touches the filters that are actually edited,
+so key order, indentation and anything stored in [`Filter::meta`] survive.
+References to definitions (`{"$ref": ...}`) are kept as long as what they
+refer to still fits the edited filter, otherwise they're replaced by the
+edited values.
+
+[`RulesFile`]: struct.RulesFile.html
+[`Filter::meta`]: ../struct.Filter.html#structfield.meta
+*/
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::ser::PrettyFormatter;
+use serde_json::{Map, Serializer, Value};
+
+use crate::defs::{self, Defs};
+use crate::error::Error::*;
+use crate::error::Result;
+use crate::maildir::{replace_file, unique_name};
+use crate::report::{list_id, sender_domain};
+use crate::Filter;
+
+use notmuch::Message;
+
+/// A rules file that can be modified and written back with minimal changes
+#[derive(Debug, Clone)]
+pub struct RulesFile {
+    filters: Vec<Value>,
+    defs: Defs,
+    /// The whole file if it's an object with `defs` and `filters` rather than
+    /// just the list of filters
+    object: Option<Map<String, Value>>,
+    indent: String,
+}
+
+impl Default for RulesFile {
+    fn default() -> Self {
+        RulesFile {
+            filters: Vec::new(),
+            defs: Defs::default(),
+            object: None,
+            indent: "  ".to_string(),
+        }
+    }
+}
+
+impl RulesFile {
+    /// An empty rules file
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Parses rules from a string, remembering its indentation
+    pub fn parse(buf: &str) -> Result<Self> {
+        let file: Value = serde_json::from_str(buf)?;
+        let object = file.as_object().cloned();
+        let (defs, filters) = defs::split(file)?;
+        let rules = RulesFile {
+            filters,
+            defs,
+            object,
+            indent: detect_indent(buf).unwrap_or_else(|| "  ".to_string()),
+        };
+        rules.filters()?;
+        Ok(rules)
+    }
+
+    /// Reads rules from a file
+    pub fn open<P>(filename: &P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut buf = String::new();
+        let mut file = File::open(filename)?;
+        file.read_to_string(&mut buf)?;
+        RulesFile::parse(&buf)
+    }
+
+    /// Like [`RulesFile::open`], but starts out empty if the file doesn't
+    /// exist yet
+    ///
+    /// [`RulesFile::open`]: struct.RulesFile.html#method.open
+    pub fn open_or_new<P>(filename: &P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        match RulesFile::open(filename) {
+            Err(e) if e.is_not_found() => Ok(RulesFile::new()),
+            r => r,
+        }
+    }
+
+    /// Compiled filters, in the order they're defined in
+    pub fn filters(&self) -> Result<Vec<Filter>> {
+        self.filters
+            .iter()
+            .map(|v| self.filter(v)?.compile())
+            .collect()
+    }
+
+    /// Deserializes a filter, resolving references to definitions
+    fn filter(&self, value: &Value) -> Result<Filter> {
+        Ok(serde_json::from_value(defs::resolve(value, &self.defs)?)?)
+    }
+
+    /// Names of all filters, see [`Filter::name`]
+    ///
+    /// [`Filter::name`]: ../struct.Filter.html#method.name
+    pub fn names(&self) -> Result<Vec<String>> {
+        Ok(self.filters()?.iter().map(|f| f.name()).collect())
+    }
+
+    fn position(&self, name: &str) -> Result<Option<usize>> {
+        Ok(self.names()?.iter().position(|n| n == name))
+    }
+
+    /// Appends a filter, which is validated first. Filter names must be
+    /// unique.
+    pub fn add(&mut self, filter: Filter) -> Result<()> {
+        let filter = filter.compile()?;
+        if self.position(&filter.name())?.is_some() {
+            return Err(DuplicateFilter(filter.name()));
+        }
+        self.filters.push(serde_json::to_value(&filter)?);
+        Ok(())
+    }
+
+    /// Removes the filter called `name` and returns it
+    pub fn remove(&mut self, name: &str) -> Result<Filter> {
+        match self.position(name)? {
+            Some(i) => {
+                let filter = self.filter(&self.filters[i])?;
+                self.filters.remove(i);
+                Ok(filter)
+            }
+            None => Err(UnknownFilter(name.to_string())),
+        }
+    }
+
+    /// Renames the filter called `from`
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        if self.position(to)?.is_some() {
+            return Err(DuplicateFilter(to.to_string()));
+        }
+        self.update(from, |f| f.set_name(to))
+    }
+
+    /// Changes the filter called `name` through `f`
+    ///
+    /// Only values that actually changed are replaced in the file.
+    pub fn update<F>(&mut self, name: &str, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Filter),
+    {
+        let i = self
+            .position(name)?
+            .ok_or_else(|| UnknownFilter(name.to_string()))?;
+        let mut filter = self.filter(&self.filters[i])?;
+        f(&mut filter);
+        let filter = filter.compile()?;
+        merge(
+            &mut self.filters[i],
+            serde_json::to_value(&filter)?,
+            &self.defs,
+        );
+        Ok(())
+    }
+
+    /// Changes every filter through `f`, see [`RulesFile::update`]
+    ///
+    /// [`RulesFile::update`]: struct.RulesFile.html#method.update
+    pub fn update_all<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut Filter),
+    {
+        for i in 0..self.filters.len() {
+            let mut filter = self.filter(&self.filters[i])?;
+            f(&mut filter);
+            let filter = filter.compile()?;
+            merge(
+                &mut self.filters[i],
+                serde_json::to_value(&filter)?,
+                &self.defs,
+            );
+        }
+        Ok(())
+    }
+
+    /// Serializes the rules using the original indentation
+    pub fn to_string(&self) -> Result<String> {
+        let mut buf = Vec::new();
+        let fmt = PrettyFormatter::with_indent(self.indent.as_bytes());
+        let mut ser = Serializer::with_formatter(&mut buf, fmt);
+        match &self.object {
+            Some(object) => {
+                let mut object = object.clone();
+                object.insert("filters".to_string(), Value::Array(self.filters.clone()));
+                object.serialize(&mut ser)?;
+            }
+            None => self.filters.serialize(&mut ser)?,
+        }
+        buf.push(b'\n');
+        // serde_json only ever writes valid UTF-8
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    /// Writes the rules to a file, overwriting it
+    ///
+    /// The rules are written to a temporary file next to it first and renamed
+    /// over it once they're synced to disk, so the file is never left
+    /// truncated. A symlink is followed rather than replaced.
+    pub fn save<P>(&self, filename: &P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let buf = self.to_string()?;
+        let file = fs::canonicalize(filename).unwrap_or_else(|_| filename.as_ref().to_path_buf());
+        let dir = file.parent().unwrap_or_else(|| Path::new("."));
+        let tmp = dir.join(format!(".{}", unique_name()));
+        replace_file(&tmp, &file, buf.as_bytes())
+    }
+}
+
+/// Suggests a rule matching messages like `msg`, along with a name for it
+///
+/// Mailing list messages are matched by their `List-Id`, everything else by
+/// the domain of the sender. Returns `None` if the message has neither.
+pub fn suggest_rule(msg: &Message) -> Result<Option<(String, BTreeMap<String, crate::Value>)>> {
+    let (field, id, pattern) = match msg.header("list-id")? {
+        Some(header) if !header.trim().is_empty() => {
+            let id = list_id(&header);
+            let pattern = format!("(?i){}", regex::escape(&id));
+            ("list-id", id, pattern)
+        }
+        _ => match msg.header("from")?.and_then(|f| sender_domain(&f)) {
+            Some(domain) => {
+                let pattern = format!("(?i)@{}", regex::escape(&domain));
+                ("from", domain, pattern)
+            }
+            None => return Ok(None),
+        },
+    };
+    let mut rule = BTreeMap::new();
+    rule.insert(field.to_string(), crate::Value::Single(pattern));
+    Ok(Some((id, rule)))
+}
+
+/// Guesses the indentation from the first indented line
+fn detect_indent(buf: &str) -> Option<String> {
+    buf.lines().find_map(|line| {
+        let trimmed = line.trim_start_matches([' ', '\t']);
+        if trimmed.is_empty() || trimmed.len() == line.len() {
+            return None;
+        }
+        Some(line[..line.len() - trimmed.len()].to_string())
+    })
+}
+
+/// Replaces `old` with `new`, keeping the key order of `old` for everything
+/// that's in both, as well as references to `defs` and variables that
+/// resolve to what's in `new`
+fn merge(old: &mut Value, new: Value, defs: &Defs) {
+    if defs::resolve(old, defs).is_ok_and(|resolved| resolved == new) {
+        return;
+    }
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let mut merged = Map::new();
+            for (key, mut value) in std::mem::take(old) {
+                if let Some(n) = new.get(&key) {
+                    merge(&mut value, n.clone(), defs);
+                    merged.insert(key, value);
+                }
+            }
+            for (key, value) in new {
+                if !merged.contains_key(&key) {
+                    merged.insert(key, value);
+                }
+            }
+            *old = merged;
+        }
+        (Value::Array(old), Value::Array(new)) if old.len() == new.len() => {
+            for (o, n) in old.iter_mut().zip(new) {
+                merge(o, n, defs);
+            }
+        }
+        (old, new) => *old = new,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keys in an order of their own, four spaces, metadata and a reference
+    const RULES: &str = r#"{
+    "vars": {
+        "domain": "work\\.example"
+    },
+    "defs": {
+        "work": [
+            "@{{domain}}$"
+        ]
+    },
+    "filters": [
+        {
+            "op": {
+                "add": "work"
+            },
+            "rules": [
+                {
+                    "from": {
+                        "$ref": "work"
+                    }
+                }
+            ],
+            "name": "work",
+            "meta": {
+                "since": "2024"
+            }
+        },
+        {
+            "name": "boss",
+            "rules": [
+                {
+                    "from": "^boss@{{domain}}$"
+                }
+            ],
+            "op": {
+                "add": [
+                    "boss",
+                    "important"
+                ]
+            }
+        }
+    ]
+}
+"#;
+
+    #[test]
+    fn unchanged_files_round_trip() {
+        let rules = RulesFile::parse(RULES).unwrap();
+        assert_eq!(rules.to_string().unwrap(), RULES);
+        let names = rules.names().unwrap();
+        assert_eq!(names, ["work", "boss"]);
+    }
+
+    #[test]
+    fn updates_only_touch_what_changed() {
+        let mut rules = RulesFile::parse(RULES).unwrap();
+        rules
+            .update("work", |f| {
+                f.op.rm = Some(crate::Value::Single("inbox".into()))
+            })
+            .unwrap();
+        let expected = RULES.replace(
+            r#"                "add": "work"
+            },"#,
+            r#"                "add": "work",
+                "rm": "inbox"
+            },"#,
+        );
+        assert_eq!(rules.to_string().unwrap(), expected);
+    }
+
+    #[test]
+    fn updates_keep_references_and_variables() {
+        let mut rules = RulesFile::parse(RULES).unwrap();
+        rules
+            .update_all(|f| f.set_name(&f.name().to_uppercase()))
+            .unwrap();
+        let expected = RULES
+            .replace(r#""name": "work""#, r#""name": "WORK""#)
+            .replace(r#""name": "boss""#, r#""name": "BOSS""#);
+        assert_eq!(rules.to_string().unwrap(), expected);
+    }
+
+    #[test]
+    fn changed_references_and_variables_are_replaced() {
+        let mut rules = RulesFile::parse(RULES).unwrap();
+        rules
+            .update("boss", |f| {
+                f.rules[0].insert(
+                    "from".to_string(),
+                    crate::Value::Single("^ceo@work\\.example$".into()),
+                );
+            })
+            .unwrap();
+        let file = rules.to_string().unwrap();
+        assert!(file.contains(r#""from": "^ceo@work\\.example$""#));
+        // the other filter's reference is untouched
+        assert!(file.contains(r#""$ref": "work""#));
+        assert!(!file.contains("^boss@"));
+    }
+
+    #[test]
+    fn adding_and_removing_filters() {
+        let mut rules = RulesFile::parse(RULES).unwrap();
+        let boss = rules.remove("boss").unwrap();
+        assert!(rules.remove("boss").is_err());
+        assert!(rules.add(rules.filters().unwrap().remove(0)).is_err());
+        rules.add(boss).unwrap();
+        assert_eq!(rules.names().unwrap(), ["work", "boss"]);
+        assert!(rules.rename("work", "boss").is_err());
+        rules.rename("work", "office").unwrap();
+        assert!(rules.to_string().unwrap().contains(r#""since": "2024""#));
+    }
+
+    #[test]
+    fn indentation_is_detected() {
+        let rules = RulesFile::parse("[\n\t{\"rules\": [{\"from\": \"a\"}]}\n]").unwrap();
+        assert_eq!(
+            rules.to_string().unwrap(),
+            "[\n\t{\n\t\t\"rules\": [\n\t\t\t{\n\t\t\t\t\"from\": \"a\"\n\t\t\t}\n\t\t]\n\t}\n]\n"
+        );
+    }
+
+    #[test]
+    fn saving_replaces_the_target_of_a_symlink() {
+        let dir = std::env::temp_dir().join(format!("notcoal-rules-{}", unique_name()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("rules.json");
+        let link = dir.join("link.json");
+        fs::write(&file, "[]").unwrap();
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        RulesFile::parse(RULES).unwrap().save(&link).unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&file).unwrap(), RULES);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
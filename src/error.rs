@@ -1,6 +1,9 @@
 use std::convert::From;
+use std::path::PathBuf;
 use std::{fmt, io, result};
 
+use notmuch::Message;
+
 pub type Result<T> = result::Result<T, Error>;
 
 // XXX The following ought to be handled by a macro
@@ -15,11 +18,179 @@ pub enum Error {
     UnsupportedQuery(String),
     UnsupportedValue(String),
     RegexUncompiled(String),
+    InvalidTag(String),
+    UnknownFilter(String),
+    DuplicateFilter(String),
+    PluginError(String),
+    RunError(Box<RunError>),
+}
+
+impl Error {
+    /// Checks if this error (or the one wrapped in a [`RunError`]) was caused
+    /// by a file that doesn't exist (anymore)
+    ///
+    /// [`RunError`]: struct.RunError.html
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Error::IoError(e) => e.kind() == io::ErrorKind::NotFound,
+            Error::RunError(e) => e.error.is_not_found(),
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Error::RunError(e) => write!(f, "{}", e),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// What was being done to a message when a [`RunError`] occurred
+///
+/// [`RunError`]: struct.RunError.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Testing if a filter matches
+    Match,
+    /// The `rm` operation
+    RemoveTags,
+    /// The `add` operation
+    AddTags,
+    /// The `run` operation
+    Run,
+    /// The `export` operation
+    Export,
+    /// The `copy` operation
+    Copy,
+    /// The `move` operation
+    Move,
+    /// The `del` operation
+    Delete,
+    /// Removing the query tag after all filters ran
+    QueryTag,
+    /// Syncing tags to maildir flags after all filters ran
+    SyncFlags,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Stage::Match => "matching",
+            Stage::RemoveTags => "removing tags",
+            Stage::AddTags => "adding tags",
+            Stage::Run => "running command",
+            Stage::Export => "exporting",
+            Stage::Copy => "copying",
+            Stage::Move => "moving",
+            Stage::Delete => "deleting",
+            Stage::QueryTag => "removing query tag",
+            Stage::SyncFlags => "syncing maildir flags",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// An [`Error`] that happened while processing a specific message
+///
+/// [`Error`]: enum.Error.html
+#[derive(Debug)]
+pub struct RunError {
+    /// Message-ID of the message being processed
+    pub msg_id: String,
+    /// File name of the message being processed
+    pub filename: PathBuf,
+    /// Name of the filter being applied, if the error happened within one
+    pub filter: Option<String>,
+    /// What was being done when the error occurred
+    pub stage: Stage,
+    /// The underlying error
+    pub error: Error,
+}
+
+impl RunError {
+    /// Adds the context of the message being processed to an error, unless it
+    /// already carries that context
+    pub(crate) fn from_error<E>(e: E, msg: &Message, filter: Option<&str>, stage: Stage) -> Self
+    where
+        E: Into<Error>,
+    {
+        match e.into() {
+            Error::RunError(e) => *e,
+            e => RunError {
+                msg_id: msg.id().to_string(),
+                filename: msg.filename(),
+                filter: filter.map(|f| f.to_string()),
+                stage,
+                error: e,
+            },
+        }
+    }
+
+    /// Like [`RunError::from_error`], but returns an [`Error`]
+    ///
+    /// [`RunError::from_error`]: struct.RunError.html#method.from_error
+    /// [`Error`]: enum.Error.html
+    pub(crate) fn wrap<E>(e: E, msg: &Message, filter: Option<&str>, stage: Stage) -> Error
+    where
+        E: Into<Error>,
+    {
+        Error::RunError(Box::new(Self::from_error(e, msg, filter, stage)))
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} failed for {} ({})",
+            self.stage,
+            self.msg_id,
+            self.filename.display()
+        )?;
+        if let Some(filter) = &self.filter {
+            write!(f, " in filter {}", filter)?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+/// Non-fatal conditions noticed while loading or applying filters
+///
+/// Unlike [`Error`], these don't abort a run. They are collected and handed
+/// back to the caller, who may decide to print or otherwise surface them.
+#[derive(Debug)]
+pub enum Warning {
+    /// A field or value that still works, but won't in the future
+    Deprecated(String),
+    /// A value had to be converted in a way that may lose information
+    LossyConversion(String),
+    /// A message was left untouched instead of failing the whole run
+    SkippedMessage(String),
+    /// An operation was left out because it can't be applied in this context
+    SkippedOperation(String),
+    /// A filter matched more messages than it may, see [`Filter::max_matches`]
+    ///
+    /// [`Filter::max_matches`]: ../struct.Filter.html#structfield.max_matches
+    MatchLimit(String),
+    /// An encrypted message couldn't be decrypted, see [`Filter::decrypt`]
+    ///
+    /// [`Filter::decrypt`]: ../struct.Filter.html#structfield.decrypt
+    Undecryptable(String),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::Deprecated(s) => write!(f, "deprecated: {}", s),
+            Warning::LossyConversion(s) => write!(f, "lossy conversion: {}", s),
+            Warning::SkippedMessage(s) => write!(f, "skipped message: {}", s),
+            Warning::SkippedOperation(s) => write!(f, "skipped operation: {}", s),
+            Warning::MatchLimit(s) => write!(f, "match limit reached: {}", s),
+            Warning::Undecryptable(s) => write!(f, "couldn't decrypt: {}", s),
+        }
     }
 }
 
@@ -0,0 +1,131 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::date::{format_asctime, format_template, now};
+use crate::error::Error::*;
+use crate::error::{Result, RunError, Stage};
+use crate::maildir::unique_name;
+
+use notmuch::Message;
+
+/// Copy a message into an archive outside the maildir, see
+/// [`Operations::export`]
+///
+/// [`Operations::export`]: struct.Operations.html#structfield.export
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ExportOp {
+    /// Where the message goes, e.g. `~/mail-archive/%Y/%m/`
+    ///
+    /// `%Y`, `%m` and `%d` are replaced with the year, month and day the
+    /// message was sent (in UTC), a leading `~/` with the home directory.
+    /// For `eml` this is a directory, for `mbox` the file the message is
+    /// appended to, or a directory holding `archive.mbox` if it ends in `/`.
+    pub to: String,
+    /// Format of the archive, `eml` by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<ExportFormat>,
+}
+
+/// Format of an archive messages are exported to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// A file per message, named like a maildir file with `.eml` appended
+    #[default]
+    Eml,
+    /// Messages appended to an mbox file (in its `mboxrd` flavour)
+    Mbox,
+}
+
+/// Checks that `op` has a destination with nothing but known placeholders
+pub(crate) fn validate_export(op: &ExportOp) -> Result<()> {
+    if op.to.is_empty() {
+        return Err(UnsupportedValue("export needs a destination".to_string()));
+    }
+    format_template(&op.to, now()).map(|_| ())
+}
+
+/// The destination of a message sent at `date`
+fn destination(op: &ExportOp, date: i64) -> Result<PathBuf> {
+    let to = format_template(&op.to, date)?;
+    Ok(match (to.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(to),
+    })
+}
+
+/// Makes sure the entries of `dir` are on disk, e.g. a file just renamed
+/// into it
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Writes `contents` as a new file in `dir`
+///
+/// Like [`write_file`], the file is written under a temporary name and
+/// synced to disk before it's renamed, so it's complete whenever it's
+/// visible.
+///
+/// [`write_file`]: maildir/fn.write_file.html
+fn write_eml(dir: &Path, contents: &[u8]) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let name = unique_name();
+    let tmp = dir.join(format!(".{}.tmp", name));
+    let res = File::create(&tmp).and_then(|mut f| {
+        f.write_all(contents)?;
+        f.sync_all()
+    });
+    if let Err(e) = res.and_then(|_| fs::rename(&tmp, dir.join(format!("{}.eml", name)))) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    sync_dir(dir)
+}
+
+/// Appends `contents` to the mbox file `file`, quoting lines that would be
+/// taken for the start of another message
+fn append_mbox(file: &Path, contents: &[u8], date: i64) -> Result<()> {
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut buf = format!("From MAILER-DAEMON {}\n", format_asctime(date)).into_bytes();
+    for line in contents.split_inclusive(|b| *b == b'\n') {
+        let quoted = line.iter().position(|b| *b != b'>');
+        if quoted.is_some_and(|i| line[i..].starts_with(b"From ")) {
+            buf.push(b'>');
+        }
+        buf.extend_from_slice(line);
+    }
+    if !buf.ends_with(b"\n") {
+        buf.push(b'\n');
+    }
+    buf.push(b'\n');
+    let mut mbox = OpenOptions::new().create(true).append(true).open(file)?;
+    mbox.write_all(&buf)?;
+    mbox.sync_all()?;
+    Ok(())
+}
+
+/// Copies a message into the archive `op` names
+///
+/// Only returns once the copy is synced to disk, so the message can safely
+/// be deleted afterwards: a crash in between leaves it in both places rather
+/// than in neither.
+pub(crate) fn export(msg: &Message, op: &ExportOp, name: &str) -> Result<()> {
+    let wrap = |e| RunError::wrap(e, msg, Some(name), Stage::Export);
+    let contents = fs::read(msg.filename()).map_err(|e| wrap(e.into()))?;
+    let to = destination(op, msg.date()).map_err(wrap)?;
+    match op.format.unwrap_or_default() {
+        ExportFormat::Eml => write_eml(&to, &contents),
+        ExportFormat::Mbox if op.to.ends_with('/') => {
+            append_mbox(&to.join("archive.mbox"), &contents, msg.date())
+        }
+        ExportFormat::Mbox => append_mbox(&to, &contents, msg.date()),
+    }
+    .map_err(wrap)
+}
@@ -1,45 +1,916 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::BTreeMap;
-use std::collections::HashMap;
-use std::convert::AsRef;
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::hash::Hasher;
-use std::io::Read;
 use std::iter::Iterator;
+use std::net::IpAddr;
+use std::path::Path;
+use std::rc::Rc;
+use std::result;
+use std::time::Instant;
 
 use mailparse::*;
-use regex::Regex;
 use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
 
 use crate::error::Error::*;
+use crate::error::Warning::*;
 use crate::error::*;
 
+use crate::calendar::validate_calendar;
+use crate::cidr::Cidr;
+use crate::date::{parse_duration, DateSpec};
+use crate::digest::validate_digest;
+use crate::export::validate_export;
+use crate::html::to_text;
+use crate::mail::DbMail;
+use crate::maildir;
+use crate::number::NumberSpec;
+use crate::pattern::{Pattern, PatternSet};
+use crate::report::mailing_list;
+use crate::validate_tag;
+#[cfg(feature = "dkim")]
+use crate::verify::dkim_valid;
+#[cfg(feature = "smime")]
+use crate::verify::smime_valid;
+use crate::Mail;
 use crate::Operations;
+use crate::Timings;
 use crate::Value;
 use crate::Value::*;
 
-use notmuch::{Database, Message, Query, Threads};
+use notmuch::{Database, Message};
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Filter {
+    #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<String>,
     /// Description
     ///
     /// Not really used for anything at this point in time, but may be good for
     /// users to remember what this specific filter is doing
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub desc: Option<String>,
     /// List of rules
     ///
     /// This list is an OR list, meaning the filter will match if any rule
-    /// matches. However, AND combinations may happen within a rule
+    /// matches. However, AND combinations may happen within a rule. Prefixing
+    /// a field with `!` (e.g. `"!from"`) negates its condition, which for
+    /// headers also matches messages without that header
     // at the moment, since we are generating a hash in the name function this
     // field needs to be consistent in the order it prints its key/value pairs
     pub rules: Vec<BTreeMap<String, Value>>,
     /// Operations that will be applied if this any rule matches
+    #[serde(default)]
     pub op: Operations,
+    /// Filters only evaluated for messages this one matched
+    ///
+    /// Saves repeating conditions shared by several filters, e.g. the
+    /// sender. The filters in here are evaluated right after this one, in
+    /// its phase and ordered by their own [`Filter::priority`], like any
+    /// other filter otherwise, so a filter with nothing but rules and `then`
+    /// just groups them. [`filters_from`] moves them behind this one, see
+    /// [`flatten`].
+    ///
+    /// [`Filter::priority`]: struct.Filter.html#structfield.priority
+    /// [`filters_from`]: fn.filters_from.html
+    /// [`flatten`]: fn.flatten.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub then: Option<Vec<Filter>>,
+    /// Filters only evaluated for messages this one didn't match, like
+    /// [`Filter::then`] otherwise
+    ///
+    /// [`Filter::then`]: struct.Filter.html#structfield.then
+    #[serde(rename = "else", skip_serializing_if = "Option::is_none")]
+    pub otherwise: Option<Vec<Filter>>,
+    /// Observation-only mode
+    ///
+    /// If set, matches are reported by [`filter`] but [`Filter::op`] is never
+    /// applied, which allows trying out new filters on live mail
+    ///
+    /// [`filter`]: fn.filter.html
+    /// [`Filter::op`]: struct.Filter.html#structfield.op
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry: Option<bool>,
+    /// Don't evaluate any further filters for a message once this one
+    /// matched it
+    ///
+    /// Like procmail's delivering recipes, e.g. for a spam filter whose tags
+    /// later filters shouldn't add to. Filters in observation-only mode
+    /// never stop, and [`classify`] and [`trace`] still evaluate every
+    /// filter.
+    ///
+    /// [`classify`]: fn.classify.html
+    /// [`trace`]: fn.trace.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<bool>,
+    /// Stop applying this filter once it matched this many messages in a
+    /// single run
+    ///
+    /// A safety valve for overly broad rules: further matches are ignored
+    /// and a [`Warning`] is emitted, while all other filters keep running.
+    ///
+    /// [`Warning`]: error/enum.Warning.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_matches: Option<usize>,
+    /// Pipeline phase, see [`Phase`]
+    ///
+    /// [`Phase`]: enum.Phase.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phase: Option<Phase>,
+    /// Where the filter goes within its phase, see [`sort_by_priority`]
+    ///
+    /// Filters with a lower priority are applied first, those without have
+    /// a priority of 0.
+    ///
+    /// [`sort_by_priority`]: fn.sort_by_priority.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i64>,
+    /// Points added to the score of messages this filter matched
+    ///
+    /// For heuristics that only make sense in aggregate: filters with a
+    /// score (and possibly no operations) add up evidence, and later ones
+    /// act on the total with an `@score` rule, e.g. `{"@score": {"ge": 5}}`.
+    /// Scores may be negative, and filters in observation-only mode don't
+    /// score.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// Databases this filter applies to, see [`Filter::applies_to`]
+    ///
+    /// [`Filter::applies_to`]: struct.Filter.html#method.applies_to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub databases: Option<Vec<String>>,
+    /// Evaluate the cheap conditions of each rule first, the default
+    ///
+    /// Conditions within a rule are evaluated one after the other, and
+    /// evaluation stops at the first one that doesn't hold. They're ordered
+    /// by how expensive they are: headers first, then tags, paths and the
+    /// like, then thread queries, and everything reading the message file
+    /// last. That way e.g. `@body` is only parsed if the headers in the same
+    /// rule matched. If set to `false`, conditions are evaluated in
+    /// alphabetical order of their fields instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cheap_first: Option<bool>,
+    /// Decrypt PGP/MIME encrypted messages for rules on their body
+    ///
+    /// `@body`, `@body-text`, `@attachment` and the other fields read from
+    /// the body see the decrypted message, decrypted with `gpg` (and thus
+    /// the user's agent). Messages that can't be decrypted are matched as
+    /// they are, with a [`Warning`]. Needs the `openpgp` feature.
+    ///
+    /// [`Warning`]: error/enum.Warning.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decrypt: Option<bool>,
+    /// How urgent matching messages are, see [`Urgency`]
+    ///
+    /// [`Urgency`]: enum.Urgency.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub urgency: Option<Urgency>,
+    /// Free-form metadata
+    ///
+    /// Ignored by notcoal but preserved when filters are written back, so
+    /// front-ends can keep their own data (e.g. colors, key bindings or
+    /// categories) in the same rules file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<BTreeMap<String, serde_json::Value>>,
+    #[serde(skip)]
+    re: Vec<CompiledRule>,
+    #[serde(skip)]
+    matchers: Matchers,
+    /// What this filter made of the last message, if it has filters in
+    /// [`Filter::then`] or [`Filter::otherwise`] depending on it
+    ///
+    /// [`Filter::then`]: struct.Filter.html#structfield.then
+    /// [`Filter::otherwise`]: struct.Filter.html#structfield.otherwise
     #[serde(skip)]
-    re: Vec<HashMap<String, Vec<Regex>>>,
+    outcome: Option<Outcome>,
+    /// The outcome of the filter this one is in [`Filter::then`] (`true`) or
+    /// [`Filter::otherwise`] (`false`) of
+    ///
+    /// [`Filter::then`]: struct.Filter.html#structfield.then
+    /// [`Filter::otherwise`]: struct.Filter.html#structfield.otherwise
+    #[serde(skip)]
+    guard: Option<(Outcome, bool)>,
+    /// If the tags to add are templates, see [`Filter::operations_for`]
+    ///
+    /// [`Filter::operations_for`]: struct.Filter.html#method.operations_for
+    #[serde(skip)]
+    templates: bool,
+}
+
+/// Message-ID of the last message a filter was evaluated for, and if it
+/// matched
+type Outcome = Rc<RefCell<Option<(String, bool)>>>;
+
+/// Fields of a rule along with their patterns, in the order they're evaluated
+type CompiledRule = Vec<(String, Vec<Pattern>)>;
+
+/// When a filter is applied relative to the others
+///
+/// For every message, all filters in the classify phase are applied before
+/// any filter in the act phase, so actions can depend on the complete set of
+/// tags assigned during classification. Within a phase, filters are ordered
+/// by [`Filter::priority`], then keep the order they're defined in. Filters
+/// without a phase are in the act phase.
+///
+/// [`Filter::priority`]: struct.Filter.html#structfield.priority
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Classify,
+    #[default]
+    Act,
+}
+
+/// How urgent the messages a filter matches are, for triaging
+///
+/// Instead of every rules file inventing its own tags for this, matching
+/// messages get the tag `urgent` for high and `low-priority` for low urgency,
+/// and the tag of the other level is removed, so whichever filter matched
+/// last decides. Normal urgency removes both. See [`Filter::operations`].
+///
+/// [`Filter::operations`]: struct.Filter.html#method.operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Urgency {
+    High,
+    Normal,
+    Low,
+}
+
+/// Tags maintained for [`Urgency`] levels
+///
+/// [`Urgency`]: enum.Urgency.html
+const URGENCY_TAGS: &[(Urgency, &str)] =
+    &[(Urgency::High, "urgent"), (Urgency::Low, "low-priority")];
+
+impl Urgency {
+    /// The tag messages of this urgency get, if any
+    pub fn tag(&self) -> Option<&'static str> {
+        URGENCY_TAGS
+            .iter()
+            .find(|(u, _)| u == self)
+            .map(|(_, t)| *t)
+    }
+
+    /// Name as used in rules files
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Urgency::High => "high",
+            Urgency::Normal => "normal",
+            Urgency::Low => "low",
+        }
+    }
+}
+
+/// Adds `tag` to the tags of an operation
+fn push_tag(value: &mut Option<Value>, tag: &str) {
+    *value = match value.take() {
+        None | Some(Bool(false)) => Some(Single(tag.to_string())),
+        Some(Single(t)) => Some(Multiple(vec![t, tag.to_string()])),
+        Some(Multiple(mut tags)) => {
+            tags.push(tag.to_string());
+            Some(Multiple(tags))
+        }
+        other => other,
+    };
+}
+
+/// A single condition evaluated by [`Filter::trace`]
+///
+/// [`Filter::trace`]: struct.Filter.html#method.trace
+#[derive(Debug, Clone)]
+pub struct TraceStep {
+    /// Index of the rule in [`Filter::rules`] the condition belongs to
+    ///
+    /// [`Filter::rules`]: struct.Filter.html#structfield.rules
+    pub rule: usize,
+    /// Field as written in the rule, e.g. `from` or `!@tags`
+    pub field: String,
+    /// What the message has for that field, e.g. the header's value
+    pub values: Vec<String>,
+    /// If the condition held, taking negation into account
+    pub matched: bool,
+    /// What matched the condition's patterns, empty unless it held because
+    /// of something in the message (i.e. it's not negated)
+    pub spans: Vec<MatchSpan>,
+}
+
+/// Text in a message that matched a rule, e.g. to highlight it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchSpan {
+    /// Header name or special field, e.g. `subject` or `@body`, without
+    /// negation
+    pub field: String,
+    /// Which of the field's values the text is in, for fields with more than
+    /// one like `received`, see [`TraceStep::values`]
+    ///
+    /// [`TraceStep::values`]: struct.TraceStep.html#structfield.values
+    pub value: usize,
+    /// Byte offset of the start of the match in that value
+    pub start: usize,
+    /// Byte offset of the end of the match in that value
+    pub end: usize,
+    /// The matching text itself
+    pub text: String,
+}
+
+/// Where any of `patterns` match in any of `values`
+fn match_spans(field: &str, values: &[String], patterns: &[Pattern]) -> Vec<MatchSpan> {
+    let mut spans = Vec::new();
+    for (i, v) in values.iter().enumerate() {
+        for (start, end) in patterns.iter().filter_map(|re| re.find(v)) {
+            spans.push(MatchSpan {
+                field: field.to_string(),
+                value: i,
+                start,
+                end,
+                text: v[start..end].to_string(),
+            });
+        }
+    }
+    spans
+}
+
+/// Provides the values of a special field like `@body` for a message
+///
+/// All built-in special fields are matchers, and applications embedding
+/// notcoal can add their own with [`Filter::register`], e.g. an `@contact`
+/// field looking up the sender in an address book. As with any special field,
+/// a condition on it holds if any of the values matches any of its patterns.
+///
+/// [`Filter::register`]: struct.Filter.html#method.register
+pub trait Matcher {
+    /// Everything the message has for the field, or `None` if conditions on
+    /// it shouldn't count at all, like those on unknown special fields
+    fn values(&self, msg: &dyn Mail, warnings: &mut Vec<Warning>) -> Result<Option<Vec<String>>>;
+
+    /// Roughly how expensive getting the values is, from 0 for headers to 4
+    /// for reading the whole message file, see [`Filter::cheap_first`]
+    ///
+    /// [`Filter::cheap_first`]: struct.Filter.html#structfield.cheap_first
+    fn cost(&self) -> u8 {
+        4
+    }
+}
+
+/// A built-in field, either a special field or a header
+struct Builtin<'a>(&'a str);
+
+impl Matcher for Builtin<'_> {
+    fn values(&self, msg: &dyn Mail, warnings: &mut Vec<Warning>) -> Result<Option<Vec<String>>> {
+        field_values(self.0, msg, warnings)
+    }
+
+    fn cost(&self) -> u8 {
+        cost(self.0)
+    }
+}
+
+/// Special fields registered with [`Filter::register`], by name
+///
+/// [`Filter::register`]: struct.Filter.html#method.register
+#[derive(Default)]
+struct Matchers(BTreeMap<String, Rc<dyn Matcher>>);
+
+impl fmt::Debug for Matchers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// Everything the message has for a field in a rule, or `None` if it's an
+/// unknown special field
+fn field_values(
+    part: &str,
+    msg: &dyn Mail,
+    warnings: &mut Vec<Warning>,
+) -> Result<Option<Vec<String>>> {
+    let values = match part {
+        "@path" => msg
+            .filenames()
+            .iter()
+            .map(|f| match f.to_str() {
+                Some(n) => n.to_string(),
+                None => {
+                    let n = f.to_string_lossy().to_string();
+                    let w = format!("path {:?} is not valid UTF-8", f);
+                    warnings.push(LossyConversion(w));
+                    n
+                }
+            })
+            .collect(),
+        "@tags" => msg.tags(),
+        "@folder" => msg.folders(),
+        "@flags" => msg
+            .filenames()
+            .iter()
+            .map(|f| maildir::flags(f).unwrap_or_default().to_string())
+            .collect(),
+        "@duplicate" => vec![(msg.filenames().len() > 1).to_string()],
+        "@size" => msg
+            .filenames()
+            .first()
+            .map(|f| Ok::<_, std::io::Error>(f.metadata()?.len().to_string()))
+            .transpose()?
+            .into_iter()
+            .collect(),
+        "@date" => msg.date().map(|d| d.to_string()).into_iter().collect(),
+        "@thread-tags" => msg.thread_tags()?,
+        "@thread-size" => vec![msg.thread_size()?.to_string()],
+        "@thread-depth" => vec![msg.thread_depth()?.to_string()],
+        "@score" => vec![msg.score().to_string()],
+        _ if BODY_FIELDS.contains(&part) => {
+            // since we might combine these we try avoid parsing the
+            // same file over and over again.
+            let buf = msg.raw()?;
+            body_values(part, &parse_mail(&buf)?)?
+        }
+        "@list" => mailing_list(|h| msg.header(h))?.into_iter().collect(),
+        "@spam-score" => spam_score(|h| msg.header(h))?
+            .map(|s| s.to_string())
+            .into_iter()
+            .collect(),
+        // only known with statistics, see SenderHistory::matcher
+        "@sender-score" => Vec::new(),
+        "@recipient-count" => {
+            let mut count = 0;
+            for header in ["to", "cc"] {
+                count += addresses(&msg.header(header)?.unwrap_or_default(), "addr").len();
+            }
+            vec![count.to_string()]
+        }
+        "@dkim" | "@spf" | "@dmarc" => {
+            let buf = msg.raw()?;
+            let (headers, _) = parse_headers(&buf)?;
+            auth_results(&headers, &part[1..])
+        }
+        #[cfg(feature = "dkim")]
+        "@dkim-valid" => dkim_valid(&msg.raw()?)?,
+        #[cfg(feature = "smime")]
+        "@smime-valid" => smime_valid(&msg.raw()?)?,
+        "@received-ip" => {
+            let buf = msg.raw()?;
+            let (headers, _) = parse_headers(&buf)?;
+            received_ips(&headers)
+        }
+        _ if part.starts_with('@') && (part.ends_with("-addr") || part.ends_with("-name")) => {
+            let (header, what) = part[1..].rsplit_once('-').unwrap_or_default();
+            match msg.header(header)? {
+                Some(h) => addresses(&h, what),
+                None => Vec::new(),
+            }
+        }
+        _ if part.starts_with('@') => return Ok(None),
+        _ => msg.header(part)?.into_iter().collect(),
+    };
+    Ok(Some(values))
+}
+
+/// Special fields read from the body of a message
+pub(crate) const BODY_FIELDS: &[&str] = &[
+    "@attachment",
+    "@attachment-count",
+    "@attachment-body",
+    "@body",
+    "@body-text",
+    "@mime-type",
+];
+
+/// Everything the parsed message has for one of the [`BODY_FIELDS`]
+///
+/// [`BODY_FIELDS`]: constant.BODY_FIELDS.html
+pub(crate) fn body_values(part: &str, parsed: &ParsedMail) -> Result<Vec<String>> {
+    Ok(if part == "@attachment" {
+        parsed
+            .subparts
+            .iter()
+            .filter_map(|s| s.get_content_disposition().params.get("filename").cloned())
+            .collect()
+    } else if part == "@body" {
+        vec![parsed.get_body()?]
+    } else if part == "@mime-type" {
+        mime_types(parsed)
+    } else if part == "@body-text" {
+        body_text(parsed)?
+    } else if part == "@attachment-count" {
+        vec![attachment_count(parsed).to_string()]
+    } else {
+        parsed
+            .subparts
+            .iter()
+            // XXX are we sure we only care about text
+            // mime types? There others?
+            .filter(|s| s.ctype.mimetype.starts_with("text"))
+            .map(|s| s.get_body())
+            .collect::<result::Result<_, _>>()?
+    })
+}
+
+/// Special fields holding numbers, whose rules are comparisons rather than
+/// regular expressions
+pub(crate) const NUMERIC_FIELDS: &[&str] = &[
+    "@attachment-count",
+    "@size",
+    "@recipient-count",
+    "@spam-score",
+    "@sender-score",
+    "@thread-size",
+    "@thread-depth",
+    "@score",
+];
+
+/// Special fields whose values change without the message changing, e.g.
+/// with the rest of its thread or the database, see
+/// [`Filter::depends_on_state`]
+///
+/// [`Filter::depends_on_state`]: struct.Filter.html#method.depends_on_state
+const STATEFUL_FIELDS: &[&str] = &[
+    "@query",
+    "@thread-tags",
+    "@thread-size",
+    "@thread-depth",
+    "@sender-score",
+    "@dkim-valid",
+    "@smime-valid",
+];
+
+/// Special fields only available with a cargo feature, the feature and if it's
+/// enabled
+const FEATURE_FIELDS: &[(&str, &str, bool)] = &[
+    (
+        "@attachment",
+        "special-body",
+        cfg!(feature = "special-body"),
+    ),
+    (
+        "@attachment-body",
+        "special-body",
+        cfg!(feature = "special-body"),
+    ),
+    (
+        "@attachment-count",
+        "special-body",
+        cfg!(feature = "special-body"),
+    ),
+    ("@body", "special-body", cfg!(feature = "special-body")),
+    ("@body-text", "special-body", cfg!(feature = "special-body")),
+    ("@mime-type", "special-body", cfg!(feature = "special-body")),
+    ("@dkim", "special-body", cfg!(feature = "special-body")),
+    ("@spf", "special-body", cfg!(feature = "special-body")),
+    ("@dmarc", "special-body", cfg!(feature = "special-body")),
+    (
+        "@received-ip",
+        "special-body",
+        cfg!(feature = "special-body"),
+    ),
+    ("@dkim-valid", "dkim", cfg!(feature = "dkim")),
+    ("@smime-valid", "smime", cfg!(feature = "smime")),
+    (
+        "@thread-tags",
+        "special-thread",
+        cfg!(feature = "special-thread"),
+    ),
+    (
+        "@thread-size",
+        "special-thread",
+        cfg!(feature = "special-thread"),
+    ),
+    (
+        "@thread-depth",
+        "special-thread",
+        cfg!(feature = "special-thread"),
+    ),
+];
+
+/// Roughly how expensive it is to evaluate a field, see
+/// [`Filter::cheap_first`]
+///
+/// [`Filter::cheap_first`]: struct.Filter.html#structfield.cheap_first
+fn cost(field: &str) -> u8 {
+    match field {
+        "@tags" | "@date" | "@score" => 1,
+        "@path" | "@folder" | "@flags" | "@duplicate" | "@size" => 2,
+        "@query" | "@thread-tags" | "@thread-size" | "@thread-depth" => 3,
+        "@attachment" | "@attachment-body" | "@attachment-count" | "@body" | "@body-text"
+        | "@mime-type" | "@dkim" | "@spf" | "@dmarc" | "@received-ip" | "@dkim-valid"
+        | "@smime-valid" => 4,
+        // headers, including the special fields derived from them
+        _ => 0,
+    }
+}
+
+/// Special fields that are either `true` or `false`
+const BOOLEAN_FIELDS: &[&str] = &["@duplicate"];
+
+/// Content types of a message part and all parts nested in it
+fn mime_types(part: &ParsedMail) -> Vec<String> {
+    let mut types = vec![part.ctype.mimetype.clone()];
+    for sub in &part.subparts {
+        types.extend(mime_types(sub));
+    }
+    types
+}
+
+/// Bodies of all plain text parts, or if there are none, the HTML parts
+/// converted to text
+fn body_text(part: &ParsedMail) -> Result<Vec<String>> {
+    fn collect<'a>(part: &'a ParsedMail<'a>, parts: &mut Vec<&'a ParsedMail<'a>>) {
+        if part.subparts.is_empty() {
+            parts.push(part);
+        }
+        for sub in &part.subparts {
+            collect(sub, parts);
+        }
+    }
+    let mut parts = Vec::new();
+    collect(part, &mut parts);
+    let is = |p: &&ParsedMail, mimetype: &str| p.ctype.mimetype == mimetype;
+    let plain: Vec<_> = parts.iter().filter(|p| is(p, "text/plain")).collect();
+    if !plain.is_empty() {
+        return Ok(plain
+            .iter()
+            .map(|p| p.get_body())
+            .collect::<result::Result<_, _>>()?);
+    }
+    parts
+        .iter()
+        .filter(|p| is(p, "text/html"))
+        .map(|p| Ok(to_text(&p.get_body()?)))
+        .collect()
+}
+
+/// Counts the attachments in a message part and all parts nested in it
+///
+/// Besides parts explicitly marked as attachments, this counts parts with a
+/// file name, and parts that aren't text and come without any disposition
+/// (as some mailers send them).
+fn attachment_count(part: &ParsedMail) -> usize {
+    if !part.subparts.is_empty() {
+        return part.subparts.iter().map(attachment_count).sum();
+    }
+    let disposition = part.get_content_disposition();
+    let undisposed = part
+        .headers
+        .get_first_value("Content-Disposition")
+        .is_none();
+    let is_attachment = disposition.disposition == DispositionType::Attachment
+        || disposition.params.contains_key("filename")
+        || part.ctype.params.contains_key("name")
+        || (undisposed && !part.ctype.mimetype.starts_with("text/"));
+    is_attachment as usize
+}
+
+/// Addresses (`what` being `addr`) or display names (`name`) in an address
+/// header like From or To
+fn addresses(header: &str, what: &str) -> Vec<String> {
+    let addrs = match addrparse(header) {
+        Ok(addrs) => addrs,
+        Err(_) => return Vec::new(),
+    };
+    addrs
+        .iter()
+        .flat_map(|a| match a {
+            MailAddr::Single(info) => vec![info.clone()],
+            MailAddr::Group(group) => group.addrs.clone(),
+        })
+        .filter_map(|info| match what {
+            "addr" => Some(info.addr),
+            _ => info.display_name,
+        })
+        .collect()
+}
+
+/// The score spam filters assigned to a message, if any
+///
+/// Taken from the first of `X-Spam-Score` (SpamAssassin and others, e.g.
+/// `5.3` or `5.3 (+++++)`), `X-Rspamd-Score` and the `score=` part of
+/// `X-Spam-Status` that has a score.
+fn spam_score<F>(header: F) -> Result<Option<f64>>
+where
+    F: Fn(&str) -> Result<Option<String>>,
+{
+    for name in ["x-spam-score", "x-rspamd-score"] {
+        let score =
+            header(name)?.and_then(|h| h.split_whitespace().next().and_then(|s| s.parse().ok()));
+        if score.is_some() {
+            return Ok(score);
+        }
+    }
+    let status = header("x-spam-status")?.unwrap_or_default();
+    Ok(status
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .find_map(|part| part.strip_prefix("score=")?.parse().ok()))
+}
+
+/// IP addresses of the relays in all Received headers, from the most recent
+/// one down
+///
+/// Addresses are taken from square brackets, which is how the receiving
+/// server records the address it was connected from, e.g.
+/// `from mail.example.org (mail.example.org [192.0.2.1])`.
+fn received_ips(headers: &[MailHeader]) -> Vec<String> {
+    let mut ips = Vec::new();
+    for received in headers.get_all_values("Received") {
+        for bracketed in received.split('[').skip(1) {
+            let candidate = bracketed.split(']').next().unwrap_or_default();
+            let candidate = candidate.strip_prefix("IPv6:").unwrap_or(candidate);
+            if let Ok(ip) = candidate.parse::<IpAddr>() {
+                ips.push(ip.to_string());
+            }
+        }
+    }
+    ips
+}
+
+/// Results of an authentication method (`dkim`, `spf` or `dmarc`), e.g.
+/// `pass` or `fail`, or `none` if there are none
+///
+/// Only the topmost Authentication-Results header is used, as it's the one
+/// added by the receiving server, while others further down may have been
+/// added by anyone. SPF results are taken from Received-SPF as a fallback.
+fn auth_results(headers: &[MailHeader], method: &str) -> Vec<String> {
+    let mut results = Vec::new();
+    if let Some(ar) = headers.get_first_value("Authentication-Results") {
+        // the first part is the server that authenticated the message
+        for info in ar.split(';').skip(1) {
+            let (m, result) = match info.trim().split_once('=') {
+                Some(r) => r,
+                None => continue,
+            };
+            if m.trim().eq_ignore_ascii_case(method) {
+                let result = result.split_whitespace().next().unwrap_or_default();
+                results.push(result.to_lowercase());
+            }
+        }
+    }
+    if results.is_empty() && method == "spf" {
+        if let Some(spf) = headers.get_first_value("Received-SPF") {
+            results.extend(spf.split_whitespace().next().map(|r| r.to_lowercase()));
+        }
+    }
+    if results.is_empty() {
+        results.push("none".to_string());
+    }
+    results
+}
+
+/// A part of a tag template, see [`Filter::operations_for`]
+///
+/// [`Filter::operations_for`]: struct.Filter.html#method.operations_for
+#[derive(Debug, PartialEq, Eq)]
+enum Piece<'a> {
+    /// Text taken as it is
+    Text(&'a str),
+    /// The value of a capture group, by name or number
+    Group(&'a str),
+}
+
+/// Splits a tag template into text and references to capture groups
+fn template_pieces(template: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        pieces.push(Piece::Text(&rest[..start]));
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            pieces.push(Piece::Text("$"));
+            rest = after;
+            continue;
+        }
+        let (name, after) = match rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+            Some((name, after)) => (name, after),
+            None => {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        match name.is_empty() {
+            // a lone $ stands for itself
+            true => pieces.push(Piece::Text("$")),
+            false => pieces.push(Piece::Group(name)),
+        }
+        rest = after;
+    }
+    pieces.push(Piece::Text(rest));
+    pieces
+}
+
+/// Fills in a tag template, see [`Filter::operations_for`]
+///
+/// [`Filter::operations_for`]: struct.Filter.html#method.operations_for
+fn expand_template(template: &str, captures: &HashMap<String, String>) -> String {
+    template_pieces(template)
+        .into_iter()
+        .map(|piece| match piece {
+            Piece::Text(text) => text,
+            Piece::Group(group) => captures.get(group).map_or("", String::as_str),
+        })
+        .collect()
+}
+
+/// Sorts filters by [`Filter::priority`], keeping the order of filters with
+/// the same priority
+///
+/// [`filters_from`] already does this, so filters from several files can be
+/// concatenated and sorted again: the result is ordered by priority, then by
+/// the order of the files, then by the order within each file, no matter
+/// how many files there are.
+///
+/// [`Filter::priority`]: struct.Filter.html#structfield.priority
+/// [`filters_from`]: fn.filters_from.html
+pub fn sort_by_priority(filters: &mut [Filter]) {
+    filters.sort_by_key(|f| f.priority());
+}
+
+/// Moves the filters in [`Filter::then`] and [`Filter::otherwise`] right
+/// behind the filter they're in, recursively
+///
+/// They keep depending on that filter: they're only evaluated for a message
+/// right after it was, and only if it matched (or didn't). They take on its
+/// phase, priority and databases (unless they have their own), so neither
+/// [`in_phase_order`] nor [`sort_by_priority`] separate them from it.
+///
+/// [`Filter::then`]: struct.Filter.html#structfield.then
+/// [`Filter::otherwise`]: struct.Filter.html#structfield.otherwise
+/// [`in_phase_order`]: fn.in_phase_order.html
+/// [`sort_by_priority`]: fn.sort_by_priority.html
+pub fn flatten(filters: Vec<Filter>) -> Vec<Filter> {
+    let mut flat = Vec::new();
+    for filter in filters {
+        filter.flatten_into(&mut flat, None);
+    }
+    flat
+}
+
+/// Matches the regular expressions all `filters` use on the same header in a
+/// single pass
+///
+/// Each header's expressions are combined into a [`RegexSet`], which the
+/// first filter looking at the header matches it against, while all other
+/// filters reuse the result. With hundreds of filters on e.g. `from` that's
+/// much faster than trying their expressions one by one, and it doesn't
+/// change what matches. Fields derived from the message body or its files
+/// are still matched expression by expression. [`filters_from`] already does
+/// this, filters compiled one by one have to be combined again after
+/// [`Filter::compile`].
+///
+/// [`RegexSet`]: https://docs.rs/regex/latest/regex/struct.RegexSet.html
+/// [`filters_from`]: fn.filters_from.html
+/// [`Filter::compile`]: struct.Filter.html#method.compile
+pub fn combine_patterns(filters: &mut [Filter]) {
+    // the distinct expressions on each header, in the order they came up
+    let mut fields: BTreeMap<String, (Vec<String>, HashMap<String, usize>)> = BTreeMap::new();
+    let conditions = filters.iter().flat_map(|f| f.re.iter().flatten());
+    for (key, patterns) in conditions {
+        let field = key.trim_start_matches('!').to_lowercase();
+        if cost(&field) > 0 {
+            continue;
+        }
+        let (res, index) = fields.entry(field).or_default();
+        for pattern in patterns {
+            if let Pattern::Regex(re) = pattern {
+                index.entry(re.as_str().to_string()).or_insert_with(|| {
+                    res.push(re.as_str().to_string());
+                    res.len() - 1
+                });
+            }
+        }
+    }
+    let sets: BTreeMap<_, _> = fields
+        .into_iter()
+        .filter(|(_, (res, _))| res.len() > 1)
+        .filter_map(|(field, (res, index))| Some((field, (Rc::new(PatternSet::new(&res)?), index))))
+        .collect();
+    let conditions = filters.iter_mut().flat_map(|f| f.re.iter_mut().flatten());
+    for (key, patterns) in conditions {
+        let (set, index) = match sets.get(&key.trim_start_matches('!').to_lowercase()) {
+            Some(set) => set,
+            None => continue,
+        };
+        for pattern in patterns.iter_mut() {
+            if let Pattern::Regex(re) = pattern {
+                *pattern = Pattern::InSet(re.clone(), Rc::clone(set), index[re.as_str()]);
+            }
+        }
+    }
+}
+
+/// Iterates over filters in the order they're applied in, see [`Phase`]
+///
+/// [`Phase`]: enum.Phase.html
+pub fn in_phase_order(filters: &[Filter]) -> impl Iterator<Item = &Filter> {
+    let classify = |f: &&Filter| f.phase() == Phase::Classify;
+    let act = |f: &&Filter| f.phase() == Phase::Act;
+    filters
+        .iter()
+        .filter(classify)
+        .chain(filters.iter().filter(act))
 }
 
 impl Filter {
@@ -70,21 +941,429 @@ impl Filter {
         self.name = Some(name.to_string());
     }
 
+    /// Checks if this filter is in observation-only mode, see [`Filter::dry`]
+    ///
+    /// [`Filter::dry`]: struct.Filter.html#structfield.dry
+    pub fn is_dry(&self) -> bool {
+        self.dry == Some(true)
+    }
+
+    /// Checks if other filters depend on this one, see [`Filter::then`]
+    ///
+    /// [`Filter::then`]: struct.Filter.html#structfield.then
+    pub fn has_branches(&self) -> bool {
+        self.outcome.is_some() || self.then.is_some() || self.otherwise.is_some()
+    }
+
+    /// Adds this filter and the ones depending on it to `flat`, see
+    /// [`flatten`]
+    ///
+    /// [`flatten`]: fn.flatten.html
+    fn flatten_into(mut self, flat: &mut Vec<Filter>, guard: Option<(Outcome, bool)>) {
+        let branches = [
+            (self.then.take().unwrap_or_default(), true),
+            (self.otherwise.take().unwrap_or_default(), false),
+        ];
+        let outcome = Outcome::default();
+        if branches.iter().any(|(b, _)| !b.is_empty()) {
+            self.outcome = Some(Rc::clone(&outcome));
+        }
+        self.guard = guard;
+        let (phase, priority) = (self.phase, self.priority);
+        let databases = self.databases.clone();
+        flat.push(self);
+        for (mut branch, matched) in branches {
+            sort_by_priority(&mut branch);
+            for mut filter in branch {
+                filter.phase = phase;
+                filter.priority = priority;
+                if filter.databases.is_none() {
+                    filter.databases = databases.clone();
+                }
+                filter.flatten_into(flat, Some((Rc::clone(&outcome), matched)));
+            }
+        }
+    }
+
+    /// Checks if no further filters are evaluated once this one matched,
+    /// see [`Filter::stop`]
+    ///
+    /// [`Filter::stop`]: struct.Filter.html#structfield.stop
+    pub fn stops(&self) -> bool {
+        self.stop == Some(true) && !self.is_dry()
+    }
+
+    /// The operations applied to matching messages: [`Filter::op`], plus
+    /// maintaining the tags of [`Filter::urgency`] if set
+    ///
+    /// [`Filter::op`]: struct.Filter.html#structfield.op
+    /// [`Filter::urgency`]: struct.Filter.html#structfield.urgency
+    pub fn operations(&self) -> Cow<'_, Operations> {
+        let urgency = match self.urgency {
+            Some(urgency) => urgency,
+            None => return Cow::Borrowed(&self.op),
+        };
+        let mut op = self.op.clone();
+        for (u, tag) in URGENCY_TAGS {
+            if *u == urgency {
+                push_tag(&mut op.add, tag);
+            } else if !matches!(op.rm, Some(Bool(true))) {
+                push_tag(&mut op.rm, tag);
+            }
+        }
+        Cow::Owned(op)
+    }
+
+    /// The operations applied to `msg`, which this filter matched: those of
+    /// [`Filter::operations`], with the templates among the tags to add
+    /// filled in
+    ///
+    /// Tags to add are templates if the filter's rules have capture groups,
+    /// otherwise they're taken as they are, `$` and all. Templates refer to
+    /// the capture groups of the regular expressions in the rule that
+    /// matched, e.g. `lists/$1` for a rule like
+    /// `{"list-id": "<([a-z0-9-]+)\\."}`. `$name` and `${name}` refer to
+    /// named groups in any of its expressions, `$1`, `$2` and so on to the
+    /// groups of the first expression with any (in the order conditions are
+    /// evaluated, so rules with several expressions with groups had better
+    /// name them), and `$$` is a `$`. Referring to a group none of the rules
+    /// has is an error when the filter is compiled. Groups that didn't
+    /// participate in the match are empty. Tags that end up invalid, e.g.
+    /// empty, are left out with a [`Warning`].
+    ///
+    /// [`Filter::operations`]: struct.Filter.html#method.operations
+    /// [`Warning`]: error/enum.Warning.html
+    pub fn operations_for<M: Mail>(
+        &self,
+        msg: &M,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<Cow<'_, Operations>> {
+        let op = self.operations();
+        if !self.templates {
+            return Ok(op);
+        }
+        // the outcome of the filter this one depends on is known to be right
+        // if it matched, so there's no need to go through evaluate
+        let captures = match self.evaluate_rules(msg, warnings, None, None)? {
+            Some(rule) => self.captures(msg, rule, warnings)?,
+            None => return Ok(op),
+        };
+        let mut tags = Vec::new();
+        for tag in op.added_tags() {
+            let tag = expand_template(tag, &captures);
+            match validate_tag(&tag) {
+                Ok(()) => tags.push(tag),
+                Err(InvalidTag(e)) => {
+                    let w = format!("{}: {} in filter {}", msg.id(), e, self.name());
+                    warnings.push(SkippedOperation(w));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let mut op = op.into_owned();
+        op.add = Some(Multiple(tags));
+        Ok(Cow::Owned(op))
+    }
+
+    /// Capture groups of the regular expressions in rule `rule` matching
+    /// `msg`, by name and number, see [`Filter::operations_for`]
+    ///
+    /// [`Filter::operations_for`]: struct.Filter.html#method.operations_for
+    fn captures<M: Mail>(
+        &self,
+        msg: &M,
+        rule: usize,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<HashMap<String, String>> {
+        let mut captures = HashMap::new();
+        let mut numbered = false;
+        for (key, patterns) in &self.re[rule] {
+            // negated conditions only hold if nothing matched
+            if key.starts_with('!') || key == "@query" {
+                continue;
+            }
+            let values = match self.with_matcher(key, |m| m.values(msg, warnings))? {
+                Some(values) => values,
+                None => continue,
+            };
+            for re in patterns.iter().filter_map(Pattern::regex) {
+                let caps = match values.iter().find_map(|v| re.captures(v)) {
+                    Some(caps) => caps,
+                    None => continue,
+                };
+                for (i, name) in re.capture_names().enumerate().skip(1) {
+                    let value = caps.get(i).map(|m| m.as_str()).unwrap_or_default();
+                    if let Some(name) = name {
+                        captures
+                            .entry(name.to_string())
+                            .or_insert_with(|| value.to_string());
+                    }
+                    if !numbered {
+                        captures.insert(i.to_string(), value.to_string());
+                    }
+                }
+                numbered |= re.captures_len() > 1;
+            }
+        }
+        Ok(captures)
+    }
+
+    /// Returns [`Filter::phase`], defaulting to [`Phase::Act`]
+    ///
+    /// [`Filter::phase`]: struct.Filter.html#structfield.phase
+    /// [`Phase::Act`]: enum.Phase.html#variant.Act
+    pub fn phase(&self) -> Phase {
+        self.phase.unwrap_or_default()
+    }
+
+    /// Returns [`Filter::priority`], defaulting to 0
+    ///
+    /// [`Filter::priority`]: struct.Filter.html#structfield.priority
+    pub fn priority(&self) -> i64 {
+        self.priority.unwrap_or_default()
+    }
+
+    /// Returns [`Filter::score`], defaulting to 0
+    ///
+    /// [`Filter::score`]: struct.Filter.html#structfield.score
+    pub fn score(&self) -> f64 {
+        self.score.unwrap_or_default()
+    }
+
+    /// Checks if whether this filter matches may change while the message
+    /// (its headers, tags and files) stays the same, e.g. with relative
+    /// `@date` ages, `@query` or the rest of the thread
+    ///
+    /// Special fields registered with [`Filter::register`] count as well
+    /// (unless they only decrypt the body), as there's no telling what they
+    /// look at.
+    ///
+    /// [`Filter::register`]: struct.Filter.html#method.register
+    pub fn depends_on_state(&self) -> bool {
+        self.re.iter().flatten().any(|(key, patterns)| {
+            let field = key.trim_start_matches('!').to_lowercase();
+            STATEFUL_FIELDS.contains(&field.as_str())
+                || (self.matchers.0.contains_key(&field) && !BODY_FIELDS.contains(&field.as_str()))
+                || patterns.iter().any(|p| {
+                    matches!(
+                        p,
+                        Pattern::Date(DateSpec::OlderThan(_) | DateSpec::NewerThan(_))
+                    )
+                })
+        })
+    }
+
+    /// Checks if this filter was given a name, rather than being named after
+    /// its rules and operations
+    pub(crate) fn is_named(&self) -> bool {
+        self.name.is_some()
+    }
+
+    /// Checks if this filter is used for messages in `db`
+    ///
+    /// Filters without [`Filter::databases`] apply everywhere, all others
+    /// only to the databases listed, given either by their full path or just
+    /// their directory name (e.g. `lists` for `/home/me/mail/lists`).
+    ///
+    /// [`Filter::databases`]: struct.Filter.html#structfield.databases
+    pub fn applies_to(&self, db: &Database) -> bool {
+        let path = db.path();
+        self.databases.as_ref().is_none_or(|dbs| {
+            dbs.iter()
+                .any(|d| Path::new(d) == path || path.file_name().is_some_and(|n| n == d.as_str()))
+        })
+    }
+
+    /// Replaces the tag `from` with `to` in [`Filter::op`] as well as in
+    /// `@tags` and `@thread-tags` rules matching exactly that tag
+    ///
+    /// Returns how many occurrences were replaced. Since regular expressions
+    /// aren't recompiled, this is meant for rewriting rule files rather than
+    /// filters in use.
+    ///
+    /// [`Filter::op`]: struct.Filter.html#structfield.op
+    pub fn rename_tag(&mut self, from: &str, to: &str) -> usize {
+        let mut replaced = 0;
+        let exact = |s: &str| (s == from).then(|| to.to_string());
+        if let Some(add) = &mut self.op.add {
+            replaced += add.replace_with(exact);
+        }
+        if let Some(rm) = &mut self.op.rm {
+            replaced += rm.replace_with(exact);
+        }
+        let (from_re, to_re) = (regex::escape(from), regex::escape(to));
+        let anchored = |s: &str| {
+            if s == from_re {
+                Some(to_re.clone())
+            } else if s == format!("^{}$", from_re) {
+                Some(format!("^{}$", to_re))
+            } else {
+                None
+            }
+        };
+        for rule in &mut self.rules {
+            for (key, value) in rule.iter_mut() {
+                let field = key.trim_start_matches('!');
+                if field != "@tags" && field != "@thread-tags" {
+                    continue;
+                }
+                replaced += match value {
+                    Literal(crate::Literal::Eq(_) | crate::Literal::Glob(_)) => {
+                        value.replace_with(exact)
+                    }
+                    _ => value.replace_with(anchored),
+                };
+            }
+        }
+        replaced
+    }
+
+    /// Normalizes the tags in [`Filter::op`] to Unicode Normalization Form C
+    ///
+    /// The same tag may be written using different sequences of code points,
+    /// e.g. with "ü" either being a single character or a "u" followed by a
+    /// combining diaeresis. notmuch treats those as different tags.
+    ///
+    /// [`Filter::op`]: struct.Filter.html#structfield.op
+    pub fn normalize_tags(&mut self) {
+        let nfc = |t: &str| {
+            let n = t.nfc().collect::<String>();
+            (n != t).then_some(n)
+        };
+        if let Some(add) = &mut self.op.add {
+            add.replace_with(nfc);
+        }
+        if let Some(rm) = &mut self.op.rm {
+            rm.replace_with(nfc);
+        }
+    }
+
     /// When filters are deserialized from json or have been assembled via code,
     /// the regular expressions contained in [`Filter::rules`] need to be
     /// compiled before any matches are to be made.
     ///
     /// [`Filter::rules`]: struct.Filter.html#structfield.rules
+    ///
+    /// Tags used in [`Filter::op`] are validated at this point as well, so
+    /// invalid ones are caught before any message is processed.
+    ///
+    /// [`Filter::op`]: struct.Filter.html#structfield.op
     pub fn compile(mut self) -> Result<Self> {
+        let tags = self
+            .op
+            .added_tags()
+            .into_iter()
+            .chain(self.op.removed_tags());
+        for tag in tags.filter(|t| *t != "*") {
+            validate_tag(tag).map_err(|e| match e {
+                InvalidTag(e) => InvalidTag(format!("{} in filter {}", e, self.name())),
+                e => e,
+            })?;
+        }
+        if self
+            .op
+            .run_capture
+            .as_ref()
+            .is_some_and(|c| c.cmd.is_empty())
+        {
+            let e = format!("run_capture of filter {} needs a command", self.name());
+            return Err(UnsupportedValue(e));
+        }
+        self.op.validate_run_env()?;
+        self.op.validate_folders()?;
+        if let Some(duration) = &self.op.snooze {
+            parse_duration(duration, "snooze")?;
+        }
+        if let Some(duration) = &self.op.expect_reply {
+            parse_duration(duration, "expect_reply")?;
+        }
+        if let Some(digest) = &self.op.digest {
+            validate_digest(digest)?;
+        }
+        if let Some(calendar) = &self.op.calendar {
+            validate_calendar(calendar)?;
+        }
+        if let Some(export) = &self.op.export {
+            validate_export(export)?;
+        }
+        if self.decrypt == Some(true) {
+            self.use_decryption()?;
+        }
+        let compile_all = |filters: Option<Vec<Filter>>| {
+            filters.map(|f| f.into_iter().map(Filter::compile).collect())
+        };
+        self.then = compile_all(self.then.take()).transpose()?;
+        self.otherwise = compile_all(self.otherwise.take()).transpose()?;
+        let unsupported = |v: &Option<Value>| matches!(v, Some(Literal(_) | Comparison(_)));
+        if unsupported(&self.op.add) || unsupported(&self.op.rm) {
+            let e = format!(
+                "Operations of filter {} can't use eq, contains, glob or comparisons",
+                self.name()
+            );
+            return Err(UnsupportedValue(e));
+        }
         for rule in &self.rules {
-            let mut compiled = HashMap::new();
+            let mut compiled = Vec::new();
             for (key, value) in rule.iter() {
                 let mut res = Vec::new();
+                let field = key.trim_start_matches('!');
+                if let Some((_, feature, false)) = FEATURE_FIELDS.iter().find(|f| f.0 == field) {
+                    let e = format!("{} needs notcoal built with the {} feature", field, feature);
+                    return Err(UnsupportedValue(e));
+                }
+                if field == "@date" || field == "@query" || NUMERIC_FIELDS.contains(&field) {
+                    let specs = match value {
+                        Single(spec) => vec![spec],
+                        Multiple(specs) => specs.iter().collect(),
+                        Comparison(c) if NUMERIC_FIELDS.contains(&field) => {
+                            res.push(Pattern::Comparison(*c));
+                            compiled.push((key.to_string(), res));
+                            continue;
+                        }
+                        _ if field == "@date" => {
+                            let e = "@date needs dates or ages".to_string();
+                            return Err(UnsupportedValue(e));
+                        }
+                        _ if field == "@query" => {
+                            let e = "@query needs notmuch queries".to_string();
+                            return Err(UnsupportedValue(e));
+                        }
+                        _ => {
+                            let e = format!("{} needs comparisons like >0", field);
+                            return Err(UnsupportedValue(e));
+                        }
+                    };
+                    for spec in specs {
+                        res.push(match field {
+                            "@date" => Pattern::Date(DateSpec::parse(spec)?),
+                            "@query" => Pattern::Query(spec.to_string()),
+                            _ => Pattern::Number(NumberSpec::parse(spec)?),
+                        });
+                    }
+                    compiled.push((key.to_string(), res));
+                    continue;
+                }
+                // address ranges, or regular expressions like everywhere else
+                let pattern = |re: &str| match Cidr::parse(re) {
+                    Some(cidr) if field == "@received-ip" => Ok(Pattern::Cidr(cidr)),
+                    _ => Pattern::new(re),
+                };
                 match value {
-                    Single(re) => res.push(Regex::new(re)?),
+                    Single(re) => res.push(pattern(re)?),
+                    Literal(lit) => res.push(Pattern::from_literal(lit)?),
+                    Comparison(c) if *c == crate::Comparison::default() => {
+                        let e = "Comparisons need at least one of gt, ge, lt, le or eq";
+                        return Err(UnsupportedValue(e.to_string()));
+                    }
+                    Comparison(c) => res.push(Pattern::Comparison(*c)),
+                    Bool(b) if BOOLEAN_FIELDS.contains(&field) => {
+                        res.push(Pattern::from_literal(&crate::Literal::Eq(b.to_string()))?)
+                    }
+                    // whether a header is there at all
+                    Bool(b) if !field.starts_with('@') => res.push(Pattern::Present(*b)),
                     Multiple(mre) => {
                         for re in mre {
-                            res.push(Regex::new(re)?);
+                            res.push(pattern(re)?);
                         }
                     }
                     _ => {
@@ -92,13 +1371,134 @@ impl Filter {
                         return Err(UnsupportedValue(e));
                     }
                 }
-                compiled.insert(key.to_string(), res);
+                compiled.push((key.to_string(), res));
             }
             self.re.push(compiled);
         }
+        self.sort_conditions();
+        self.check_templates()?;
         Ok(self)
     }
 
+    /// Decides if the tags to add are templates, which they are if the rules
+    /// have capture groups, and checks that they only refer to groups that
+    /// exist, see [`Filter::operations_for`]
+    ///
+    /// [`Filter::operations_for`]: struct.Filter.html#method.operations_for
+    fn check_templates(&mut self) -> Result<()> {
+        let mut numbered = 0;
+        let mut names = HashSet::new();
+        let regexes = self
+            .re
+            .iter()
+            .flatten()
+            .filter(|(key, _)| !key.starts_with('!') && key != "@query")
+            .flat_map(|(_, patterns)| patterns.iter().filter_map(Pattern::regex));
+        for re in regexes {
+            numbered = numbered.max(re.captures_len() - 1);
+            names.extend(re.capture_names().flatten().map(str::to_string));
+        }
+        self.templates = numbered > 0;
+        if !self.templates {
+            return Ok(());
+        }
+        for tag in self.op.added_tags() {
+            for piece in template_pieces(tag) {
+                let group = match piece {
+                    Piece::Group(group) => group,
+                    Piece::Text(_) => continue,
+                };
+                let exists = group
+                    .parse::<usize>()
+                    .is_ok_and(|n| n >= 1 && n <= numbered)
+                    || names.contains(group);
+                if !exists {
+                    let e = format!(
+                        "Tag {:?} of filter {} refers to ${}, which none of its rules captures \
+                         (use $$ for a $)",
+                        tag,
+                        self.name(),
+                        group
+                    );
+                    return Err(UnsupportedValue(e));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a special field, or replaces a built-in one, see [`Matcher`]
+    ///
+    /// `field` has to start with `@`, e.g. `@contact`. Rules may use it as
+    /// soon as it's registered, and conditions on it are evaluated in order of
+    /// [`Matcher::cost`] like all others. Only this filter uses the matcher,
+    /// [`register_matcher`] registers one for a set of filters.
+    ///
+    /// [`Matcher`]: trait.Matcher.html
+    /// [`Matcher::cost`]: trait.Matcher.html#method.cost
+    /// [`register_matcher`]: fn.register_matcher.html
+    pub fn register(&mut self, field: &str, matcher: Rc<dyn Matcher>) -> Result<()> {
+        if !field.starts_with('@') || field.len() < 2 {
+            let e = format!("Special field {:?} has to start with @", field);
+            return Err(UnsupportedValue(e));
+        }
+        self.matchers.0.insert(field.to_string(), matcher);
+        self.sort_conditions();
+        Ok(())
+    }
+
+    /// Replaces the fields read from the body with ones decrypting messages
+    /// first, see [`Filter::decrypt`]
+    ///
+    /// [`Filter::decrypt`]: struct.Filter.html#structfield.decrypt
+    #[cfg(feature = "openpgp")]
+    fn use_decryption(&mut self) -> Result<()> {
+        for field in BODY_FIELDS {
+            let matcher = Rc::new(crate::decrypt::Decrypted(field));
+            self.matchers.0.insert(field.to_string(), matcher);
+        }
+        Ok(())
+    }
+
+    /// Replaces the fields read from the body with ones decrypting messages
+    /// first, see [`Filter::decrypt`]
+    ///
+    /// [`Filter::decrypt`]: struct.Filter.html#structfield.decrypt
+    #[cfg(not(feature = "openpgp"))]
+    fn use_decryption(&mut self) -> Result<()> {
+        let e = format!(
+            "decrypt in filter {} needs notcoal built with the openpgp feature",
+            self.name()
+        );
+        Err(UnsupportedValue(e))
+    }
+
+    /// Calls `f` with the matcher providing the values of `field`
+    fn with_matcher<T>(&self, field: &str, f: impl FnOnce(&dyn Matcher) -> T) -> T {
+        match self.matchers.0.get(field) {
+            Some(matcher) => f(matcher.as_ref()),
+            None => f(&Builtin(field)),
+        }
+    }
+
+    /// Orders the conditions of every rule by how expensive they are, unless
+    /// [`Filter::cheap_first`] is turned off
+    ///
+    /// [`Filter::cheap_first`]: struct.Filter.html#structfield.cheap_first
+    fn sort_conditions(&mut self) {
+        if self.cheap_first == Some(false) {
+            return;
+        }
+        let mut re = std::mem::take(&mut self.re);
+        for compiled in &mut re {
+            compiled.sort_by_cached_key(|(key, _)| {
+                let cost = self.with_matcher(key.trim_start_matches('!'), |m| m.cost());
+                (cost, key.clone())
+            });
+        }
+        self.re = re;
+    }
+
     /// Combines [`Filter::is_match`] and [`Operations::apply`]
     ///
     /// Returns a tuple of two bools, the first representing if the filter has
@@ -108,8 +1508,24 @@ impl Filter {
     /// [`Filter::is_match`]: struct.Filter.html#method.is_match
     /// [`Operations::apply`]: struct.Operations.html#method.apply
     pub fn apply_if_match(&self, msg: &Message, db: &Database) -> Result<(bool, bool)> {
-        if self.is_match(msg, db)? {
-            Ok((true, self.op.apply(msg, db, &self.name())?))
+        self.apply_if_match_with_warnings(msg, db, &mut Vec::new())
+    }
+
+    /// Like [`Filter::apply_if_match`], but collects any [`Warning`]s that
+    /// came up while matching
+    ///
+    /// [`Filter::apply_if_match`]: struct.Filter.html#method.apply_if_match
+    /// [`Warning`]: error/enum.Warning.html
+    pub fn apply_if_match_with_warnings(
+        &self,
+        msg: &Message,
+        db: &Database,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<(bool, bool)> {
+        let mail = DbMail::new(msg, db, None, true);
+        if self.is_mail_match(&mail, warnings)? {
+            let op = self.operations_for(&mail, warnings)?;
+            Ok((true, op.apply(msg, db, &self.name())?))
         } else {
             Ok((false, false))
         }
@@ -120,115 +1536,386 @@ impl Filter {
     ///
     /// [`Filter::rules`]: struct.Filter.html#structfield.rules
     pub fn is_match(&self, msg: &Message, db: &Database) -> Result<bool> {
-        /// Test if any of the supplied values match any of our supplied regular
-        /// expressions.
-        fn sub_match<I, S>(res: &[Regex], values: I) -> bool
-        where
-            S: AsRef<str>,
-            I: Iterator<Item = S>,
-        {
-            for value in values {
-                for re in res {
-                    if re.is_match(value.as_ref()) {
-                        return true;
-                    }
-                }
-            }
-            false
+        self.is_match_with_warnings(msg, db, &mut Vec::new())
+    }
+
+    /// Like [`Filter::is_match`], but collects any [`Warning`]s that came up
+    /// while matching, e.g. file names that aren't valid UTF-8
+    ///
+    /// [`Filter::is_match`]: struct.Filter.html#method.is_match
+    /// [`Warning`]: error/enum.Warning.html
+    pub fn is_match_with_warnings(
+        &self,
+        msg: &Message,
+        db: &Database,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<bool> {
+        self.is_mail_match(&DbMail::new(msg, db, None, true), warnings)
+    }
+
+    /// Checks if any message implementing [`Mail`] matches, e.g. a
+    /// [`MailFile`] outside of any notmuch database
+    ///
+    /// [`Mail`]: trait.Mail.html
+    /// [`MailFile`]: struct.MailFile.html
+    pub fn is_mail_match<M: Mail>(&self, msg: &M, warnings: &mut Vec<Warning>) -> Result<bool> {
+        self.is_mail_match_timed(msg, warnings, None)
+    }
+
+    /// Like [`Filter::is_mail_match`], additionally adding the time spent on
+    /// each field to [`Timings::fields`]
+    ///
+    /// [`Filter::is_mail_match`]: struct.Filter.html#method.is_mail_match
+    /// [`Timings::fields`]: struct.Timings.html#structfield.fields
+    pub(crate) fn is_mail_match_timed<M: Mail>(
+        &self,
+        msg: &M,
+        warnings: &mut Vec<Warning>,
+        timings: Option<&mut Timings>,
+    ) -> Result<bool> {
+        Ok(self.evaluate(msg, warnings, timings, None)?.is_some())
+    }
+
+    /// Checks if a message matches, recording every condition that was
+    /// evaluated along the way, see [`TraceStep`]
+    ///
+    /// [`TraceStep`]: struct.TraceStep.html
+    pub fn trace<M: Mail>(
+        &self,
+        msg: &M,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<(bool, Vec<TraceStep>)> {
+        let mut steps = Vec::new();
+        let matched = self.evaluate(msg, warnings, None, Some(&mut steps))?;
+        Ok((matched.is_some(), steps))
+    }
+
+    /// Checks if a message matches, returning what matched in the first rule
+    /// that did, or `None` if none did
+    ///
+    /// Negated conditions don't contribute anything, see [`MatchSpan`].
+    ///
+    /// [`MatchSpan`]: struct.MatchSpan.html
+    pub fn match_spans<M: Mail>(
+        &self,
+        msg: &M,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<Option<Vec<MatchSpan>>> {
+        let mut steps = Vec::new();
+        let rule = self.evaluate(msg, warnings, None, Some(&mut steps))?;
+        Ok(rule.map(|rule| {
+            steps
+                .into_iter()
+                .filter(|s| s.rule == rule)
+                .flat_map(|s| s.spans)
+                .collect()
+        }))
+    }
+
+    /// Index of the first rule matching the message, if any
+    ///
+    /// Filters depending on another one (see [`Filter::then`]) never match
+    /// unless that one was just evaluated for the same message, with the
+    /// outcome they depend on.
+    ///
+    /// [`Filter::then`]: struct.Filter.html#structfield.then
+    fn evaluate<M: Mail>(
+        &self,
+        msg: &M,
+        warnings: &mut Vec<Warning>,
+        timings: Option<&mut Timings>,
+        trace: Option<&mut Vec<TraceStep>>,
+    ) -> Result<Option<usize>> {
+        if self.guard.is_none() && self.outcome.is_none() {
+            return self.evaluate_rules(msg, warnings, timings, trace);
+        }
+        let id = msg.id();
+        let taken = self.guard.as_ref().is_none_or(|(outcome, matched)| {
+            outcome
+                .borrow()
+                .as_ref()
+                .is_some_and(|(last, m)| *last == id && m == matched)
+        });
+        let rule = match taken {
+            true => self.evaluate_rules(msg, warnings, timings, trace)?,
+            false => None,
+        };
+        if let Some(outcome) = &self.outcome {
+            // filters depending on this one aren't evaluated either if it
+            // wasn't
+            *outcome.borrow_mut() = taken.then(|| (id, rule.is_some()));
+        }
+        Ok(rule)
+    }
+
+    /// Forgets that this filter matched the message it was last evaluated
+    /// for, so the filters depending on it (see [`Filter::then`] and
+    /// [`Filter::otherwise`]) aren't applied to it either
+    ///
+    /// For when it matched but isn't applied, e.g. because of
+    /// [`Filter::max_matches`].
+    ///
+    /// [`Filter::then`]: struct.Filter.html#structfield.then
+    /// [`Filter::otherwise`]: struct.Filter.html#structfield.otherwise
+    /// [`Filter::max_matches`]: struct.Filter.html#structfield.max_matches
+    pub(crate) fn forget_outcome(&self) {
+        if let Some(outcome) = &self.outcome {
+            *outcome.borrow_mut() = None;
         }
+    }
 
+    /// Index of the first rule matching the message, if any, regardless of
+    /// any filter this one depends on
+    fn evaluate_rules<M: Mail>(
+        &self,
+        msg: &M,
+        warnings: &mut Vec<Warning>,
+        mut timings: Option<&mut Timings>,
+        mut trace: Option<&mut Vec<TraceStep>>,
+    ) -> Result<Option<usize>> {
         // self.re will only be populated after self.compile()
         if self.re.len() != self.rules.len() {
             let e = "Filters need to be compiled before tested".to_string();
             return Err(RegexUncompiled(e));
         }
 
-        for rule in &self.re {
+        for (i, rule) in self.re.iter().enumerate() {
             let mut is_match = true;
-            for (part, res) in rule {
-                let q: Query;
-                let mut r: Threads;
-                if part == "@path" {
-                    // XXX we might want to return an error here if we can't
-                    // make the path to a valid utf-8 str? Or maybe go for
-                    // to_str_lossy?
-                    let vs = msg
-                        .filenames()
-                        .filter_map(|f| f.to_str().map(|n| n.to_string()));
-                    is_match = sub_match(res, vs) && is_match;
-                } else if part == "@tags" {
-                    is_match = sub_match(res, msg.tags()) && is_match;
-                } else if part == "@thread-tags" {
-                    // creating a new query as we don't have information about
-                    // our own thread yet
-                    q = db.create_query(&format!("thread:{}", msg.thread_id()))?;
-                    r = q.search_threads()?;
-                    if let Some(thread) = r.next() {
-                        is_match = sub_match(res, thread.tags()) && is_match;
-                    }
-                } else if part == "@attachment" || part == "@attachment-body" || part == "@body" {
-                    // since we might combine these we try avoid parsing the
-                    // same file over and over again.
-                    let mut buf = Vec::new();
-                    // XXX-file notmuch says it returns a random filename if
-                    // multiple are present. Question is if the new tag is even
-                    // applied to messages we've already seen, do we ever run
-                    // into that being a problem at all?
-                    let mut file = File::open(msg.filename())?;
-                    file.read_to_end(&mut buf)?;
-                    let parsed = parse_mail(&buf)?;
-                    if part == "@attachment" {
-                        // XXX Check if this can be refactored with less cloning
-                        let fns = parsed
-                            .subparts
-                            .iter()
-                            .map(|s| s.get_content_disposition().params.get("filename").cloned())
-                            .collect::<Vec<Option<String>>>();
-                        let fns = fns.iter().filter_map(|f| f.clone());
-                        is_match = sub_match(res, fns) && is_match;
-                    } else if part == "@body" {
-                        is_match = sub_match(res, [parsed.get_body()?].iter()) && is_match;
-                    } else if part == "@attachment-body" {
-                        let bodys = parsed
-                            .subparts
-                            .iter()
-                            .map(|s| {
-                                // XXX are we sure we only care about text
-                                // mime types? There others?
-                                if s.ctype.mimetype.starts_with("text") {
-                                    Ok(Some(s.get_body()?))
-                                } else {
-                                    Ok(None)
-                                }
-                            })
-                            .collect::<Result<Vec<Option<String>>>>()?;
-                        let bodys = bodys.iter().filter_map(|f| f.clone());
-                        is_match = sub_match(res, bodys) && is_match;
-                    }
+            for (key, res) in rule {
+                let start = Instant::now();
+                // a leading ! negates the whole condition
+                let (negate, part) = match key.strip_prefix('!') {
+                    Some(part) => (true, part),
+                    None => (false, key.as_str()),
+                };
+                let values = match part {
+                    // the queries the message matches, so they match themselves
+                    "@query" => res
+                        .iter()
+                        .filter_map(|re| match re {
+                            Pattern::Query(q) => Some(q),
+                            _ => None,
+                        })
+                        .filter_map(|q| match msg.matches_query(q) {
+                            Ok(true) => Some(Ok(q.clone())),
+                            Ok(false) => None,
+                            Err(e) => Some(Err(e)),
+                        })
+                        .collect::<Result<_>>()?,
+                    _ => match self.with_matcher(part, |m| m.values(msg, warnings))? {
+                        Some(values) => values,
+                        // unknown special fields don't affect the outcome
+                        None => continue,
+                    },
+                };
+                let matched = if part.starts_with('@') {
+                    // any of the values matching any of the patterns will do
+                    values.iter().any(|v| res.iter().any(|re| re.is_match(v)))
+                } else if values.is_empty() {
+                    // missing headers only match if that's what's asked for
+                    res.iter().all(|re| matches!(re, Pattern::Present(false)))
+                } else {
+                    // headers have to match every pattern
+                    values.iter().all(|v| res.iter().all(|re| re.is_match(v)))
+                };
+                is_match = (matched != negate) && is_match;
+                if let Some(t) = timings.as_deref_mut() {
+                    let field = match part.starts_with('@') {
+                        true => part,
+                        false => "headers",
+                    };
+                    *t.fields.entry(field.to_string()).or_default() += start.elapsed();
                 }
-                if part.starts_with('@') {
-                    continue;
+                if let Some(trace) = trace.as_deref_mut() {
+                    let spans = match matched && !negate {
+                        true => match_spans(part, &values, res),
+                        false => Vec::new(),
+                    };
+                    trace.push(TraceStep {
+                        rule: i,
+                        field: key.clone(),
+                        values,
+                        matched: matched != negate,
+                        spans,
+                    });
                 }
-
-                match msg.header(part) {
-                    Ok(None) => {
-                        is_match = false;
-                    }
-                    Ok(Some(p)) => {
-                        for re in res {
-                            is_match = re.is_match(&p) && is_match;
-                            if !is_match {
-                                break;
-                            }
-                        }
-                    }
-                    Err(e) => return Err(NotmuchError(e)),
+                // no need to look any further once a condition doesn't hold
+                if !is_match {
+                    break;
                 }
             }
             if is_match {
-                return Ok(true);
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filters_from, MailFile};
+
+    const MESSAGE: &[u8] = b"From: Rust <announce@rust.example>\r\n\
+        List-Id: <rust-announce.lists.example>\r\n\
+        Subject: Release\r\n\
+        Message-ID: <release@rust.example>\r\n\
+        \r\n\
+        Released.\r\n";
+
+    /// Tags `add` of a single filter with `rule` adds to the message
+    fn added(rule: &str, add: &str) -> Result<Vec<String>> {
+        let json = format!(r#"[{{"rules": [{rule}], "op": {{"add": {add:?}}}}}]"#);
+        let filters = filters_from(json.as_bytes())?;
+        let mail = MailFile::from_bytes(MESSAGE.to_vec());
+        let mut warnings = Vec::new();
+        assert!(filters[0].is_mail_match(&mail, &mut warnings)?);
+        let op = filters[0].operations_for(&mail, &mut warnings)?;
+        Ok(op.added_tags().into_iter().map(str::to_string).collect())
+    }
+
+    #[test]
+    fn templates_fill_in_numbered_groups() {
+        let rule = r#"{"list-id": "<([a-z]+)-([a-z]+)\\."}"#;
+        assert_eq!(added(rule, "lists/$1/$2").unwrap(), ["lists/rust/announce"]);
+    }
+
+    #[test]
+    fn templates_fill_in_named_groups() {
+        let rule = r#"{"list-id": "<(?P<project>[a-z]+)-"}"#;
+        assert_eq!(
+            added(rule, "projects/${project}").unwrap(),
+            ["projects/rust"]
+        );
+        assert_eq!(added(rule, "$project-lists").unwrap(), ["rust-lists"]);
+    }
+
+    #[test]
+    fn templates_escape_dollars() {
+        let rule = r#"{"list-id": "<([a-z]+)-"}"#;
+        assert_eq!(added(rule, "$1$$").unwrap(), ["rust$"]);
+        assert_eq!(added(rule, "$1-$").unwrap(), ["rust-$"]);
+    }
+
+    #[test]
+    fn templates_reject_unknown_groups() {
+        let rule = r#"{"list-id": "<(?P<project>[a-z]+)-"}"#;
+        assert!(added(rule, "lists/$list").is_err());
+        assert!(added(rule, "lists/${list}").is_err());
+        assert!(added(rule, "lists/$2").is_err());
+        assert!(added(rule, "lists/$0").is_err());
+    }
+
+    #[test]
+    fn tags_without_groups_are_not_templates() {
+        let rule = r#"{"list-id": "rust-announce"}"#;
+        assert_eq!(added(rule, "price$USD").unwrap(), ["price$USD"]);
+        assert_eq!(added(rule, "a$$b").unwrap(), ["a$$b"]);
+    }
+
+    #[test]
+    fn negated_conditions_provide_no_groups() {
+        let rule = r#"{"list-id": "rust", "!subject": "(draft)"}"#;
+        assert_eq!(added(rule, "price$1").unwrap(), ["price$1"]);
+    }
+
+    fn depends_on_state(rule: &str) -> bool {
+        let json = format!(r#"[{{"rules": [{rule}], "op": {{"add": "x"}}}}]"#);
+        filters_from(json.as_bytes()).unwrap()[0].depends_on_state()
+    }
+
+    #[test]
+    fn filters_depending_on_more_than_the_message() {
+        assert!(depends_on_state(r#"{"@date": ">30d"}"#));
+        assert!(depends_on_state(r#"{"@date": "<12h"}"#));
+        assert!(depends_on_state(r#"{"@thread-tags": "^muted$"}"#));
+        assert!(depends_on_state(r#"{"!@query": "tag:flagged"}"#));
+        assert!(!depends_on_state(r#"{"@date": "2024-01-01..2024-01-31"}"#));
+        assert!(!depends_on_state(r#"{"@date": ">2024-01-31"}"#));
+        assert!(!depends_on_state(
+            r#"{"from": "@example\\.org", "@tags": "^inbox$"}"#
+        ));
+    }
+
+    /// Filters sharing expressions on some headers, but not on all
+    const SHARED: &str = r#"[
+        {"name": "a", "rules": [{"from": "^Rust.*@", "subject": "(?i)rel.ase"}], "op": {"add": "a"}},
+        {"name": "b", "rules": [{"From": "rust\\.example>?$", "!from": "bo+t@"}], "op": {"add": "b"}},
+        {"name": "c", "rules": [{"from": "^Rust.*@", "subject": "^Draft.*"}], "op": {"add": "c"}},
+        {"name": "d", "rules": [{"list-id": "rust-(announce|users)"}, {"@body": "Releas.d"}],
+         "op": {"add": "d"}},
+        {"name": "e", "rules": [{"@body": "^x.*"}, {"from": "announce"}], "op": {"add": "e"}}
+    ]"#;
+
+    /// The patterns of all filters on `field`
+    fn patterns<'f>(filters: &'f [Filter], field: &str) -> Vec<&'f Pattern> {
+        let conditions = filters.iter().flat_map(|f| f.re.iter().flatten());
+        conditions
+            .filter(|(key, _)| key.trim_start_matches('!').eq_ignore_ascii_case(field))
+            .flat_map(|(_, patterns)| patterns)
+            .collect()
+    }
+
+    #[test]
+    fn patterns_on_a_header_are_combined() {
+        let filters = filters_from(SHARED.as_bytes()).unwrap();
+        let from = patterns(&filters, "from");
+        assert_eq!(from.len(), 5);
+        let (set, indices): (Vec<_>, Vec<_>) = from
+            .iter()
+            .filter_map(|p| match p {
+                Pattern::InSet(_, set, i) => Some((Rc::as_ptr(set), *i)),
+                _ => None,
+            })
+            .unzip();
+        // across filters, header case and negation, but not the literal
+        assert_eq!(indices, [0, 1, 2, 0]);
+        assert!(set.iter().all(|s| *s == set[0]));
+        assert!(matches!(from[4], Pattern::Literal { .. }));
+
+        let subject = patterns(&filters, "subject");
+        assert!(subject.iter().all(|p| matches!(p, Pattern::InSet(..))));
+        let subject_set = match subject[0] {
+            Pattern::InSet(_, set, _) => Rc::as_ptr(set),
+            _ => unreachable!(),
+        };
+        assert_ne!(subject_set, set[0]);
+    }
+
+    #[test]
+    fn single_patterns_and_file_fields_are_not_combined() {
+        let filters = filters_from(SHARED.as_bytes()).unwrap();
+        let list_id = patterns(&filters, "list-id");
+        assert!(matches!(list_id[..], [Pattern::Regex(_)]));
+        let body = patterns(&filters, "@body");
+        assert_eq!(body.len(), 2);
+        assert!(body.iter().all(|p| matches!(p, Pattern::Regex(_))));
+    }
+
+    #[test]
+    fn combined_patterns_match_the_same() {
+        let separate: Vec<Filter> = serde_json::from_str::<Vec<Filter>>(SHARED)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.compile().unwrap())
+            .collect();
+        let combined = filters_from(SHARED.as_bytes()).unwrap();
+        let other = b"From: Rust Bot <bot@rust.example>\r\n\
+            Subject: Draft release\r\n\
+            \r\n\
+            x\r\n";
+        for message in [MESSAGE, other] {
+            let mail = MailFile::from_bytes(message.to_vec());
+            let mut warnings = Vec::new();
+            for (s, c) in separate.iter().zip(&combined) {
+                assert_eq!(
+                    s.is_mail_match(&mail, &mut warnings).unwrap(),
+                    c.is_mail_match(&mail, &mut warnings).unwrap(),
+                    "{}",
+                    s.name()
+                );
             }
         }
-        Ok(false)
     }
 }
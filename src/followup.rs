@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::date::{after, now};
+use crate::error::Warning::*;
+use crate::error::{Error, Result, RunError, Stage, Warning};
+use crate::report::sender_address;
+use crate::state;
+
+use notmuch::{Database, Message};
+
+/// Tag for messages nobody replied to in time
+pub(crate) const FOLLOWUP_TAG: &str = "needs-followup";
+
+/// Sent messages waiting for a reply, see [`Operations::expect_reply`]
+///
+/// Each message is recorded along with the time a reply is expected by.
+/// [`check_followups`] tags messages without any reply by then as
+/// `needs-followup`, and forgets about messages that got one. A reply is any
+/// newer message in the same thread that isn't from the sender of the
+/// message itself. Messages are identified by their Message-ID.
+///
+/// [`Operations::expect_reply`]: struct.Operations.html#structfield.expect_reply
+/// [`check_followups`]: fn.check_followups.html
+#[derive(Debug, Clone)]
+pub struct FollowUps {
+    path: PathBuf,
+    expected: BTreeMap<String, i64>,
+    _lock: state::Lock,
+}
+
+impl FollowUps {
+    /// Reads the messages waiting for a reply from `path`, starting out empty
+    /// if there's no such file yet
+    ///
+    /// Waits for whoever else has them open until they're dropped.
+    pub fn open<P>(path: &P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let (expected, lock) = state::open(path.as_ref())?;
+        Ok(FollowUps {
+            path: path.as_ref().to_path_buf(),
+            expected,
+            _lock: lock,
+        })
+    }
+
+    /// Unix timestamp a reply to the message with Message-ID `id` is
+    /// expected by, if it's waiting for one
+    pub fn get(&self, id: &str) -> Option<i64> {
+        self.expected.get(id).copied()
+    }
+
+    /// Records a message, replacing the time a reply is expected by if it's
+    /// waiting for one already
+    pub fn insert(&mut self, id: &str, until: i64) {
+        self.expected.insert(id.to_string(), until);
+    }
+
+    /// How many messages are waiting for a reply
+    pub fn len(&self) -> usize {
+        self.expected.len()
+    }
+
+    /// Checks if no message is waiting for a reply
+    pub fn is_empty(&self) -> bool {
+        self.expected.is_empty()
+    }
+
+    /// Writes the messages back to the file they were read from
+    pub fn save(&self) -> Result<()> {
+        state::write(&self.path, &self.expected)
+    }
+}
+
+/// Records that a reply to a message is expected within `duration` of it
+/// being sent
+pub(crate) fn expect_reply(
+    msg: &Message,
+    duration: &str,
+    name: &str,
+    followups: &mut FollowUps,
+) -> Result<()> {
+    let until = after(msg.date(), duration, "expect_reply")
+        .map_err(|e| RunError::wrap(e, msg, Some(name), Stage::Run))?;
+    followups.insert(&msg.id(), until);
+    Ok(())
+}
+
+/// Checks if anyone replied to a message, i.e. if there's a newer message in
+/// its thread from someone else
+fn has_reply(db: &Database, msg: &Message) -> Result<bool> {
+    let quote = |s: &str| format!("\"{}\"", s.replace('"', "\"\""));
+    let mut query = format!(
+        "thread:{} and date:@{}.. and not id:{}",
+        msg.thread_id(),
+        msg.date(),
+        quote(&msg.id())
+    );
+    let from = msg.header("from")?.map(|f| f.to_string());
+    if let Some(addr) = from.as_deref().and_then(sender_address) {
+        query.push_str(&format!(" and not from:{}", quote(&addr)));
+    }
+    Ok(db.create_query(&query)?.count_messages()? > 0)
+}
+
+/// Tags the messages waiting for a reply that didn't get one in time as
+/// `needs-followup`, and forgets about them as well as those that got one
+///
+/// Returns the Message-IDs of the messages tagged. Messages that aren't in
+/// the database anymore are forgotten with a [`Warning`].
+///
+/// [`Warning`]: error/enum.Warning.html
+pub fn check_followups(
+    db: &Database,
+    followups: &mut FollowUps,
+) -> Result<(Vec<String>, Vec<Warning>)> {
+    let now = now();
+    let mut tagged = Vec::new();
+    let mut warnings = Vec::new();
+    let expected: Vec<(String, i64)> = followups
+        .expected
+        .iter()
+        .map(|(id, until)| (id.clone(), *until))
+        .collect();
+    for (id, until) in expected {
+        let msg = match db.find_message(&id)? {
+            Some(msg) => msg,
+            None => {
+                let w = format!("{}: not in the database anymore", id);
+                warnings.push(SkippedMessage(w));
+                followups.expected.remove(&id);
+                continue;
+            }
+        };
+        let wrap = |e: Error| RunError::wrap(e, &msg, None, Stage::AddTags);
+        if has_reply(db, &msg).map_err(wrap)? {
+            followups.expected.remove(&id);
+        } else if until <= now {
+            msg.add_tag(FOLLOWUP_TAG).map_err(|e| wrap(e.into()))?;
+            followups.expected.remove(&id);
+            tagged.push(id);
+        }
+    }
+    Ok((tagged, warnings))
+}
@@ -0,0 +1,84 @@
+/// Turns HTML into plain text, good enough for matching on it
+///
+/// Tags are dropped (along with everything inside `<script>`, `<style>` and
+/// `<head>`), block elements and `<br>` become line breaks, and the common
+/// named as well as all numeric character references are decoded.
+pub(crate) fn to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(i) = rest.find(['<', '&']) {
+        text.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if rest.starts_with('&') {
+            let (decoded, len) = entity(rest);
+            text.push_str(&decoded);
+            rest = &rest[len..];
+            continue;
+        }
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = rest[1..end].trim_start_matches('/');
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        rest = &rest[end + 1..];
+        match name.as_str() {
+            "script" | "style" | "head" if !rest.is_empty() => {
+                let close = format!("</{name}");
+                rest = find_ignore_case(rest, &close)
+                    .and_then(|i| rest[i..].find('>').map(|j| &rest[i + j + 1..]))
+                    .unwrap_or("");
+            }
+            "br" | "p" | "div" | "tr" | "li" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6"
+            | "table" | "blockquote" => text.push('\n'),
+            "td" | "th" => text.push(' '),
+            _ => {}
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+/// Decodes the character reference at the start of `s`, returning it along
+/// with how many bytes it took up; unknown ones are kept as they are
+fn entity(s: &str) -> (String, usize) {
+    let end = match s.bytes().take(12).position(|b| b == b';') {
+        Some(end) => end,
+        None => return ("&".to_string(), 1),
+    };
+    let c = match &s[1..end] {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        name => {
+            let code = match name.strip_prefix('#') {
+                Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16).ok(),
+                Some(dec) => dec.parse().ok(),
+                None => None,
+            };
+            code.and_then(char::from_u32)
+        }
+    };
+    match c {
+        Some(c) => (c.to_string(), end + 1),
+        None => ("&".to_string(), 1),
+    }
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle.as_bytes()))
+}
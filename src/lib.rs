@@ -12,7 +12,19 @@ respective header for a match. If an appropriate match is found, it is then able
 to add or remove tags, run an arbitrary binary for further processing, or delete
 the notmuch database entry and the corresponding file.
 
-Rules can be combined with AND as well as OR.
+Rules can be combined with AND as well as OR, and conditions can be negated
+by prefixing their field with `!`. A header given `true` or `false` instead of
+a pattern matches on whether the message has that header at all.
+
+Filters with `"dry": true` only report what they match, without applying any
+of their operations. This is handy to try out new filters on live mail while
+all others keep running as usual.
+
+Filters can declare how urgent the messages they match are with `"urgency"`
+(`high`, `normal` or `low`), which maintains the tags `urgent` and
+`low-priority` for a triage inbox, see [`Urgency`].
+
+[`Urgency`]: enum.Urgency.html
 
 # Example: a filter in a JSON file
 
@@ -58,12 +70,40 @@ NOTCOAL_FILE_NAME=/path/to/maildir/new/filename
 NOTCOAL_MSG_ID=e81cadebe7dab1cc6fac7e6a41@some-isp
 ```
 
+Fragments used by several filters can be defined once: instead of a list of
+filters, the file may be an object with the filters under `filters` and the
+fragments under `defs`, which filters refer to with `{"$ref": "name"}`.
+Definitions that are lists are spliced into the list they're referred to
+in, and other keys next to `$ref` are added to a definition that's an
+object. Strings may also contain `{{name}}` variables, defined under `vars`
+(or in the JSON file `vars` names) or taken from the environment.
+
+```json,ignore
+{
+    "defs": {
+        "work": "@(work|client)\\.example",
+        "bulk": [{"list-unsubscribe": true}, {"precedence": "bulk"}]
+    },
+    "filters": [{
+        "name": "work",
+        "rules": [{"from": {"$ref": "work"}}, {"to": {"$ref": "work"}}],
+        "op": {"add": "work"}
+    }, {
+        "name": "bulk",
+        "rules": [{"$ref": "bulk"}, {"from": "newsletter@"}],
+        "op": {"add": "bulk"}
+    }]
+}
+```
+
 # What notcoal can match
 
 Arbitrary headers! Matching `from` and `subject` are in no way a special case
 since all headers are treated equal (and case-insensitive). The mere existence
-of a header may be occasionally enough for classification, and while the
-[`Value`] enum also has a boolean field, it can not be used in rules.
+of a header may be occasionally enough for classification, which is what the
+boolean [`Value`] is for: `{"list-unsubscribe": true}` matches messages that
+have the header (with any value, even an empty one), and
+`{"list-unsubscribe": false}` those that don't.
 
 In addition to arbitrary headers, notcoal also supports "special field checks":
 
@@ -71,145 +111,1403 @@ In addition to arbitrary headers, notcoal also supports "special field checks":
 * `@path`: the file system path of the message being processed
 * `@attachment`: any attachment file names
 * `@body`: the message body. The first (usually plain text) body part only.
+* `@body-text`: the plain text parts of the message, or if there are none its
+  HTML parts converted to text
 * `@attachment-body`: any attachments contents as long as the MIME type starts
   with `text`
+* `@mime-type`: the content types of all message parts, including nested ones
+* `@attachment-count`: how many attachments the message has, compared with
+  e.g. `>0` or `>=3`
+* `@thread-size` and `@thread-depth`: how many messages and levels of replies
+  the message's thread has, compared the same way
+* `@folder`: the maildir folders the message is in, relative to the database
+  root, e.g. `Work/Projects`
+* `@flags`: the maildir flags in the names of the message's files, e.g. `FS`
+  for flagged and seen
+* `@recipient-count`: how many addresses the message is sent to in To and
+  Cc, compared like `@attachment-count`
+* `@score`: the sum of the [`Filter::score`] of the filters that matched
+  earlier, compared like `@attachment-count`
+* `@spam-score`: the score spam filters assigned to the message, from
+  `X-Spam-Score`, `X-Rspamd-Score` or `X-Spam-Status`, compared like
+  `@attachment-count`
+* `@size`: the size of the message file in bytes, compared like
+  `@attachment-count`
+* `@received-ip`: the relay addresses in all Received headers, matched
+  against address ranges like `192.0.2.0/24` or regular expressions
+* `@duplicate`: `true` if the message is stored in more than one file
+* `@query`: notmuch queries the message has to match, e.g. `folder:Lists` or
+  `from:me and tag:sent`
+* `@<header>-addr`, `@<header>-name`: only the addresses or display names in an
+  address header, e.g. `@from-addr` or `@cc-name`
+* `@list`: the identifier of the mailing list the message was sent through,
+  from `List-Id`, `List-Post` or `X-Mailing-List`
+* `@dkim`, `@spf`, `@dmarc`: results of these checks (like `pass`, `fail` or
+  `none`) from the topmost `Authentication-Results` header
+* `@dkim-valid`: the domains of the DKIM signatures that notcoal verified
+  itself, looking up their keys in DNS (needs the `dkim` feature)
+* `@smime-valid`: the subject and email addresses of the certificate an S/MIME
+  signature was made with, if it verified against the system's trusted
+  certificates (needs the `smime` feature)
 * `@thread-tags`: match on any tag in the thread that we belong to (e.g.
   *mute*).<br>
   **Please note, this applies to the *entire* thread**, not only to the local
   branch.
+* `@date`: the date of the message, either as a day (`2024-01-31`), a range of
+  days (`2024-01-01..2024-01-31`), before or after a day (`<2024-01-01`) or
+  relative to now (`>30d` for messages older than 30 days)
 
 [regex]: https://docs.rs/regex/
 [notmuch]: https://notmuchmail.org/
 [initial tagging]: https://notmuchmail.org/initial_tagging/
 [notmuch-rs]: https://github.com/vhdirk/notmuch-rs/
 [`Value`]: enum.Value.html
+[`Filter::score`]: struct.Filter.html#structfield.score
 */
 
 use serde::{Deserialize, Serialize};
 
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use notmuch::Database;
+use notmuch::{Database, Message};
 
 pub mod error;
 use crate::error::Error::*;
 use crate::error::Result;
+use crate::error::Warning::*;
+use crate::error::{Error, RunError, Stage, Warning};
+mod cache;
+pub use crate::cache::*;
+mod calendar;
+pub use crate::calendar::*;
+mod cidr;
+mod date;
+#[cfg(feature = "openpgp")]
+mod decrypt;
+mod defs;
+mod digest;
+pub use crate::digest::*;
+pub mod edit;
+mod export;
+pub use crate::export::*;
 mod filter;
 pub use crate::filter::*;
+mod followup;
+pub use crate::followup::*;
+mod html;
+mod lint;
+pub use crate::lint::*;
+mod mail;
+pub use crate::mail::*;
+pub mod maildir;
+mod number;
 mod operations;
 pub use crate::operations::*;
+mod pattern;
+mod plan;
+pub use crate::plan::*;
+mod plugin;
+pub use crate::plugin::*;
+mod report;
+pub use crate::report::*;
+mod senders;
+pub use crate::senders::*;
+mod snooze;
+pub use crate::snooze::*;
+mod state;
+#[cfg(any(feature = "dkim", feature = "smime"))]
+mod verify;
+
+use crate::digest::collect;
+use crate::followup::expect_reply;
+use crate::mail::{DbMail, Tracked};
+use crate::operations::{move_message, Children};
+use crate::snooze::snooze_message;
 
 /// Possible values for operations and rules
 ///
 /// To make the JSON files more legible in case they are hand-crafted, provide
 /// different options for the same fields.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 #[serde(untagged)]
 pub enum Value {
     Single(String),
     Multiple(Vec<String>),
     Bool(bool),
+    Literal(Literal),
+    Comparison(Comparison),
+}
+
+/// Plain strings to match in rules, instead of regular expressions
+///
+/// Written as `{"eq": "boss@work.example"}` or `{"contains": "@work.example"}`
+/// in rule files, saving the need to escape dots and other characters regular
+/// expressions treat specially. Shell-style wildcards are available as
+/// `{"glob": "*@*.work.example"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "lowercase")]
+pub enum Literal {
+    /// Matches values that are exactly this string
+    Eq(String),
+    /// Matches values containing this string
+    Contains(String),
+    /// Matches values that match this glob pattern as a whole, where `*`
+    /// stands for any characters, `?` for a single one and `[...]` for one
+    /// of a set (`[!...]` for one not in it)
+    Glob(String),
+}
+
+/// Numeric comparisons in rules, instead of regular expressions
+///
+/// Written as `{"gt": 5}`, `{"le": 100000}` or combined like
+/// `{"ge": 1, "lt": 10}` in rule files, matching values that are numbers
+/// satisfying all given comparisons. Works for any field, e.g. headers like
+/// `X-Spam-Score` or special fields like `@size`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Comparison {
+    /// Greater than
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gt: Option<f64>,
+    /// Greater than or equal to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ge: Option<f64>,
+    /// Less than
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lt: Option<f64>,
+    /// Less than or equal to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub le: Option<f64>,
+    /// Equal to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eq: Option<f64>,
+}
+
+impl Comparison {
+    /// Checks if `n` satisfies all comparisons
+    pub fn matches(&self, n: f64) -> bool {
+        self.gt.is_none_or(|m| n > m)
+            && self.ge.is_none_or(|m| n >= m)
+            && self.lt.is_none_or(|m| n < m)
+            && self.le.is_none_or(|m| n <= m)
+            && self.eq.is_none_or(|m| n == m)
+    }
+}
+
+impl Value {
+    /// Replaces every string for which `f` returns a replacement
+    ///
+    /// Returns how many strings were replaced.
+    pub fn replace_with<F>(&mut self, f: F) -> usize
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut replaced = 0;
+        let mut replace = |s: &mut String| {
+            if let Some(r) = f(s) {
+                *s = r;
+                replaced += 1;
+            }
+        };
+        match self {
+            Value::Single(s) => replace(s),
+            Value::Multiple(ss) => ss.iter_mut().for_each(replace),
+            Value::Bool(_) | Value::Comparison(_) => {}
+            Value::Literal(Literal::Eq(s))
+            | Value::Literal(Literal::Contains(s))
+            | Value::Literal(Literal::Glob(s)) => replace(s),
+        }
+        replaced
+    }
 }
 
 /// Determines behaviour for filter execution
+///
+/// Operations keeping state of their own, like [`Operations::snooze`], are
+/// skipped with a [`Warning`] if there's nowhere to keep it.
+///
+/// [`Operations::snooze`]: struct.Operations.html#structfield.snooze
+/// [`Warning`]: error/enum.Warning.html
 pub struct FilterOptions {
     /// To leave "query tag" in place instead of removing it once all filters ran
     pub leave_tag: bool,
     /// Force maildir flag syncing
     pub sync_tags: bool,
+    /// Shadow mode: prefix to apply tag operations under instead of the real
+    /// tags, see [`Operations::apply_shadowed`]
+    ///
+    /// The prefixed tags are validated before any message is processed.
+    /// Nothing else is changed: the query tag is left in place and maildir
+    /// flags aren't synced, so a regular run still processes the messages
+    /// afterwards. Neither [`FilterOptions::hooks`] nor
+    /// [`FilterOptions::max_destructive`] apply, and [`FilterOptions::cache`],
+    /// [`FilterOptions::snoozes`], [`FilterOptions::followups`],
+    /// [`FilterOptions::senders`], [`FilterOptions::plugins`] and
+    /// [`FilterOptions::digests`] aren't used.
+    ///
+    /// [`Operations::apply_shadowed`]: struct.Operations.html#method.apply_shadowed
+    /// [`FilterOptions::hooks`]: struct.FilterOptions.html#structfield.hooks
+    /// [`FilterOptions::max_destructive`]: struct.FilterOptions.html#structfield.max_destructive
+    /// [`FilterOptions::cache`]: struct.FilterOptions.html#structfield.cache
+    /// [`FilterOptions::snoozes`]: struct.FilterOptions.html#structfield.snoozes
+    /// [`FilterOptions::followups`]: struct.FilterOptions.html#structfield.followups
+    /// [`FilterOptions::senders`]: struct.FilterOptions.html#structfield.senders
+    /// [`FilterOptions::plugins`]: struct.FilterOptions.html#structfield.plugins
+    /// [`FilterOptions::digests`]: struct.FilterOptions.html#structfield.digests
+    pub shadow_prefix: Option<String>,
+    /// Messages for which this returns `true` are skipped before any filter
+    /// is evaluated, and keep their query tag
+    ///
+    /// Meant for conditions specific to the embedding application, like
+    /// messages currently open in the UI.
+    pub skip_if: Option<SkipPredicate>,
+    /// Read at most this many bytes of a message's file when matching
+    /// `@body`, `@attachment` or `@attachment-body`
+    ///
+    /// The limit applies to the file as a whole, headers included, rather
+    /// than to each part: parts beyond it are ignored and the part it falls
+    /// into is cut off there, so a large attachment coming first can hide
+    /// the text after it from `@body`. That keeps memory usage in check for
+    /// huge messages.
+    pub body_limit: Option<usize>,
+    /// Memory-map message files instead of reading them into memory
+    ///
+    /// Saves copying every message that is matched on `@body` or attachments,
+    /// but may be slower (or less robust) on network filesystems. A message
+    /// file truncated by someone else while it's mapped kills the process
+    /// with SIGBUS.
+    pub mmap: bool,
+    /// Callbacks for every operation applied, see [`Hooks`]
+    ///
+    /// [`Hooks`]: struct.Hooks.html
+    pub hooks: Hooks,
+    /// Skip messages that were processed with the same filters before and
+    /// haven't changed since, and record the ones that weren't, see
+    /// [`MatchCache`]
+    ///
+    /// [`MatchCache`]: struct.MatchCache.html
+    pub cache: Option<RefCell<MatchCache>>,
+    /// Measure how long matching takes, see [`RunOutcome::timings`]
+    ///
+    /// [`RunOutcome::timings`]: struct.RunOutcome.html#structfield.timings
+    pub timing: bool,
+    /// Run at most this many `run` commands at the same time
+    ///
+    /// Once the limit is reached, processing waits for the oldest command to
+    /// exit before spawning another one. Without a limit, commands are
+    /// spawned without waiting for any of them.
+    pub max_children: Option<usize>,
+    /// Delete, move or copy at most this many messages per run
+    ///
+    /// Defense in depth against broken rules: once the budget is used up,
+    /// messages that would be deleted, moved or copied are skipped with a
+    /// [`Warning`] and keep their query tag, so they can be processed again
+    /// once the rules are fixed (or the budget raised). Each message is checked against the
+    /// budget before any filter is applied to it, so it's never left
+    /// half-processed.
+    ///
+    /// [`Warning`]: error/enum.Warning.html
+    pub max_destructive: Option<usize>,
+    /// Stop processing a message once the first filter matched it
+    ///
+    /// For rules structured as mutually exclusive routes, procmail-style,
+    /// which saves evaluating all later filters. Filters in observation-only
+    /// mode and filters others depend on (see [`Filter::then`]) don't count
+    /// as matches. Dry runs, [`plan`] and [`classify`] still
+    /// report every matching filter.
+    ///
+    /// [`Filter::then`]: struct.Filter.html#structfield.then
+    /// [`plan`]: fn.plan.html
+    /// [`classify`]: fn.classify.html
+    pub first_match_only: bool,
+    /// Where to record messages snoozed by [`Operations::snooze`]
+    ///
+    /// [`Operations::snooze`]: struct.Operations.html#structfield.snooze
+    pub snoozes: Option<RefCell<Snoozes>>,
+    /// Where to record messages waiting for a reply, see
+    /// [`Operations::expect_reply`]
+    ///
+    /// [`Operations::expect_reply`]: struct.Operations.html#structfield.expect_reply
+    pub followups: Option<RefCell<FollowUps>>,
+    /// Where to record processed messages for `@sender-score` statistics,
+    /// see [`SenderHistory`]
+    ///
+    /// The scores themselves come from [`SenderHistory::matcher`].
+    ///
+    /// [`SenderHistory`]: struct.SenderHistory.html
+    /// [`SenderHistory::matcher`]: struct.SenderHistory.html#method.matcher
+    pub senders: Option<RefCell<SenderHistory>>,
+    /// Plugins [`Operations::plugins`] may use, by name
+    ///
+    /// Operations using any other plugin are skipped with a [`Warning`].
+    /// Rules use plugins registered as matchers instead, see [`Plugin`].
+    ///
+    /// [`Operations::plugins`]: struct.Operations.html#structfield.plugins
+    /// [`Warning`]: error/enum.Warning.html
+    /// [`Plugin`]: struct.Plugin.html
+    pub plugins: BTreeMap<String, Rc<Plugin>>,
+    /// Where to collect messages for [`Operations::digest`]
+    ///
+    /// [`Operations::digest`]: struct.Operations.html#structfield.digest
+    pub digests: Option<RefCell<Digests>>,
+}
+
+/// Predicate deciding which messages to skip, see [`FilterOptions::skip_if`]
+///
+/// [`FilterOptions::skip_if`]: struct.FilterOptions.html#structfield.skip_if
+pub type SkipPredicate = Box<dyn Fn(&Message) -> bool>;
+
+/// Longest tag notmuch accepts, in bytes
+pub const TAG_MAX: usize = 200;
+
+/// Checks if notmuch (including its command line tools) can handle a tag
+///
+/// Tags can't be empty or longer than [`TAG_MAX`] bytes. Tags starting with
+/// `-` or containing control characters (like newlines) are rejected too, as
+/// they can't be used with `notmuch tag`.
+///
+/// [`TAG_MAX`]: constant.TAG_MAX.html
+pub fn validate_tag(tag: &str) -> Result<()> {
+    let e = if tag.is_empty() {
+        "Tags can't be empty".to_string()
+    } else if tag.len() > TAG_MAX {
+        format!("Tag {} is longer than {} bytes", tag, TAG_MAX)
+    } else if tag.starts_with('-') {
+        format!("Tag {} can't start with -", tag)
+    } else if tag.chars().any(|c| c.is_control()) {
+        format!("Tag {:?} can't contain control characters", tag)
+    } else {
+        return Ok(());
+    };
+    Err(InvalidTag(e))
 }
 
-/// Very basic sanitisation for our (user supplied) query
-fn validate_query_tag(tag: &str) -> Result<String> {
+/// Checks that the tags a shadow run with `prefix` adds instead of applying
+/// `ops` (pairs of filter name and the operations it applies) are valid, see
+/// [`FilterOptions::shadow_prefix`]
+///
+/// Meant to be called before any message is processed, so a prefix leading
+/// to invalid tags fails the run rather than every message.
+///
+/// [`FilterOptions::shadow_prefix`]: struct.FilterOptions.html#structfield.shadow_prefix
+pub(crate) fn validate_shadow_tags<'o>(
+    prefix: Option<&str>,
+    ops: impl Iterator<Item = (String, Cow<'o, Operations>)>,
+) -> Result<()> {
+    let prefix = match prefix {
+        Some(prefix) => prefix,
+        None => return Ok(()),
+    };
+    let invalid = |e| match e {
+        InvalidTag(e) => InvalidTag(format!("{} with shadow prefix {:?}", e, prefix)),
+        e => e,
+    };
+    validate_tag(prefix).map_err(invalid)?;
+    for (name, op) in ops {
+        let added = op
+            .added_tags()
+            .into_iter()
+            .map(|t| format!("{}{}", prefix, t));
+        let removed = op
+            .removed_tags()
+            .into_iter()
+            .map(|t| format!("{}-{}", prefix, t));
+        for tag in added.chain(removed) {
+            validate_tag(&tag).map_err(|e| match invalid(e) {
+                InvalidTag(e) => InvalidTag(format!("{} in filter {}", e, name)),
+                e => e,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds a query for messages with the given (user supplied) tag
+///
+/// The tag is quoted the way notmuch itself does it (doubling any quotes
+/// within), so tags containing whitespace, quotes or parentheses are
+/// supported, while tags notmuch can't handle are rejected.
+pub(crate) fn validate_query_tag(tag: &str) -> Result<String> {
     if tag.is_empty() {
         let e = "Tag to query can't be empty".to_string();
         return Err(UnsupportedQuery(e));
     };
-    if tag.contains(' ') || tag.contains('"') || tag.contains('\'') {
-        let e = "Query tags can't contain whitespace or quotes".to_string();
-        Err(UnsupportedQuery(e))
-    } else {
-        Ok(format!("tag:{}", tag))
+    validate_tag(tag).map_err(|e| match e {
+        InvalidTag(e) => UnsupportedQuery(e),
+        e => e,
+    })?;
+    Ok(format!("tag:\"{}\"", tag.replace('"', "\"\"")))
+}
+
+/// Turns errors caused by a message's file having vanished from disk (e.g.
+/// because a concurrently running sync moved it) into a [`Warning`]
+///
+/// [`Warning`]: error/enum.Warning.html
+pub(crate) fn skip_if_missing<T>(
+    res: Result<T>,
+    msg: &Message,
+    warnings: &mut Vec<Warning>,
+) -> Result<Option<T>> {
+    match res {
+        Err(e) if e.is_not_found() => {
+            warnings.push(SkippedMessage(format!("{}: {}", msg.id(), e)));
+            Ok(None)
+        }
+        r => r.map(Some),
+    }
+}
+
+/// Summary of a [`filter`] run
+///
+/// Failing to process a single message doesn't abort the whole run. Instead,
+/// the error is recorded here, the message keeps its query tag and processing
+/// continues with the next one.
+///
+/// [`filter`]: fn.filter.html
+#[derive(Debug, Default)]
+pub struct RunOutcome {
+    /// How many filters were applied
+    pub applied: usize,
+    /// How many messages were skipped, either because of
+    /// [`FilterOptions::skip_if`] or for the reasons in
+    /// [`RunOutcome::warnings`]
+    ///
+    /// [`FilterOptions::skip_if`]: struct.FilterOptions.html#structfield.skip_if
+    /// [`RunOutcome::warnings`]: struct.RunOutcome.html#structfield.warnings
+    pub skipped: usize,
+    /// How many messages weren't evaluated because they're in
+    /// [`FilterOptions::cache`]
+    ///
+    /// [`FilterOptions::cache`]: struct.FilterOptions.html#structfield.cache
+    pub cached: usize,
+    /// Matches of filters in observation-only mode, as Message-ID and filter
+    /// name, see [`Filter::dry`]
+    ///
+    /// [`Filter::dry`]: struct.Filter.html#structfield.dry
+    pub observed: Vec<String>,
+    /// Errors that occurred while processing individual messages
+    pub errors: Vec<RunError>,
+    /// Non-fatal conditions that came up along the way
+    pub warnings: Vec<Warning>,
+    /// Where matching spent its time, empty unless [`FilterOptions::timing`]
+    /// is set
+    ///
+    /// [`FilterOptions::timing`]: struct.FilterOptions.html#structfield.timing
+    pub timings: Timings,
+    /// How many messages ended up at each urgency, decided by the last filter
+    /// with [`Filter::urgency`] applied to them
+    ///
+    /// [`Filter::urgency`]: struct.Filter.html#structfield.urgency
+    pub urgency: BTreeMap<Urgency, usize>,
+    /// Output of the commands run with [`RunOutput::Capture`], in the order
+    /// they exited
+    ///
+    /// [`RunOutput::Capture`]: enum.RunOutput.html#variant.Capture
+    pub output: Vec<CommandOutput>,
+}
+
+/// Cumulative time spent matching, see [`FilterOptions::timing`]
+///
+/// Only evaluating rules is measured, applying operations isn't.
+///
+/// [`FilterOptions::timing`]: struct.FilterOptions.html#structfield.timing
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    /// Time spent per filter, by filter name
+    pub filters: BTreeMap<String, Duration>,
+    /// Time spent per field, with special fields like `@body` (including
+    /// reading and parsing the message) or `@thread-tags` (including the
+    /// thread query) listed by their name and all headers summed up as
+    /// `headers`
+    pub fields: BTreeMap<String, Duration>,
+}
+
+impl RunOutcome {
+    /// Checks if every message was processed without errors
+    pub fn is_success(&self) -> bool {
+        self.errors.is_empty()
     }
+
+    /// Adds up the outcome of another run, e.g. on a different database
+    pub fn merge(&mut self, other: RunOutcome) {
+        self.applied += other.applied;
+        self.skipped += other.skipped;
+        self.cached += other.cached;
+        self.observed.extend(other.observed);
+        for (urgency, n) in other.urgency {
+            *self.urgency.entry(urgency).or_default() += n;
+        }
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+        self.output.extend(other.output);
+        for (name, time) in other.timings.filters {
+            *self.timings.filters.entry(name).or_default() += time;
+        }
+        for (name, time) in other.timings.fields {
+            *self.timings.fields.entry(name).or_default() += time;
+        }
+    }
+}
+
+/// What needs to be kept track of across messages during a run
+#[derive(Default)]
+pub(crate) struct RunState {
+    children: Children,
+    /// How often each filter with [`Filter::max_matches`] matched so far
+    ///
+    /// [`Filter::max_matches`]: struct.Filter.html#structfield.max_matches
+    matches: HashMap<String, usize>,
+    /// How many destructive operations were applied so far
+    destructive: usize,
+    /// If any filter changes the message store, see
+    /// [`Operations::changes_store`]
+    ///
+    /// [`Operations::changes_store`]: struct.Operations.html#method.changes_store
+    changes_store: Option<bool>,
+    /// If messages found in [`FilterOptions::cache`] may be skipped, which
+    /// they can't be if any filter depends on more than the message, see
+    /// [`Filter::depends_on_state`]
+    ///
+    /// [`FilterOptions::cache`]: struct.FilterOptions.html#structfield.cache
+    /// [`Filter::depends_on_state`]: struct.Filter.html#method.depends_on_state
+    skip_cached: Option<bool>,
+}
+
+impl RunState {
+    pub fn new(options: &FilterOptions) -> Self {
+        RunState {
+            children: Children::new(options.max_children),
+            matches: HashMap::new(),
+            destructive: 0,
+            changes_store: None,
+            skip_cached: None,
+        }
+    }
+
+    /// Checks if destructive operations have to be counted at all, see
+    /// [`FilterOptions::max_destructive`]
+    ///
+    /// [`FilterOptions::max_destructive`]: struct.FilterOptions.html#structfield.max_destructive
+    fn has_budget(&mut self, filters: &[Filter], options: &FilterOptions) -> bool {
+        options.max_destructive.is_some()
+            && options.shadow_prefix.is_none()
+            && *self
+                .changes_store
+                .get_or_insert_with(|| filters.iter().any(|f| !f.is_dry() && f.op.changes_store()))
+    }
+
+    /// Reserves the destructive operations `ops` (the operations of all
+    /// filters applied to a message, in order) amount to, returning if
+    /// they're all still within [`FilterOptions::max_destructive`]
+    ///
+    /// Every copy counts, a deletion ends the message's processing and only
+    /// the last move is carried out, see [`store_changes`]. Meant to be
+    /// called before anything is applied to the message, so it's either
+    /// processed completely or not at all.
+    ///
+    /// [`FilterOptions::max_destructive`]: struct.FilterOptions.html#structfield.max_destructive
+    /// [`store_changes`]: fn.store_changes.html
+    pub fn within_budget<'o>(
+        &mut self,
+        ops: impl Iterator<Item = &'o Operations>,
+        msg: &Message,
+        options: &FilterOptions,
+        warnings: &mut Vec<Warning>,
+    ) -> bool {
+        if options.shadow_prefix.is_some() {
+            return true;
+        }
+        let max = match options.max_destructive {
+            Some(max) => max,
+            None => return true,
+        };
+        let needed = store_changes(ops);
+        if self.destructive + needed > max {
+            warnings.push(SkippedMessage(format!(
+                "{}: filters would delete, move or copy it, but only {} such operations are \
+                 allowed per run",
+                msg.id(),
+                max
+            )));
+            return false;
+        }
+        self.destructive += needed;
+        true
+    }
+
+    /// Checks if `filter` may still be applied, without counting a match
+    fn may_allow(&self, filter: &Filter) -> bool {
+        filter.max_matches.is_none_or(|max| {
+            let n = self.matches.get(&filter.name()).copied().unwrap_or(0);
+            n < max
+        })
+    }
+
+    /// Counts a match of `filter`, returning if it may still be applied
+    ///
+    /// If it may not, the filters depending on it aren't applied either.
+    pub fn allow(&mut self, filter: &Filter, warnings: &mut Vec<Warning>) -> bool {
+        let max = match filter.max_matches {
+            Some(max) => max,
+            None => return true,
+        };
+        let n = self.matches.entry(filter.name()).or_default();
+        *n += 1;
+        if *n == max + 1 {
+            warnings.push(MatchLimit(format!(
+                "{} matched more than {} messages, not applying it for the rest of the run",
+                filter.name(),
+                max
+            )));
+        }
+        if *n > max {
+            filter.forget_outcome();
+        }
+        *n <= max
+    }
+}
+
+/// Apply all supplied filters to a single message, then remove the query tag
+/// (if any) and sync maildir flags if requested
+fn filter_message(
+    msg: &Message,
+    db: &Database,
+    query_tag: Option<&str>,
+    options: &FilterOptions,
+    filters: &[Filter],
+    outcome: &mut RunOutcome,
+    state: &mut RunState,
+) -> Result<()> {
+    if options.skip_if.as_ref().is_some_and(|skip| skip(msg)) {
+        outcome.skipped += 1;
+        return Ok(());
+    }
+    let cache = options
+        .cache
+        .as_ref()
+        .filter(|_| options.shadow_prefix.is_none());
+    let skip_cached = *state
+        .skip_cached
+        .get_or_insert_with(|| !filters.iter().any(Filter::depends_on_state));
+    if skip_cached && cache.is_some_and(|c| c.borrow().is_processed(db, msg, query_tag)) {
+        outcome.cached += 1;
+        return finish_message(msg, query_tag, options);
+    }
+    let mut mail = DbMail::new(msg, db, options.body_limit, options.mmap);
+    if state.has_budget(filters, options) {
+        // see what would be applied without applying it, so a message that's
+        // over budget is left alone entirely
+        let mut tracked = Tracked::new(&mail);
+        let applicable = in_phase_order(filters).filter(|f| f.applies_to(db));
+        let first_match_only = options.first_match_only;
+        let mut warnings = Vec::new();
+        let res = planned_operations(
+            &mut tracked,
+            applicable,
+            first_match_only,
+            state,
+            &mut warnings,
+        );
+        let ops = match res {
+            Ok(ops) => ops,
+            Err((e, name)) => {
+                skip_if_missing(Err::<(), _>(e), msg, &mut outcome.warnings)
+                    .map_err(|e| RunError::wrap(e, msg, Some(&name), Stage::Match))?;
+                outcome.skipped += 1;
+                return Ok(());
+            }
+        };
+        if !state.within_budget(ops.iter(), msg, options, &mut outcome.warnings) {
+            outcome.skipped += 1;
+            return Ok(());
+        }
+    }
+    let mut matched = Vec::new();
+    let mut urgency = None;
+    let mut move_to = None;
+    for filter in in_phase_order(filters).filter(|f| f.applies_to(db)) {
+        let start = Instant::now();
+        let timings = options.timing.then_some(&mut outcome.timings);
+        let res = filter.is_mail_match_timed(&mail, &mut outcome.warnings, timings);
+        if options.timing {
+            *outcome.timings.filters.entry(filter.name()).or_default() += start.elapsed();
+        }
+        let res = res.map(|m| m && (filter.is_dry() || state.allow(filter, &mut outcome.warnings)));
+        let res = res.and_then(|m| match m && !filter.is_dry() {
+            true => Ok((
+                m,
+                Some(filter.operations_for(&mail, &mut outcome.warnings)?),
+            )),
+            false => Ok((m, None)),
+        });
+        let res = res.and_then(|(m, op)| {
+            let op = match op {
+                Some(op) => op,
+                None => return Ok((m, false)),
+            };
+            if let Some(prefix) = &options.shadow_prefix {
+                op.apply_shadowed(msg, &filter.name(), prefix)?;
+                Ok((m, false))
+            } else {
+                let name = filter.name();
+                let deleted =
+                    op.apply_limited(msg, db, &name, &options.hooks, &mut state.children)?;
+                if !deleted {
+                    apply_extras(msg, db, &op, &name, options, &mut outcome.warnings)?;
+                    if let Some(folder) = &op.move_to {
+                        move_to = Some((folder.clone(), name));
+                    }
+                }
+                Ok((m, deleted))
+            }
+        });
+        let res = skip_if_missing(res, msg, &mut outcome.warnings)
+            .map_err(|e| RunError::wrap(e, msg, Some(&filter.name()), Stage::Match))?;
+        let (applied, deleted) = match res {
+            Some(r) => r,
+            None => {
+                outcome.skipped += 1;
+                return Ok(());
+            }
+        };
+        if applied {
+            matched.push(filter.name());
+        }
+        if applied && filter.is_dry() {
+            outcome
+                .observed
+                .push(format!("{}: {}", msg.id(), filter.name()));
+        } else if applied {
+            outcome.applied += 1;
+            urgency = filter.urgency.or(urgency);
+            mail.score += filter.score();
+        }
+        if deleted {
+            return Ok(());
+        }
+        let first_match = !filter.is_dry() && !filter.has_branches() && options.first_match_only;
+        if applied && (filter.stops() || first_match) {
+            break;
+        }
+    }
+    finish_message(msg, query_tag, options)?;
+    if let Some(urgency) = urgency {
+        *outcome.urgency.entry(urgency).or_default() += 1;
+    }
+    if let Some(senders) = options.senders.as_ref() {
+        if options.shadow_prefix.is_none() {
+            senders
+                .borrow_mut()
+                .record(&mail)
+                .map_err(|e| RunError::wrap(e, msg, None, Stage::Match))?;
+        }
+    }
+    // only now that nothing needs the message's files anymore
+    if let Some((folder, name)) = move_to {
+        if let Err(e) = move_message(msg, db, &folder, &name) {
+            restore_query_tag(msg, query_tag, options)?;
+            return Err(e);
+        }
+    }
+    // with the state the message ends up in
+    if let Some(cache) = cache {
+        cache.borrow_mut().insert(db, msg, query_tag, matched);
+    }
+    Ok(())
+}
+
+/// Applies the operations needing more than the message itself: snoozing,
+/// expecting a reply, plugins and digests, see [`Operations::snooze`],
+/// [`Operations::expect_reply`], [`Operations::plugins`] and
+/// [`Operations::digest`]
+///
+/// [`Operations::snooze`]: struct.Operations.html#structfield.snooze
+/// [`Operations::expect_reply`]: struct.Operations.html#structfield.expect_reply
+/// [`Operations::plugins`]: struct.Operations.html#structfield.plugins
+/// [`Operations::digest`]: struct.Operations.html#structfield.digest
+pub(crate) fn apply_extras(
+    msg: &Message,
+    db: &Database,
+    op: &Operations,
+    name: &str,
+    options: &FilterOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<()> {
+    if let Some(duration) = &op.snooze {
+        match &options.snoozes {
+            Some(snoozes) => {
+                let snoozes = &mut snoozes.borrow_mut();
+                snooze_message(msg, duration, name, snoozes, &options.hooks)?;
+            }
+            None => {
+                let w = format!("{}: snooze of {} without a snooze file", msg.id(), name);
+                warnings.push(SkippedOperation(w));
+            }
+        }
+    }
+    if let Some(duration) = &op.expect_reply {
+        match &options.followups {
+            Some(followups) => {
+                expect_reply(msg, duration, name, &mut followups.borrow_mut())?;
+            }
+            None => {
+                let w = format!(
+                    "{}: expect_reply of {} without a follow-up file",
+                    msg.id(),
+                    name
+                );
+                warnings.push(SkippedOperation(w));
+            }
+        }
+    }
+    if let Some(digest) = &op.digest {
+        match &options.digests {
+            Some(digests) => collect(msg, digest, name, &mut digests.borrow_mut())?,
+            None => {
+                let w = format!("{}: digest of {} without a digest file", msg.id(), name);
+                warnings.push(SkippedOperation(w));
+            }
+        }
+    }
+    for plugin in op.plugins.iter().flatten() {
+        let plugin = match options.plugins.get(plugin) {
+            Some(plugin) => plugin,
+            None => {
+                let w = format!("{}: plugin {} of {} isn't loaded", msg.id(), plugin, name);
+                warnings.push(SkippedOperation(w));
+                continue;
+            }
+        };
+        let wrap = |e, stage| RunError::wrap(e, msg, Some(name), stage);
+        let mail = DbMail::new(msg, db, options.body_limit, options.mmap);
+        for tag in plugin.apply(&mail).map_err(|e| wrap(e, Stage::Run))? {
+            validate_tag(&tag).map_err(|e| wrap(e, Stage::Run))?;
+            msg.add_tag(&tag)
+                .map_err(|e| wrap(e.into(), Stage::AddTags))?;
+            if let Some(hook) = &options.hooks.on_tag_added {
+                hook(msg, &tag);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes the query tag (unless it's supposed to be left in place) and syncs
+/// maildir flags once a message has been processed
+///
+/// Shadow runs leave both alone, so the real run still picks the message up.
+pub(crate) fn finish_message(
+    msg: &Message,
+    query_tag: Option<&str>,
+    options: &FilterOptions,
+) -> Result<()> {
+    if options.shadow_prefix.is_some() {
+        return Ok(());
+    }
+    if let Some(query_tag) = query_tag.filter(|_| !options.leave_tag) {
+        msg.remove_tag(query_tag)
+            .map_err(|e| RunError::wrap(e, msg, None, Stage::QueryTag))?;
+    }
+    if options.sync_tags {
+        msg.tags_to_maildir_flags()
+            .map_err(|e| RunError::wrap(e, msg, None, Stage::SyncFlags))?;
+    }
+    Ok(())
+}
+
+/// Gives `msg` back the query tag [`finish_message`] removed, so a message
+/// whose move failed is processed again by the next run
+///
+/// [`finish_message`]: fn.finish_message.html
+pub(crate) fn restore_query_tag(
+    msg: &Message,
+    query_tag: Option<&str>,
+    options: &FilterOptions,
+) -> Result<()> {
+    if options.shadow_prefix.is_some() {
+        return Ok(());
+    }
+    if let Some(query_tag) = query_tag.filter(|_| !options.leave_tag) {
+        msg.add_tag(query_tag)
+            .map_err(|e| RunError::wrap(e, msg, None, Stage::QueryTag))?;
+    }
+    Ok(())
 }
 
 /// Apply all supplied filters to the corresponding matching messages
 ///
-/// Either fails or returns how many filters were applied
+/// Only fails if the messages to process can't be determined, otherwise
+/// returns a [`RunOutcome`]. Messages whose file disappeared before they could
+/// be processed are skipped and keep their query tag, as do messages that
+/// couldn't be moved.
+///
+/// Filters are applied in order, and tags added or removed by one filter are
+/// visible to the `@tags` rules of all filters after it, which allows staged
+/// filters, e.g. one classifying messages and others routing them based on
+/// that.
+///
+/// [`RunOutcome`]: struct.RunOutcome.html
 pub fn filter(
     db: &Database,
     query_tag: &str,
     options: &FilterOptions,
     filters: &[Filter],
-) -> Result<usize> {
+) -> Result<RunOutcome> {
     let query = validate_query_tag(query_tag)?;
+    validate_shadow_tags(
+        options.shadow_prefix.as_deref(),
+        filters.iter().map(|f| (f.name(), f.operations())),
+    )?;
     let q = db.create_query(&query)?;
-    let mut matches = 0;
+    let mut outcome = RunOutcome::default();
+    let mut state = RunState::new(options);
     for msg in q.search_messages()? {
-        let mut exists = true;
-        for filter in filters {
-            let (applied, deleted) = filter.apply_if_match(&msg, db)?;
-            if applied {
-                matches += 1;
-            }
-            if deleted {
-                exists = !deleted;
-                break;
-            }
+        let res = filter_message(
+            &msg,
+            db,
+            Some(query_tag),
+            options,
+            filters,
+            &mut outcome,
+            &mut state,
+        );
+        if let Err(e) = res {
+            // everything in filter_message is wrapped already, the stage here
+            // is merely a fallback
+            outcome
+                .errors
+                .push(RunError::from_error(e, &msg, None, Stage::Match));
         }
-        if exists {
-            if !options.leave_tag {
-                msg.remove_tag(query_tag)?;
-            }
-            if options.sync_tags {
-                msg.tags_to_maildir_flags()?;
-            }
+    }
+    outcome.output = state.children.finish()?;
+    Ok(outcome)
+}
+
+/// Like [`filter`], one database after the other, adding up the outcomes
+///
+/// Which filters apply to which database can be restricted with
+/// [`Filter::databases`]. Fails as soon as the messages to process in any of
+/// the databases can't be determined.
+///
+/// [`filter`]: fn.filter.html
+/// [`Filter::databases`]: struct.Filter.html#structfield.databases
+pub fn filter_databases(
+    dbs: &[Database],
+    query_tag: &str,
+    options: &FilterOptions,
+    filters: &[Filter],
+) -> Result<RunOutcome> {
+    let mut outcome = RunOutcome::default();
+    for db in dbs {
+        outcome.merge(filter(db, query_tag, options, filters)?);
+    }
+    Ok(outcome)
+}
+
+/// Apply the supplied filters to all messages matching an arbitrary notmuch
+/// query, e.g. to retroactively apply a newly added filter
+///
+/// Unlike [`filter`], no query tag is involved, hence
+/// [`FilterOptions::leave_tag`] has no effect. After each message `progress`
+/// is called with the number of messages processed so far and the total.
+///
+/// [`filter`]: fn.filter.html
+/// [`FilterOptions::leave_tag`]: struct.FilterOptions.html#structfield.leave_tag
+pub fn backfill<F>(
+    db: &Database,
+    query: &str,
+    options: &FilterOptions,
+    filters: &[Filter],
+    mut progress: F,
+) -> Result<RunOutcome>
+where
+    F: FnMut(usize, usize),
+{
+    validate_shadow_tags(
+        options.shadow_prefix.as_deref(),
+        filters.iter().map(|f| (f.name(), f.operations())),
+    )?;
+    let q = db.create_query(query)?;
+    let total = q.count_messages()? as usize;
+    let mut outcome = RunOutcome::default();
+    let mut state = RunState::new(options);
+    for (i, msg) in q.search_messages()?.enumerate() {
+        let res = filter_message(&msg, db, None, options, filters, &mut outcome, &mut state);
+        if let Err(e) = res {
+            outcome
+                .errors
+                .push(RunError::from_error(e, &msg, None, Stage::Match));
         }
+        progress(i + 1, total);
     }
-    Ok(matches)
+    outcome.output = state.children.finish()?;
+    Ok(outcome)
 }
 
+/// Message-ID and name of the filter that matched it, see [`filter_dry`]
+///
+/// [`filter_dry`]: fn.filter_dry.html
+pub type DryMatch = (String, String);
+
 /// Returns how many matches there are as well as what Message-IDs have been
-/// matched by which filters, without running any of the operations
+/// matched by which filters (as pairs of Message-ID and filter name), without
+/// running any of the operations
+///
+/// Tag operations are tracked in memory though, so `@tags` rules match the
+/// same way they would in [`filter`]. Any [`Warning`]s are returned as well.
+///
+/// [`filter`]: fn.filter.html
+/// [`Warning`]: error/enum.Warning.html
 pub fn filter_dry(
     db: &Database,
     query_tag: &str,
     filters: &[Filter],
-) -> Result<(usize, Vec<String>)> {
+) -> Result<(usize, Vec<DryMatch>, Vec<Warning>)> {
     let query = validate_query_tag(query_tag)?;
     let q = db.create_query(&query)?;
     let mut matches = 0;
-    let mut mtchinf = Vec::<String>::new();
+    let mut mtchinf = Vec::new();
+    let mut warnings = Vec::new();
     for msg in q.search_messages()? {
-        let mut msg_matches = 0;
-        match filters
-            .iter()
-            .map(|f| {
-                let is_match = f.is_match(&msg, db)?;
-                if is_match {
-                    msg_matches += 1;
-                    mtchinf.push(format!("{}: {}", msg.id(), f.name()));
-                }
-                Ok(())
-            })
-            .collect::<Result<Vec<()>>>()
-        {
-            Ok(_) => matches += msg_matches,
-            Err(e) => return Err(e),
+        let mail = DbMail::new(&msg, db, None, true);
+        let mut mail = Tracked::new(&mail);
+        let applicable = in_phase_order(filters).filter(|f| f.applies_to(db));
+        let matched = match track_matches(&mut mail, applicable, &mut warnings) {
+            Ok(matched) => matched,
+            Err((e, name)) => {
+                skip_if_missing(Err::<(), _>(e), &msg, &mut warnings)
+                    .map_err(|e| RunError::wrap(e, &msg, Some(&name), Stage::Match))?;
+                continue;
+            }
         };
+        matches += matched.len();
+        mtchinf.extend(matched.iter().map(|f| (msg.id().to_string(), f.name())));
+    }
+    Ok((matches, mtchinf, warnings))
+}
+
+/// Evaluates filters against a message whose tags are tracked in memory,
+/// the way [`filter`] would apply them, and returns the filters that matched
+///
+/// Tags added or removed by a filter are visible to the `@tags` rules of all
+/// filters after it. Errors come with the name of the filter causing them.
+///
+/// [`filter`]: fn.filter.html
+/// The operations [`filter`] would apply to a message, filter by filter,
+/// without applying them
+///
+/// Follows the filters the way `filter_message` does: filters that reached
+/// [`Filter::max_matches`] aren't applied, a deletion or a filter stopping
+/// the evaluation (including with `first_match_only`) ends it, and tags
+/// added or removed along the way are seen by later filters.
+///
+/// [`filter`]: fn.filter.html
+/// [`Filter::max_matches`]: struct.Filter.html#structfield.max_matches
+fn planned_operations<'f, M: Mail>(
+    mail: &mut Tracked<'_, M>,
+    filters: impl Iterator<Item = &'f Filter>,
+    first_match_only: bool,
+    state: &RunState,
+    warnings: &mut Vec<Warning>,
+) -> std::result::Result<Vec<Operations>, (Error, String)> {
+    let mut ops = Vec::new();
+    for f in filters {
+        let fail = |e| (e, f.name());
+        if !f.is_mail_match(&*mail, warnings).map_err(fail)? {
+            continue;
+        }
+        if !f.is_dry() {
+            if !state.may_allow(f) {
+                f.forget_outcome();
+                continue;
+            }
+            let op = f.operations_for(&*mail, warnings).map_err(fail)?;
+            op.apply_to_tags(&mut mail.tags);
+            mail.score += f.score();
+            let deleted = op.del == Some(true);
+            ops.push(op.into_owned());
+            if deleted {
+                break;
+            }
+        }
+        let first_match = !f.is_dry() && !f.has_branches() && first_match_only;
+        if f.stops() || first_match {
+            break;
+        }
+    }
+    Ok(ops)
+}
+
+/// How many times `ops`, the operations applied to a message in order,
+/// delete, move or copy it
+///
+/// Every copy counts, a deletion ends the message's processing, and only the
+/// last move is carried out once all filters ran.
+pub(crate) fn store_changes<'o>(ops: impl Iterator<Item = &'o Operations>) -> usize {
+    let mut changes = 0;
+    let mut moved = false;
+    for op in ops {
+        changes += usize::from(op.copy_to.is_some());
+        if op.del == Some(true) {
+            return changes + 1;
+        }
+        moved |= op.move_to.is_some();
+    }
+    changes + usize::from(moved)
+}
+
+fn track_matches<'f, M: Mail>(
+    mail: &mut Tracked<'_, M>,
+    filters: impl Iterator<Item = &'f Filter>,
+    warnings: &mut Vec<Warning>,
+) -> std::result::Result<Vec<&'f Filter>, (Error, String)> {
+    let mut matched = Vec::new();
+    for f in filters {
+        let fail = |e| (e, f.name());
+        if !f.is_mail_match(&*mail, warnings).map_err(fail)? {
+            continue;
+        }
+        if !f.is_dry() {
+            f.operations_for(&*mail, warnings)
+                .map_err(fail)?
+                .apply_to_tags(&mut mail.tags);
+            mail.score += f.score();
+        }
+        matched.push(f);
+        if f.stops() {
+            break;
+        }
+    }
+    Ok(matched)
+}
+
+/// Evaluates filters against a message outside of any notmuch database
+///
+/// Filters are evaluated in order, like [`filter`] does, but their tag
+/// operations only change [`MailFile::tags`] and neither `run` nor `del` are
+/// ever executed. A filter that would delete the message or that stops (see
+/// [`Filter::stop`]) ends the evaluation though. Returns the names of all
+/// filters that matched.
+///
+/// [`filter`]: fn.filter.html
+/// [`MailFile::tags`]: struct.MailFile.html#structfield.tags
+/// [`Filter::stop`]: struct.Filter.html#structfield.stop
+pub fn simulate(
+    mail: &mut MailFile,
+    filters: &[Filter],
+    warnings: &mut Vec<Warning>,
+) -> Result<Vec<String>> {
+    let mut matched = Vec::new();
+    for filter in in_phase_order(filters) {
+        if !filter.is_mail_match(mail, warnings)? {
+            continue;
+        }
+        matched.push(filter.name());
+        if filter.is_dry() {
+            continue;
+        }
+        filter
+            .operations_for(&*mail, warnings)?
+            .apply_to_tags(&mut mail.tags);
+        mail.score += filter.score();
+        if filter.op.del == Some(true) || filter.stops() {
+            break;
+        }
+    }
+    Ok(matched)
+}
+
+/// A filter matching a message, see [`classify`]
+///
+/// [`classify`]: fn.classify.html
+#[derive(Debug, Clone)]
+pub struct FilterMatch {
+    /// Name of the filter
+    pub filter: String,
+    /// What matched in the first of its rules that did
+    pub spans: Vec<MatchSpan>,
+}
+
+/// Message-ID and all filters matching it, see [`classify`]
+///
+/// [`classify`]: fn.classify.html
+pub type Classification = (String, Vec<FilterMatch>);
+
+/// Checks which filters match the messages selected by a notmuch query,
+/// without running any of the operations
+///
+/// Unlike [`filter_dry`], messages no filter matches are included as well,
+/// and for each filter that does, what it matched is returned too (see
+/// [`Filter::match_spans`]). As with [`filter_dry`], tag operations are
+/// tracked in memory for `@tags` rules.
+///
+/// [`Filter::match_spans`]: struct.Filter.html#method.match_spans
+///
+/// [`filter_dry`]: fn.filter_dry.html
+pub fn classify(
+    db: &Database,
+    query: &str,
+    filters: &[Filter],
+) -> Result<(Vec<Classification>, Vec<Warning>)> {
+    let q = db.create_query(query)?;
+    let mut classified = Vec::new();
+    let mut warnings = Vec::new();
+    'messages: for msg in q.search_messages()? {
+        let mut matches = Vec::new();
+        let mail = DbMail::new(&msg, db, None, true);
+        let mut mail = Tracked::new(&mail);
+        for f in in_phase_order(filters).filter(|f| f.applies_to(db)) {
+            let res = f.match_spans(&mail, &mut warnings);
+            let res = skip_if_missing(res, &msg, &mut warnings)
+                .map_err(|e| RunError::wrap(e, &msg, Some(&f.name()), Stage::Match))?;
+            match res {
+                Some(Some(spans)) => {
+                    if !f.is_dry() {
+                        f.operations_for(&mail, &mut warnings)
+                            .map_err(|e| RunError::wrap(e, &msg, Some(&f.name()), Stage::Match))?
+                            .apply_to_tags(&mut mail.tags);
+                        mail.score += f.score();
+                    }
+                    matches.push(FilterMatch {
+                        filter: f.name(),
+                        spans,
+                    })
+                }
+                Some(None) => {}
+                None => continue 'messages,
+            }
+        }
+        classified.push((msg.id().to_string(), matches));
+    }
+    Ok((classified, warnings))
+}
+
+/// How a single filter fared against a message, see [`trace`]
+///
+/// [`trace`]: fn.trace.html
+#[derive(Debug, Clone)]
+pub struct FilterTrace {
+    /// Name of the filter
+    pub filter: String,
+    /// If any of its rules matched
+    pub matched: bool,
+    /// Every condition that was evaluated, in order
+    pub steps: Vec<TraceStep>,
+}
+
+/// Message-ID and how every filter fared against it, see [`trace`]
+///
+/// [`trace`]: fn.trace.html
+pub type MessageTrace = (String, Vec<FilterTrace>);
+
+/// Evaluates every filter against the messages selected by a notmuch query,
+/// recording each condition along with the values it was checked against
+///
+/// Like [`classify`], nothing is applied, and tag operations are tracked in
+/// memory for `@tags` rules.
+///
+/// [`classify`]: fn.classify.html
+pub fn trace(
+    db: &Database,
+    query: &str,
+    filters: &[Filter],
+) -> Result<(Vec<MessageTrace>, Vec<Warning>)> {
+    let q = db.create_query(query)?;
+    let mut traced = Vec::new();
+    let mut warnings = Vec::new();
+    'messages: for msg in q.search_messages()? {
+        let mut filter_traces = Vec::new();
+        let mail = DbMail::new(&msg, db, None, true);
+        let mut mail = Tracked::new(&mail);
+        for f in in_phase_order(filters).filter(|f| f.applies_to(db)) {
+            let res = f.trace(&mail, &mut warnings);
+            let res = skip_if_missing(res, &msg, &mut warnings)
+                .map_err(|e| RunError::wrap(e, &msg, Some(&f.name()), Stage::Match))?;
+            let (matched, steps) = match res {
+                Some(res) => res,
+                None => continue 'messages,
+            };
+            if matched && !f.is_dry() {
+                f.operations_for(&mail, &mut warnings)
+                    .map_err(|e| RunError::wrap(e, &msg, Some(&f.name()), Stage::Match))?
+                    .apply_to_tags(&mut mail.tags);
+                mail.score += f.score();
+            }
+            filter_traces.push(FilterTrace {
+                filter: f.name(),
+                matched,
+                steps,
+            });
+        }
+        traced.push((msg.id().to_string(), filter_traces));
+    }
+    Ok((traced, warnings))
+}
+
+/// Replaces the tag `from` with `to` on all messages that have it
+///
+/// Optionally restricted to messages matching `query`. Returns how many
+/// messages were retagged.
+pub fn retag(db: &Database, from: &str, to: &str, query: Option<&str>) -> Result<usize> {
+    validate_query_tag(to)?;
+    let mut query_str = validate_query_tag(from)?;
+    if let Some(query) = query {
+        query_str = format!("{} and ({})", query_str, query);
     }
-    Ok((matches, mtchinf))
+    let q = db.create_query(&query_str)?;
+    let mut retagged = 0;
+    for msg in q.search_messages()? {
+        msg.add_tag(to)
+            .map_err(|e| RunError::wrap(e, &msg, None, Stage::AddTags))?;
+        msg.remove_tag(from)
+            .map_err(|e| RunError::wrap(e, &msg, None, Stage::RemoveTags))?;
+        retagged += 1;
+    }
+    Ok(retagged)
 }
 
-/// Deserialize filters from bytes
+/// Deserialize filters from bytes, resolving references to definitions
+///
+/// Filters are sorted by priority, see [`sort_by_priority`], and the ones
+/// depending on others are moved behind them, see [`flatten`]. Their
+/// regular expressions on headers are combined, see [`combine_patterns`].
+///
+/// [`sort_by_priority`]: fn.sort_by_priority.html
+/// [`flatten`]: fn.flatten.html
+/// [`combine_patterns`]: fn.combine_patterns.html
 pub fn filters_from(buf: &[u8]) -> Result<Vec<Filter>> {
-    serde_json::from_slice::<Vec<Filter>>(buf)?
-        .into_iter()
-        .map(|f| f.compile())
-        .collect()
+    let (defs, filters) = defs::split(serde_json::from_slice(buf)?)?;
+    let mut filters = filters
+        .iter()
+        .map(|f| serde_json::from_value::<Filter>(defs::resolve(f, &defs)?)?.compile())
+        .collect::<Result<Vec<_>>>()?;
+    sort_by_priority(&mut filters);
+    let mut filters = flatten(filters);
+    combine_patterns(&mut filters);
+    Ok(filters)
+}
+
+/// Registers a special field for all `filters`, see [`Filter::register`]
+///
+/// [`Filter::register`]: struct.Filter.html#method.register
+pub fn register_matcher(
+    filters: &mut [Filter],
+    field: &str,
+    matcher: Rc<dyn Matcher>,
+) -> Result<()> {
+    for filter in filters {
+        filter.register(field, Rc::clone(&matcher))?;
+    }
+    Ok(())
 }
 
 /// Deserialize a filters from file
@@ -222,3 +1520,206 @@ where
     file.read_to_end(&mut buf)?;
     filters_from(&buf)
 }
+
+/// Serialize filters to a file, overwriting it
+pub fn filters_to_file<P>(filters: &[Filter], filename: &P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let mut buf = serde_json::to_vec_pretty(filters)?;
+    buf.push(b'\n');
+    let mut file = File::create(filename)?;
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    const MESSAGE: &[u8] = b"From: CI <ci@example.org>\r\n\
+        Subject: Build failed\r\n\
+        Message-ID: <build-1@example.org>\r\n\
+        \r\n\
+        The build failed.\r\n";
+
+    /// A classifier tagging the message and a filter routing on that tag
+    const STAGED: &[u8] = br#"[
+        {"name": "classify", "rules": [{"from": "ci@example\\.org"}], "op": {"add": "ci"}},
+        {"name": "route", "rules": [{"@tags": "^ci$"}], "op": {"add": "alerts", "rm": "inbox"}}
+    ]"#;
+
+    fn mail() -> MailFile {
+        let mut mail = MailFile::from_bytes(MESSAGE.to_vec());
+        mail.tags.insert("inbox".to_string());
+        mail
+    }
+
+    fn names(filters: &[&Filter]) -> Vec<String> {
+        filters.iter().map(|f| f.name()).collect()
+    }
+
+    fn tags(tags: &[&str]) -> BTreeSet<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn tags_added_by_a_filter_are_seen_by_later_ones() {
+        let filters = filters_from(STAGED).unwrap();
+        let mut mail = mail();
+        let matched = simulate(&mut mail, &filters, &mut Vec::new()).unwrap();
+        assert_eq!(matched, ["classify", "route"]);
+        assert_eq!(mail.tags, tags(&["alerts", "ci"]));
+    }
+
+    #[test]
+    fn tags_added_by_a_filter_are_seen_by_later_ones_in_dry_runs() {
+        let filters = filters_from(STAGED).unwrap();
+        let mail = mail();
+        let mut tracked = Tracked::new(&mail);
+        let matched = track_matches(&mut tracked, filters.iter(), &mut Vec::new()).unwrap();
+        assert_eq!(names(&matched), ["classify", "route"]);
+        assert_eq!(tracked.tags, tags(&["alerts", "ci"]));
+        // nothing is applied to the message itself
+        assert_eq!(mail.tags, tags(&["inbox"]));
+    }
+
+    #[test]
+    fn tags_removed_by_a_filter_are_gone_for_later_ones() {
+        let filters = filters_from(
+            br#"[
+                {"name": "archive", "rules": [{"from": "ci@"}], "op": {"rm": "inbox"}},
+                {"name": "inbox", "rules": [{"@tags": "^inbox$"}], "op": {"add": "seen-inbox"}}
+            ]"#,
+        )
+        .unwrap();
+        let mut mail = mail();
+        let matched = simulate(&mut mail, &filters, &mut Vec::new()).unwrap();
+        assert_eq!(matched, ["archive"]);
+
+        let mail = self::mail();
+        let mut tracked = Tracked::new(&mail);
+        let matched = track_matches(&mut tracked, filters.iter(), &mut Vec::new()).unwrap();
+        assert_eq!(names(&matched), ["archive"]);
+        assert!(tracked.tags.is_empty());
+    }
+
+    #[test]
+    fn tags_added_later_are_not_seen_by_earlier_filters() {
+        let mut filters = filters_from(STAGED).unwrap();
+        filters.reverse();
+        let mut mail = mail();
+        let matched = simulate(&mut mail, &filters, &mut Vec::new()).unwrap();
+        assert_eq!(matched, ["classify"]);
+        assert_eq!(mail.tags, tags(&["ci", "inbox"]));
+
+        let mail = self::mail();
+        let mut tracked = Tracked::new(&mail);
+        let matched = track_matches(&mut tracked, filters.iter(), &mut Vec::new()).unwrap();
+        assert_eq!(names(&matched), ["classify"]);
+    }
+
+    #[test]
+    fn filters_depending_on_a_capped_filter_are_not_applied() {
+        let filters = filters_from(
+            br#"[{
+                "name": "ci",
+                "rules": [{"from": "ci@"}],
+                "op": {"add": "ci"},
+                "max_matches": 1,
+                "then": [{"name": "failed", "rules": [{"subject": "failed"}], "op": {"add": "red"}}],
+                "else": [{"name": "other", "rules": [{"from": "."}], "op": {"add": "other"}}]
+            }]"#,
+        )
+        .unwrap();
+        let mail = mail();
+        let mut state = RunState::default();
+        let mut warnings = Vec::new();
+        let applied = |state: &mut RunState, warnings: &mut Vec<Warning>| {
+            let mut names = Vec::new();
+            for f in &filters {
+                if f.is_mail_match(&mail, warnings).unwrap() && state.allow(f, warnings) {
+                    names.push(f.name());
+                }
+            }
+            names
+        };
+        assert_eq!(applied(&mut state, &mut warnings), ["ci", "failed"]);
+        assert!(warnings.is_empty());
+        assert!(applied(&mut state, &mut warnings).is_empty());
+        assert_eq!(warnings.len(), 1);
+    }
+
+    fn store_changes_of(filters: &[u8], first_match_only: bool, state: &RunState) -> usize {
+        let filters = filters_from(filters).unwrap();
+        let mail = mail();
+        let mut tracked = Tracked::new(&mail);
+        let ops = planned_operations(
+            &mut tracked,
+            filters.iter(),
+            first_match_only,
+            state,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        store_changes(ops.iter())
+    }
+
+    #[test]
+    fn first_match_only_leaves_later_moves_out_of_the_budget() {
+        let filters = br#"[
+            {"name": "ci", "rules": [{"from": "ci@"}], "op": {"add": "ci"}},
+            {"name": "file", "rules": [{"subject": "Build"}], "op": {"move": "CI"}}
+        ]"#;
+        let state = RunState::default();
+        assert_eq!(store_changes_of(filters, true, &state), 0);
+        assert_eq!(store_changes_of(filters, false, &state), 1);
+    }
+
+    #[test]
+    fn only_the_last_move_counts_against_the_budget() {
+        let filters = br#"[
+            {"name": "ci", "rules": [{"from": "ci@"}], "op": {"move": "CI"}},
+            {"name": "failed", "rules": [{"subject": "failed"}], "op": {"move": "Failed"}},
+            {"name": "keep", "rules": [{"subject": "Build"}], "op": {"copy": "Archive"}}
+        ]"#;
+        assert_eq!(store_changes_of(filters, false, &RunState::default()), 2);
+    }
+
+    #[test]
+    fn nothing_after_a_deletion_counts_against_the_budget() {
+        let filters = br#"[
+            {"name": "ci", "rules": [{"from": "ci@"}], "op": {"move": "CI"}},
+            {"name": "drop", "rules": [{"subject": "failed"}], "op": {"del": true}},
+            {"name": "keep", "rules": [{"subject": "Build"}], "op": {"copy": "Archive"}}
+        ]"#;
+        assert_eq!(store_changes_of(filters, false, &RunState::default()), 1);
+    }
+
+    #[test]
+    fn capped_filters_do_not_count_against_the_budget() {
+        let filters = br#"[
+            {"name": "ci", "rules": [{"from": "ci@"}], "op": {"move": "CI"}, "max_matches": 1}
+        ]"#;
+        let mut state = RunState::default();
+        assert_eq!(store_changes_of(filters, false, &state), 1);
+        state.matches.insert("ci".to_string(), 1);
+        assert_eq!(store_changes_of(filters, false, &state), 0);
+    }
+
+    #[test]
+    fn shadow_tags_are_validated() {
+        let filters = filters_from(STAGED).unwrap();
+        let ops = || filters.iter().map(|f| (f.name(), f.operations()));
+        validate_shadow_tags(None, ops()).unwrap();
+        validate_shadow_tags(Some("shadow/"), ops()).unwrap();
+        for prefix in ["", "-shadow/", "shadow\n"] {
+            let res = validate_shadow_tags(Some(prefix), ops());
+            assert!(matches!(res, Err(InvalidTag(_))), "{prefix:?}");
+        }
+        let long = "x".repeat(TAG_MAX - 2);
+        let e = validate_shadow_tags(Some(&long), ops()).unwrap_err();
+        assert!(e.to_string().contains("in filter route"), "{e}");
+    }
+}
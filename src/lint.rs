@@ -0,0 +1,225 @@
+use std::fmt;
+
+use regex::Regex;
+
+use crate::cidr::Cidr;
+use crate::filter::NUMERIC_FIELDS;
+use crate::Filter;
+use crate::Literal;
+use crate::Value;
+
+/// Headers holding addresses, whose values include display names
+const ADDRESS_HEADERS: &[&str] = &["from", "to", "cc", "bcc", "reply-to", "sender"];
+
+/// A filter that's valid, but likely doesn't do what it's meant to, see
+/// [`lint`]
+///
+/// [`lint`]: fn.lint.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    /// Name of the filter
+    pub filter: String,
+    /// The key of the condition in question (including its `!`), empty if
+    /// it's about the filter as a whole
+    pub field: String,
+    /// What's wrong
+    pub problem: String,
+    /// How to fix it
+    pub hint: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.field.is_empty() {
+            true => write!(f, "{}: {}", self.filter, self.problem),
+            false => write!(f, "{}: {}: {}", self.filter, self.field, self.problem),
+        }
+    }
+}
+
+/// Checks if the regular expression `re` matches any value at all, like `""`
+/// or `.*` do
+fn matches_anything(re: &str) -> bool {
+    // something matching both the empty string and text it can't possibly
+    // be looking for matches the empty string anywhere
+    Regex::new(re).is_ok_and(|re| re.is_match("") && re.is_match("\u{1}notcoal\u{1}"))
+}
+
+/// Checks if `re` is nothing but a word (or a few), which in an address
+/// header also matches display names and parts of longer words
+fn is_bare_word(re: &str) -> bool {
+    let re = re.strip_prefix("(?i)").unwrap_or(re);
+    !re.is_empty()
+        && re
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ' ')
+}
+
+/// Looks for rules that are likely mistakes: patterns matching any value
+/// (like `""` or `.*`), bare words like `bank` on address headers (which also
+/// match display names and e.g. `bankruptcy`) and filters deleting messages
+/// without a name
+///
+/// Meant to be run after changing the rules, e.g. by `notcoal doctor`. None of
+/// these keep filters from working, some may even be intended.
+pub fn lint(filters: &[Filter]) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    for filter in filters {
+        let mut lint = |field: &str, problem: String, hint: String| {
+            lints.push(Lint {
+                filter: filter.name(),
+                field: field.to_string(),
+                problem,
+                hint,
+            })
+        };
+        if filter.op.del == Some(true) && !filter.is_named() {
+            lint(
+                "",
+                "deletes messages, but has no name".to_string(),
+                "Name it, so dry runs and plans tell which filter deletes what".to_string(),
+            );
+        }
+        for (key, value) in filter.rules.iter().flatten() {
+            let field = key.trim_start_matches('!').to_lowercase();
+            let negated = key.starts_with('!');
+            if field == "@date" || field == "@query" || NUMERIC_FIELDS.contains(&field.as_str()) {
+                continue;
+            }
+            let res = match value {
+                Value::Single(re) => vec![re.as_str()],
+                Value::Multiple(res) => res.iter().map(|re| re.as_str()).collect(),
+                Value::Literal(Literal::Contains(s)) if s.is_empty() => vec![""],
+                Value::Literal(Literal::Glob(g))
+                    if !g.is_empty() && g.chars().all(|c| c == '*') =>
+                {
+                    vec![""]
+                }
+                _ => continue,
+            };
+            for re in res {
+                if field == "@received-ip" && Cidr::parse(re).is_some() {
+                    continue;
+                }
+                if matches_anything(re) {
+                    let hint = match negated {
+                        true => "The condition never holds, drop the rule or the !",
+                        false => "Use a more specific pattern, or true for any value of a header",
+                    };
+                    lint(key, format!("{re:?} matches any value"), hint.to_string());
+                } else if ADDRESS_HEADERS.contains(&field.as_str()) && is_bare_word(re) {
+                    let word = re.trim_start_matches("(?i)").to_lowercase();
+                    lint(
+                        key,
+                        format!("{re:?} also matches display names and parts of other words"),
+                        format!(
+                            "Anchor it to an address or domain like \"@{word}\\\\.example$\", \
+                             or match @{field}-addr instead"
+                        ),
+                    );
+                }
+            }
+        }
+    }
+    lints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters_from;
+
+    fn lints(rules: &str) -> Vec<(String, String)> {
+        let json = format!(
+            r#"[{{"name": "f", "rules": [{}], "op": {{"add": "x"}}}}]"#,
+            rules
+        );
+        let filters = filters_from(json.as_bytes()).unwrap();
+        lint(&filters)
+            .into_iter()
+            .map(|l| (l.field, l.problem))
+            .collect()
+    }
+
+    #[test]
+    fn patterns_matching_anything() {
+        for re in ["", ".*", "(?i).*", "^", "x*", "a|"] {
+            assert!(matches_anything(re), "{re:?}");
+        }
+        for re in ["a", "^$", ".+", "^.*x", "(", "[a-"] {
+            assert!(!matches_anything(re), "{re:?}");
+        }
+    }
+
+    #[test]
+    fn bare_words() {
+        for re in [
+            "bank", "(?i)Bank", "my-bank", "big_bank", "the bank", "bänk",
+        ] {
+            assert!(is_bare_word(re), "{re:?}");
+        }
+        for re in ["", "(?i)", "bank\\.example", "@bank", "^bank", "bank$"] {
+            assert!(!is_bare_word(re), "{re:?}");
+        }
+    }
+
+    #[test]
+    fn any_value_is_reported_negated_or_not() {
+        assert_eq!(
+            lints(r#"{"subject": ".*"}"#),
+            [(
+                "subject".to_string(),
+                r#"".*" matches any value"#.to_string()
+            )]
+        );
+        assert_eq!(
+            lints(r#"{"!Subject": ["x", ""]}"#),
+            [(
+                "!Subject".to_string(),
+                r#""" matches any value"#.to_string()
+            )]
+        );
+        assert_eq!(lints(r#"{"subject": {"contains": ""}}"#).len(), 1);
+        assert_eq!(lints(r#"{"subject": {"glob": "**"}}"#).len(), 1);
+        assert!(lints(r#"{"subject": {"glob": "*x"}}"#).is_empty());
+
+        let negated = lint(&filters_from(br#"[{"rules": [{"!to": ""}], "op": {}}]"#).unwrap());
+        assert!(
+            negated[0].hint.contains("never holds"),
+            "{}",
+            negated[0].hint
+        );
+    }
+
+    #[test]
+    fn bare_words_are_only_reported_on_address_headers() {
+        let found = lints(r#"{"from": "bank", "subject": "bank"}"#);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, "from");
+        assert!(lints(r#"{"from": "@bank\\.example$"}"#).is_empty());
+        assert_eq!(lints(r#"{"!Reply-To": "(?i)bank"}"#).len(), 1);
+    }
+
+    #[test]
+    fn received_ip_ranges_are_not_patterns() {
+        assert!(Cidr::parse("0.0.0.0/0").is_some());
+        assert!(lints(r#"{"@received-ip": "0.0.0.0/0"}"#).is_empty());
+        assert!(lints(r#"{"@received-ip": "::/0"}"#).is_empty());
+        assert_eq!(lints(r#"{"@received-ip": ".*"}"#).len(), 1);
+    }
+
+    #[test]
+    fn unnamed_deletions_are_reported() {
+        let filters = filters_from(
+            br#"[
+                {"rules": [{"from": "spam@"}], "op": {"del": true}},
+                {"name": "spam", "rules": [{"from": "spam@"}], "op": {"del": true}}
+            ]"#,
+        )
+        .unwrap();
+        let found = lint(&filters);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].field.is_empty());
+        assert_eq!(found[0].problem, "deletes messages, but has no name");
+    }
+}
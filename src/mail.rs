@@ -0,0 +1,643 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use mailparse::{dateparse, parse_headers, MailHeaderMap};
+use memmap2::Mmap;
+
+use crate::error::*;
+
+use notmuch::{ConfigKey, Database, Message, Messages};
+
+/// Everything rules can match on
+///
+/// Implemented for messages in a notmuch database as well as for
+/// [`MailFile`]s read straight from disk, which allows evaluating filters
+/// without a database.
+///
+/// [`MailFile`]: struct.MailFile.html
+pub trait Mail {
+    /// Message-ID, without angle brackets
+    fn id(&self) -> String;
+    /// Decoded value of the first header called `name`, if any
+    fn header(&self, name: &str) -> Result<Option<String>>;
+    /// Date of the message as Unix timestamp, if it has a valid one
+    fn date(&self) -> Option<i64>;
+    /// Files the message is stored in
+    fn filenames(&self) -> Vec<PathBuf>;
+    /// Maildir folders the message's files are in, relative to the root of
+    /// the database, see [`folder`]
+    ///
+    /// [`folder`]: fn.folder.html
+    fn folders(&self) -> Vec<String>;
+    /// Tags the message currently has
+    ///
+    /// Must reflect changes made by filters applied earlier in the same run,
+    /// so `@tags` rules can build on each other.
+    fn tags(&self) -> Vec<String>;
+    /// Tags present anywhere in the message's thread
+    fn thread_tags(&self) -> Result<Vec<String>>;
+    /// How many messages the message's thread has, including itself
+    fn thread_size(&self) -> Result<usize>;
+    /// How many levels of replies the message's thread has, 1 for a thread
+    /// without any replies
+    fn thread_depth(&self) -> Result<usize>;
+    /// Sum of the [`Filter::score`] of the filters that matched the message
+    /// so far
+    ///
+    /// Like [`Mail::tags`], must reflect filters applied earlier in the same
+    /// run, so `@score` rules can act on it.
+    ///
+    /// [`Filter::score`]: struct.Filter.html#structfield.score
+    /// [`Mail::tags`]: trait.Mail.html#tymethod.tags
+    fn score(&self) -> f64 {
+        0.0
+    }
+    /// Checks if the message matches the notmuch query `query`
+    fn matches_query(&self, query: &str) -> Result<bool>;
+    /// The message as it's stored on disk
+    fn raw(&self) -> Result<RawMessage<'_>>;
+}
+
+/// The contents of a message, see [`Mail::raw`]
+///
+/// [`Mail::raw`]: trait.Mail.html#tymethod.raw
+#[derive(Debug)]
+pub enum RawMessage<'a> {
+    /// Contents that were in memory already
+    Borrowed(&'a [u8]),
+    /// Contents read (and possibly decompressed) from disk
+    Owned(Vec<u8>),
+    /// A memory-mapped file, of which only the given number of bytes are used
+    Mapped(Mmap, usize),
+}
+
+impl Deref for RawMessage<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            RawMessage::Borrowed(buf) => buf,
+            RawMessage::Owned(buf) => buf,
+            RawMessage::Mapped(map, len) => &map[..*len],
+        }
+    }
+}
+
+/// A message in a notmuch database
+///
+/// Meant to live for as long as a message is being processed, as header
+/// lookups and the tags of the rest of the thread are cached to save calls
+/// into libnotmuch (and thread queries) for every filter.
+pub(crate) struct DbMail<'a> {
+    msg: &'a Message,
+    db: &'a Database,
+    /// See [`FilterOptions::body_limit`]
+    ///
+    /// [`FilterOptions::body_limit`]: struct.FilterOptions.html#structfield.body_limit
+    limit: Option<usize>,
+    /// See [`FilterOptions::mmap`]
+    ///
+    /// [`FilterOptions::mmap`]: struct.FilterOptions.html#structfield.mmap
+    mmap: bool,
+    headers: RefCell<HashMap<String, Option<String>>>,
+    thread: RefCell<Option<ThreadInfo>>,
+    /// See [`Mail::score`]
+    ///
+    /// [`Mail::score`]: trait.Mail.html#method.score
+    pub score: f64,
+}
+
+/// What's looked up about a message's thread, see [`DbMail`]
+///
+/// [`DbMail`]: struct.DbMail.html
+#[derive(Debug, Clone, Default)]
+struct ThreadInfo {
+    /// Tags of all other messages in the thread
+    tags: BTreeSet<String>,
+    size: usize,
+    depth: usize,
+}
+
+impl<'a> DbMail<'a> {
+    pub fn new(msg: &'a Message, db: &'a Database, limit: Option<usize>, mmap: bool) -> Self {
+        DbMail {
+            msg,
+            db,
+            limit,
+            mmap,
+            headers: RefCell::new(HashMap::new()),
+            thread: RefCell::new(None),
+            score: 0.0,
+        }
+    }
+
+    /// Queries the message's thread, once
+    fn thread(&self) -> Result<ThreadInfo> {
+        fn depth(msgs: Messages) -> usize {
+            msgs.map(|m| 1 + depth(m.replies())).max().unwrap_or(0)
+        }
+        if let Some(info) = self.thread.borrow().as_ref() {
+            return Ok(info.clone());
+        }
+        // creating a new query as we don't have information about our own
+        // thread yet
+        let q = self
+            .db
+            .create_query(&format!("thread:{}", self.msg.thread_id()))?;
+        let mut threads = q.search_threads()?;
+        let id = self.msg.id();
+        let info = match threads.next() {
+            Some(thread) => ThreadInfo {
+                tags: thread
+                    .messages()
+                    .filter(|m| m.id() != id)
+                    .flat_map(|m| m.tags())
+                    .collect(),
+                size: thread.total_messages().max(1) as usize,
+                depth: depth(thread.toplevel_messages()).max(1),
+            },
+            None => ThreadInfo {
+                size: 1,
+                depth: 1,
+                ..Default::default()
+            },
+        };
+        *self.thread.borrow_mut() = Some(info.clone());
+        Ok(info)
+    }
+}
+
+impl Mail for DbMail<'_> {
+    fn id(&self) -> String {
+        self.msg.id().to_string()
+    }
+
+    fn header(&self, name: &str) -> Result<Option<String>> {
+        let name = name.to_lowercase();
+        if let Some(value) = self.headers.borrow().get(&name) {
+            return Ok(value.clone());
+        }
+        let value = self.msg.header(&name)?.map(|h| h.to_string());
+        self.headers.borrow_mut().insert(name, value.clone());
+        Ok(value)
+    }
+
+    fn date(&self) -> Option<i64> {
+        // notmuch parses the Date header while indexing already
+        Some(self.msg.date())
+    }
+
+    fn filenames(&self) -> Vec<PathBuf> {
+        self.msg.filenames().collect()
+    }
+
+    fn folders(&self) -> Vec<String> {
+        // the mail root and the database may be in different places
+        let root = match self.db.config(ConfigKey::MailRoot) {
+            Some(root) => PathBuf::from(root),
+            None => self.db.path().to_path_buf(),
+        };
+        self.msg
+            .filenames()
+            .filter_map(|f| folder(&root, &f))
+            .collect()
+    }
+
+    fn tags(&self) -> Vec<String> {
+        // never cached, as filters change tags while the message is processed
+        self.msg.tags().collect()
+    }
+
+    fn thread_tags(&self) -> Result<Vec<String>> {
+        // our own tags may have been changed by previous filters, so they're
+        // never cached
+        let mut tags = self.thread()?.tags;
+        tags.extend(self.msg.tags());
+        Ok(tags.into_iter().collect())
+    }
+
+    fn thread_size(&self) -> Result<usize> {
+        Ok(self.thread()?.size)
+    }
+
+    fn thread_depth(&self) -> Result<usize> {
+        Ok(self.thread()?.depth)
+    }
+
+    fn score(&self) -> f64 {
+        self.score
+    }
+
+    fn matches_query(&self, query: &str) -> Result<bool> {
+        let id = self.msg.id().replace('"', "\"\"");
+        let q = self
+            .db
+            .create_query(&format!("id:\"{id}\" and ({query})"))?;
+        Ok(q.count_messages()? > 0)
+    }
+
+    fn raw(&self) -> Result<RawMessage<'_>> {
+        // XXX-file notmuch says it returns a random filename if multiple are
+        // present. Question is if the new tag is even applied to messages
+        // we've already seen, do we ever run into that being a problem at all?
+        let path = self.msg.filename();
+        if self.mmap {
+            map_file(&path, self.limit)
+        } else {
+            Ok(RawMessage::Owned(read_file(&path, self.limit)?))
+        }
+    }
+}
+
+/// A message whose tags are tracked in memory, so filters can be evaluated the
+/// way [`filter`] would without applying any operations
+///
+/// [`filter`]: fn.filter.html
+pub(crate) struct Tracked<'a, M> {
+    mail: &'a M,
+    pub tags: BTreeSet<String>,
+    pub score: f64,
+}
+
+impl<'a, M: Mail> Tracked<'a, M> {
+    pub fn new(mail: &'a M) -> Self {
+        Tracked {
+            mail,
+            tags: mail.tags().into_iter().collect(),
+            score: mail.score(),
+        }
+    }
+}
+
+impl<M: Mail> Mail for Tracked<'_, M> {
+    fn id(&self) -> String {
+        self.mail.id()
+    }
+
+    fn header(&self, name: &str) -> Result<Option<String>> {
+        self.mail.header(name)
+    }
+
+    fn date(&self) -> Option<i64> {
+        self.mail.date()
+    }
+
+    fn filenames(&self) -> Vec<PathBuf> {
+        self.mail.filenames()
+    }
+
+    fn folders(&self) -> Vec<String> {
+        self.mail.folders()
+    }
+
+    fn tags(&self) -> Vec<String> {
+        self.tags.iter().cloned().collect()
+    }
+
+    fn thread_tags(&self) -> Result<Vec<String>> {
+        self.mail.thread_tags()
+    }
+
+    fn thread_size(&self) -> Result<usize> {
+        self.mail.thread_size()
+    }
+
+    fn thread_depth(&self) -> Result<usize> {
+        self.mail.thread_depth()
+    }
+
+    fn score(&self) -> f64 {
+        self.score
+    }
+
+    fn matches_query(&self, query: &str) -> Result<bool> {
+        self.mail.matches_query(query)
+    }
+
+    fn raw(&self) -> Result<RawMessage<'_>> {
+        self.mail.raw()
+    }
+}
+
+/// Maildir folder of the message file `file`, relative to `root`
+///
+/// Levels are separated by `/` (e.g. `Work/Projects`), the `cur` or `new`
+/// directory the file is in isn't part of it, and a maildir at `root` itself
+/// is the empty string. Files outside of `root` don't have a folder.
+pub fn folder(root: &Path, file: &Path) -> Option<String> {
+    let mut dir = file.parent()?;
+    if dir.ends_with("cur") || dir.ends_with("new") {
+        dir = dir.parent()?;
+    }
+    let levels: Option<Vec<&str>> = dir
+        .strip_prefix(root)
+        .ok()?
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect();
+    Some(levels?.join("/"))
+}
+
+/// Reads (at most `limit` bytes of) a message file, transparently
+/// decompressing it if it's gzipped, which notmuch supports as well
+fn read_file(path: &Path, limit: Option<usize>) -> Result<Vec<u8>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let gzipped = file.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    let reader: Box<dyn Read> = if gzipped {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let mut buf = Vec::new();
+    reader
+        .take(limit.map_or(u64::MAX, |l| l as u64))
+        .read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Memory-maps a message file, avoiding copying it, unless it's empty or
+/// needs to be decompressed
+fn map_file(path: &Path, limit: Option<usize>) -> Result<RawMessage<'static>> {
+    let file = File::open(path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(RawMessage::Owned(Vec::new()));
+    }
+    // SAFETY: the mapping is only read while matching, and message files are
+    // only ever replaced (by renaming), not modified in place. Should that
+    // happen anyway, matching may see inconsistent contents, and if the file
+    // is truncated while mapped, reading past its new end kills the process
+    // with SIGBUS.
+    let map = unsafe { Mmap::map(&file)? };
+    if map.starts_with(&[0x1f, 0x8b]) {
+        return Ok(RawMessage::Owned(read_file(path, limit)?));
+    }
+    let len = limit.map_or(map.len(), |l| l.min(map.len()));
+    Ok(RawMessage::Mapped(map, len))
+}
+
+/// A message outside of any notmuch database, e.g. a file in a maildir
+///
+/// Since there's no database, tags are kept in [`MailFile::tags`] and there
+/// are no threads and no database root: `@thread-tags` and `@folder` never
+/// match, and every message is a thread of its own for `@thread-size` and
+/// `@thread-depth`. Rules using `@query` fail.
+///
+/// [`MailFile::tags`]: struct.MailFile.html#structfield.tags
+#[derive(Debug, Clone, Default)]
+pub struct MailFile {
+    /// Where the message was read from, if anywhere
+    pub path: Option<PathBuf>,
+    /// Position within the mbox at [`MailFile::path`], counting from 1
+    ///
+    /// [`MailFile::path`]: struct.MailFile.html#structfield.path
+    pub mbox_index: Option<usize>,
+    /// Tags the message has, see [`Operations::apply_to_tags`]
+    ///
+    /// [`Operations::apply_to_tags`]: struct.Operations.html#method.apply_to_tags
+    pub tags: BTreeSet<String>,
+    /// Score the message has, see [`Mail::score`]
+    ///
+    /// [`Mail::score`]: trait.Mail.html#method.score
+    pub score: f64,
+    raw: Vec<u8>,
+}
+
+impl MailFile {
+    /// Reads a message from a file
+    pub fn open<P>(path: &P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(MailFile {
+            path: Some(path.as_ref().to_path_buf()),
+            raw: read_file(path.as_ref(), None)?,
+            ..Default::default()
+        })
+    }
+
+    /// Reads all messages from an mbox file
+    ///
+    /// Messages are split on `From ` lines at the start of the file or
+    /// following an empty line. Quoted `>From ` lines are unquoted as in the
+    /// mboxrd format.
+    pub fn open_mbox<P>(path: &P) -> Result<Vec<Self>>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let buf = read_file(path, None)?;
+        let mails = split_mbox(&buf)
+            .into_iter()
+            .enumerate()
+            .map(|(i, raw)| MailFile {
+                path: Some(path.to_path_buf()),
+                mbox_index: Some(i + 1),
+                raw,
+                ..Default::default()
+            })
+            .collect();
+        Ok(mails)
+    }
+
+    /// Checks if a file looks like an mbox, i.e. starts with a `From ` line
+    pub fn is_mbox<P>(path: &P) -> Result<bool>
+    where
+        P: AsRef<Path>,
+    {
+        let mut start = [0; 5];
+        let mut file = File::open(path)?;
+        match file.read_exact(&mut start) {
+            Ok(()) => Ok(&start == b"From "),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// A message that isn't stored anywhere
+    pub fn from_bytes(raw: Vec<u8>) -> Self {
+        MailFile {
+            raw,
+            ..Default::default()
+        }
+    }
+
+    /// Where the message came from, for showing it to users
+    pub fn describe(&self) -> String {
+        match (&self.path, self.mbox_index) {
+            (Some(path), Some(i)) => format!("{}#{}", path.display(), i),
+            (Some(path), None) => path.display().to_string(),
+            (None, _) => self.id(),
+        }
+    }
+}
+
+/// Splits an mbox into its messages, see [`MailFile::open_mbox`]
+///
+/// [`MailFile::open_mbox`]: struct.MailFile.html#method.open_mbox
+fn split_mbox(buf: &[u8]) -> Vec<Vec<u8>> {
+    let mut mails = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    let mut after_empty = true;
+    for line in buf.split_inclusive(|b| *b == b'\n') {
+        if after_empty && line.starts_with(b"From ") {
+            if let Some(mut mail) = current.take() {
+                // the empty line before a separator belongs to the separator
+                if mail.ends_with(b"\r\n\r\n") {
+                    mail.truncate(mail.len() - 2);
+                } else if mail.ends_with(b"\n\n") {
+                    mail.pop();
+                }
+                mails.push(mail);
+            }
+            current = Some(Vec::new());
+            after_empty = false;
+            continue;
+        }
+        after_empty = line == b"\n" || line == b"\r\n";
+        if let Some(mail) = &mut current {
+            let quoted = line.iter().position(|b| *b != b'>').unwrap_or(0);
+            if quoted > 0 && line[quoted..].starts_with(b"From ") {
+                mail.extend_from_slice(&line[1..]);
+            } else {
+                mail.extend_from_slice(line);
+            }
+        }
+    }
+    mails.extend(current);
+    mails
+}
+
+impl Mail for MailFile {
+    fn id(&self) -> String {
+        match self.header("message-id") {
+            Ok(Some(id)) => id
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn header(&self, name: &str) -> Result<Option<String>> {
+        let (headers, _) = parse_headers(&self.raw)?;
+        Ok(headers.get_first_value(name))
+    }
+
+    fn date(&self) -> Option<i64> {
+        let date = self.header("date").ok()??;
+        dateparse(&date).ok()
+    }
+
+    fn filenames(&self) -> Vec<PathBuf> {
+        self.path.iter().cloned().collect()
+    }
+
+    fn folders(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn tags(&self) -> Vec<String> {
+        self.tags.iter().cloned().collect()
+    }
+
+    fn thread_tags(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn thread_size(&self) -> Result<usize> {
+        Ok(1)
+    }
+
+    fn thread_depth(&self) -> Result<usize> {
+        Ok(1)
+    }
+
+    fn score(&self) -> f64 {
+        self.score
+    }
+
+    fn matches_query(&self, _query: &str) -> Result<bool> {
+        let e = "@query needs a notmuch database".to_string();
+        Err(Error::UnsupportedQuery(e))
+    }
+
+    fn raw(&self) -> Result<RawMessage<'_>> {
+        Ok(RawMessage::Borrowed(&self.raw))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(buf: &[u8]) -> Vec<String> {
+        split_mbox(buf)
+            .into_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn mboxes_are_split_on_from_lines_after_empty_ones() {
+        let mbox = b"From a@example.org Mon Jan  1 00:00:00 2024\n\
+            Subject: one\n\
+            \n\
+            Not a separator: From here on.\n\
+            \n\
+            From b@example.org Mon Jan  1 00:00:00 2024\n\
+            Subject: two\n\
+            \n\
+            Body\n";
+        assert_eq!(
+            split(mbox),
+            [
+                "Subject: one\n\nNot a separator: From here on.\n",
+                "Subject: two\n\nBody\n"
+            ]
+        );
+        assert!(split(b"").is_empty());
+        assert!(split(b"Subject: no separator\n\nBody\n").is_empty());
+    }
+
+    #[test]
+    fn quoted_from_lines_are_unquoted() {
+        let mbox = b"From a@example.org Mon Jan  1 00:00:00 2024\n\
+            Subject: one\n\
+            \n\
+            >From the start\n\
+            \n\
+            >>From twice\n\
+            > From a reply\n\
+            >Fromage\n";
+        assert_eq!(
+            split(mbox),
+            ["Subject: one\n\nFrom the start\n\n>From twice\n> From a reply\n>Fromage\n"]
+        );
+    }
+
+    #[test]
+    fn crlf_mboxes_are_split_the_same_way() {
+        let mbox = b"From a@example.org Mon Jan  1 00:00:00 2024\r\n\
+            Subject: one\r\n\
+            \r\n\
+            >From the start\r\n\
+            \r\n\
+            From b@example.org Mon Jan  1 00:00:00 2024\r\n\
+            Subject: two\r\n\
+            \r\n\
+            Body\r\n";
+        assert_eq!(
+            split(mbox),
+            [
+                "Subject: one\r\n\r\nFrom the start\r\n",
+                "Subject: two\r\n\r\nBody\r\n"
+            ]
+        );
+    }
+}
@@ -0,0 +1,273 @@
+/*!
+Working with maildir stores directly, for operations that move message files
+
+Stores name their folders in one of two ways. Most tools simply use
+directories, so the folder `Lists/Rust` is `Lists/Rust/{cur,new,tmp}` below
+the root of the store. Dovecot and Courier use Maildir++ instead, where the
+root is the inbox itself and every other folder is a dot-separated directory
+right next to it, e.g. `.Lists.Rust/{cur,new,tmp}`. [`Layout`] resolves folder
+names for both, either as configured or detected from the store.
+
+Moving a message file has to keep its flags (the `:2,` suffix of the file
+name, e.g. `:2,FS` for flagged and seen) consistent with its notmuch tags,
+and put it into `cur` unless it's a new message without any flags, see
+[`destination`].
+
+Files are never written in place: like a delivery, new contents go into
+`tmp` first, are synced to disk and then renamed into place, so tools
+syncing the store at the same time (e.g. mbsync) never see partial files,
+see [`write_file`] and [`move_file`].
+
+[`Layout`]: enum.Layout.html
+[`destination`]: fn.destination.html
+[`write_file`]: fn.write_file.html
+[`move_file`]: fn.move_file.html
+*/
+
+use std::fs::{self, create_dir_all, read_dir, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error::*;
+use crate::error::Result;
+
+/// How a store names its folders
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// Folders are nested directories
+    Directories,
+    /// Folders are `.`-prefixed and `.`-separated, next to the inbox
+    #[serde(rename = "maildir++")]
+    MaildirPlusPlus,
+}
+
+impl Layout {
+    /// Guesses the layout of the store at `root`
+    ///
+    /// A store is considered Maildir++ if its root is a maildir itself and
+    /// has at least one `.`-prefixed folder next to it, otherwise it's
+    /// assumed to use directories, which is also what's used for empty stores.
+    pub fn detect(root: &Path) -> Result<Self> {
+        if !is_maildir(root) {
+            return Ok(Layout::Directories);
+        }
+        for entry in read_dir(root)? {
+            let path = entry?.path();
+            let dotted = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with('.') && n.len() > 1 && n != "..");
+            if dotted && is_maildir(&path) {
+                return Ok(Layout::MaildirPlusPlus);
+            }
+        }
+        Ok(Layout::Directories)
+    }
+
+    /// Path of the folder `name` in the store at `root`
+    ///
+    /// Folder names use `/` to separate levels no matter the layout, e.g.
+    /// `Lists/Rust`. An empty name or `INBOX` is the root of a Maildir++
+    /// store. Names that would escape the store are rejected.
+    pub fn folder(&self, root: &Path, name: &str) -> Result<PathBuf> {
+        let levels: Vec<&str> = name.split('/').filter(|l| !l.is_empty()).collect();
+        let invalid = |l: &&str| *l == "." || *l == ".." || l.contains('\0');
+        if levels.iter().any(invalid) {
+            return Err(UnsupportedValue(format!("Invalid folder {:?}", name)));
+        }
+        match self {
+            Layout::Directories => Ok(levels.iter().fold(root.to_path_buf(), |p, l| p.join(l))),
+            Layout::MaildirPlusPlus => match levels.as_slice() {
+                [] | ["INBOX"] => Ok(root.to_path_buf()),
+                levels if levels.iter().any(|l| l.contains('.')) => {
+                    let e = format!("Maildir++ folder {:?} can't contain dots", name);
+                    Err(UnsupportedValue(e))
+                }
+                levels => Ok(root.join(format!(".{}", levels.join(".")))),
+            },
+        }
+    }
+
+    /// Like [`Layout::folder`], additionally creating the folder (including
+    /// `cur`, `new` and `tmp`) if it doesn't exist yet
+    ///
+    /// Maildir++ folders also get the empty `maildirfolder` file Courier and
+    /// Dovecot expect.
+    ///
+    /// [`Layout::folder`]: enum.Layout.html#method.folder
+    pub fn create_folder(&self, root: &Path, name: &str) -> Result<PathBuf> {
+        let path = self.folder(root, name)?;
+        for sub in ["cur", "new", "tmp"] {
+            create_dir_all(path.join(sub))?;
+        }
+        if *self == Layout::MaildirPlusPlus && path != root {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path.join("maildirfolder"))?;
+        }
+        Ok(path)
+    }
+}
+
+/// Checks if `path` is a maildir, i.e. has `cur`, `new` and `tmp`
+pub fn is_maildir(path: &Path) -> bool {
+    ["cur", "new", "tmp"]
+        .iter()
+        .all(|sub| path.join(sub).is_dir())
+}
+
+/// Maildir flags notmuch keeps in sync with tags, see notmuch-config(1)
+const SYNCED_FLAGS: &[(char, &str)] = &[
+    ('D', "draft"),
+    ('F', "flagged"),
+    ('P', "passed"),
+    ('R', "replied"),
+];
+
+/// The flags of a message file, i.e. what follows `:2,` in its name
+pub fn flags(file: &Path) -> Option<&str> {
+    let name = file.file_name()?.to_str()?;
+    name.rsplit_once(":2,").map(|(_, flags)| flags)
+}
+
+/// Maildir flags matching a message's notmuch tags, keeping any flags in
+/// `existing` notmuch doesn't know about
+///
+/// Like notmuch's own synchronization, `S` (seen) is set for messages that
+/// aren't `unread`. Flags are returned in ASCII order, as the maildir
+/// specification asks for.
+pub fn flags_from_tags(tags: &[String], existing: &str) -> String {
+    let has = |tag: &str| tags.iter().any(|t| t == tag);
+    let mut flags: Vec<char> = existing
+        .chars()
+        .filter(|c| *c != 'S' && SYNCED_FLAGS.iter().all(|(f, _)| f != c))
+        .collect();
+    flags.extend(SYNCED_FLAGS.iter().filter(|(_, t)| has(t)).map(|(f, _)| *f));
+    if !has("unread") {
+        flags.push('S');
+    }
+    flags.sort_unstable();
+    flags.dedup();
+    flags.into_iter().collect()
+}
+
+/// Where the message `file` ends up when it's moved into the maildir
+/// `folder`
+///
+/// With `tags`, the flags are updated to match them (see
+/// [`flags_from_tags`]), otherwise they're kept as they are. Messages in
+/// `new` stay new as long as they don't get any flags, everything else goes
+/// into `cur`, with the flags appended to the file name.
+///
+/// [`flags_from_tags`]: fn.flags_from_tags.html
+pub fn destination(file: &Path, folder: &Path, tags: Option<&[String]>) -> Result<PathBuf> {
+    let invalid = || UnsupportedValue(format!("Not a maildir file {:?}", file));
+    let name = file
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(invalid)?;
+    let (base, existing) = match name.rsplit_once(":2,") {
+        Some((base, flags)) => (base, Some(flags)),
+        None => (name, None),
+    };
+    let is_new = file
+        .parent()
+        .and_then(|p| p.file_name())
+        .is_some_and(|p| p == "new");
+    let flags = match tags {
+        Some(tags) => Some(flags_from_tags(tags, existing.unwrap_or_default())),
+        None => existing.map(|f| f.to_string()),
+    };
+    Ok(match flags {
+        Some(flags) if !(is_new && flags.is_empty()) => {
+            folder.join("cur").join(format!("{base}:2,{flags}"))
+        }
+        _ if is_new => folder.join("new").join(base),
+        _ => folder.join("cur").join(format!("{base}:2,")),
+    })
+}
+
+/// A file name no other delivery uses, following the maildir specification
+pub fn unique_name() -> String {
+    static DELIVERIES: AtomicUsize = AtomicUsize::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let host = fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "localhost".to_string());
+    // the specification asks for these to be encoded
+    let host = host.replace('/', "\\057").replace(':', "\\072");
+    format!(
+        "{}.M{}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        process::id(),
+        DELIVERIES.fetch_add(1, Ordering::Relaxed),
+        host
+    )
+}
+
+/// The `tmp` directory of the maildir `file` is (or will be) in
+fn tmp_dir(file: &Path) -> Result<PathBuf> {
+    match file.parent().and_then(|p| p.parent()) {
+        Some(folder) => Ok(folder.join("tmp")),
+        None => Err(UnsupportedValue(format!("Not a maildir file {:?}", file))),
+    }
+}
+
+/// Writes `contents` to `file`, a file in the `cur` or `new` directory of a
+/// maildir, replacing it if it exists
+///
+/// The contents are written to the maildir's `tmp` directory and synced to
+/// disk first, and only then renamed to `file`, so it's complete whenever
+/// it's visible.
+pub fn write_file(file: &Path, contents: &[u8]) -> Result<()> {
+    replace_file(&tmp_dir(file)?.join(unique_name()), file, contents)
+}
+
+/// Writes `contents` to `tmp`, syncs it to disk and renames it to `file`,
+/// removing `tmp` again if anything fails
+///
+/// `tmp` has to be on the same filesystem as `file` for the rename to
+/// replace it at once.
+pub(crate) fn replace_file(tmp: &Path, file: &Path, contents: &[u8]) -> Result<()> {
+    let res = File::create(tmp).and_then(|mut f| {
+        f.write_all(contents)?;
+        f.sync_all()
+    });
+    if let Err(e) = res.and_then(|_| fs::rename(tmp, file)) {
+        let _ = fs::remove_file(tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Moves the message file `from` to `to`, e.g. one determined by
+/// [`destination`]
+///
+/// Within a filesystem that's a simple rename. Otherwise the file is copied
+/// with [`write_file`] and removed afterwards, so there's never a moment
+/// where it's missing or partial in either place.
+///
+/// [`destination`]: fn.destination.html
+/// [`write_file`]: fn.write_file.html
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            write_file(to, &fs::read(from)?)?;
+            fs::remove_file(from)?;
+            Ok(())
+        }
+        res => Ok(res?),
+    }
+}
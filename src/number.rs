@@ -0,0 +1,58 @@
+use crate::error::Error::*;
+use crate::error::Result;
+
+/// A condition on a number, as used by rules on counts like
+/// `@attachment-count`
+///
+/// Supported are comparisons (`>0`, `>=3`, `<10`, `<=2`), exact values (`3`
+/// or `=3`) and ranges where either end may be left out (`2..5`, both ends
+/// included).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum NumberSpec {
+    /// Numbers from and until, both included
+    Range(Option<f64>, Option<f64>),
+    GreaterThan(f64),
+    LessThan(f64),
+}
+
+impl NumberSpec {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        let invalid = || UnsupportedValue(format!("Invalid comparison {:?}", spec));
+        let number = |s: &str| s.trim().parse::<f64>().map_err(|_| invalid());
+        if let Some((from, to)) = spec.split_once("..") {
+            let from = match from.trim() {
+                "" => None,
+                from => Some(number(from)?),
+            };
+            let to = match to.trim() {
+                "" => None,
+                to => Some(number(to)?),
+            };
+            return Ok(NumberSpec::Range(from, to));
+        }
+        if let Some(rest) = spec.strip_prefix(">=") {
+            return Ok(NumberSpec::Range(Some(number(rest)?), None));
+        }
+        if let Some(rest) = spec.strip_prefix("<=") {
+            return Ok(NumberSpec::Range(None, Some(number(rest)?)));
+        }
+        if let Some(rest) = spec.strip_prefix('>') {
+            return Ok(NumberSpec::GreaterThan(number(rest)?));
+        }
+        if let Some(rest) = spec.strip_prefix('<') {
+            return Ok(NumberSpec::LessThan(number(rest)?));
+        }
+        let n = number(spec.strip_prefix('=').unwrap_or(spec))?;
+        Ok(NumberSpec::Range(Some(n), Some(n)))
+    }
+
+    /// Checks if `n` satisfies the condition
+    pub fn matches(&self, n: f64) -> bool {
+        match *self {
+            NumberSpec::Range(from, to) => from.is_none_or(|f| n >= f) && to.is_none_or(|t| n <= t),
+            NumberSpec::GreaterThan(m) => n > m,
+            NumberSpec::LessThan(m) => n < m,
+        }
+    }
+}
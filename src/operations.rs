@@ -1,14 +1,26 @@
-use std::fs::remove_file;
-use std::process::{Command, Stdio};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, remove_file, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
 
 use serde::{Deserialize, Serialize};
 
+use crate::calendar::apply_calendar;
 use crate::error::Error::*;
+use crate::error::RunError;
 use crate::error::*;
+use crate::export::export;
+use crate::maildir::{self, destination, move_file, unique_name, write_file, Layout};
+use crate::report::{list_name, mailing_list};
+use crate::snooze::SNOOZED_TAGS;
+use crate::validate_tag;
+use crate::CalendarOp;
+use crate::DigestOp;
+use crate::ExportOp;
 use crate::Value;
 use crate::Value::*;
 
-use notmuch::{Database, Message};
+use notmuch::{ConfigKey, Database, Message, Status};
 
 /// Operations filters can apply.
 ///
@@ -16,82 +28,902 @@ use notmuch::{Database, Message};
 /// [`Operations::apply`].
 ///
 /// [`Operations::apply`]: struct.Operations.html#method.apply
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Operations {
     /// Remove tags
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub rm: Option<Value>,
     /// Add tags
+    ///
+    /// If the filter's rules have capture groups, tags containing `$` are
+    /// templates filled in from those of the rule that matched, e.g.
+    /// `lists/$1`, see [`Filter::operations_for`].
+    ///
+    /// [`Filter::operations_for`]: struct.Filter.html#method.operations_for
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub add: Option<Value>,
+    /// Add a tag named after the mailing list the message was sent through,
+    /// prefixed with this (e.g. `lists/`)
+    ///
+    /// The name is the first part of the list's identifier, see `@list`. Does
+    /// nothing for messages that weren't sent through a mailing list.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub list_tag: Option<String>,
     /// Run arbitrary commands
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub run: Option<Vec<String>>,
+    /// Run a command and wait for it to exit, see [`RunCapture`]
+    ///
+    /// [`RunCapture`]: struct.RunCapture.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_capture: Option<RunCapture>,
+    /// Additional environment variables for `run` and `run_capture`, each
+    /// prefixed with `NOTCOAL_`
+    ///
+    /// `{"PROJECT": "acme"}` sets `NOTCOAL_PROJECT=acme`, so a single script
+    /// can serve filters that only differ in a parameter or two. Names may
+    /// only consist of letters, digits and `_`, and can't be one of the
+    /// variables notcoal sets anyway.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_env: Option<BTreeMap<String, String>>,
+    /// Working directory of `run` and `run_capture`, notcoal's own by
+    /// default
+    ///
+    /// A leading `~/` stands for the home directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_cwd: Option<String>,
+    /// Where the standard output of `run` goes, see [`RunOutput`]
+    ///
+    /// [`RunOutput`]: enum.RunOutput.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_stdout: Option<RunOutput>,
+    /// Where the standard error of `run` goes, see [`RunOutput`]
+    ///
+    /// [`RunOutput`]: enum.RunOutput.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_stderr: Option<RunOutput>,
+    /// Copy into an archive outside the maildir, see [`ExportOp`]
+    ///
+    /// The copy is on disk before `del` removes the message, if both are
+    /// given.
+    ///
+    /// [`ExportOp`]: struct.ExportOp.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub export: Option<ExportOp>,
+    /// Copy the message file into this maildir folder of the database (e.g.
+    /// `Archive/2024`), adding the copy to the message in the database
+    ///
+    /// Folders are named like in [`Layout::folder`] and created as needed.
+    /// The copy gets a new file name, but keeps the flags. Messages already
+    /// having a file in the folder aren't copied again.
+    ///
+    /// [`Layout::folder`]: maildir/enum.Layout.html#method.folder
+    #[serde(rename = "copy", skip_serializing_if = "Option::is_none")]
+    pub copy_to: Option<String>,
+    /// Move the message's files into this maildir folder of the database,
+    /// like [`Operations::copy_to`], and update the database accordingly
+    ///
+    /// Messages are moved once all filters were applied (and maildir flags
+    /// were synced), so that later filters still find their files. If
+    /// several filters move a message, the last one wins, and deleted
+    /// messages aren't moved at all.
+    ///
+    /// [`Operations::copy_to`]: struct.Operations.html#structfield.copy_to
+    #[serde(rename = "move", skip_serializing_if = "Option::is_none")]
+    pub move_to: Option<String>,
     /// Delete from disk and notmuch database
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub del: Option<bool>,
+    /// Take the message out of the inbox for a while, e.g. `3d`, see
+    /// [`Snoozes`]
+    ///
+    /// Durations are given in hours (`h`), days (`d`) or weeks (`w`). Only
+    /// applied by runs with [`FilterOptions::snoozes`], [`Operations::apply`]
+    /// ignores it.
+    ///
+    /// [`Snoozes`]: struct.Snoozes.html
+    /// [`FilterOptions::snoozes`]: struct.FilterOptions.html#structfield.snoozes
+    /// [`Operations::apply`]: struct.Operations.html#method.apply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snooze: Option<String>,
+    /// Expect a reply to the message within this long of it being sent,
+    /// e.g. `7d`, tagging it `needs-followup` otherwise, see [`FollowUps`]
+    ///
+    /// Meant for outgoing mail, e.g. with a rule like `"from": "me@example.com"`.
+    /// Durations are given like for [`Operations::snooze`]. Only applied by
+    /// runs with [`FilterOptions::followups`]; whether a reply arrived is
+    /// checked by [`check_followups`], e.g. from a periodic run.
+    ///
+    /// [`FollowUps`]: struct.FollowUps.html
+    /// [`Operations::snooze`]: struct.Operations.html#structfield.snooze
+    /// [`FilterOptions::followups`]: struct.FilterOptions.html#structfield.followups
+    /// [`check_followups`]: fn.check_followups.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect_reply: Option<String>,
+    /// Add the tags returned by these plugins, see [`Plugin`]
+    ///
+    /// Only applied by runs with the plugins in [`FilterOptions::plugins`],
+    /// [`Operations::apply`] ignores it.
+    ///
+    /// [`Plugin`]: struct.Plugin.html
+    /// [`FilterOptions::plugins`]: struct.FilterOptions.html#structfield.plugins
+    /// [`Operations::apply`]: struct.Operations.html#method.apply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plugins: Option<Vec<String>>,
+    /// Collect the message into a digest sent every so often, rather than
+    /// having it stand out on its own, see [`DigestOp`]
+    ///
+    /// Only applied by runs with [`FilterOptions::digests`], [`Operations::apply`]
+    /// ignores it. Digests that are due are sent by [`send_digests`].
+    ///
+    /// [`DigestOp`]: struct.DigestOp.html
+    /// [`FilterOptions::digests`]: struct.FilterOptions.html#structfield.digests
+    /// [`Operations::apply`]: struct.Operations.html#method.apply
+    /// [`send_digests`]: fn.send_digests.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<DigestOp>,
+    /// Pipe the calendar part of an invitation into an importer and answer
+    /// it, see [`CalendarOp`]
+    ///
+    /// Messages without a `text/calendar` part are left alone, so this goes
+    /// well with a rule like `{"@mime-type": "^text/calendar$"}`.
+    ///
+    /// [`CalendarOp`]: struct.CalendarOp.html
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub calendar: Option<CalendarOp>,
+}
+
+/// A command whose output may become a tag, e.g. an external classifier
+///
+/// Unlike `run`, the command gets the message on stdin and notcoal waits for
+/// it to exit. The same environment variables are set.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RunCapture {
+    /// Command and its arguments
+    pub cmd: Vec<String>,
+    /// Add the command's output (with surrounding whitespace trimmed) as a
+    /// tag, unless it's empty
+    ///
+    /// The exit status is ignored, and output that isn't a valid tag is an
+    /// error.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tag_from_stdout: bool,
+}
+
+/// Where the output of a `run` command goes, see [`Operations::run_stdout`]
+///
+/// [`Operations::run_stdout`]: struct.Operations.html#structfield.run_stdout
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunOutput {
+    /// Shared with notcoal, e.g. ending up in the output of `notmuch new`
+    #[default]
+    Inherit,
+    /// Discarded
+    Null,
+    /// Appended to a file, e.g. `{"file": "~/.cache/notcoal/run.log"}`,
+    /// with a leading `~/` standing for the home directory
+    File(String),
+    /// Collected into [`RunOutcome::output`] once the command exited
+    ///
+    /// Runs wait for such commands before they return. Outside of runs, e.g.
+    /// with [`Operations::apply`], the output is discarded.
+    ///
+    /// [`RunOutcome::output`]: struct.RunOutcome.html#structfield.output
+    /// [`Operations::apply`]: struct.Operations.html#method.apply
+    Capture,
+}
+
+/// What a `run` command with captured output wrote, see
+/// [`RunOutput::Capture`]
+///
+/// [`RunOutput::Capture`]: enum.RunOutput.html#variant.Capture
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    /// Message-ID of the message the command was run for
+    pub id: String,
+    /// Name of the filter that ran it
+    pub filter: String,
+    /// Command and its arguments
+    pub cmd: Vec<String>,
+    /// Exit code, if it exited rather than being killed by a signal
+    pub code: Option<i32>,
+    /// Standard output, if it was captured
+    pub stdout: Option<String>,
+    /// Standard error, if it was captured
+    pub stderr: Option<String>,
+}
+
+/// How many tag changes and irreversible operations (see
+/// [`Operations::irreversible`]) a dry run or plan amounts to
+///
+/// [`Operations::irreversible`]: struct.Operations.html#method.irreversible
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpCounts {
+    /// How many times tags are changed
+    pub tag_changes: usize,
+    /// How many times each irreversible operation is applied, by name
+    pub irreversible: BTreeMap<&'static str, usize>,
+}
+
+impl OpCounts {
+    /// Counts the operations of another filter match
+    pub fn add(&mut self, op: &Operations) {
+        if op.changes_tags() {
+            self.tag_changes += 1;
+        }
+        for name in op.irreversible() {
+            *self.irreversible.entry(name).or_default() += 1;
+        }
+    }
+
+    /// Checks if any irreversible operation is counted
+    pub fn is_destructive(&self) -> bool {
+        !self.irreversible.is_empty()
+    }
+}
+
+/// Callback for a tag added to or removed from a message
+pub type TagHook = Box<dyn Fn(&Message, &str)>;
+/// Callback for a command spawned for a message, along with its arguments
+pub type CommandHook = Box<dyn Fn(&Message, &[String])>;
+/// Callback for a deleted message, with its Message-ID and file name
+pub type DeleteHook = Box<dyn Fn(&str, &Path)>;
+
+/// Callbacks invoked for every operation that was actually applied
+///
+/// Allows applications embedding notcoal to follow along during a run, e.g.
+/// to update a list of messages as it happens instead of refreshing it once
+/// everything is done. See [`FilterOptions::hooks`].
+///
+/// [`FilterOptions::hooks`]: struct.FilterOptions.html#structfield.hooks
+#[derive(Default)]
+pub struct Hooks {
+    /// Called for every tag added
+    pub on_tag_added: Option<TagHook>,
+    /// Called for every tag removed, with `*` if all tags were removed
+    pub on_tag_removed: Option<TagHook>,
+    /// Called after a `run` command was spawned
+    pub on_command_spawned: Option<CommandHook>,
+    /// Called once a message was deleted from disk and the database
+    pub on_message_deleted: Option<DeleteHook>,
+}
+
+/// Commands spawned by `run` that may still be running
+///
+/// Without a limit, commands are left to themselves once they're spawned,
+/// unless their output is captured. Otherwise they're kept track of, and
+/// spawning another one first waits for the oldest to exit while the limit
+/// is reached. See [`FilterOptions::max_children`].
+///
+/// [`FilterOptions::max_children`]: struct.FilterOptions.html#structfield.max_children
+#[derive(Debug, Default)]
+pub(crate) struct Children {
+    max: Option<usize>,
+    running: Vec<(Child, Option<Captured>)>,
+    /// If output can be captured at all, i.e. this is part of a run
+    collect: bool,
+    /// Output of the commands with captured output that exited
+    output: Vec<CommandOutput>,
+}
+
+/// A command whose output is captured, with the temporary files it's
+/// written to
+#[derive(Debug)]
+struct Captured {
+    output: CommandOutput,
+    stdout: Option<PathBuf>,
+    stderr: Option<PathBuf>,
+}
+
+impl Captured {
+    /// Reads the output of the command once it exited with `code`
+    fn finish(mut self, code: Option<i32>) -> Result<CommandOutput> {
+        self.output.code = code;
+        self.output.stdout = self.stdout.take().map(read_captured).transpose()?;
+        self.output.stderr = self.stderr.take().map(read_captured).transpose()?;
+        Ok(std::mem::take(&mut self.output))
+    }
+}
+
+impl Drop for Captured {
+    fn drop(&mut self) {
+        for path in self.stdout.iter().chain(&self.stderr) {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+/// Reads and removes a file output was captured to
+fn read_captured(path: PathBuf) -> Result<String> {
+    let buf = fs::read(&path);
+    let _ = remove_file(&path);
+    Ok(String::from_utf8_lossy(&buf?).into_owned())
+}
+
+/// Expands a leading `~/` to the home directory
+pub(crate) fn home_path(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// The maildir folder `name` of the database, created if it doesn't exist
+/// yet
+pub(crate) fn store_folder(db: &Database, name: &str) -> Result<PathBuf> {
+    // the mail root and the database may be in different places
+    let root = match db.config(ConfigKey::MailRoot) {
+        Some(root) => PathBuf::from(root),
+        None => db.path().to_path_buf(),
+    };
+    Layout::detect(&root)?.create_folder(&root, name)
+}
+
+/// Checks if the message `file` is in the maildir `folder`
+fn in_folder(file: &Path, folder: &Path) -> bool {
+    file.parent().and_then(|p| p.parent()) == Some(folder)
+}
+
+/// Where `file` goes in the maildir `folder`, under a new name with the same
+/// flags
+///
+/// Names aren't reused, as tools syncing the store (e.g. mbsync) keep IDs
+/// in them that have to be unique within a folder.
+fn new_file(file: &Path, folder: &Path) -> Result<PathBuf> {
+    let name = match maildir::flags(file) {
+        Some(flags) => format!("{}:2,{}", unique_name(), flags),
+        None => unique_name(),
+    };
+    destination(&file.with_file_name(name), folder, None)
+}
+
+/// notmuch reports adding a file to a message that has one already, and
+/// removing one from a message that has others, which is all copying and
+/// moving do
+fn other_files_ok(res: std::result::Result<(), notmuch::Error>) -> Result<()> {
+    match res {
+        Err(notmuch::Error::NotmuchError(Status::DuplicateMessageID)) => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+/// Copies the file of `msg` into the maildir folder `folder`, see
+/// [`Operations::copy_to`]
+///
+/// [`Operations::copy_to`]: struct.Operations.html#structfield.copy_to
+fn copy_message(msg: &Message, db: &Database, folder: &str) -> Result<()> {
+    let folder = store_folder(db, folder)?;
+    if msg.filenames().any(|f| in_folder(&f, &folder)) {
+        return Ok(());
+    }
+    let file = msg.filename();
+    let to = new_file(&file, &folder)?;
+    write_file(&to, &fs::read(&file)?)?;
+    other_files_ok(db.index_file(&to, None).map(|_| ()))
+}
+
+/// Moves the files of `msg` into the maildir folder `folder`, see
+/// [`Operations::move_to`]
+///
+/// Each new file is indexed before the old one is removed from the database,
+/// so the message and its tags never leave it.
+///
+/// [`Operations::move_to`]: struct.Operations.html#structfield.move_to
+pub(crate) fn move_message(msg: &Message, db: &Database, folder: &str, name: &str) -> Result<()> {
+    let wrap = |e| RunError::wrap(e, msg, Some(name), Stage::Move);
+    let folder = store_folder(db, folder).map_err(wrap)?;
+    let files: Vec<PathBuf> = msg.filenames().filter(|f| !in_folder(f, &folder)).collect();
+    for file in files {
+        let to = new_file(&file, &folder).map_err(wrap)?;
+        move_file(&file, &to).map_err(wrap)?;
+        other_files_ok(db.index_file(&to, None).map(|_| ())).map_err(wrap)?;
+        other_files_ok(db.remove_message(&file)).map_err(wrap)?;
+    }
+    Ok(())
+}
+
+impl Children {
+    pub fn new(max: Option<usize>) -> Self {
+        Children {
+            max,
+            collect: true,
+            ..Default::default()
+        }
+    }
+
+    /// Blocks until another command may be spawned
+    fn make_room(&mut self) -> Result<()> {
+        let mut i = 0;
+        while i < self.running.len() {
+            match self.running[i].0.try_wait()? {
+                Some(status) => {
+                    let (_, captured) = self.running.remove(i);
+                    self.exited(captured, status.code())?;
+                }
+                None => i += 1,
+            }
+        }
+        let max = match self.max {
+            Some(max) => max.max(1),
+            None => return Ok(()),
+        };
+        while self.running.len() >= max {
+            let (mut child, captured) = self.running.remove(0);
+            let status = child.wait()?;
+            self.exited(captured, status.code())?;
+        }
+        Ok(())
+    }
+
+    fn exited(&mut self, captured: Option<Captured>, code: Option<i32>) -> Result<()> {
+        if let Some(captured) = captured {
+            self.output.push(captured.finish(code)?);
+        }
+        Ok(())
+    }
+
+    /// Where output goes for `to`, with the file it's captured to if it is
+    fn stdio(&self, to: Option<&RunOutput>) -> Result<(Stdio, Option<PathBuf>)> {
+        Ok(match to.cloned().unwrap_or_default() {
+            RunOutput::Inherit => (Stdio::inherit(), None),
+            RunOutput::Null => (Stdio::null(), None),
+            RunOutput::Capture if !self.collect => (Stdio::null(), None),
+            RunOutput::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(home_path(&path))?;
+                (file.into(), None)
+            }
+            RunOutput::Capture => {
+                let path = std::env::temp_dir().join(format!("notcoal-{}.out", unique_name()));
+                (File::create(&path)?.into(), Some(path))
+            }
+        })
+    }
+
+    fn push(&mut self, child: Child, captured: Option<Captured>) {
+        if self.max.is_some() || captured.is_some() {
+            self.running.push((child, captured));
+        }
+    }
+
+    /// Waits for the commands with captured output that are still running,
+    /// returning what all of them wrote
+    pub fn finish(&mut self) -> Result<Vec<CommandOutput>> {
+        for (mut child, captured) in std::mem::take(&mut self.running) {
+            if captured.is_some() {
+                let status = child.wait()?;
+                self.exited(captured, status.code())?;
+            }
+        }
+        Ok(std::mem::take(&mut self.output))
+    }
 }
 
 impl Operations {
+    /// Returns the tags the `rm` operation removes, `*` standing for all tags
+    pub fn removed_tags(&self) -> Vec<&str> {
+        match &self.rm {
+            Some(Single(tag)) => vec![tag.as_str()],
+            Some(Multiple(tags)) => tags.iter().map(|t| t.as_str()).collect(),
+            Some(Bool(true)) => vec!["*"],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns the tags the `add` operation adds
+    pub fn added_tags(&self) -> Vec<&str> {
+        match &self.add {
+            Some(Single(tag)) => vec![tag.as_str()],
+            Some(Multiple(tags)) => tags.iter().map(|t| t.as_str()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Checks if these operations add or remove any tags
+    pub fn changes_tags(&self) -> bool {
+        !self.added_tags().is_empty() || !self.removed_tags().is_empty() || self.list_tag.is_some()
+    }
+
+    /// Checks if these operations delete, move or copy message files, which
+    /// counts against [`FilterOptions::max_destructive`]
+    ///
+    /// [`FilterOptions::max_destructive`]: struct.FilterOptions.html#structfield.max_destructive
+    pub fn changes_store(&self) -> bool {
+        self.del == Some(true) || self.move_to.is_some() || self.copy_to.is_some()
+    }
+
+    /// Names of the operations that can't be undone by changing tags back:
+    /// `run`, `run_capture`, `calendar` (which may send replies), `copy`,
+    /// `move` and `del`
+    pub fn irreversible(&self) -> Vec<&'static str> {
+        let mut ops = Vec::new();
+        if self.run.is_some() {
+            ops.push("run");
+        }
+        if self.run_capture.is_some() {
+            ops.push("run_capture");
+        }
+        if self.calendar.is_some() {
+            ops.push("calendar");
+        }
+        if self.copy_to.is_some() {
+            ops.push("copy");
+        }
+        if self.move_to.is_some() {
+            ops.push("move");
+        }
+        if self.del == Some(true) {
+            ops.push("del");
+        }
+        ops
+    }
+
+    /// Applies the tag operations to a set of tags instead of a message
+    ///
+    /// Used to simulate filters on messages outside of any database, hence
+    /// `run`, `run_capture`, `calendar`, `export`, `copy`, `move` and `del`
+    /// are ignored, as is `list_tag`. Snoozing removes the tags it would.
+    pub fn apply_to_tags(&self, tags: &mut BTreeSet<String>) {
+        for tag in self.removed_tags() {
+            if tag == "*" {
+                tags.clear();
+            } else {
+                tags.remove(tag);
+            }
+        }
+        for tag in self.added_tags() {
+            tags.insert(tag.to_string());
+        }
+        if self.snooze.is_some() {
+            for tag in SNOOZED_TAGS {
+                tags.remove(*tag);
+            }
+        }
+    }
+
+    /// The variables [`Operations::run_env`] adds to the environment of
+    /// commands, with their prefix
+    ///
+    /// [`Operations::run_env`]: struct.Operations.html#structfield.run_env
+    fn run_env(&self) -> impl Iterator<Item = (String, &String)> {
+        self.run_env
+            .iter()
+            .flatten()
+            .map(|(name, value)| (format!("NOTCOAL_{}", name), value))
+    }
+
+    /// Checks that the names of [`Operations::run_env`] make for valid
+    /// environment variables that don't replace notcoal's own
+    ///
+    /// [`Operations::run_env`]: struct.Operations.html#structfield.run_env
+    pub(crate) fn validate_run_env(&self) -> Result<()> {
+        for name in self.run_env.iter().flatten().map(|(name, _)| name) {
+            let valid =
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                let e = format!("run_env: invalid variable name {:?}", name);
+                return Err(UnsupportedValue(e));
+            }
+            if ["FILE_NAME", "MSG_ID", "FILTER_NAME"].contains(&name.as_str()) {
+                let e = format!("run_env: NOTCOAL_{} is set by notcoal", name);
+                return Err(UnsupportedValue(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the folders of `copy` and `move` don't escape the store
+    pub(crate) fn validate_folders(&self) -> Result<()> {
+        let folders = [("copy", &self.copy_to), ("move", &self.move_to)];
+        for (op, folder) in folders {
+            if let Some(folder) = folder {
+                Layout::Directories
+                    .folder(Path::new(""), folder)
+                    .map_err(|e| match e {
+                        UnsupportedValue(e) => UnsupportedValue(format!("{}: {}", op, e)),
+                        e => e,
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The tag [`Operations::list_tag`] adds to `msg`, if any
+    ///
+    /// [`Operations::list_tag`]: struct.Operations.html#structfield.list_tag
+    fn list_tag(&self, msg: &Message) -> Result<Option<String>> {
+        let prefix = match &self.list_tag {
+            Some(prefix) => prefix,
+            None => return Ok(None),
+        };
+        let list = mailing_list(|h| Ok(msg.header(h)?.map(|v| v.to_string())))?;
+        match list {
+            Some(id) => {
+                let tag = format!("{}{}", prefix, list_name(&id));
+                validate_tag(&tag)?;
+                Ok(Some(tag))
+            }
+            None => Ok(None),
+        }
+    }
+
     /// Apply the operations defined in [`Filter::op`] to the supplied message
     /// regardless if matches this filter or not
     ///
     /// Operations can fail, but if not they let you know if the message's file
     /// was deleted and dropped from the database.
     ///
-    /// If operations have both `run` and `del` defined, the command is run
-    /// before the message is deleted.
+    /// If operations have both `run` or `export` and `del` defined, the
+    /// command is run and the message exported before it's deleted. `move`
+    /// is applied last, and not at all if the message was deleted.
     ///
     /// [`Filter::op`]: struct.Filter.html#structfield.op
     pub fn apply(&self, msg: &Message, db: &Database, name: &str) -> Result<bool> {
+        self.apply_with_hooks(msg, db, name, &Hooks::default())
+    }
+
+    /// Like [`Operations::apply`], but calls the supplied [`Hooks`] for every
+    /// operation applied
+    ///
+    /// [`Operations::apply`]: struct.Operations.html#method.apply
+    /// [`Hooks`]: struct.Hooks.html
+    pub fn apply_with_hooks(
+        &self,
+        msg: &Message,
+        db: &Database,
+        name: &str,
+        hooks: &Hooks,
+    ) -> Result<bool> {
+        let deleted = self.apply_limited(msg, db, name, hooks, &mut Children::default())?;
+        if let Some(folder) = self.move_to.as_ref().filter(|_| !deleted) {
+            move_message(msg, db, folder, name)?;
+        }
+        Ok(deleted)
+    }
+
+    /// Like [`Operations::apply_with_hooks`], spawning commands through
+    /// `children`
+    ///
+    /// `move` is left to the caller, see `move_message`.
+    /// [`Operations::apply_with_hooks`]: struct.Operations.html#method.apply_with_hooks
+    pub(crate) fn apply_limited(
+        &self,
+        msg: &Message,
+        db: &Database,
+        name: &str,
+        hooks: &Hooks,
+        children: &mut Children,
+    ) -> Result<bool> {
+        let wrap = |e, stage| RunError::wrap(e, msg, Some(name), stage);
+        let removed = |tag: &str| {
+            if let Some(hook) = &hooks.on_tag_removed {
+                hook(msg, tag);
+            }
+        };
+        let added = |tag: &str| {
+            if let Some(hook) = &hooks.on_tag_added {
+                hook(msg, tag);
+            }
+        };
         if let Some(rm) = &self.rm {
             match rm {
                 Single(tag) => {
-                    msg.remove_tag(tag)?;
+                    msg.remove_tag(tag)
+                        .map_err(|e| wrap(e.into(), Stage::RemoveTags))?;
+                    removed(tag);
                 }
                 Multiple(tags) => {
                     for tag in tags {
-                        msg.remove_tag(tag)?;
+                        msg.remove_tag(tag)
+                            .map_err(|e| wrap(e.into(), Stage::RemoveTags))?;
+                        removed(tag);
                     }
                 }
                 Bool(all) => {
                     if *all {
-                        msg.remove_all_tags()?;
+                        msg.remove_all_tags()
+                            .map_err(|e| wrap(e.into(), Stage::RemoveTags))?;
+                        removed("*");
                     }
                 }
+                Literal(_) | Comparison(_) => {
+                    let e = "'rm' operation doesn't support eq, contains, glob or comparisons"
+                        .to_string();
+                    return Err(wrap(UnsupportedValue(e), Stage::RemoveTags));
+                }
             }
         }
         if let Some(add) = &self.add {
             match add {
                 Single(tag) => {
-                    msg.add_tag(tag)?;
+                    msg.add_tag(tag)
+                        .map_err(|e| wrap(e.into(), Stage::AddTags))?;
+                    added(tag);
                 }
                 Multiple(tags) => {
                     for tag in tags {
-                        msg.add_tag(tag)?;
+                        msg.add_tag(tag)
+                            .map_err(|e| wrap(e.into(), Stage::AddTags))?;
+                        added(tag);
                     }
                 }
                 Bool(_) => {
                     let e = "'add' operation doesn't support bool types".to_string();
-                    return Err(UnsupportedValue(e));
+                    return Err(wrap(UnsupportedValue(e), Stage::AddTags));
                 }
+                Literal(_) | Comparison(_) => {
+                    let e = "'add' operation doesn't support eq, contains, glob or comparisons"
+                        .to_string();
+                    return Err(wrap(UnsupportedValue(e), Stage::AddTags));
+                }
+            }
+        }
+        if let Some(tag) = self.list_tag(msg).map_err(|e| wrap(e, Stage::AddTags))? {
+            msg.add_tag(&tag)
+                .map_err(|e| wrap(e.into(), Stage::AddTags))?;
+            added(&tag);
+        }
+        if let Some(capture) = &self.run_capture {
+            let stdin = File::open(msg.filename()).map_err(|e| wrap(e.into(), Stage::Run))?;
+            let mut cmd = Command::new(&capture.cmd[0]);
+            if let Some(cwd) = &self.run_cwd {
+                cmd.current_dir(home_path(cwd));
             }
+            let output = cmd
+                .args(&capture.cmd[1..])
+                .stdin(stdin)
+                .stdout(Stdio::piped())
+                .env("NOTCOAL_FILE_NAME", msg.filename())
+                .env("NOTCOAL_MSG_ID", msg.id().as_ref())
+                .env("NOTCOAL_FILTER_NAME", name)
+                .envs(self.run_env())
+                .output()
+                .map_err(|e| wrap(e.into(), Stage::Run))?;
+            if let Some(hook) = &hooks.on_command_spawned {
+                hook(msg, &capture.cmd);
+            }
+            let tag = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if capture.tag_from_stdout && !tag.is_empty() {
+                validate_tag(&tag).map_err(|e| wrap(e, Stage::Run))?;
+                msg.add_tag(&tag)
+                    .map_err(|e| wrap(e.into(), Stage::AddTags))?;
+                added(&tag);
+            }
+        }
+        if let Some(calendar) = &self.calendar {
+            apply_calendar(msg, db, calendar, name, hooks)?;
         }
         if let Some(argv) = &self.run {
-            Command::new(&argv[0])
+            children.make_room().map_err(|e| wrap(e, Stage::Run))?;
+            let (stdout, stdout_file) = children
+                .stdio(self.run_stdout.as_ref())
+                .map_err(|e| wrap(e, Stage::Run))?;
+            let (stderr, stderr_file) = children
+                .stdio(self.run_stderr.as_ref())
+                .map_err(|e| wrap(e, Stage::Run))?;
+            let captured = (stdout_file.is_some() || stderr_file.is_some()).then(|| Captured {
+                output: CommandOutput {
+                    id: msg.id().to_string(),
+                    filter: name.to_string(),
+                    cmd: argv.clone(),
+                    ..Default::default()
+                },
+                stdout: stdout_file,
+                stderr: stderr_file,
+            });
+            let mut cmd = Command::new(&argv[0]);
+            if let Some(cwd) = &self.run_cwd {
+                cmd.current_dir(home_path(cwd));
+            }
+            let child = cmd
                 .args(&argv[1..])
-                .stdout(Stdio::inherit())
+                .stdout(stdout)
+                .stderr(stderr)
                 .env("NOTCOAL_FILE_NAME", msg.filename())
                 .env("NOTCOAL_MSG_ID", msg.id().as_ref())
                 .env("NOTCOAL_FILTER_NAME", name)
-                .spawn()?;
+                .envs(self.run_env())
+                .spawn()
+                .map_err(|e| wrap(e.into(), Stage::Run))?;
+            children.push(child, captured);
+            if let Some(hook) = &hooks.on_command_spawned {
+                hook(msg, argv);
+            }
+        }
+        if let Some(op) = &self.export {
+            export(msg, op, name)?;
+        }
+        if let Some(folder) = &self.copy_to {
+            copy_message(msg, db, folder).map_err(|e| wrap(e, Stage::Copy))?;
         }
         if let Some(del) = &self.del {
             if *del {
                 // This file was just indexed, so we assume it exists - or do
                 // we? See XXX-file in filter.rs
-                remove_file(msg.filename())?;
-                db.remove_message(msg.filename())?;
+                remove_file(msg.filename()).map_err(|e| wrap(e.into(), Stage::Delete))?;
+                db.remove_message(msg.filename())
+                    .map_err(|e| wrap(e.into(), Stage::Delete))?;
+                if let Some(hook) = &hooks.on_message_deleted {
+                    hook(&msg.id(), &msg.filename());
+                }
                 return Ok(true);
             }
         }
         Ok(false)
     }
+
+    /// Apply the tag operations defined in [`Filter::op`] in "shadow mode"
+    ///
+    /// Instead of the real tags, every tag that would be added is added as
+    /// `prefix` followed by the tag, and every tag that would be removed is
+    /// added as `prefix`, `-` and the tag (`prefix-*` if all tags were to be
+    /// removed). Neither `run`, `run_capture`, `calendar`, `export`, `copy`,
+    /// `move`, `del`, `snooze`, `expect_reply`, `plugins` nor `digest` are
+    /// applied. This allows seeing what changed filters would do on live mail
+    /// without committing to them.
+    ///
+    /// [`Filter::op`]: struct.Filter.html#structfield.op
+    pub fn apply_shadowed(&self, msg: &Message, name: &str, prefix: &str) -> Result<()> {
+        let wrap = |e, stage| RunError::wrap(e, msg, Some(name), stage);
+        if let Some(rm) = &self.rm {
+            let shadow = |tag: &str| format!("{}-{}", prefix, tag);
+            match rm {
+                Single(tag) => {
+                    msg.add_tag(&shadow(tag))
+                        .map_err(|e| wrap(e.into(), Stage::RemoveTags))?;
+                }
+                Multiple(tags) => {
+                    for tag in tags {
+                        msg.add_tag(&shadow(tag))
+                            .map_err(|e| wrap(e.into(), Stage::RemoveTags))?;
+                    }
+                }
+                Bool(all) => {
+                    if *all {
+                        msg.add_tag(&shadow("*"))
+                            .map_err(|e| wrap(e.into(), Stage::RemoveTags))?;
+                    }
+                }
+                Literal(_) | Comparison(_) => {
+                    let e = "'rm' operation doesn't support eq, contains, glob or comparisons"
+                        .to_string();
+                    return Err(wrap(UnsupportedValue(e), Stage::RemoveTags));
+                }
+            }
+        }
+        if let Some(add) = &self.add {
+            let shadow = |tag: &str| format!("{}{}", prefix, tag);
+            match add {
+                Single(tag) => {
+                    msg.add_tag(&shadow(tag))
+                        .map_err(|e| wrap(e.into(), Stage::AddTags))?;
+                }
+                Multiple(tags) => {
+                    for tag in tags {
+                        msg.add_tag(&shadow(tag))
+                            .map_err(|e| wrap(e.into(), Stage::AddTags))?;
+                    }
+                }
+                Bool(_) => {
+                    let e = "'add' operation doesn't support bool types".to_string();
+                    return Err(wrap(UnsupportedValue(e), Stage::AddTags));
+                }
+                Literal(_) | Comparison(_) => {
+                    let e = "'add' operation doesn't support eq, contains, glob or comparisons"
+                        .to_string();
+                    return Err(wrap(UnsupportedValue(e), Stage::AddTags));
+                }
+            }
+        }
+        if let Some(tag) = self.list_tag(msg).map_err(|e| wrap(e, Stage::AddTags))? {
+            msg.add_tag(&format!("{}{}", prefix, tag))
+                .map_err(|e| wrap(e.into(), Stage::AddTags))?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,460 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use regex::{Regex, RegexSet, SetMatches};
+
+use crate::cidr::Cidr;
+use crate::date::DateSpec;
+use crate::error::Result;
+use crate::number::NumberSpec;
+use crate::{Comparison, Literal};
+
+/// Where a literal has to occur in the text it's matched against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Anchor {
+    Anywhere,
+    Start,
+    End,
+    Both,
+}
+
+/// A compiled rule value
+///
+/// Most rules in real rule files are plain strings like `@lists\.example\.org`
+/// or `^newsletter$`. Those are matched with simple string comparisons
+/// instead of going through the regex engine, everything else is a regular
+/// expression.
+#[derive(Debug)]
+pub(crate) enum Pattern {
+    Regex(Regex),
+    /// A regular expression that's part of a [`PatternSet`], at the given
+    /// index
+    InSet(Regex, Rc<PatternSet>, usize),
+    Literal {
+        needle: String,
+        anchor: Anchor,
+        /// Only set for ASCII needles, which are lowercased already
+        ignore_case: bool,
+    },
+    /// Matches Unix timestamps, see [`DateSpec`]
+    Date(DateSpec),
+    /// Matches numbers, see [`NumberSpec`]
+    Number(NumberSpec),
+    /// A notmuch query for `@query`, matching nothing but itself as the
+    /// query is run against the database rather than matched against text
+    Query(String),
+    /// Matches numbers, see [`Comparison`]
+    Comparison(Comparison),
+    /// Matches IP addresses in a range, see [`Cidr`]
+    Cidr(Cidr),
+    /// Matches any header if `true`, as only headers that are present are
+    /// matched against patterns at all
+    Present(bool),
+}
+
+impl Pattern {
+    pub fn new(re: &str) -> Result<Self> {
+        // compile in any case, so invalid expressions are rejected the same
+        // way no matter if they look like literals
+        let regex = Regex::new(re)?;
+        Ok(literal(re).unwrap_or(Pattern::Regex(regex)))
+    }
+
+    /// Matches a string without involving regular expressions at all, except
+    /// for globs with wildcards
+    pub fn from_literal(lit: &Literal) -> Result<Self> {
+        let (needle, anchor) = match lit {
+            Literal::Eq(s) => (s, Anchor::Both),
+            Literal::Contains(s) => (s, Anchor::Anywhere),
+            Literal::Glob(glob) => return Pattern::new(&glob_to_regex(glob)),
+        };
+        Ok(Pattern::Literal {
+            needle: needle.clone(),
+            anchor,
+            ignore_case: false,
+        })
+    }
+
+    /// The regular expression, unless this is matched some other way
+    pub fn regex(&self) -> Option<&Regex> {
+        match self {
+            Pattern::Regex(re) | Pattern::InSet(re, _, _) => Some(re),
+            _ => None,
+        }
+    }
+
+    /// Byte offsets of the first match in `haystack`, for highlighting
+    ///
+    /// Patterns that don't search for text, like dates or numbers, cover the
+    /// whole haystack if they match.
+    pub fn find(&self, haystack: &str) -> Option<(usize, usize)> {
+        match self {
+            Pattern::Regex(re) | Pattern::InSet(re, _, _) => {
+                re.find(haystack).map(|m| (m.start(), m.end()))
+            }
+            Pattern::Literal {
+                needle,
+                anchor,
+                ignore_case,
+            } => {
+                let (h, n) = (haystack.as_bytes(), needle.as_bytes());
+                let start = match anchor {
+                    Anchor::Both | Anchor::Start => 0,
+                    Anchor::End => h.len().checked_sub(n.len())?,
+                    Anchor::Anywhere if !ignore_case => haystack.find(needle.as_str())?,
+                    Anchor::Anywhere if n.is_empty() => 0,
+                    // ASCII needles only ever match ASCII bytes, so this
+                    // can't end up in the middle of a character
+                    Anchor::Anywhere => {
+                        h.windows(n.len()).position(|w| w.eq_ignore_ascii_case(n))?
+                    }
+                };
+                self.is_match(haystack).then_some((start, start + n.len()))
+            }
+            _ => self.is_match(haystack).then_some((0, haystack.len())),
+        }
+    }
+
+    pub fn is_match(&self, haystack: &str) -> bool {
+        match self {
+            Pattern::Regex(re) => re.is_match(haystack),
+            Pattern::InSet(_, set, i) => set.is_match(*i, haystack),
+            Pattern::Date(spec) => haystack.parse().is_ok_and(|d| spec.matches(d)),
+            Pattern::Number(spec) => haystack.parse().is_ok_and(|n| spec.matches(n)),
+            Pattern::Query(query) => haystack == query,
+            Pattern::Cidr(cidr) => haystack.parse().is_ok_and(|ip| cidr.contains(&ip)),
+            Pattern::Comparison(c) => haystack.trim().parse().is_ok_and(|n| c.matches(n)),
+            Pattern::Present(present) => *present,
+            Pattern::Literal {
+                needle,
+                anchor,
+                ignore_case,
+            } => {
+                let (h, n) = (haystack.as_bytes(), needle.as_bytes());
+                let eq = |a: &[u8]| match ignore_case {
+                    true => a.eq_ignore_ascii_case(n),
+                    false => a == n,
+                };
+                match anchor {
+                    Anchor::Both => eq(h),
+                    Anchor::Start => h.len() >= n.len() && eq(&h[..n.len()]),
+                    Anchor::End => h.len() >= n.len() && eq(&h[h.len() - n.len()..]),
+                    Anchor::Anywhere if !ignore_case => haystack.contains(needle.as_str()),
+                    Anchor::Anywhere => n.is_empty() || h.windows(n.len()).any(eq),
+                }
+            }
+        }
+    }
+}
+
+/// How many texts a [`PatternSet`] remembers the matches for
+const SET_CACHE: usize = 64;
+
+/// Regular expressions used on the same field, possibly by many filters,
+/// matched against a text in a single pass
+///
+/// Which of them matched is kept for the texts matched last, so the first
+/// filter looking at e.g. a message's `From` header matches it against all
+/// of them, and the others just look the result up.
+#[derive(Debug)]
+pub(crate) struct PatternSet {
+    set: RegexSet,
+    matches: RefCell<HashMap<String, SetMatches>>,
+}
+
+impl PatternSet {
+    /// Combines `res`, unless there are too many for a single set
+    pub fn new(res: &[String]) -> Option<Self> {
+        Some(PatternSet {
+            set: RegexSet::new(res).ok()?,
+            matches: RefCell::new(HashMap::new()),
+        })
+    }
+
+    /// Checks if the expression at `index` matches `haystack`
+    fn is_match(&self, index: usize, haystack: &str) -> bool {
+        if let Some(matches) = self.matches.borrow().get(haystack) {
+            return matches.matched(index);
+        }
+        let matches = self.set.matches(haystack);
+        let matched = matches.matched(index);
+        let mut cache = self.matches.borrow_mut();
+        if cache.len() >= SET_CACHE {
+            cache.clear();
+        }
+        cache.insert(haystack.to_string(), matches);
+        matched
+    }
+}
+
+/// Translates a glob pattern into an anchored regular expression
+///
+/// Wildcards match across line breaks, as folded headers may contain them.
+/// A `[` without a closing `]` stands for itself, and within a set `-` only
+/// has a meaning between two characters.
+fn glob_to_regex(glob: &str) -> String {
+    let escape = |c: char| regex::escape(c.encode_utf8(&mut [0; 4]));
+    let mut re = String::from("^");
+    let mut chars = glob.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => re.push_str("(?s:.*)"),
+            '?' => re.push_str("(?s:.)"),
+            '[' => {
+                let rest = chars.as_str();
+                let (negate, set) = match rest.strip_prefix('!') {
+                    Some(set) => (true, set),
+                    None => (false, rest),
+                };
+                // a `]` right at the start is part of the set
+                let end = set.char_indices().skip(1).find(|(_, c)| *c == ']');
+                let end = match end {
+                    Some((end, _)) => end,
+                    None => {
+                        re.push_str("\\[");
+                        continue;
+                    }
+                };
+                re.push('[');
+                if negate {
+                    re.push('^');
+                }
+                // everything but the `-` of ranges is escaped, so the class
+                // operators `--`, `&&` and `~~` stand for themselves
+                let set_chars: Vec<char> = set[..end].chars().collect();
+                let mut i = 0;
+                while i < set_chars.len() {
+                    re.push_str(&escape(set_chars[i]));
+                    if set_chars.get(i + 1) == Some(&'-') && i + 2 < set_chars.len() {
+                        re.push('-');
+                        re.push_str(&escape(set_chars[i + 2]));
+                        i += 3;
+                    } else {
+                        i += 1;
+                    }
+                }
+                re.push(']');
+                chars = set[end + 1..].chars();
+            }
+            c => re.push_str(&escape(c)),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// Turns a regular expression into a [`Pattern::Literal`] if it's nothing but
+/// a (possibly anchored or case-insensitive) string
+fn literal(re: &str) -> Option<Pattern> {
+    let (ignore_case, re) = match re.strip_prefix("(?i)") {
+        Some(re) => (true, re),
+        None => (false, re),
+    };
+    let (start, re) = match re.strip_prefix('^') {
+        Some(re) => (true, re),
+        None => (false, re),
+    };
+    let mut needle = String::new();
+    let mut end = false;
+    let mut chars = re.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // escaped punctuation stands for itself, `\d` and the like don't,
+            // neither do the word boundaries `\<` and `\>`
+            '\\' => match chars.next() {
+                Some(e) if e.is_ascii_punctuation() && e != '<' && e != '>' => needle.push(e),
+                _ => return None,
+            },
+            '$' if chars.as_str().is_empty() => end = true,
+            '.' | '+' | '*' | '?' | '(' | ')' | '|' | '[' | ']' | '{' | '}' | '^' | '$' => {
+                return None
+            }
+            c => needle.push(c),
+        }
+    }
+    // (?i) folds case following Unicode rules, where e.g. `k` also matches
+    // the Kelvin sign, so only simple ASCII needles are compared directly
+    if ignore_case {
+        if !needle.is_ascii() || needle.contains(['k', 'K', 's', 'S']) {
+            return None;
+        }
+        needle.make_ascii_lowercase();
+    }
+    let anchor = match (start, end) {
+        (false, false) => Anchor::Anywhere,
+        (true, false) => Anchor::Start,
+        (false, true) => Anchor::End,
+        (true, true) => Anchor::Both,
+    };
+    Some(Pattern::Literal {
+        needle,
+        anchor,
+        ignore_case,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HAYSTACKS: &[&str] = &[
+        "",
+        "newsletter",
+        "Newsletter@Lists.Example.org",
+        "weekly newsletter",
+        "NEWSLETTER",
+        "Update",
+        "weekly UPDATE",
+        "upd\u{e4}te",
+        "a.b",
+        "axb",
+        "a+b",
+        "(paren)",
+        "\u{212a}elvin",
+        "Kelvin",
+        "\u{17f}pam",
+        "spam",
+        "Stra\u{df}e",
+        "line\nbreak",
+    ];
+
+    const REGEXES: &[&str] = &[
+        "newsletter",
+        "^newsletter",
+        "newsletter$",
+        "^newsletter$",
+        "(?i)newsletter",
+        "(?i)^newsletter$",
+        "(?i)update",
+        "(?i)^update",
+        "(?i)update$",
+        "(?i)^update$",
+        "(?i)lists\\.example",
+        "(?i)kelvin",
+        "(?i)spam",
+        "(?i)^K",
+        "(?i)stra\u{df}e",
+        "a\\.b",
+        "a.b",
+        "a\\+b",
+        "\\(paren\\)",
+        "^$",
+        "",
+        "(?i)",
+        "line\nbreak",
+        "\\bspam",
+        "x$y",
+    ];
+
+    #[test]
+    fn literals_match_like_the_regex() {
+        for re in REGEXES {
+            let regex = match Regex::new(re) {
+                Ok(regex) => regex,
+                Err(_) => continue,
+            };
+            let pattern = Pattern::new(re).unwrap();
+            for haystack in HAYSTACKS {
+                assert_eq!(
+                    pattern.is_match(haystack),
+                    regex.is_match(haystack),
+                    "{re:?} on {haystack:?}"
+                );
+                let found = regex.find(haystack).map(|m| (m.start(), m.end()));
+                assert_eq!(pattern.find(haystack), found, "{re:?} on {haystack:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn only_plain_strings_are_literals() {
+        let is_literal = |re| matches!(Pattern::new(re).unwrap(), Pattern::Literal { .. });
+        for re in ["newsletter", "^a\\.b$", "(?i)^update", "", "\\(paren\\)"] {
+            assert!(is_literal(re), "{re:?}");
+        }
+        // Unicode case folding, classes, escapes with a meaning of their own
+        for re in [
+            "(?i)kelvin",
+            "(?i)spam",
+            "(?i)stra\u{df}e",
+            "a.b",
+            "\\bspam",
+            "\\<a",
+        ] {
+            assert!(!is_literal(re), "{re:?}");
+        }
+    }
+
+    #[test]
+    fn sets_match_like_the_regex() {
+        let res: Vec<String> = REGEXES.iter().map(|re| re.to_string()).collect();
+        let set = Rc::new(PatternSet::new(&res).unwrap());
+        for (i, re) in REGEXES.iter().enumerate() {
+            let regex = Regex::new(re).unwrap();
+            let pattern = Pattern::InSet(regex.clone(), Rc::clone(&set), i);
+            // twice, to go through the remembered matches as well
+            for haystack in HAYSTACKS.iter().chain(HAYSTACKS) {
+                assert_eq!(
+                    pattern.is_match(haystack),
+                    regex.is_match(haystack),
+                    "{re:?} on {haystack:?}"
+                );
+            }
+        }
+    }
+
+    fn glob(glob: &str, haystack: &str) -> bool {
+        Pattern::from_literal(&Literal::Glob(glob.to_string()))
+            .unwrap()
+            .is_match(haystack)
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("*.pdf", "report.pdf"));
+        assert!(glob("*.pdf", "two\nlines.pdf"));
+        assert!(!glob("*.pdf", "report.pdf.exe"));
+        assert!(glob("report-??.txt", "report-01.txt"));
+        assert!(!glob("report-??.txt", "report-1.txt"));
+        assert!(glob("a.b", "a.b"));
+        assert!(!glob("a.b", "axb"));
+        assert!(glob("[", "["));
+        assert!(glob("x[y", "x[y"));
+    }
+
+    #[test]
+    fn glob_sets() {
+        assert!(glob("[abc]", "b"));
+        assert!(!glob("[abc]", "d"));
+        assert!(glob("[!abc]", "d"));
+        assert!(!glob("[!abc]", "a"));
+        assert!(glob("[a-c]", "b"));
+        assert!(!glob("[a-c]", "-"));
+        assert!(glob("[]]", "]"));
+        assert!(glob("[!]]", "a"));
+        assert!(!glob("[!]]", "]"));
+        // `-` at either end stands for itself
+        assert!(glob("[-a]", "-"));
+        assert!(glob("[a-]", "-"));
+        assert!(!glob("[a-]", "b"));
+        // a range ending in `-`
+        assert!(glob("[+--]", ","));
+        assert!(!glob("[+--]", "a"));
+        // no class operators
+        for (set, c) in [
+            ("[--]", "-"),
+            ("[a&&b]", "&"),
+            ("[a~~b]", "~"),
+            ("[&&]", "&"),
+        ] {
+            assert!(glob(set, c), "{set:?} on {c:?}");
+            assert!(glob(set, &set[1..2]), "{set:?}");
+        }
+        assert!(glob("[a&&b]", "b"));
+        assert!(!glob("[a&&b]", "c"));
+        // a range from `-` rather than taking `b` out of `a-c`
+        assert!(glob("[a-c--b]", "b"));
+        assert!(glob("[a-c--b]", "."));
+    }
+}
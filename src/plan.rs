@@ -0,0 +1,348 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Warning::*;
+use crate::error::*;
+use crate::mail::{DbMail, Tracked};
+use crate::operations::move_message;
+use crate::{
+    apply_extras, finish_message, in_phase_order, restore_query_tag, skip_if_missing,
+    validate_query_tag, validate_shadow_tags,
+};
+use crate::{Filter, FilterOptions, OpCounts, Operations, RunOutcome, RunState};
+
+use notmuch::{Database, Message};
+
+/// Operations of a single filter that matched a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlannedOp {
+    /// Name of the filter that matched
+    pub filter: String,
+    /// Operations that will be applied
+    pub op: Operations,
+}
+
+/// A message along with everything that will be done to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlannedMessage {
+    /// Message-ID
+    pub id: String,
+    /// Operations in the order they will be applied, possibly none
+    pub ops: Vec<PlannedOp>,
+}
+
+/// Concrete operations for specific messages, determined by [`plan`] and
+/// executed later on by [`Plan::apply`]
+///
+/// Plans can be serialized, which allows reviewing them before applying them,
+/// or applying the same plan to a different copy of the database.
+///
+/// [`plan`]: fn.plan.html
+/// [`Plan::apply`]: struct.Plan.html#method.apply
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Plan {
+    /// Tag the messages were selected with, removed once they're processed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_tag: Option<String>,
+    /// Messages to process
+    pub messages: Vec<PlannedMessage>,
+}
+
+impl Plan {
+    /// How many filter matches are planned
+    pub fn len(&self) -> usize {
+        self.messages.iter().map(|m| m.ops.len()).sum()
+    }
+
+    /// Checks if no filter matched any message
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Counts the tag changes and irreversible operations planned
+    pub fn op_counts(&self) -> OpCounts {
+        let mut counts = OpCounts::default();
+        for p in self.messages.iter().flat_map(|m| &m.ops) {
+            counts.add(&p.op);
+        }
+        counts
+    }
+
+    /// Converts the tag operations into input for `notmuch tag --batch`
+    ///
+    /// One line is written per message, removing the query tag as well unless
+    /// [`FilterOptions::leave_tag`] is set, and honouring
+    /// [`FilterOptions::shadow_prefix`]. Neither commands and `del` nor
+    /// removing all tags can be expressed that way, so these are skipped with
+    /// a [`Warning`].
+    ///
+    /// [`FilterOptions::leave_tag`]: struct.FilterOptions.html#structfield.leave_tag
+    /// [`FilterOptions::shadow_prefix`]: struct.FilterOptions.html#structfield.shadow_prefix
+    /// [`Warning`]: error/enum.Warning.html
+    pub fn tag_batch(&self, options: &FilterOptions) -> (String, Vec<Warning>) {
+        let mut out = String::new();
+        let mut warnings = Vec::new();
+        for planned in &self.messages {
+            let mut line = Vec::new();
+            for p in &planned.ops {
+                let skipped = |what: &str| {
+                    SkippedOperation(format!("{}: {} of {}", planned.id, what, p.filter))
+                };
+                for tag in p.op.removed_tags() {
+                    match (tag, &options.shadow_prefix) {
+                        ("*", None) => warnings.push(skipped("removing all tags")),
+                        (tag, None) => line.push(format!("-{}", hex_encode(tag))),
+                        (tag, Some(prefix)) => {
+                            line.push(format!("+{}", hex_encode(&format!("{prefix}-{tag}"))))
+                        }
+                    }
+                }
+                for tag in p.op.added_tags() {
+                    let prefix = options.shadow_prefix.as_deref().unwrap_or_default();
+                    line.push(format!("+{}", hex_encode(&format!("{prefix}{tag}"))));
+                }
+                if p.op.list_tag.is_some() {
+                    warnings.push(skipped("list_tag"));
+                }
+                if options.shadow_prefix.is_none() {
+                    if p.op.run.is_some() {
+                        warnings.push(skipped("run"));
+                    }
+                    if p.op.run_capture.is_some() {
+                        warnings.push(skipped("run_capture"));
+                    }
+                    if p.op.calendar.is_some() {
+                        warnings.push(skipped("calendar"));
+                    }
+                    if p.op.export.is_some() {
+                        warnings.push(skipped("export"));
+                    }
+                    if p.op.copy_to.is_some() {
+                        warnings.push(skipped("copy"));
+                    }
+                    if p.op.move_to.is_some() {
+                        warnings.push(skipped("move"));
+                    }
+                    if p.op.del == Some(true) {
+                        warnings.push(skipped("del"));
+                    }
+                    if p.op.snooze.is_some() {
+                        warnings.push(skipped("snooze"));
+                    }
+                    if p.op.expect_reply.is_some() {
+                        warnings.push(skipped("expect_reply"));
+                    }
+                    if p.op.plugins.is_some() {
+                        warnings.push(skipped("plugins"));
+                    }
+                    if p.op.digest.is_some() {
+                        warnings.push(skipped("digest"));
+                    }
+                }
+            }
+            if let Some(tag) = self.query_tag.as_ref().filter(|_| !options.leave_tag) {
+                line.push(format!("-{}", hex_encode(tag)));
+            }
+            if !line.is_empty() {
+                out.push_str(&format!(
+                    "{} -- id:{}\n",
+                    line.join(" "),
+                    hex_encode(&planned.id)
+                ));
+            }
+        }
+        (out, warnings)
+    }
+
+    /// Applies the planned operations to the messages still in the database
+    ///
+    /// Behaves like [`filter`], without evaluating any rules: messages that
+    /// can't be found anymore are skipped, and errors are recorded in the
+    /// [`RunOutcome`] rather than aborting.
+    ///
+    /// [`filter`]: fn.filter.html
+    /// [`RunOutcome`]: struct.RunOutcome.html
+    pub fn apply(&self, db: &Database, options: &FilterOptions) -> Result<RunOutcome> {
+        let ops = self.messages.iter().flat_map(|m| &m.ops);
+        validate_shadow_tags(
+            options.shadow_prefix.as_deref(),
+            ops.map(|p| (p.filter.clone(), Cow::Borrowed(&p.op))),
+        )?;
+        let mut outcome = RunOutcome::default();
+        let mut state = RunState::new(options);
+        for planned in &self.messages {
+            let msg = match db.find_message(&planned.id)? {
+                Some(msg) => msg,
+                None => {
+                    let w = format!("{}: not in the database anymore", planned.id);
+                    outcome.warnings.push(SkippedMessage(w));
+                    outcome.skipped += 1;
+                    continue;
+                }
+            };
+            let res = self.apply_message(&msg, db, planned, options, &mut outcome, &mut state);
+            if let Err(e) = res {
+                // operations wrap their errors already, the stage here is
+                // merely a fallback
+                outcome
+                    .errors
+                    .push(RunError::from_error(e, &msg, None, Stage::Match));
+            }
+        }
+        outcome.output = state.children.finish()?;
+        Ok(outcome)
+    }
+
+    fn apply_message(
+        &self,
+        msg: &Message,
+        db: &Database,
+        planned: &PlannedMessage,
+        options: &FilterOptions,
+        outcome: &mut RunOutcome,
+        state: &mut RunState,
+    ) -> Result<()> {
+        if options.skip_if.as_ref().is_some_and(|skip| skip(msg)) {
+            outcome.skipped += 1;
+            return Ok(());
+        }
+        let ops = planned.ops.iter().map(|p| &p.op);
+        if !state.within_budget(ops, msg, options, &mut outcome.warnings) {
+            outcome.skipped += 1;
+            return Ok(());
+        }
+        for p in &planned.ops {
+            let res = match &options.shadow_prefix {
+                Some(prefix) => p.op.apply_shadowed(msg, &p.filter, prefix).map(|_| false),
+                None => {
+                    let children = &mut state.children;
+                    p.op.apply_limited(msg, db, &p.filter, &options.hooks, children)
+                        .and_then(|deleted| {
+                            if !deleted {
+                                let warnings = &mut outcome.warnings;
+                                apply_extras(msg, db, &p.op, &p.filter, options, warnings)?;
+                            }
+                            Ok(deleted)
+                        })
+                }
+            };
+            let deleted = match skip_if_missing(res, msg, &mut outcome.warnings)? {
+                Some(deleted) => deleted,
+                None => {
+                    outcome.skipped += 1;
+                    return Ok(());
+                }
+            };
+            outcome.applied += 1;
+            if deleted {
+                return Ok(());
+            }
+        }
+        finish_message(msg, self.query_tag.as_deref(), options)?;
+        let move_to = planned
+            .ops
+            .iter()
+            .filter(|_| options.shadow_prefix.is_none())
+            .filter_map(|p| Some((p.op.move_to.as_ref()?, &p.filter)))
+            .last();
+        if let Some((folder, name)) = move_to {
+            if let Err(e) = move_message(msg, db, folder, name) {
+                restore_query_tag(msg, self.query_tag.as_deref(), options)?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hex-encodes everything but the characters notmuch leaves alone in its
+/// batch format, see notmuch-tag(1)
+fn hex_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"+-_@=.,".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02x}", b));
+        }
+    }
+    out
+}
+
+/// Determines which operations [`filter`] would apply to which messages,
+/// without applying any of them
+///
+/// Filters in observation-only mode aren't part of the plan. Tag operations
+/// are tracked in memory while planning, so rules matching `@tags` see the
+/// tags earlier filters would add or remove, just like with [`filter`]. Any
+/// [`Warning`]s are returned as well.
+///
+/// [`filter`]: fn.filter.html
+/// [`Warning`]: error/enum.Warning.html
+pub fn plan(db: &Database, query_tag: &str, filters: &[Filter]) -> Result<(Plan, Vec<Warning>)> {
+    let query = validate_query_tag(query_tag)?;
+    plan_messages(db, &query, Some(query_tag), filters)
+}
+
+/// Like [`plan`], for all messages matching an arbitrary notmuch query
+///
+/// No query tag is involved, so applying the plan only changes what the
+/// filters do.
+///
+/// [`plan`]: fn.plan.html
+pub fn plan_query(db: &Database, query: &str, filters: &[Filter]) -> Result<(Plan, Vec<Warning>)> {
+    plan_messages(db, query, None, filters)
+}
+
+fn plan_messages(
+    db: &Database,
+    query: &str,
+    query_tag: Option<&str>,
+    filters: &[Filter],
+) -> Result<(Plan, Vec<Warning>)> {
+    let q = db.create_query(query)?;
+    let mut plan = Plan {
+        query_tag: query_tag.map(|t| t.to_string()),
+        messages: Vec::new(),
+    };
+    let mut warnings = Vec::new();
+    let mut state = RunState::default();
+    'messages: for msg in q.search_messages()? {
+        let mut ops = Vec::new();
+        let mail = DbMail::new(&msg, db, None, true);
+        let mut mail = Tracked::new(&mail);
+        for f in in_phase_order(filters).filter(|f| !f.is_dry() && f.applies_to(db)) {
+            let res = f.is_mail_match(&mail, &mut warnings);
+            let res = skip_if_missing(res, &msg, &mut warnings)
+                .map_err(|e| RunError::wrap(e, &msg, Some(&f.name()), Stage::Match))?;
+            match res {
+                Some(true) if state.allow(f, &mut warnings) => {
+                    let op = f
+                        .operations_for(&mail, &mut warnings)
+                        .map_err(|e| RunError::wrap(e, &msg, Some(&f.name()), Stage::Match))?
+                        .into_owned();
+                    op.apply_to_tags(&mut mail.tags);
+                    mail.score += f.score();
+                    ops.push(PlannedOp {
+                        filter: f.name(),
+                        op,
+                    });
+                    if f.op.del == Some(true) || f.stops() {
+                        break;
+                    }
+                }
+                Some(_) => {}
+                None => continue 'messages,
+            }
+        }
+        plan.messages.push(PlannedMessage {
+            id: msg.id().to_string(),
+            ops,
+        });
+    }
+    Ok((plan, warnings))
+}
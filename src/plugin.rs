@@ -0,0 +1,145 @@
+use std::path::Path;
+
+#[cfg(feature = "wasm")]
+use wasmtime::{Config, Engine, Instance, Module, Store};
+
+use crate::error::Error::*;
+use crate::error::{Result, Warning};
+use crate::Mail;
+use crate::Matcher;
+
+/// How many instructions (roughly) a plugin may execute per message before
+/// it's stopped
+#[cfg(feature = "wasm")]
+const FUEL: u64 = 1_000_000_000;
+
+/// A WebAssembly plugin providing logic regular expressions can't express
+///
+/// Plugins are sandboxed: they don't get to import anything, so all they can
+/// do is compute, and every call gets a fresh instance with a limited amount
+/// of fuel. Filters refer to a plugin by its name, as the special field
+/// `@plugin:<name>` in rules (see [`Plugin::field`]) and in
+/// [`Operations::plugins`].
+///
+/// A plugin module exports its `memory` and the functions
+///
+/// * `notcoal_alloc(len: i32) -> i32`, returning where to put an input of
+///   `len` bytes
+/// * `notcoal_match(ptr: i32, len: i32) -> i64` for rules, getting the
+///   message as stored on disk and returning the values of the field
+/// * `notcoal_apply(ptr: i32, len: i32) -> i64` for operations, getting the
+///   message the same way and returning tags to add
+///
+/// of which the last two are optional. Both return where their output is as
+/// `ptr << 32 | len`, with one value or tag per line.
+///
+/// Only available with the `wasm` feature, otherwise loading a plugin fails.
+///
+/// [`Plugin::field`]: struct.Plugin.html#method.field
+/// [`Operations::plugins`]: struct.Operations.html#structfield.plugins
+pub struct Plugin {
+    name: String,
+    #[cfg(feature = "wasm")]
+    engine: Engine,
+    #[cfg(feature = "wasm")]
+    module: Module,
+}
+
+impl std::fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Plugin").field("name", &self.name).finish()
+    }
+}
+
+impl Plugin {
+    /// Loads the plugin `name` from the WebAssembly module at `path`
+    #[cfg(feature = "wasm")]
+    pub fn load<P>(name: &str, path: &P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let err = |e: wasmtime::Error| PluginError(format!("{}: {}", name, e));
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(err)?;
+        let module = Module::new(&engine, std::fs::read(path)?).map_err(err)?;
+        Ok(Plugin {
+            name: name.to_string(),
+            engine,
+            module,
+        })
+    }
+
+    /// Loads the plugin `name` from the WebAssembly module at `path`
+    #[cfg(not(feature = "wasm"))]
+    pub fn load<P>(name: &str, _path: &P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let e = format!("{}: notcoal was built without the wasm feature", name);
+        Err(PluginError(e))
+    }
+
+    /// Name filters refer to the plugin by
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The special field rules use the plugin with, `@plugin:` followed by
+    /// its name
+    pub fn field(&self) -> String {
+        format!("@plugin:{}", self.name)
+    }
+
+    /// Tags the plugin's `notcoal_apply` adds to `msg`
+    pub fn apply(&self, msg: &dyn Mail) -> Result<Vec<String>> {
+        self.call("notcoal_apply", &msg.raw()?)
+    }
+
+    /// Calls `export` with `input`, returning the lines of its output
+    #[cfg(feature = "wasm")]
+    fn call(&self, export: &str, input: &[u8]) -> Result<Vec<String>> {
+        let err = |e: wasmtime::Error| PluginError(format!("{}: {}", self.name, e));
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(FUEL).map_err(err)?;
+        let instance = Instance::new(&mut store, &self.module, &[]).map_err(err)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| PluginError(format!("{}: doesn't export memory", self.name)))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "notcoal_alloc")
+            .map_err(err)?;
+        let func = instance
+            .get_typed_func::<(i32, i32), i64>(&mut store, export)
+            .map_err(err)?;
+        let len = i32::try_from(input.len())
+            .map_err(|_| PluginError(format!("{}: message too large", self.name)))?;
+        let ptr = alloc.call(&mut store, len).map_err(err)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, input)
+            .map_err(|e| err(e.into()))?;
+        let res = func.call(&mut store, (ptr, len)).map_err(err)?;
+        let mut output = vec![0; res as u32 as usize];
+        memory
+            .read(&store, (res >> 32) as u32 as usize, &mut output)
+            .map_err(|e| err(e.into()))?;
+        Ok(String::from_utf8_lossy(&output)
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Calls `export` with `input`, returning the lines of its output
+    #[cfg(not(feature = "wasm"))]
+    fn call(&self, _export: &str, _input: &[u8]) -> Result<Vec<String>> {
+        unreachable!("plugins can't be loaded without the wasm feature")
+    }
+}
+
+impl Matcher for Plugin {
+    fn values(&self, msg: &dyn Mail, _: &mut Vec<Warning>) -> Result<Option<Vec<String>>> {
+        Ok(Some(self.call("notcoal_match", &msg.raw()?)?))
+    }
+}
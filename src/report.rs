@@ -0,0 +1,541 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::result;
+use std::str::FromStr;
+
+use mailparse::{addrparse, MailAddr};
+
+use crate::error::*;
+use crate::mail::DbMail;
+use crate::Filter;
+
+use notmuch::Database;
+
+/// Output formats reports can be rendered in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Aligned plain text, for terminals
+    Text,
+    /// Markdown tables, e.g. for wikis
+    Markdown,
+    /// A static HTML page with bar charts
+    Html,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "markdown" | "md" => Ok(Format::Markdown),
+            "html" => Ok(Format::Html),
+            _ => Err(format!("Unknown output format {}", s)),
+        }
+    }
+}
+
+/// A titled table of strings, what all reports boil down to for rendering
+#[derive(Debug, Default)]
+pub struct Table {
+    pub title: String,
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+    /// Index of a numeric column to draw a bar chart for in HTML output
+    pub chart: Option<usize>,
+}
+
+impl Table {
+    fn new(title: &str, headers: &[&str]) -> Self {
+        Table {
+            title: title.to_string(),
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn render_text(&self, out: &mut String) {
+        let mut widths = self
+            .headers
+            .iter()
+            .map(|h| h.chars().count())
+            .collect::<Vec<_>>();
+        for row in &self.rows {
+            for (w, cell) in widths.iter_mut().zip(row) {
+                *w = (*w).max(cell.chars().count());
+            }
+        }
+        let line = |cells: &[String]| {
+            let cells = cells
+                .iter()
+                .zip(&widths)
+                .map(|(c, w)| format!("{:w$}", c, w = w))
+                .collect::<Vec<_>>();
+            cells.join("  ").trim_end().to_string()
+        };
+        let _ = writeln!(out, "{}:", self.title);
+        let _ = writeln!(out, "{}", line(&self.headers));
+        for row in &self.rows {
+            let _ = writeln!(out, "{}", line(row));
+        }
+    }
+
+    fn render_markdown(&self, out: &mut String) {
+        let escape = |s: &String| s.replace('|', "\\|");
+        let _ = writeln!(out, "## {}\n", self.title);
+        let headers = self.headers.iter().map(escape).collect::<Vec<_>>();
+        let _ = writeln!(out, "| {} |", headers.join(" | "));
+        let _ = writeln!(out, "|{}", "---|".repeat(self.headers.len()));
+        for row in &self.rows {
+            let _ = writeln!(
+                out,
+                "| {} |",
+                row.iter().map(escape).collect::<Vec<_>>().join(" | ")
+            );
+        }
+    }
+
+    fn render_html(&self, out: &mut String) {
+        let max = self
+            .chart
+            .map(|c| {
+                self.rows
+                    .iter()
+                    .filter_map(|r| r.get(c)?.parse::<usize>().ok())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .unwrap_or(0);
+        let _ = writeln!(out, "<h2>{}</h2>\n<table>", html_escape(&self.title));
+        let _ = write!(out, "<tr>");
+        for h in &self.headers {
+            let _ = write!(out, "<th>{}</th>", html_escape(h));
+        }
+        if self.chart.is_some() {
+            let _ = write!(out, "<th></th>");
+        }
+        let _ = writeln!(out, "</tr>");
+        for row in &self.rows {
+            let _ = write!(out, "<tr>");
+            for cell in row {
+                let _ = write!(out, "<td>{}</td>", html_escape(cell));
+            }
+            if let Some(c) = self.chart {
+                let n = row
+                    .get(c)
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .unwrap_or(0);
+                let width = (n * 100).checked_div(max).unwrap_or(0);
+                let _ = write!(
+                    out,
+                    "<td><div class=\"bar\" style=\"width: {}%\"></div></td>",
+                    width
+                );
+            }
+            let _ = writeln!(out, "</tr>");
+        }
+        let _ = writeln!(out, "</table>");
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Render tables in the given format
+///
+/// HTML output is a complete, self-contained page.
+pub fn render(title: &str, tables: &[Table], format: Format) -> String {
+    let mut out = String::new();
+    match format {
+        Format::Text => {
+            for (i, table) in tables.iter().enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                }
+                table.render_text(&mut out);
+            }
+        }
+        Format::Markdown => {
+            let _ = writeln!(out, "# {}", title);
+            for table in tables {
+                out.push('\n');
+                table.render_markdown(&mut out);
+            }
+        }
+        Format::Html => {
+            let _ = writeln!(
+                out,
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>",
+                html_escape(title)
+            );
+            let _ = writeln!(
+                out,
+                "<style>td, th {{ padding: 0 1em; text-align: left; }} \
+                 td:last-child {{ width: 30%; }} \
+                 .bar {{ background: steelblue; height: 1em; }}</style>"
+            );
+            let _ = writeln!(out, "</head>\n<body>\n<h1>{}</h1>", html_escape(title));
+            for table in tables {
+                table.render_html(&mut out);
+            }
+            let _ = writeln!(out, "</body>\n</html>");
+        }
+    }
+    out
+}
+
+/// Comparison of the tags in a database and those used by a set of filters,
+/// see [`tag_report`]
+///
+/// [`tag_report`]: fn.tag_report.html
+#[derive(Debug, Default)]
+pub struct TagReport {
+    /// Tags present in the database that no filter adds
+    pub unproduced: Vec<String>,
+    /// Tags added by filters that no message in the database has, as filter
+    /// name and tag
+    pub unused: Vec<(String, String)>,
+}
+
+impl TagReport {
+    /// Prepares the report for [`render`]
+    ///
+    /// [`render`]: fn.render.html
+    pub fn tables(&self) -> Vec<Table> {
+        let mut unproduced = Table::new("Tags no filter adds", &["tag"]);
+        unproduced.rows = self.unproduced.iter().map(|t| vec![t.clone()]).collect();
+        let mut unused = Table::new(
+            "Tags added by filters that no message has",
+            &["tag", "filter"],
+        );
+        unused.rows = self
+            .unused
+            .iter()
+            .map(|(f, t)| vec![t.clone(), f.clone()])
+            .collect();
+        vec![unproduced, unused]
+    }
+}
+
+/// Compare tags present in the database against tags added by filters
+///
+/// Helps cleaning up tag taxonomies that grew over the years, by listing both
+/// tags nothing produces (anymore) and filters whose tags never stuck.
+pub fn tag_report(db: &Database, filters: &[Filter]) -> Result<TagReport> {
+    let present = db.all_tags()?.collect::<BTreeSet<String>>();
+    let mut produced = BTreeSet::new();
+    let mut report = TagReport::default();
+    for filter in filters {
+        let urgency_tag = filter.urgency.and_then(|u| u.tag());
+        for tag in filter.op.added_tags().into_iter().chain(urgency_tag) {
+            if !present.contains(tag) {
+                report.unused.push((filter.name(), tag.to_string()));
+            }
+            produced.insert(tag);
+        }
+    }
+    report.unproduced = present
+        .iter()
+        .filter(|t| !produced.contains(t.as_str()))
+        .cloned()
+        .collect();
+    Ok(report)
+}
+
+/// Messages no filter matched, see [`coverage_report`]
+///
+/// [`coverage_report`]: fn.coverage_report.html
+#[derive(Debug, Default)]
+pub struct CoverageReport {
+    /// How many messages were checked
+    pub total: usize,
+    /// Message-ID and `From` header of messages no filter matched
+    pub unmatched: Vec<(String, Option<String>)>,
+    /// Non-fatal conditions that came up along the way
+    pub warnings: Vec<Warning>,
+}
+
+impl CoverageReport {
+    /// Counts unmatched messages by sender domain
+    ///
+    /// Messages without a parseable `From` header are counted under an empty
+    /// domain.
+    pub fn by_domain(&self) -> BTreeMap<String, usize> {
+        let mut domains = BTreeMap::new();
+        for (_, from) in &self.unmatched {
+            let domain = from.as_deref().and_then(sender_domain).unwrap_or_default();
+            *domains.entry(domain).or_insert(0) += 1;
+        }
+        domains
+    }
+
+    /// Prepares the report for [`render`], optionally counting messages by
+    /// sender domain instead of listing them
+    ///
+    /// [`render`]: fn.render.html
+    pub fn tables(&self, by_domain: bool) -> Vec<Table> {
+        let title = format!(
+            "{} of {} messages matched no filter",
+            self.unmatched.len(),
+            self.total
+        );
+        if by_domain {
+            let mut table = Table::new(&title, &["messages", "domain"]);
+            let mut domains = self.by_domain().into_iter().collect::<Vec<_>>();
+            domains.sort_by_key(|d| Reverse(d.1));
+            table.rows = domains
+                .into_iter()
+                .map(|(d, n)| vec![n.to_string(), d])
+                .collect();
+            table.chart = Some(0);
+            vec![table]
+        } else {
+            let mut table = Table::new(&title, &["message-id", "from"]);
+            table.rows = self
+                .unmatched
+                .iter()
+                .map(|(id, from)| vec![id.clone(), from.clone().unwrap_or_default()])
+                .collect();
+            vec![table]
+        }
+    }
+}
+
+/// Extracts the first address in a header value
+pub(crate) fn sender_address(header: &str) -> Option<String> {
+    let addrs = addrparse(header).ok()?;
+    match addrs.iter().next()? {
+        MailAddr::Single(info) => Some(info.addr.clone()),
+        MailAddr::Group(group) => Some(group.addrs.first()?.addr.clone()),
+    }
+}
+
+/// Extracts the (lowercased) domain of the first address in a header value
+pub(crate) fn sender_domain(header: &str) -> Option<String> {
+    let addr = sender_address(header)?;
+    let (_, domain) = addr.rsplit_once('@')?;
+    Some(domain.to_lowercase())
+}
+
+/// Find messages matching a notmuch query that none of the filters match
+///
+/// Useful to discover what's slipping through and which filters are still
+/// missing.
+pub fn coverage_report(db: &Database, query: &str, filters: &[Filter]) -> Result<CoverageReport> {
+    let q = db.create_query(query)?;
+    let mut report = CoverageReport::default();
+    'messages: for msg in q.search_messages()? {
+        report.total += 1;
+        let mail = DbMail::new(&msg, db, None, true);
+        for filter in filters {
+            if filter.is_mail_match(&mail, &mut report.warnings)? {
+                continue 'messages;
+            }
+        }
+        let from = msg.header("from")?.map(|f| f.to_string());
+        report.unmatched.push((msg.id().to_string(), from));
+    }
+    Ok(report)
+}
+
+/// How many messages a sender sent and how many of those a filter matched
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SenderStats {
+    /// Messages from this sender
+    pub messages: usize,
+    /// Messages from this sender that at least one filter matched
+    pub matched: usize,
+}
+
+/// Message counts by sender, see [`sender_report`]
+///
+/// [`sender_report`]: fn.sender_report.html
+#[derive(Debug, Default)]
+pub struct SenderReport {
+    /// Statistics by sender, which is either the `List-Id` (prefixed with
+    /// `list:`) or the `From` domain
+    pub senders: BTreeMap<String, SenderStats>,
+    /// Non-fatal conditions that came up along the way
+    pub warnings: Vec<Warning>,
+}
+
+impl SenderReport {
+    /// Returns senders ordered by how many messages they sent, most first
+    pub fn by_volume(&self) -> Vec<(&str, SenderStats)> {
+        let mut senders = self
+            .senders
+            .iter()
+            .map(|(s, stats)| (s.as_str(), *stats))
+            .collect::<Vec<_>>();
+        senders.sort_by_key(|s| Reverse(s.1.messages));
+        senders
+    }
+
+    /// Prepares the report for [`render`]
+    ///
+    /// [`render`]: fn.render.html
+    pub fn tables(&self) -> Vec<Table> {
+        let mut table = Table::new("Messages by sender", &["messages", "filtered", "sender"]);
+        table.rows = self
+            .by_volume()
+            .into_iter()
+            .map(|(s, stats)| {
+                vec![
+                    stats.messages.to_string(),
+                    stats.matched.to_string(),
+                    s.to_string(),
+                ]
+            })
+            .collect();
+        table.chart = Some(0);
+        vec![table]
+    }
+}
+
+/// Normalizes a `List-Id` header value to the identifier between the angle
+/// brackets, dropping the (optional) description
+pub(crate) fn list_id(header: &str) -> String {
+    match (header.rfind('<'), header.rfind('>')) {
+        (Some(start), Some(end)) if start < end => header[start + 1..end].trim().to_lowercase(),
+        _ => header.trim().to_lowercase(),
+    }
+}
+
+/// Identifies the mailing list a message was sent through, using the
+/// `List-Id`, `List-Post` or `X-Mailing-List` header (in that order), as
+/// looked up by `header`
+///
+/// The identifier is normalized like [`list_id`] does, with list addresses
+/// like `foo@lists.example.org` turned into `foo.lists.example.org`.
+pub(crate) fn mailing_list<F>(header: F) -> Result<Option<String>>
+where
+    F: Fn(&str) -> Result<Option<String>>,
+{
+    if let Some(id) = header("list-id")?.filter(|id| !id.trim().is_empty()) {
+        return Ok(Some(list_id(&id)));
+    }
+    for name in ["list-post", "x-mailing-list"] {
+        if let Some(value) = header(name)? {
+            let addr = list_id(&value);
+            let addr = addr.trim_start_matches("mailto:");
+            // List-Post may have further parameters, like ?subject=
+            let addr = addr.split(['?', ',']).next().unwrap_or_default().trim();
+            if !addr.is_empty() && addr != "no" {
+                return Ok(Some(addr.replace('@', ".")));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Short name of a mailing list, the first part of its identifier, see
+/// [`mailing_list`]
+pub(crate) fn list_name(id: &str) -> &str {
+    id.split('.').next().unwrap_or(id)
+}
+
+/// Summarize how many messages matching a notmuch query come from which
+/// mailing list or sender domain, and how many of them filters already match
+///
+/// Meant to point out where new filters would have the biggest impact.
+pub fn sender_report(db: &Database, query: &str, filters: &[Filter]) -> Result<SenderReport> {
+    let q = db.create_query(query)?;
+    let mut report = SenderReport::default();
+    for msg in q.search_messages()? {
+        let sender = match msg.header("list-id")? {
+            Some(id) if !id.trim().is_empty() => format!("list:{}", list_id(&id)),
+            _ => msg
+                .header("from")?
+                .and_then(|f| sender_domain(&f))
+                .unwrap_or_default(),
+        };
+        let mut matched = false;
+        let mail = DbMail::new(&msg, db, None, true);
+        for filter in filters {
+            if filter.is_mail_match(&mail, &mut report.warnings)? {
+                matched = true;
+                break;
+            }
+        }
+        let stats = report.senders.entry(sender).or_default();
+        stats.messages += 1;
+        if matched {
+            stats.matched += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// How often each filter matched, see [`filter_report`]
+///
+/// [`filter_report`]: fn.filter_report.html
+#[derive(Debug, Default)]
+pub struct FilterReport {
+    /// Filter names and how many messages they matched, in filter order
+    pub matches: Vec<(String, usize)>,
+    /// The most recent matches as date, Message-ID and filter name, newest
+    /// first
+    pub recent: Vec<(i64, String, String)>,
+    /// Non-fatal conditions that came up along the way
+    pub warnings: Vec<Warning>,
+}
+
+impl FilterReport {
+    /// Prepares the report for [`render`]
+    ///
+    /// [`render`]: fn.render.html
+    pub fn tables(&self) -> Vec<Table> {
+        let mut matches = Table::new("Matches by filter", &["matches", "filter"]);
+        matches.rows = self
+            .matches
+            .iter()
+            .map(|(f, n)| vec![n.to_string(), f.clone()])
+            .collect();
+        matches.chart = Some(0);
+        let mut recent = Table::new("Recent matches", &["date", "message-id", "filter"]);
+        recent.rows = self
+            .recent
+            .iter()
+            .map(|(d, id, f)| vec![d.to_string(), id.clone(), f.clone()])
+            .collect();
+        vec![matches, recent]
+    }
+}
+
+/// Count how many messages matching a notmuch query each filter matches, and
+/// keep track of the `recent` newest matches
+pub fn filter_report(
+    db: &Database,
+    query: &str,
+    filters: &[Filter],
+    recent: usize,
+) -> Result<FilterReport> {
+    let q = db.create_query(query)?;
+    let mut report = FilterReport {
+        matches: filters.iter().map(|f| (f.name(), 0)).collect(),
+        ..Default::default()
+    };
+    for msg in q.search_messages()? {
+        let mail = DbMail::new(&msg, db, None, true);
+        for (i, filter) in filters.iter().enumerate() {
+            if filter.is_mail_match(&mail, &mut report.warnings)? {
+                report.matches[i].1 += 1;
+                report
+                    .recent
+                    .push((msg.date(), msg.id().to_string(), filter.name()));
+            }
+        }
+        if report.recent.len() > recent * 2 {
+            report.recent.sort_by_key(|r| Reverse(r.0));
+            report.recent.truncate(recent);
+        }
+    }
+    report.recent.sort_by_key(|r| Reverse(r.0));
+    report.recent.truncate(recent);
+    Ok(report)
+}
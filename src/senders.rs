@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::date::{now, DAY};
+use crate::error::Warning::*;
+use crate::error::{Result, Warning};
+use crate::report::sender_address;
+use crate::state;
+use crate::Mail;
+use crate::Matcher;
+
+use notmuch::Database;
+
+/// How long after a message was sent it's considered dealt with, i.e.
+/// either replied to, archived or left alone
+const SETTLE_TIME: i64 = 7 * DAY;
+
+/// How often messages from each sender were replied to or archived unread,
+/// behind `@sender-score` rules
+///
+/// Runs record every message they process here (see
+/// [`FilterOptions::senders`]). Once it's a week old, [`update_sender_history`]
+/// looks at what became of it: whether it's tagged `replied`, or lost its `inbox` tag
+/// while still `unread`, i.e. was archived without being read. Senders are
+/// identified by their lowercased address.
+///
+/// [`FilterOptions::senders`]: struct.FilterOptions.html#structfield.senders
+/// [`update_sender_history`]: fn.update_sender_history.html
+#[derive(Debug, Clone)]
+pub struct SenderHistory {
+    path: PathBuf,
+    stats: StatsFile,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct StatsFile {
+    senders: BTreeMap<String, SenderStat>,
+    /// Messages not settled yet, by Message-ID
+    pending: BTreeMap<String, Pending>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Pending {
+    sender: String,
+    since: i64,
+}
+
+/// What became of the messages from a single sender
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SenderStat {
+    /// Messages looked at so far
+    pub received: u32,
+    /// Messages that were replied to
+    pub replied: u32,
+    /// Messages that were archived without being read
+    pub ignored: u32,
+}
+
+impl SenderStat {
+    /// The sender's score, from -1 if every message was archived unread to 1
+    /// if every one was replied to, or `None` if there were none yet
+    pub fn score(&self) -> Option<f64> {
+        match self.received {
+            0 => None,
+            n => Some((f64::from(self.replied) - f64::from(self.ignored)) / f64::from(n)),
+        }
+    }
+}
+
+impl SenderHistory {
+    /// Reads the statistics from `path`, starting out empty if there's no
+    /// such file yet
+    pub fn open<P>(path: &P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let stats = state::read(path.as_ref())?;
+        Ok(SenderHistory {
+            path: path.as_ref().to_path_buf(),
+            stats,
+        })
+    }
+
+    /// Statistics of the sender with address `addr`, if any of their
+    /// messages settled yet
+    pub fn get(&self, addr: &str) -> Option<&SenderStat> {
+        self.stats.senders.get(&addr.to_lowercase())
+    }
+
+    /// All senders with statistics, by address
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &SenderStat)> {
+        self.stats.senders.iter()
+    }
+
+    /// How many messages weren't looked at yet
+    pub fn pending(&self) -> usize {
+        self.stats.pending.len()
+    }
+
+    /// Records a message to look at once it settled, unless it's recorded
+    /// already or has no sender
+    pub(crate) fn record(&mut self, msg: &dyn Mail) -> Result<()> {
+        let from = msg.header("from")?;
+        if let Some(sender) = from.as_deref().and_then(sender_address) {
+            let pending = Pending {
+                sender: sender.to_lowercase(),
+                since: msg.date().unwrap_or_else(now),
+            };
+            self.stats.pending.entry(msg.id()).or_insert(pending);
+        }
+        Ok(())
+    }
+
+    /// A snapshot of the current scores for `@sender-score` rules, to
+    /// register with [`Filter::register`] or [`register_matcher`]
+    ///
+    /// [`Filter::register`]: struct.Filter.html#method.register
+    /// [`register_matcher`]: fn.register_matcher.html
+    pub fn matcher(&self) -> Rc<dyn Matcher> {
+        let scores = self
+            .stats
+            .senders
+            .iter()
+            .filter_map(|(addr, stat)| Some((addr.clone(), stat.score()?)))
+            .collect();
+        Rc::new(SenderScores(scores))
+    }
+
+    /// Writes the statistics back to the file they were read from
+    pub fn save(&self) -> Result<()> {
+        state::write(&self.path, &self.stats)
+    }
+}
+
+/// Scores by sender address, see [`SenderHistory::matcher`]
+///
+/// [`SenderHistory::matcher`]: struct.SenderHistory.html#method.matcher
+struct SenderScores(BTreeMap<String, f64>);
+
+impl Matcher for SenderScores {
+    fn values(&self, msg: &dyn Mail, _: &mut Vec<Warning>) -> Result<Option<Vec<String>>> {
+        let from = msg.header("from")?;
+        let score = from
+            .as_deref()
+            .and_then(sender_address)
+            .and_then(|addr| self.0.get(&addr.to_lowercase()));
+        Ok(Some(score.map(|s| s.to_string()).into_iter().collect()))
+    }
+
+    fn cost(&self) -> u8 {
+        0
+    }
+}
+
+/// Looks at what became of the recorded messages that settled, adding them
+/// to their senders' statistics
+///
+/// Returns how many messages were looked at. Messages that aren't in the
+/// database anymore are forgotten with a [`Warning`].
+///
+/// [`Warning`]: error/enum.Warning.html
+pub fn update_sender_history(
+    db: &Database,
+    senders: &mut SenderHistory,
+) -> Result<(usize, Vec<Warning>)> {
+    let settled_before = now() - SETTLE_TIME;
+    let settled: Vec<(String, String)> = senders
+        .stats
+        .pending
+        .iter()
+        .filter(|(_, p)| p.since <= settled_before)
+        .map(|(id, p)| (id.clone(), p.sender.clone()))
+        .collect();
+    let mut warnings = Vec::new();
+    for (id, sender) in &settled {
+        senders.stats.pending.remove(id);
+        let msg = match db.find_message(id)? {
+            Some(msg) => msg,
+            None => {
+                let w = format!("{}: not in the database anymore", id);
+                warnings.push(SkippedMessage(w));
+                continue;
+            }
+        };
+        let tags: Vec<String> = msg.tags().collect();
+        let has = |tag: &str| tags.iter().any(|t| t == tag);
+        let stat = senders.stats.senders.entry(sender.clone()).or_default();
+        stat.received += 1;
+        if has("replied") {
+            stat.replied += 1;
+        } else if has("unread") && !has("inbox") {
+            stat.ignored += 1;
+        }
+    }
+    Ok((settled.len(), warnings))
+}
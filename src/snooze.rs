@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::date::{after, now};
+use crate::error::Warning::*;
+use crate::error::{Error, Result, RunError, Stage, Warning};
+use crate::state;
+use crate::Hooks;
+
+use notmuch::{Database, Message};
+
+/// Tags snoozed messages are hidden from until they wake up again
+pub(crate) const SNOOZED_TAGS: &[&str] = &["inbox", "unread"];
+
+/// Messages taken out of the inbox for a while, see [`Operations::snooze`]
+///
+/// Snoozing a message removes its `inbox` and `unread` tags and records it
+/// here, along with when it's due and which of those tags it had. Once due,
+/// [`wake`] gives them back. Messages are identified by their Message-ID.
+///
+/// [`Operations::snooze`]: struct.Operations.html#structfield.snooze
+/// [`wake`]: fn.wake.html
+#[derive(Debug, Clone)]
+pub struct Snoozes {
+    path: PathBuf,
+    snoozed: BTreeMap<String, Snoozed>,
+    _lock: state::Lock,
+}
+
+/// A single snoozed message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snoozed {
+    /// Unix timestamp of when the message is due again
+    pub until: i64,
+    /// Tags to add back once it is
+    pub tags: Vec<String>,
+}
+
+impl Snoozes {
+    /// Reads the snoozed messages from `path`, starting out empty if there's
+    /// no such file yet
+    ///
+    /// Waits for whoever else has them open, e.g. a concurrent `wake`, until
+    /// they're dropped.
+    pub fn open<P>(path: &P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let (snoozed, lock) = state::open(path.as_ref())?;
+        Ok(Snoozes {
+            path: path.as_ref().to_path_buf(),
+            snoozed,
+            _lock: lock,
+        })
+    }
+
+    /// The message with Message-ID `id`, if it's snoozed
+    pub fn get(&self, id: &str) -> Option<&Snoozed> {
+        self.snoozed.get(id)
+    }
+
+    /// All snoozed messages by Message-ID, due or not
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Snoozed)> {
+        self.snoozed.iter()
+    }
+
+    /// Records a message, replacing when it's due if it's snoozed already
+    /// and keeping the tags it had back then
+    pub fn insert(&mut self, id: &str, until: i64, tags: Vec<String>) {
+        let snoozed = self.snoozed.entry(id.to_string()).or_insert(Snoozed {
+            until,
+            tags: Vec::new(),
+        });
+        snoozed.until = until;
+        for tag in tags {
+            if !snoozed.tags.contains(&tag) {
+                snoozed.tags.push(tag);
+            }
+        }
+    }
+
+    /// How many messages are snoozed
+    pub fn len(&self) -> usize {
+        self.snoozed.len()
+    }
+
+    /// Checks if no message is snoozed
+    pub fn is_empty(&self) -> bool {
+        self.snoozed.is_empty()
+    }
+
+    /// Writes the snoozed messages back to the file they were read from
+    pub fn save(&self) -> Result<()> {
+        state::write(&self.path, &self.snoozed)
+    }
+}
+
+/// Snoozes a message for `duration`, recording it in `snoozes`
+pub(crate) fn snooze_message(
+    msg: &Message,
+    duration: &str,
+    name: &str,
+    snoozes: &mut Snoozes,
+    hooks: &Hooks,
+) -> Result<()> {
+    let wrap = |e: Error, stage| RunError::wrap(e, msg, Some(name), stage);
+    let until = after(now(), duration, "snooze").map_err(|e| wrap(e, Stage::RemoveTags))?;
+    let tags: Vec<String> = msg.tags().collect();
+    let mut removed = Vec::new();
+    for tag in SNOOZED_TAGS
+        .iter()
+        .filter(|t| tags.iter().any(|have| have == *t))
+    {
+        msg.remove_tag(tag)
+            .map_err(|e| wrap(e.into(), Stage::RemoveTags))?;
+        if let Some(hook) = &hooks.on_tag_removed {
+            hook(msg, tag);
+        }
+        removed.push(tag.to_string());
+    }
+    snoozes.insert(&msg.id(), until, removed);
+    Ok(())
+}
+
+/// Gives snoozed messages that are due their tags back and forgets about
+/// them
+///
+/// Returns the Message-IDs of the messages woken up. Messages that aren't in
+/// the database anymore are forgotten with a [`Warning`].
+///
+/// [`Warning`]: error/enum.Warning.html
+pub fn wake(db: &Database, snoozes: &mut Snoozes) -> Result<(Vec<String>, Vec<Warning>)> {
+    let now = now();
+    let due: Vec<String> = snoozes
+        .snoozed
+        .iter()
+        .filter(|(_, s)| s.until <= now)
+        .map(|(id, _)| id.clone())
+        .collect();
+    let mut woken = Vec::new();
+    let mut warnings = Vec::new();
+    for id in due {
+        let msg = match db.find_message(&id)? {
+            Some(msg) => msg,
+            None => {
+                let w = format!("{}: not in the database anymore", id);
+                warnings.push(SkippedMessage(w));
+                snoozes.snoozed.remove(&id);
+                continue;
+            }
+        };
+        if let Some(snoozed) = snoozes.snoozed.get(&id) {
+            for tag in &snoozed.tags {
+                msg.add_tag(tag)
+                    .map_err(|e| RunError::wrap(e, &msg, None, Stage::AddTags))?;
+            }
+        }
+        snoozes.snoozed.remove(&id);
+        woken.push(id);
+    }
+    Ok((woken, warnings))
+}
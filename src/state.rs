@@ -0,0 +1,128 @@
+/*!
+JSON files state is kept in across runs, e.g. by [`Snoozes`] and
+[`MatchCache`]
+
+Files are replaced rather than rewritten in place, so an interrupted run
+leaves either the old or the new state behind, never a truncated file.
+State that's read to be changed and written back is opened with [`open`],
+which keeps others doing the same waiting until it's written, e.g. `notcoal
+wake` while messages are snoozed.
+
+[`Snoozes`]: ../struct.Snoozes.html
+[`MatchCache`]: ../struct.MatchCache.html
+[`open`]: fn.open.html
+*/
+
+use std::fs::{self, File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::maildir::{replace_file, unique_name};
+
+/// An exclusive advisory lock on the state stored at a path, held until it's
+/// dropped along with all its clones, see [`open`]
+///
+/// [`open`]: fn.open.html
+#[derive(Debug, Clone)]
+pub(crate) struct Lock {
+    _file: Arc<File>,
+}
+
+/// The file locked for the state stored at `path`, e.g. `snoozed.json.lock`
+/// for `snoozed.json`
+fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    path.with_file_name(name)
+}
+
+/// Reads the state stored at `path` like [`read`], after waiting for a lock
+/// on it others opening it have to wait for in turn
+///
+/// Meant for state that's written back with [`write`] once changed, which
+/// the lock should be held until.
+///
+/// [`read`]: fn.read.html
+/// [`write`]: fn.write.html
+pub(crate) fn open<T: DeserializeOwned + Default>(path: &Path) -> Result<(T, Lock)> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path(path))?;
+    file.lock()?;
+    let lock = Lock {
+        _file: Arc::new(file),
+    };
+    Ok((read(path)?, lock))
+}
+
+/// Reads the state stored at `path`, starting out with the default if
+/// there's no such file yet
+pub(crate) fn read<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    match fs::read(path) {
+        Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Stores `state` at `path`, creating its directory if needed
+///
+/// The state is written to a temporary file next to it first and renamed to
+/// `path` once it's synced to disk, see [`replace_file`].
+///
+/// [`replace_file`]: ../maildir/fn.replace_file.html
+pub(crate) fn write<T: Serialize>(path: &Path, state: &T) -> Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+    let tmp = dir.join(format!(".{}", unique_name()));
+    replace_file(&tmp, path, &serde_json::to_vec(state)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn state_is_replaced_without_leftovers() {
+        let dir = std::env::temp_dir().join(format!("notcoal-state-{}", unique_name()));
+        let path = dir.join("nested").join("state.json");
+        let missing: BTreeMap<String, i64> = read(&path).unwrap();
+        assert!(missing.is_empty());
+
+        let mut state = BTreeMap::from([("<a@example.org>".to_string(), 1)]);
+        write(&path, &state).unwrap();
+        state.insert("<b@example.org>".to_string(), 2);
+        write(&path, &state).unwrap();
+        assert_eq!(read::<BTreeMap<String, i64>>(&path).unwrap(), state);
+
+        let files: Vec<_> = fs::read_dir(path.parent().unwrap()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn opened_state_is_locked_until_dropped() {
+        let dir = std::env::temp_dir().join(format!("notcoal-state-{}", unique_name()));
+        let path = dir.join("state.json");
+        let (state, lock): (BTreeMap<String, i64>, _) = open(&path).unwrap();
+        assert!(state.is_empty());
+        let other = File::open(lock_path(&path)).unwrap();
+        assert!(other.try_lock().is_err());
+        let clone = lock.clone();
+        drop(lock);
+        assert!(other.try_lock().is_err());
+        drop(clone);
+        other.try_lock().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
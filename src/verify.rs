@@ -0,0 +1,111 @@
+#[cfg(feature = "dkim")]
+use std::cell::OnceCell;
+#[cfg(feature = "smime")]
+use std::io::Write;
+#[cfg(feature = "smime")]
+use std::process::{Command, Stdio};
+
+#[cfg(feature = "dkim")]
+use mail_auth::{AuthenticatedMessage, DkimResult, MessageAuthenticator};
+#[cfg(feature = "dkim")]
+use tokio::runtime::{Builder, Runtime};
+
+use crate::error::Error::*;
+use crate::error::Result;
+#[cfg(feature = "smime")]
+use crate::maildir::unique_name;
+
+#[cfg(feature = "dkim")]
+thread_local! {
+    /// Runtime and resolver for DKIM lookups, set up with the first message
+    /// needing them
+    static DKIM: OnceCell<(Runtime, MessageAuthenticator)> = const { OnceCell::new() };
+}
+
+/// Domains of the DKIM signatures of a message that verified, with their
+/// keys looked up using the system's DNS configuration
+#[cfg(feature = "dkim")]
+pub(crate) fn dkim_valid(raw: &[u8]) -> Result<Vec<String>> {
+    let msg = match AuthenticatedMessage::parse(raw) {
+        Some(msg) => msg,
+        None => return Ok(Vec::new()),
+    };
+    DKIM.with(|dkim| {
+        let (runtime, authenticator) = match dkim.get() {
+            Some(dkim) => dkim,
+            None => {
+                let runtime = Builder::new_current_thread().enable_all().build()?;
+                let authenticator = MessageAuthenticator::new_system_conf()
+                    .map_err(|e| UnsupportedValue(format!("DNS resolver: {}", e)))?;
+                dkim.get_or_init(|| (runtime, authenticator))
+            }
+        };
+        let outputs = runtime.block_on(authenticator.verify_dkim(&msg));
+        Ok(outputs
+            .iter()
+            .filter(|o| *o.result() == DkimResult::Pass)
+            .filter_map(|o| o.signature())
+            .map(|s| s.d.to_lowercase())
+            .collect())
+    })
+}
+
+/// Runs `openssl` with `args`, feeding it `input`, and returns its output if
+/// it exited successfully
+#[cfg(feature = "smime")]
+fn openssl(args: &[&str], input: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut child = Command::new("openssl")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take();
+    // openssl may start writing before it read everything
+    let output = std::thread::scope(|s| {
+        s.spawn(|| match stdin.take() {
+            Some(mut stdin) => stdin.write_all(input),
+            None => Ok(()),
+        });
+        child.wait_with_output()
+    })?;
+    Ok(output.status.success().then_some(output.stdout))
+}
+
+/// Subject and email addresses of the certificate an S/MIME signed message
+/// was signed with, if the signature verified against the system's trusted
+/// certificates
+#[cfg(feature = "smime")]
+pub(crate) fn smime_valid(raw: &[u8]) -> Result<Vec<String>> {
+    let signer = std::env::temp_dir().join(format!("notcoal-{}.pem", unique_name()));
+    let signer_arg = signer.to_string_lossy().into_owned();
+    let verified = openssl(
+        &[
+            "smime",
+            "-verify",
+            "-signer",
+            &signer_arg,
+            "-out",
+            "/dev/null",
+        ],
+        raw,
+    );
+    let cert = std::fs::read(&signer);
+    let _ = std::fs::remove_file(&signer);
+    let cert = match (verified?, cert) {
+        (Some(_), Ok(cert)) => cert,
+        _ => return Ok(Vec::new()),
+    };
+    let info = match openssl(&["x509", "-noout", "-subject", "-email"], &cert)? {
+        Some(info) => info,
+        None => {
+            let e = "Couldn't read the signer's certificate".to_string();
+            return Err(UnsupportedValue(e));
+        }
+    };
+    Ok(String::from_utf8_lossy(&info)
+        .lines()
+        .map(|l| l.strip_prefix("subject=").unwrap_or(l).trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect())
+}